@@ -1,15 +1,22 @@
+// 再生位置を Wasm 側に通知する間隔（process() の呼び出し回数単位）
+// process() は 128 サンプル毎に呼ばれるので、48 kHz の場合にはおよそ 85 ミリ秒間隔となる
+const POSITION_REPORT_INTERVAL = 32
+
 // WebCodecs の音声デコーダーが生成した AudioData の中身を MediaTrack に伝えるためのプロセッサー
 class Mp4MediaStreamAudioWorkletProcessor extends AudioWorkletProcessor {
   constructor() {
     super()
     this.inputBuffer = []
     this.offset = 0
+    this.processCount = 0
     this.port.onmessage = (e) => {
       this.inputBuffer.push(e.data)
     }
   }
 
   process(inputs, outputs, parameters) {
+    this.reportPosition(outputs[0].length)
+
     for (let sampleIdx = 0; sampleIdx < outputs[0][0].length; sampleIdx++) {
       for (let channelIdx = 0; channelIdx < outputs[0].length; channelIdx++) {
         const outputChannel = outputs[0][channelIdx]
@@ -17,12 +24,9 @@ class Mp4MediaStreamAudioWorkletProcessor extends AudioWorkletProcessor {
         if (audioData === undefined) {
           // ここに来るのは、入力音声データにギャップがあるか、
           // デコード処理が詰まっていてデータの到着が遅れているケースが考えられる。
-          // 後者の場合には、ここでゼロで埋めた分だけ後で破棄しないと、
-          // 映像とのリップシンクがズレていってしまう。
           //
-          // this.inputBuffer の中にはタイムスタンプの情報も含まれているので、
-          // それを見て、より正確なゼロ埋めやサンプル破棄を行うことは可能なので、
-          // 実際にこういったケースが問題になることがあれば対応を検討すること。
+          // 後者の場合でも、映像の再生タイミングは reportPosition() で通知している
+          // 音声の再生位置に追従するため、ゼロで埋めた分だけ映像も遅れて、リップシンクは維持される。
           outputChannel[sampleIdx] = 0
         } else {
           outputChannel[sampleIdx] = audioData.samples[this.offset]
@@ -36,6 +40,25 @@ class Mp4MediaStreamAudioWorkletProcessor extends AudioWorkletProcessor {
     }
     return true
   }
+
+  // 現在再生しようとしている音声データのタイムスタンプ（マイクロ秒）を定期的にメインスレッドに通知する
+  reportPosition(numberOfChannels) {
+    this.processCount++
+    if (this.processCount < POSITION_REPORT_INTERVAL) {
+      return
+    }
+
+    const audioData = this.inputBuffer[0]
+    if (audioData === undefined) {
+      // 再生するデータがない場合には、再生位置は進んでいないので通知しない
+      return
+    }
+    this.processCount = 0
+
+    const frameOffset = Math.floor(this.offset / numberOfChannels)
+    const timestamp = audioData.timestamp + (frameOffset / sampleRate) * 1_000_000
+    this.port.postMessage({ timestamp })
+  }
 }
 
 registerProcessor('mp4-media-stream-audio-worklet-processor', Mp4MediaStreamAudioWorkletProcessor)
@@ -14,8 +14,300 @@ interface PlayOptions {
    * デフォルト値は false
    */
   repeat?: boolean
+
+  /**
+   * 再生位置が更新された際に呼び出されるコールバック
+   *
+   * 引数には MP4 ファイルの先頭からの再生位置（秒単位）が渡されます。
+   * 呼び出しは再生中におよそ 250 ミリ秒間隔で行われ、終端に達した時にも一度呼び出されます。
+   * repeat が true の場合には、先頭に戻るたびに再生位置も 0 に戻ります。
+   */
+  onTimeUpdate?: (currentTime: number) => void
+
+  /**
+   * 字幕のキューの表示開始時刻に達した際に呼び出されるコールバック
+   *
+   * MP4 ファイルに字幕トラック（tx3g / wvtt / stpp）が含まれる場合に、再生位置に合わせて呼び出されます。
+   * キューの表示を終了するタイミングは、引数の {@link SubtitleCue.endTime} を参照して判断してください。
+   * 再生の遅れなどによって、呼び出し前に表示終了時刻を過ぎてしまったキューは渡されません。
+   */
+  onCue?: (cue: SubtitleCue) => void
+
+  /**
+   * 指定された場合には、字幕のテキストを映像フレームに直接描画（焼き込み）します
+   *
+   * 字幕トラックを扱えない WebRTC の受信側などでも字幕を表示できるようにするためのオプションです。
+   * 焼き込まれるのは {@link BurnInCaptionOptions.trackId} で指定された一つの字幕トラックのみで、
+   * 映像トラックがない場合や字幕トラックがない場合には何も行われません。
+   * このオプションを指定した場合でも {@link PlayOptions.onCue} は通常通り呼び出されます。
+   */
+  burnInCaptions?: BurnInCaptionOptions
+}
+
+/**
+ * {@link PlayOptions.burnInCaptions} に指定可能なオプション
+ */
+interface BurnInCaptionOptions {
+  /**
+   * 焼き込み対象の字幕トラックのトラック ID
+   *
+   * デフォルトは {@link Mp4MediaStream.subtitleTracks} が返す最初のトラック
+   */
+  trackId?: number
+
+  /**
+   * フォントサイズ（ピクセル単位）
+   *
+   * デフォルトは映像の高さの 1/20
+   */
+  fontSize?: number
+
+  /**
+   * フォントファミリー（CSS の font-family と同じ形式）
+   *
+   * デフォルト値は 'sans-serif'
+   */
+  fontFamily?: string
+
+  /**
+   * 文字色（CSS の色の形式）
+   *
+   * デフォルト値は 'white'
+   */
+  color?: string
+
+  /**
+   * 字幕を表示する位置（映像の上端あるいは下端）
+   *
+   * デフォルト値は 'bottom'
+   */
+  position?: 'top' | 'bottom'
+
+  /**
+   * 字幕の各行の背景色（CSS の色の形式）
+   *
+   * 'transparent' を指定すると背景は描画されません。
+   * デフォルト値は 'rgba(0, 0, 0, 0.6)'
+   */
+  backgroundColor?: string
+}
+
+/**
+ * {@link PlayOptions.onCue} に渡される字幕のキュー
+ *
+ * 時刻は MP4 ファイルの先頭からの位置（秒単位）で、repeat が true の場合にも繰り返し毎のオフセットは含みません
+ */
+interface SubtitleCue {
+  /**
+   * キューが含まれる字幕トラックのトラック ID
+   */
+  trackId: number
+
+  /**
+   * 表示開始時刻
+   */
+  startTime: number
+
+  /**
+   * 表示終了時刻
+   */
+  endTime: number
+
+  /**
+   * 表示するテキスト（複数行の場合は "\n" で区切られます）
+   */
+  text: string
+
+  /**
+   * WebVTT のキューの ID（wvtt の場合のみ）
+   */
+  id?: string
+
+  /**
+   * WebVTT のキューの設定（"line:10%" など。wvtt の場合のみ）
+   */
+  settings?: string
+}
+
+/**
+ * {@link Mp4MediaStream.subtitleTracks} が返す字幕トラックの情報
+ */
+interface SubtitleTrack {
+  /**
+   * トラック ID
+   */
+  trackId: number
+
+  /**
+   * 字幕の形式（"tx3g" / "wvtt" / "stpp" のいずれか）
+   */
+  codec: string
+
+  /**
+   * ISO 639-2/T の言語コード（"eng" や "jpn" など）
+   */
+  language: string
+}
+
+/**
+ * {@link Mp4MediaStream.getStats} が返す再生統計情報
+ *
+ * 時間を表す値は全てマイクロ秒単位です
+ */
+interface PlaybackStats {
+  /**
+   * 再生時刻から 50 ミリ秒以上遅れてデコーダーに渡されたサンプルの数
+   */
+  lateSamples: number
+
+  /**
+   * サンプルがデコーダーに渡された時点での、再生時刻からの遅れの最大値
+   */
+  maxLatenessMicros: number
+
+  /**
+   * 次のサンプルの再生時刻までのスリープから復帰した回数
+   */
+  sleepCount: number
+
+  /**
+   * スリープから復帰した時刻の予定時刻からの超過時間の合計
+   */
+  totalSleepOvershootMicros: number
+
+  /**
+   * スリープから復帰した時刻の予定時刻からの超過時間の最大値
+   */
+  maxSleepOvershootMicros: number
+
+  /**
+   * 直近の音声の再生位置と、映像の再生タイミングの基準となる時計のズレ
+   *
+   * 音声のデコードや出力バッファによる遅延（再生開始直後に計測されます）は含まれません
+   */
+  audioClockDriftMicros: number
+
+  /**
+   * 音声の再生位置と、映像の再生タイミングの基準となる時計のズレの最大値
+   */
+  maxAudioClockDriftMicros: number
+
+  /**
+   * ズレが 200 ミリ秒以上となったために、映像の時計を音声の再生位置に一気に合わせた回数
+   */
+  audioClockResyncs: number
+
+  /**
+   * トラック毎の統計情報
+   */
+  tracks: TrackPlaybackStats[]
+}
+
+/**
+ * {@link PlaybackStats} に含まれるトラック毎の統計情報
+ */
+interface TrackPlaybackStats {
+  /**
+   * トラックの種類
+   */
+  kind: 'audio' | 'video'
+
+  /**
+   * デコーダーが生成された回数（途中でコーデック設定が変わるとデコーダーが再生成されます）
+   */
+  decoderConfigurations: number
+
+  /**
+   * デコーダーに渡されたサンプルの数
+   */
+  samples: number
+
+  /**
+   * デコーダーに渡されたサンプルの合計バイト数
+   */
+  bytes: number
+
+  /**
+   * デコーダーが出力したフレーム（音声の場合は AudioData）の数
+   */
+  decodedFrames: number
+
+  /**
+   * 表示期限までにデコーダーからの出力が間に合わなかったフレームの数
+   */
+  droppedFrames: number
+
+  /**
+   * 再生の遅れを取り戻すために、次のキーフレームまでサンプルを読み飛ばした回数
+   *
+   * 映像トラックの再生が 500 ミリ秒以上遅れた場合に読み飛ばしが行われます（音声トラックは対象外）
+   */
+  catchUps: number
+
+  /**
+   * 再生の遅れを取り戻すために読み飛ばされたサンプルの数
+   */
+  skippedSamples: number
+
+  /**
+   * デコーダーにサンプルを渡してから出力されるまでの時間の合計
+   */
+  totalDecodeLatencyMicros: number
+
+  /**
+   * デコーダーにサンプルを渡してから出力されるまでの時間の最大値
+   */
+  maxDecodeLatencyMicros: number
+}
+
+/**
+ * {@link Mp4MediaStream.skippedTracks} が返す、再生対象から除外されたトラックの情報
+ */
+interface SkippedTrack {
+  /**
+   * トラック ID（WebM の場合はトラック番号、MPEG-TS の場合は PID）
+   */
+  trackId: number
+
+  /**
+   * ハンドラー種別（"tmcd" や "meta" など）
+   */
+  handlerType: string
+
+  /**
+   * 除外された理由
+   */
+  reason: string
+}
+
+/**
+ * {@link Mp4MediaStream.timecode} が返す、QuickTime 形式 (MOV) のタイムコードトラックの情報
+ */
+interface Timecode {
+  /**
+   * 先頭フレームのタイムコード（"HH:MM:SS:FF" 形式。ドロップフレームの場合は "HH:MM:SS;FF" 形式）
+   */
+  start: string
+
+  /**
+   * 先頭フレームのタイムコードを、0 時 0 分 0 秒 0 フレームからのフレーム数で表した値
+   */
+  frameNumber: number
+
+  /**
+   * フレームレート（ドロップフレームの場合は 29.97 などの非整数値となります）
+   */
+  frameRate: number
+
+  /**
+   * ドロップフレームかどうか
+   */
+  dropFrame: boolean
 }
 
+// Wasm 側の EncodedChunkMetadata 構造体のバイトサイズ
+const ENCODED_CHUNK_METADATA_SIZE: number = 24
+
 const AUDIO_DECODER_ID: number = 0
 const VIDEO_DECODER_ID: number = 1
 
@@ -52,12 +344,16 @@ class Mp4MediaStream {
   /**
    * 指定された MP4 をロードします
    *
+   * WebM (Matroska) や MPEG-TS 形式のデータ、および Annex-B / IVF / ADTS / Ogg Opus 形式のエレメンタリーストリームを
+   * 指定することもできます（形式はデータの先頭部分から判定されます）
+   *
    * @param mp4 対象の MP4 データ
    *
    * @returns ロード結果の Mp4MediaStream インスタンス
    *
    * @throws
    * 指定された MP4 が不正であったり、非対応コーデックを含んでいる場合には例外が送出されます
+   * （データの形式が判定できない場合には、非対応のコンテナ形式である旨の例外が送出されます）
    */
   static async load(mp4: Blob): Promise<Mp4MediaStream> {
     // インポート関数の中で this を参照したいけど、この時点ではまだ作成されていないので
@@ -88,20 +384,35 @@ class Mp4MediaStream {
             ref.stream.createAudioDecoder(resultTx, playerId, configWasmJson)
           }
         },
+        outputAudio(
+          playerId: number,
+          timestampMicros: number,
+          samplesOffset: number,
+          samplesLen: number,
+        ) {
+          if (ref.stream) {
+            ref.stream.outputAudio(playerId, timestampMicros, samplesOffset, samplesLen)
+          }
+        },
         closeDecoder(playerId: number, decoderId: number) {
           if (ref.stream) {
             ref.stream.closeDecoder(playerId, decoderId)
           }
         },
+        resetDecoder(playerId: number, decoderId: number) {
+          if (ref.stream) {
+            ref.stream.resetDecoder(playerId, decoderId)
+          }
+        },
         decode(
           playerId: number,
           decoderId: number,
-          metadataWasmJson: number,
+          metadataOffset: number,
           dataOffset: number,
           dataLen: number,
         ) {
           if (ref.stream) {
-            ref.stream.decode(playerId, decoderId, metadataWasmJson, dataOffset, dataLen)
+            ref.stream.decode(playerId, decoderId, metadataOffset, dataOffset, dataLen)
           }
         },
         onEos(playerId: number) {
@@ -109,14 +420,21 @@ class Mp4MediaStream {
             ref.stream.onEos(playerId)
           }
         },
+        onTimeUpdate(playerId: number, positionMicros: number) {
+          if (ref.stream) {
+            ref.stream.onTimeUpdate(playerId, positionMicros)
+          }
+        },
+        outputCue(playerId: number, cueWasmJson: number) {
+          if (ref.stream) {
+            ref.stream.outputCue(playerId, cueWasmJson)
+          }
+        },
       },
     }
-    const wasmResults = await WebAssembly.instantiateStreaming(
-      fetch(`data:application/wasm;base64,${WASM_BASE64}`),
-      importObject,
-    )
+    const wasm = await instantiateWasm(importObject)
 
-    const stream = new Mp4MediaStream(wasmResults.instance)
+    const stream = new Mp4MediaStream(wasm)
     ref.stream = stream
 
     const mp4Bytes = new Uint8Array(await mp4.arrayBuffer())
@@ -150,16 +468,137 @@ class Mp4MediaStream {
     this.nextPlayerId += 1
 
     const player = new Player(this.info.audioConfigs, this.info.videoConfigs)
+    player.onTimeUpdate = options.onTimeUpdate
+    player.onCue = options.onCue
+    if (options.burnInCaptions !== undefined) {
+      player.captionRenderer = new CaptionRenderer(options.burnInCaptions, this.info.subtitleTracks)
+    }
+    player.onAudioPosition = (timestamp: number) => {
+      // 映像の再生タイミングを音声の再生位置に追従させるために Wasm 側に伝える
+      if (this.players.has(playerId)) {
+        ;(this.wasm.exports.notifyAudioPosition as CallableFunction)(
+          this.engine,
+          playerId,
+          timestamp,
+        )
+      }
+    }
     this.players.set(playerId, player)
     ;(this.wasm.exports.play as CallableFunction)(
       this.engine,
       playerId,
-      this.valueToWasmJson(options),
+      valueToWasmJson(this.wasm, { repeat: options.repeat }),
     )
 
     return player.createMediaStream()
   }
 
+  /**
+   * 指定された MediaStream の現在の再生位置を取得します
+   *
+   * @param stream {@link Mp4MediaStream.play} が返した MediaStream インスタンス
+   *
+   * @returns MP4 ファイルの先頭からの再生位置（秒単位）。停止済みあるいは未知の MediaStream が指定された場合には undefined
+   */
+  currentTime(stream: MediaStream): number | undefined {
+    for (const [playerId, player] of this.players) {
+      if (player.stream !== stream) {
+        continue
+      }
+      const positionMicros = (this.wasm.exports.currentTime as CallableFunction)(
+        this.engine,
+        playerId,
+      )
+      return positionMicros < 0 ? undefined : positionMicros / 1_000_000
+    }
+    return undefined
+  }
+
+  /**
+   * 指定された MediaStream の再生統計情報を取得します
+   *
+   * @param stream {@link Mp4MediaStream.play} が返した MediaStream インスタンス
+   *
+   * @returns 再生統計情報。停止済みあるいは未知の MediaStream が指定された場合には undefined
+   */
+  getStats(stream: MediaStream): PlaybackStats | undefined {
+    for (const [playerId, player] of this.players) {
+      if (player.stream !== stream) {
+        continue
+      }
+      const statsWasmJson = (this.wasm.exports.getStats as CallableFunction)(this.engine, playerId)
+      const stats = wasmJsonToValue(this.wasm, statsWasmJson) as PlaybackStats | null
+      return stats === null ? undefined : stats
+    }
+    return undefined
+  }
+
+  /**
+   * ロード済みの MP4 のトラックのうち、再生対象から除外されたものの一覧を取得します
+   *
+   * @returns 除外されたトラックの情報の配列（全てのトラックが再生対象の場合は空配列）
+   *
+   * 音声・映像以外のトラック（メタデータやタイムコード、ヒントトラックなど）や、
+   * 未対応のコーデックを使っている音声・映像トラックは、ロード時にエラーとはならずに除外されます。
+   * また、音声・映像トラックがそれぞれ複数ある場合には、最初のもの以外が除外されます。
+   * ただし、再生可能なトラックが一つもない場合には {@link Mp4MediaStream.load} が例外を送出します。
+   */
+  skippedTracks(): SkippedTrack[] {
+    return this.info?.skippedTracks ?? []
+  }
+
+  /**
+   * ロード済みの MP4 に含まれる字幕トラックの一覧を取得します
+   *
+   * @returns 字幕トラックの情報の配列（字幕トラックが存在しない場合は空配列）
+   *
+   * 字幕のキューは {@link PlayOptions.onCue} で指定したコールバックを通して、再生位置に合わせて渡されます。
+   * 複数の字幕トラックが存在する場合には、全てのトラックのキューが渡されるので、
+   * 必要に応じて {@link SubtitleCue.trackId} で絞り込んでください。
+   */
+  subtitleTracks(): SubtitleTrack[] {
+    return this.info?.subtitleTracks ?? []
+  }
+
+  /**
+   * ロード済みの MP4 に含まれるタイムコードの情報を取得します
+   *
+   * @returns タイムコードの情報。タイムコードトラックが存在しない場合には undefined
+   *
+   * iPhone などで撮影された QuickTime 形式 (MOV) のファイルのタイムコードトラック (tmcd) が対象です。
+   * タイムコードトラック自体は再生されません。
+   */
+  timecode(): Timecode | undefined {
+    return this.info?.timecode
+  }
+
+  /**
+   * ロード済みの MP4 の指定範囲を、再エンコードを行わずに切り出した MP4 ファイルを生成します
+   *
+   * @param start 切り出し開始位置（秒単位）
+   * @param end 切り出し終了位置（秒単位）
+   *
+   * @returns 切り出し結果の MP4 ファイル
+   *
+   * 映像トラックは開始位置の直前のキーフレームから切り出され、開始位置までの部分は
+   * MP4 の編集リスト（edts ボックス）によって再生時に表示されないように指定されます。
+   * そのため、編集リストに対応していないプレイヤーでは、開始位置よりも前の映像が再生されることがあります。
+   *
+   * @throws
+   * 不正な範囲が指定された場合や、範囲内にサンプルが存在しない場合には例外が送出されます
+   */
+  trim(start: number, end: number): Blob {
+    const resultWasmJson = (this.wasm.exports.trimMp4 as CallableFunction)(
+      this.engine,
+      start * 1_000_000,
+      end * 1_000_000,
+    )
+    wasmResultToValue(this.wasm, resultWasmJson)
+
+    const outputWasmBytes = (this.wasm.exports.takeOutput as CallableFunction)(this.engine)
+    return new Blob([wasmBytesToUint8Array(this.wasm, outputWasmBytes)], { type: 'video/mp4' })
+  }
+
   /**
    * 再生中の全ての MediaStream を停止します
    *
@@ -184,15 +623,19 @@ class Mp4MediaStream {
   }
 
   private async loadMp4(mp4Bytes: Uint8Array): Promise<{ audio: boolean; video: boolean }> {
-    const mp4WasmBytes = this.toWasmBytes(mp4Bytes)
+    const mp4WasmBytes = toWasmBytes(this.wasm, mp4Bytes)
     const resultWasmJson = (this.wasm.exports.loadMp4 as CallableFunction)(
       this.engine,
       mp4WasmBytes,
     )
 
     // MP4 内に含まれる映像・音声を WebCodecs のデコーダー扱えるかどうかをチェックする
-    const info = this.wasmResultToValue(resultWasmJson) as Mp4Info
+    const info = wasmResultToValue(this.wasm, resultWasmJson) as Mp4Info
     for (const config of info.audioConfigs) {
+      if (isPcmCodec(config.codec)) {
+        // PCM は WebCodecs のデコーダーを使わずに Wasm 側で変換されるので、チェックは不要
+        continue
+      }
       if (!(await AudioDecoder.isConfigSupported(config)).supported) {
         throw new Error(`Unsupported audio decoder configuration: ${JSON.stringify(config)}`)
       }
@@ -216,7 +659,7 @@ class Mp4MediaStream {
   }
 
   private consoleLog(messageWasmJson: number) {
-    const message = this.wasmJsonToValue(messageWasmJson)
+    const message = wasmJsonToValue(this.wasm, messageWasmJson)
     console.log(message)
   }
 
@@ -237,7 +680,7 @@ class Mp4MediaStream {
     // 一つ前のデコーダーの終了処理が進行中の場合に備えて、ここでも close を呼び出して終了を待機する
     await player.closeVideoDecoder()
 
-    const config = this.wasmJsonToValue(configWasmJson) as VideoDecoderConfig
+    const config = wasmJsonToValue(this.wasm, configWasmJson) as VideoDecoderConfig
 
     // JSON.parse() の結果では config.description の型は number[] となって期待とは異なるので
     // ここで適切な型に変換している
@@ -250,6 +693,7 @@ class Mp4MediaStream {
     const init = {
       output: async (frame: VideoFrame) => {
         try {
+          this.notifyDecoderOutput(playerId, VIDEO_DECODER_ID, frame.timestamp)
           if (player.canvas === undefined || player.canvasCtx === undefined) {
             return
           }
@@ -258,6 +702,15 @@ class Mp4MediaStream {
             player.canvas.width = frame.displayWidth
             player.canvas.height = frame.displayHeight
             player.canvasCtx.drawImage(frame, 0, 0)
+            if (player.captionRenderer !== undefined) {
+              // 描画時点の再生位置ではなく、フレーム自身のタイムスタンプから
+              // 繰り返し再生のオフセットを除いた位置で表示するキューを選ぶ
+              const mediaTimeMicros = (this.wasm.exports.mediaTime as CallableFunction)(
+                this.engine,
+                frame.timestamp,
+              )
+              player.captionRenderer.draw(player.canvasCtx, mediaTimeMicros / 1_000_000)
+            }
           } catch (error) {
             // エラーが発生した場合には再生を停止する
             await this.stopPlayer(playerId)
@@ -276,6 +729,7 @@ class Mp4MediaStream {
 
     player.videoDecoder = new VideoDecoder(init)
     player.videoDecoder.configure(config)
+    player.videoDecoderConfig = config
     ;(this.wasm.exports.notifyDecoderId as CallableFunction)(
       this.engine,
       resultTx,
@@ -294,10 +748,11 @@ class Mp4MediaStream {
     // 一つ前のデコーダーの終了処理が進行中の場合に備えて、ここでも close を呼び出して終了を待機する
     await player.closeAudioDecoder()
 
-    const config = this.wasmJsonToValue(configWasmJson) as AudioDecoderConfig
+    const config = wasmJsonToValue(this.wasm, configWasmJson) as AudioDecoderConfig
     const init = {
       output: async (data: AudioData) => {
         try {
+          this.notifyDecoderOutput(playerId, AUDIO_DECODER_ID, data.timestamp)
           if (player.audioInputNode === undefined) {
             return
           }
@@ -326,6 +781,7 @@ class Mp4MediaStream {
 
     player.audioDecoder = new AudioDecoder(init)
     player.audioDecoder.configure(config)
+    player.audioDecoderConfig = config
     ;(this.wasm.exports.notifyDecoderId as CallableFunction)(
       this.engine,
       resultTx,
@@ -333,6 +789,25 @@ class Mp4MediaStream {
     )
   }
 
+  // PCM の音声データを受け取った場合に呼ばれるコールバック
+  //
+  // デコーダーを経由せずに、AudioDecoder の出力と同じ形式（インターリーブされた f32）で AudioWorklet に渡す
+  private outputAudio(
+    playerId: number,
+    timestamp: number,
+    samplesOffset: number,
+    samplesLen: number,
+  ) {
+    const player = this.players.get(playerId)
+    if (player === undefined || player.audioInputNode === undefined) {
+      return
+    }
+
+    // samples は呼び出し中のみ有効な領域なので、ここでコピーする
+    const samples = new Float32Array(this.memory.buffer, samplesOffset, samplesLen).slice()
+    player.audioInputNode.port.postMessage({ timestamp, samples }, [samples.buffer])
+  }
+
   private async closeDecoder(playerId: number, decoderId: number) {
     const player = this.players.get(playerId)
     if (player === undefined) {
@@ -347,32 +822,115 @@ class Mp4MediaStream {
     }
   }
 
-  // MP4 の終端に達した場合に呼ばれるコールバック
-  private async onEos(playerId: number) {
-    await this.stopPlayer(playerId)
-  }
-
-  private decode(
-    playerId: number,
-    decoderId: number,
-    metadataWasmJson: number,
-    dataOffset: number,
-    dataLen: number,
-  ) {
+  // 再生の遅れを取り戻すためにサンプルを読み飛ばす際に呼ばれるコールバック
+  //
+  // デコード待ちのサンプルを破棄した上で、同じ設定でデコーダーを再設定する
+  private resetDecoder(playerId: number, decoderId: number) {
     const player = this.players.get(playerId)
     if (player === undefined) {
-      // stop() 呼び出しと競合した場合にここに来る可能性がある
-      // すでに停止済みなので、これ以上デコードを行う必要はない
+      // すでに停止済みなので、何もする必要はない
       return
     }
 
-    const chunkParams = this.wasmJsonToValue(metadataWasmJson) as
-      | EncodedAudioChunkInit
-      | EncodedVideoChunkInit
-    chunkParams.data = new Uint8Array(this.memory.buffer, dataOffset, dataLen)
-
-    if (decoderId === VIDEO_DECODER_ID) {
-      if (player.videoDecoder === undefined) {
+    if (decoderId === AUDIO_DECODER_ID) {
+      const decoder = player.audioDecoder
+      if (decoder !== undefined && decoder.state === 'configured' && player.audioDecoderConfig) {
+        decoder.reset()
+        decoder.configure(player.audioDecoderConfig)
+      }
+    } else {
+      const decoder = player.videoDecoder
+      if (decoder !== undefined && decoder.state === 'configured' && player.videoDecoderConfig) {
+        decoder.reset()
+        decoder.configure(player.videoDecoderConfig)
+      }
+    }
+  }
+
+  // MP4 の終端に達した場合に呼ばれるコールバック
+  private async onEos(playerId: number) {
+    await this.stopPlayer(playerId)
+  }
+
+  // 統計情報の収集のために、デコーダーが出力を生成したことを Wasm 側に伝える
+  private notifyDecoderOutput(playerId: number, decoderId: number, timestamp: number) {
+    if (!this.players.has(playerId)) {
+      return
+    }
+    ;(this.wasm.exports.notifyDecoderOutput as CallableFunction)(
+      this.engine,
+      playerId,
+      decoderId,
+      timestamp,
+    )
+  }
+
+  // 再生位置が更新された場合に呼ばれるコールバック
+  private onTimeUpdate(playerId: number, positionMicros: number) {
+    const player = this.players.get(playerId)
+    if (player === undefined || player.onTimeUpdate === undefined) {
+      return
+    }
+    player.onTimeUpdate(positionMicros / 1_000_000)
+  }
+
+  // 字幕のキューの表示開始時刻に達した場合に呼ばれるコールバック
+  private outputCue(playerId: number, cueWasmJson: number) {
+    // Wasm 側のメモリを解放するために、コールバックの有無に関わらず値を取り出す
+    const cue = wasmJsonToValue(this.wasm, cueWasmJson) as {
+      trackId: number
+      startMicros: number
+      endMicros: number
+      text: string
+      id?: string
+      settings?: string
+    }
+    const player = this.players.get(playerId)
+    if (player === undefined) {
+      return
+    }
+    const subtitleCue = {
+      trackId: cue.trackId,
+      startTime: cue.startMicros / 1_000_000,
+      endTime: cue.endMicros / 1_000_000,
+      text: cue.text,
+      id: cue.id,
+      settings: cue.settings,
+    }
+    if (player.captionRenderer !== undefined) {
+      player.captionRenderer.addCue(subtitleCue)
+    }
+    if (player.onCue !== undefined) {
+      player.onCue(subtitleCue)
+    }
+  }
+
+  private decode(
+    playerId: number,
+    decoderId: number,
+    metadataOffset: number,
+    dataOffset: number,
+    dataLen: number,
+  ) {
+    const player = this.players.get(playerId)
+    if (player === undefined) {
+      // stop() 呼び出しと競合した場合にここに来る可能性がある
+      // すでに停止済みなので、これ以上デコードを行う必要はない
+      return
+    }
+
+    // メタデータは Wasm 側の EncodedChunkMetadata 構造体のレイアウトに従って読み込む
+    // (呼び出し中のみ有効な領域なので、ここで同期的に値を取り出す必要がある）
+    const metadata = new DataView(this.memory.buffer, metadataOffset, ENCODED_CHUNK_METADATA_SIZE)
+    const chunkParams: EncodedAudioChunkInit | EncodedVideoChunkInit = {
+      timestamp: metadata.getFloat64(0, true),
+      duration: metadata.getFloat64(8, true),
+      type: metadata.getUint32(16, true) === 1 ? 'key' : 'delta',
+      data: new Uint8Array(this.memory.buffer, dataOffset, dataLen),
+    }
+
+    if (decoderId === VIDEO_DECODER_ID) {
+      if (player.videoDecoder === undefined) {
         // ここには来ないはず
         throw new Error('bug')
       }
@@ -387,44 +945,109 @@ class Mp4MediaStream {
       player.audioDecoder.decode(chunk)
     }
   }
+}
 
-  private wasmJsonToValue(wasmJson: number): object {
-    const offset = (this.wasm.exports.vecOffset as CallableFunction)(wasmJson)
-    const len = (this.wasm.exports.vecLen as CallableFunction)(wasmJson)
-    const buffer = new Uint8Array(this.memory.buffer, offset, len)
-    const value = JSON.parse(new TextDecoder('utf-8').decode(buffer))
+// Wasm 側で PCM のトラックに付与されるコーデック名かどうか
+function isPcmCodec(codec: string): boolean {
+  return codec.startsWith('pcm-')
+}
 
-    // Wasm 側で所有権は放棄されているので、解放するのは呼び出し側の責務
-    ;(this.wasm.exports.freeVec as CallableFunction)(wasmJson)
+type Mp4Info = {
+  audioConfigs: [AudioDecoderConfig]
+  videoConfigs: [VideoDecoderConfig]
+  timecode?: Timecode
+  skippedTracks: SkippedTrack[]
+  subtitleTracks: SubtitleTrack[]
+}
 
-    return value
+// 字幕のキューを映像フレームに焼き込むためのクラス
+//
+// 映像フレームを Canvas に描画する度に、その上に表示中のキューのテキストを描画する
+class CaptionRenderer {
+  private options: BurnInCaptionOptions
+  private trackId?: number
+  private cues: SubtitleCue[] = []
+  private lastTime = 0
+
+  constructor(options: BurnInCaptionOptions, subtitleTracks: SubtitleTrack[]) {
+    this.options = options
+    this.trackId = options.trackId ?? subtitleTracks[0]?.trackId
   }
 
-  private wasmResultToValue(wasmResult: number): object {
-    const result = this.wasmJsonToValue(wasmResult) as { Ok?: object; Err?: { message: string } }
-    if (result.Err !== undefined) {
-      throw new Error(result.Err.message)
+  addCue(cue: SubtitleCue) {
+    if (cue.trackId === this.trackId) {
+      this.cues.push(cue)
     }
-    return result.Ok as object
   }
 
-  private valueToWasmJson(value: object): number {
-    const jsonBytes = new TextEncoder().encode(JSON.stringify(value))
-    return this.toWasmBytes(jsonBytes)
-  }
+  // currentTime には描画するフレームの MP4 ファイルの先頭からの位置（秒単位）を指定する
+  draw(ctx: CanvasRenderingContext2D, currentTime: number) {
+    if (currentTime < this.lastTime) {
+      // 繰り返し再生で先頭に戻った場合には、前の周回のキューを取り除く
+      this.cues = this.cues.filter((cue) => cue.startTime <= currentTime)
+    }
+    this.lastTime = currentTime
+    this.cues = this.cues.filter((cue) => currentTime < cue.endTime)
+
+    // 先読みされたまだ表示時刻になっていないキューは、描画せずにそのまま残しておく
+    const activeCues = this.cues.filter((cue) => cue.startTime <= currentTime)
+    if (activeCues.length === 0) {
+      return
+    }
 
-  private toWasmBytes(bytes: Uint8Array): number {
-    // ここで割り当てられたメモリ領域を解放するのは Wasm 側の責務
-    const wasmBytes = (this.wasm.exports.allocateVec as CallableFunction)(bytes.length)
-    const wasmBytesOffset = (this.wasm.exports.vecOffset as CallableFunction)(wasmBytes)
-    new Uint8Array(this.memory.buffer, wasmBytesOffset, bytes.length).set(bytes)
-    return wasmBytes
+    // Canvas のサイズが変わると描画設定がリセットされるので、毎回設定し直す
+    const { width, height } = ctx.canvas
+    const fontSize = this.options.fontSize ?? Math.max(Math.round(height / 20), 1)
+    const padding = Math.ceil(fontSize / 4)
+    const lineHeight = Math.ceil(fontSize * 1.2)
+    const backgroundColor = this.options.backgroundColor ?? 'rgba(0, 0, 0, 0.6)'
+    ctx.font = `${fontSize}px ${this.options.fontFamily ?? 'sans-serif'}`
+    ctx.textAlign = 'center'
+    ctx.textBaseline = 'middle'
+
+    const lines = activeCues.flatMap((cue) =>
+      wrapCaptionText(ctx, cue.text, width * 0.9 - padding * 2),
+    )
+    let y =
+      this.options.position === 'top' ? fontSize : height - fontSize - lines.length * lineHeight
+    for (const line of lines) {
+      if (backgroundColor !== 'transparent') {
+        const lineWidth = ctx.measureText(line).width + padding * 2
+        ctx.fillStyle = backgroundColor
+        ctx.fillRect((width - lineWidth) / 2, y, lineWidth, lineHeight)
+      }
+      ctx.fillStyle = this.options.color ?? 'white'
+      ctx.fillText(line, width / 2, y + lineHeight / 2)
+      y += lineHeight
+    }
   }
 }
 
-type Mp4Info = {
-  audioConfigs: [AudioDecoderConfig]
-  videoConfigs: [VideoDecoderConfig]
+// 字幕のテキストを、描画幅が maxWidth に収まるように行に分割する
+//
+// 空白があればそこで折り返し、ない場合（日本語など）には文字単位で折り返す
+function wrapCaptionText(ctx: CanvasRenderingContext2D, text: string, maxWidth: number): string[] {
+  const lines: string[] = []
+  for (const paragraph of text.split('\n')) {
+    let line = ''
+    for (const char of paragraph) {
+      if (line !== '' && ctx.measureText(line + char).width > maxWidth) {
+        const i = line.lastIndexOf(' ')
+        if (i > 0) {
+          lines.push(line.slice(0, i))
+          line = line.slice(i + 1)
+        } else {
+          lines.push(line)
+          line = ''
+        }
+      }
+      line += char
+    }
+    if (line !== '') {
+      lines.push(line)
+    }
+  }
+  return lines
 }
 
 class Player {
@@ -434,10 +1057,17 @@ class Player {
   private sampleRate = 48000
   audioDecoder?: AudioDecoder
   videoDecoder?: VideoDecoder
+  audioDecoderConfig?: AudioDecoderConfig
+  videoDecoderConfig?: VideoDecoderConfig
   canvas?: HTMLCanvasElement
   canvasCtx?: CanvasRenderingContext2D
   audioContext?: AudioContext
   audioInputNode?: AudioWorkletNode
+  stream?: MediaStream
+  onTimeUpdate?: (currentTime: number) => void
+  onCue?: (cue: SubtitleCue) => void
+  captionRenderer?: CaptionRenderer
+  onAudioPosition?: (timestamp: number) => void
 
   constructor(audioConfigs: AudioDecoderConfig[], videoConfigs: VideoDecoderConfig[]) {
     this.audio = audioConfigs.length > 0
@@ -461,6 +1091,12 @@ class Player {
         outputChannelCount: [this.numberOfChannels],
       })
 
+      this.audioInputNode.port.onmessage = (e) => {
+        if (this.onAudioPosition !== undefined) {
+          this.onAudioPosition(e.data.timestamp)
+        }
+      }
+
       const destination = this.audioContext.createMediaStreamDestination()
       this.audioInputNode.connect(destination)
       tracks.push(destination.stream.getAudioTracks()[0])
@@ -474,7 +1110,8 @@ class Player {
       this.canvasCtx = canvasCtx
       tracks.push(this.canvas.captureStream().getVideoTracks()[0])
     }
-    return new MediaStream(tracks)
+    this.stream = new MediaStream(tracks)
+    return this.stream
   }
 
   async closeAudioDecoder() {
@@ -540,7 +1177,873 @@ class Player {
       this.canvas = undefined
       this.canvasCtx = undefined
     }
+    this.stream = undefined
   }
 }
 
-export { Mp4MediaStream, type PlayOptions }
+/**
+ * {@link Mp4Muxer.create} に指定可能なオプション
+ */
+interface Mp4MuxerOptions {
+  /**
+   * 指定された場合には、通常の MP4 の代わりに Fragmented MP4 を生成します（秒単位）
+   *
+   * 最初に初期化セグメント（ftyp + moov）が生成され、その後はおよそ指定された尺ごとに
+   * メディアセグメント（moof + mdat）が生成されます。
+   * 映像トラックがある場合にはキーフレームの位置で区切られるため、実際の尺は指定値よりも長くなることがあります。
+   *
+   * 全てのサンプルをメモリ上に保持する必要がなくなるため、長時間の録画に適しています。
+   * なお、初期化セグメントの生成後に新しいトラックを追加したり、デコーダー設定を変更することはできません。
+   */
+  fragmentDuration?: number
+
+  /**
+   * MP4 データが生成される度に呼び出されるコールバック
+   *
+   * Fragmented MP4 の場合には、初期化セグメントやメディアセグメントが生成される度に呼び出されるので、
+   * 受け取ったデータを逐次ディスクに書き込んだり、アップロードすることができます。
+   * 引数のデータを順番に連結したものが MP4 ファイル全体となります。
+   *
+   * このコールバックが指定された場合には、{@link Mp4Muxer.finish} が返す Blob は空となります
+   */
+  onData?: (data: Uint8Array) => void
+}
+
+/**
+ * WebCodecs のエンコーダーが出力したチャンクを MP4 ファイルにまとめるクラス
+ *
+ * 使用例:
+ * ```typescript
+ * const muxer = await Mp4Muxer.create()
+ * const encoder = new VideoEncoder({
+ *   output: (chunk, metadata) => muxer.appendVideoChunk(chunk, metadata),
+ *   error: (e) => console.error(e),
+ * })
+ * // ... エンコード処理 ...
+ * await encoder.flush()
+ * const mp4 = muxer.finish()
+ * ```
+ */
+class Mp4Muxer {
+  private wasm: WebAssembly.Instance
+  private muxer?: number
+  private onData?: (data: Uint8Array) => void
+
+  private constructor(wasm: WebAssembly.Instance, options: Mp4MuxerOptions) {
+    this.wasm = wasm
+    this.onData = options.onData
+
+    // Wasm 側では負の値は「Fragmented MP4 を生成しない」ことを意味する
+    const fragmentDurationMicros =
+      options.fragmentDuration === undefined ? -1 : options.fragmentDuration * 1_000_000
+    this.muxer = (this.wasm.exports.newMp4Muxer as CallableFunction)(fragmentDurationMicros)
+  }
+
+  /**
+   * Mp4Muxer のインスタンスを生成します
+   *
+   * @param options 生成オプション
+   *
+   * @returns 生成されたインスタンス
+   *
+   * @throws
+   * 不正なオプションが指定された場合には例外が送出されます
+   */
+  static async create(options: Mp4MuxerOptions = {}): Promise<Mp4Muxer> {
+    const fragmentDuration = options.fragmentDuration
+    if (
+      fragmentDuration !== undefined &&
+      !(fragmentDuration > 0 && Number.isFinite(fragmentDuration))
+    ) {
+      throw new Error(`Invalid fragment duration: ${fragmentDuration}`)
+    }
+
+    const wasm = await instantiateWasm()
+    return new Mp4Muxer(wasm, options)
+  }
+
+  /**
+   * VideoEncoder が出力したチャンクを追加します
+   *
+   * @param chunk 追加するチャンク
+   * @param metadata チャンクと一緒に渡されたメタデータ
+   * @param compositionOffset チャンクの表示時刻（chunk.timestamp）からデコード時刻を引いた値（マイクロ秒単位）
+   *
+   * [注意]
+   * metadata.decoderConfig が含まれている場合には、その設定が以降のチャンクに適用されます。
+   * そのため、最初のチャンクには decoderConfig が含まれている必要があります。
+   *
+   * B フレームを含むストリームのように、チャンクの表示時刻が単調増加しない場合には、
+   * デコード時刻（chunk.timestamp - compositionOffset）が単調増加となるように compositionOffset を指定してください。
+   * 指定された値は MP4 の ctts ボックスに格納されます。デフォルト値は 0 です。
+   *
+   * @throws
+   * 非対応のコーデックが指定された場合や、デコード時刻が単調増加していない場合などには例外が送出されます
+   */
+  appendVideoChunk(
+    chunk: EncodedVideoChunk,
+    metadata?: EncodedVideoChunkMetadata,
+    compositionOffset = 0,
+  ) {
+    const muxer = this.getMuxer()
+    if (metadata?.decoderConfig !== undefined) {
+      const config = metadata.decoderConfig
+      const configWasmJson = valueToWasmJson(this.wasm, {
+        codec: config.codec,
+        description: descriptionToArray(config.description),
+        codedWidth: config.codedWidth,
+        codedHeight: config.codedHeight,
+      })
+      const resultWasmJson = (this.wasm.exports.mp4MuxerSetVideoConfig as CallableFunction)(
+        muxer,
+        configWasmJson,
+      )
+      wasmResultToValue(this.wasm, resultWasmJson)
+    }
+    this.appendChunk(true, chunk, compositionOffset)
+  }
+
+  /**
+   * AudioEncoder が出力したチャンクを追加します
+   *
+   * @param chunk 追加するチャンク
+   * @param metadata チャンクと一緒に渡されたメタデータ
+   *
+   * [注意]
+   * metadata.decoderConfig が含まれている場合には、その設定が以降のチャンクに適用されます。
+   * そのため、最初のチャンクには decoderConfig が含まれている必要があります。
+   *
+   * @throws
+   * 非対応のコーデックが指定された場合や、タイムスタンプが単調増加していない場合などには例外が送出されます
+   */
+  appendAudioChunk(chunk: EncodedAudioChunk, metadata?: EncodedAudioChunkMetadata) {
+    const muxer = this.getMuxer()
+    if (metadata?.decoderConfig !== undefined) {
+      const config = metadata.decoderConfig
+      const configWasmJson = valueToWasmJson(this.wasm, {
+        codec: config.codec,
+        sampleRate: config.sampleRate,
+        numberOfChannels: config.numberOfChannels,
+        description: descriptionToArray(config.description),
+      })
+      const resultWasmJson = (this.wasm.exports.mp4MuxerSetAudioConfig as CallableFunction)(
+        muxer,
+        configWasmJson,
+      )
+      wasmResultToValue(this.wasm, resultWasmJson)
+    }
+    this.appendChunk(false, chunk, 0)
+  }
+
+  /**
+   * チャンクの追加を終了して、MP4 ファイルを生成します
+   *
+   * このメソッドの呼び出し後には、このインスタンスは利用できなくなります
+   *
+   * @returns 生成された MP4 ファイル（{@link Mp4MuxerOptions.onData} が指定されている場合には空）
+   */
+  finish(): Blob {
+    const muxer = this.getMuxer()
+    try {
+      const resultWasmJson = (this.wasm.exports.mp4MuxerFinish as CallableFunction)(muxer)
+      wasmResultToValue(this.wasm, resultWasmJson)
+
+      const output = this.takeOutput()
+      if (this.onData !== undefined) {
+        if (output.byteLength > 0) {
+          this.onData(output)
+        }
+        return new Blob([], { type: 'video/mp4' })
+      }
+      return new Blob([output], { type: 'video/mp4' })
+    } finally {
+      ;(this.wasm.exports.freeMp4Muxer as CallableFunction)(muxer)
+      this.muxer = undefined
+    }
+  }
+
+  private appendChunk(
+    isVideo: boolean,
+    chunk: EncodedVideoChunk | EncodedAudioChunk,
+    compositionOffset: number,
+  ) {
+    const data = new Uint8Array(chunk.byteLength)
+    chunk.copyTo(data)
+    const resultWasmJson = (this.wasm.exports.mp4MuxerAppendChunk as CallableFunction)(
+      this.getMuxer(),
+      isVideo ? 1 : 0,
+      chunk.timestamp,
+      compositionOffset,
+      chunk.duration ?? -1,
+      chunk.type === 'key' ? 1 : 0,
+      toWasmBytes(this.wasm, data),
+    )
+    wasmResultToValue(this.wasm, resultWasmJson)
+
+    if (this.onData !== undefined) {
+      // Fragmented MP4 の場合には、このチャンクの追加によって新しいセグメントが生成されている可能性がある
+      const output = this.takeOutput()
+      if (output.byteLength > 0) {
+        this.onData(output)
+      }
+    }
+  }
+
+  private takeOutput(): Uint8Array {
+    const outputWasmBytes = (this.wasm.exports.mp4MuxerTakeOutput as CallableFunction)(
+      this.getMuxer(),
+    )
+    return wasmBytesToUint8Array(this.wasm, outputWasmBytes)
+  }
+
+  private getMuxer(): number {
+    if (this.muxer === undefined) {
+      throw new Error('Mp4Muxer has already been finished')
+    }
+    return this.muxer
+  }
+}
+
+// WebCodecs のデコーダー設定の description を JSON で Wasm 側に渡せる形式に変換する
+function descriptionToArray(description?: AllowSharedBufferSource): number[] {
+  if (description === undefined) {
+    return []
+  }
+  if (ArrayBuffer.isView(description)) {
+    return Array.from(
+      new Uint8Array(description.buffer, description.byteOffset, description.byteLength),
+    )
+  }
+  return Array.from(new Uint8Array(description))
+}
+
+/**
+ * {@link inspectMp4} が返す MP4 ファイルの調査結果
+ */
+interface Mp4InspectionReport {
+  /**
+   * ファイルサイズ（バイト単位）
+   */
+  fileSize: number
+
+  /**
+   * トップレベルのボックス群（コンテナボックスは子ボックスを含む）
+   */
+  boxes: Mp4BoxReport[]
+
+  /**
+   * moov ボックス内のトラック毎のサンプルテーブルの要約
+   */
+  tracks: Mp4TrackReport[]
+
+  /**
+   * 検出された仕様違反、あるいは再生時に問題となり得る箇所の一覧
+   */
+  violations: string[]
+}
+
+/**
+ * {@link Mp4InspectionReport} に含まれるボックスの情報
+ */
+interface Mp4BoxReport {
+  /**
+   * ボックスの種別
+   */
+  type: string
+
+  /**
+   * ファイル先頭からのオフセット
+   */
+  offset: number
+
+  /**
+   * ヘッダーを含むボックス全体のサイズ
+   */
+  size: number
+
+  /**
+   * 子ボックス群（存在しない場合は省略されます）
+   */
+  children?: Mp4BoxReport[]
+}
+
+/**
+ * {@link Mp4InspectionReport} に含まれるトラックの情報
+ */
+interface Mp4TrackReport {
+  trackId: number
+
+  /**
+   * hdlr ボックスのハンドラー種別（'vide' や 'soun' など）
+   */
+  handlerType: string
+
+  timescale: number
+
+  /**
+   * サンプルエントリーの種別の一覧
+   */
+  sampleEntries: string[]
+
+  sampleCount: number
+  chunkCount: number
+
+  /**
+   * 同期サンプル（キーフレーム）の数（stss ボックスが存在せず、全てのサンプルが同期サンプル扱いの場合には null）
+   */
+  syncSampleCount: number | null
+
+  /**
+   * サンプルの尺の合計（トラックのタイムスケール単位）
+   */
+  totalDuration: number
+
+  totalDataSize: number
+  minSampleSize: number
+  maxSampleSize: number
+}
+
+/**
+ * MP4 ファイルの構造を調べて、ボックスツリーやサンプルテーブルの要約、仕様違反の一覧を返します
+ *
+ * 不正な MP4 ファイルの調査用の関数で、ファイルが壊れている場合でも例外は送出されずに、
+ * 検出された問題が {@link Mp4InspectionReport.violations} に格納されます。
+ *
+ * @param mp4 調査対象の MP4 ファイル
+ *
+ * @returns 調査結果
+ */
+async function inspectMp4(mp4: Blob): Promise<Mp4InspectionReport> {
+  const wasm = await instantiateWasm()
+  const mp4Bytes = new Uint8Array(await mp4.arrayBuffer())
+  const reportWasmJson = (wasm.exports.inspectMp4 as CallableFunction)(toWasmBytes(wasm, mp4Bytes))
+  return wasmJsonToValue(wasm, reportWasmJson) as Mp4InspectionReport
+}
+
+/**
+ * 複数の MP4 ファイルを、再エンコードを行わずに時間方向に連結します
+ *
+ * @param mp4s 連結対象の MP4 ファイル群（この順番で連結されます）
+ *
+ * @returns 連結結果の MP4 ファイル
+ *
+ * 同じ種類（音声・映像）のトラックは一つのトラックにまとめられます。
+ * 入力ファイル間で H.264 の SPS / PPS などのコーデック設定のみが異なる場合には、
+ * 途中でデコーダー設定が切り替わる MP4 が生成されます。
+ * 各入力ファイルの編集リスト（表示区間）は、連結結果にも引き継がれます。
+ *
+ * @throws
+ * 指定された MP4 が不正であったり、非対応コーデックを含んでいる場合や、
+ * 入力ファイル間でコーデックの種類・解像度・サンプリングレート・チャンネル数が異なる場合には例外が送出されます
+ */
+async function concatMp4(mp4s: Blob[]): Promise<Blob> {
+  const wasm = await instantiateWasm()
+  const concatenator = (wasm.exports.newMp4Concatenator as CallableFunction)()
+  try {
+    for (const mp4 of mp4s) {
+      const mp4Bytes = new Uint8Array(await mp4.arrayBuffer())
+      const resultWasmJson = (wasm.exports.mp4ConcatenatorAddInput as CallableFunction)(
+        concatenator,
+        toWasmBytes(wasm, mp4Bytes),
+      )
+      wasmResultToValue(wasm, resultWasmJson)
+    }
+
+    const resultWasmJson = (wasm.exports.mp4ConcatenatorFinish as CallableFunction)(concatenator)
+    wasmResultToValue(wasm, resultWasmJson)
+
+    const outputWasmBytes = (wasm.exports.mp4ConcatenatorTakeOutput as CallableFunction)(
+      concatenator,
+    )
+    return new Blob([wasmBytesToUint8Array(wasm, outputWasmBytes)], { type: 'video/mp4' })
+  } finally {
+    ;(wasm.exports.freeMp4Concatenator as CallableFunction)(concatenator)
+  }
+}
+
+/**
+ * MP4 ファイル内のボックスを、moov ボックスが mdat ボックスよりも前に来るように並べ替えます（faststart）
+ *
+ * moov ボックスがファイルの末尾にある MP4 は、HTTP 経由で配信する場合に再生開始までに時間がかかるので、
+ * 配信前にこの関数で変換しておくことで、ファイル全体のダウンロードを待たずに再生を開始できるようになります。
+ *
+ * @param mp4 対象の MP4 ファイル
+ *
+ * @returns 変換後の MP4 ファイル
+ *
+ * @throws
+ * 指定された MP4 が不正な場合には例外が送出されます
+ */
+async function faststartMp4(mp4: Blob): Promise<Blob> {
+  const wasm = await instantiateWasm()
+  const mp4Bytes = new Uint8Array(await mp4.arrayBuffer())
+  const mp4WasmBytes = toWasmBytes(wasm, mp4Bytes)
+
+  // 変換に成功した場合には mp4WasmBytes の中身が変換結果に置き換えられる
+  const resultWasmJson = (wasm.exports.faststartMp4 as CallableFunction)(mp4WasmBytes)
+  try {
+    wasmResultToValue(wasm, resultWasmJson)
+  } catch (e) {
+    ;(wasm.exports.freeVec as CallableFunction)(mp4WasmBytes)
+    throw e
+  }
+  return new Blob([wasmBytesToUint8Array(wasm, mp4WasmBytes)], { type: 'video/mp4' })
+}
+
+/**
+ * 録画中のクラッシュなどにより moov ボックスが書き込まれずに終わった MP4 ファイルを、再生可能な形に復旧します
+ *
+ * mdat ボックス内のデータを先頭から走査して、H.264 / H.265 の映像フレームと Opus / AAC の音声フレームを切り出し、
+ * 参照用の MP4 ファイルのコーデック設定やフレームの尺を使って、サンプルテーブルを再構築します。
+ * 映像フレームの尺は、GOP 単位で間に書き込まれている音声フレームの尺の合計から推定されます。
+ *
+ * なお、音声フレームには長さの情報が含まれないため、その区切り位置は参照用の MP4 ファイルを元にした推定となります。
+ *
+ * @param truncated 復旧対象の MP4 ファイル
+ * @param reference 復旧対象と同じ設定（コーデックやビットレートなど）で録画された、正常な MP4 ファイル
+ *
+ * @returns 復旧後の MP4 ファイル
+ *
+ * @throws
+ * 参照用の MP4 ファイルが不正な場合や、復旧対象から一つもサンプルを切り出せなかった場合には例外が送出されます
+ */
+async function recoverMp4(truncated: Blob, reference: Blob): Promise<Blob> {
+  const wasm = await instantiateWasm()
+  const truncatedBytes = new Uint8Array(await truncated.arrayBuffer())
+  const referenceBytes = new Uint8Array(await reference.arrayBuffer())
+  const truncatedWasmBytes = toWasmBytes(wasm, truncatedBytes)
+  const referenceWasmBytes = toWasmBytes(wasm, referenceBytes)
+
+  // 復旧に成功した場合には truncatedWasmBytes の中身が復旧結果に置き換えられる
+  const resultWasmJson = (wasm.exports.recoverMp4 as CallableFunction)(
+    truncatedWasmBytes,
+    referenceWasmBytes,
+  )
+  try {
+    wasmResultToValue(wasm, resultWasmJson)
+  } catch (e) {
+    ;(wasm.exports.freeVec as CallableFunction)(truncatedWasmBytes)
+    throw e
+  }
+  return new Blob([wasmBytesToUint8Array(wasm, truncatedWasmBytes)], { type: 'video/mp4' })
+}
+
+/**
+ * {@link CmafSegmenter.load} や {@link fragmentMp4} に指定可能なオプション
+ */
+interface CmafSegmenterOptions {
+  /**
+   * セグメントの尺の目安（秒単位、デフォルトは 2 秒）
+   *
+   * 映像トラックがある場合にはキーフレームの位置で区切られるため、実際の尺は指定値よりも長くなることがあります
+   */
+  segmentDuration?: number
+}
+
+/**
+ * {@link CmafSegmenter.tracks} に含まれるトラック毎の情報
+ *
+ * 時間を表す値は全てマイクロ秒単位です
+ */
+interface CmafTrackInfo {
+  /**
+   * トラックの種類
+   */
+  kind: 'audio' | 'video'
+
+  /**
+   * Media Source Extensions の `MediaSource.addSourceBuffer()` に渡す MIME タイプ
+   */
+  mimeType: string
+
+  /**
+   * トラックのタイムスケール
+   */
+  timescale: number
+
+  /**
+   * 音声トラックのデコーダー設定（トラックの先頭のもの）
+   */
+  audioConfig?: AudioDecoderConfig
+
+  /**
+   * 映像トラックのデコーダー設定（トラックの先頭のもの）
+   */
+  videoConfig?: VideoDecoderConfig
+
+  /**
+   * メディアセグメント毎の情報
+   */
+  segments: CmafSegmentInfo[]
+}
+
+/**
+ * {@link CmafTrackInfo} に含まれるメディアセグメント毎の情報
+ */
+interface CmafSegmentInfo {
+  /**
+   * セグメントの開始時刻
+   */
+  startMicros: number
+
+  /**
+   * セグメントの尺
+   */
+  durationMicros: number
+
+  /**
+   * セグメントに含まれるサンプルのデータサイズの合計（バイト単位）
+   */
+  dataSize: number
+}
+
+/**
+ * {@link CmafSegmenter.hlsPlaylists} や {@link CmafSegmenter.dashMpd} に指定可能なオプション
+ *
+ * 各テンプレート内の `$RepresentationID$` はトラックのインデックスに、
+ * `$Number$` はメディアセグメントのインデックス（0 始まり）に置換されます。
+ */
+interface ManifestOptions {
+  /**
+   * 初期化セグメントの URI のテンプレート（デフォルトは `init-$RepresentationID$.mp4`）
+   */
+  initSegmentTemplate?: string
+
+  /**
+   * メディアセグメントの URI のテンプレート（デフォルトは `segment-$RepresentationID$-$Number$.m4s`）
+   */
+  mediaSegmentTemplate?: string
+
+  /**
+   * HLS のメディアプレイリストの URI のテンプレート（デフォルトは `playlist-$RepresentationID$.m3u8`）
+   */
+  mediaPlaylistTemplate?: string
+}
+
+/**
+ * {@link CmafSegmenter.hlsPlaylists} が返す HLS のプレイリスト群
+ */
+interface HlsPlaylists {
+  /**
+   * マスタープレイリスト
+   */
+  masterPlaylist: string
+
+  /**
+   * トラック毎のメディアプレイリスト（{@link CmafSegmenter.tracks} と同じ順番）
+   */
+  mediaPlaylists: string[]
+}
+
+/**
+ * 通常の MP4 ファイルを、Media Source Extensions などで再生可能な CMAF 形式のセグメント群に変換するクラス
+ *
+ * トラック毎に独立した初期化セグメントとメディアセグメントが生成されます。
+ * メディアセグメントはインデックスを指定して個別に生成できるので、必要になったセグメントのみを変換することができます。
+ *
+ * 使用例:
+ * ```typescript
+ * const segmenter = await CmafSegmenter.load(mp4FileBlob)
+ * const track = segmenter.tracks[0]
+ * const sourceBuffer = mediaSource.addSourceBuffer(track.mimeType)
+ * sourceBuffer.appendBuffer(segmenter.initSegment(0))
+ * // ... updateend を待ってから ...
+ * sourceBuffer.appendBuffer(segmenter.mediaSegment(0, 0))
+ * ```
+ */
+class CmafSegmenter {
+  private wasm: WebAssembly.Instance
+  private segmenter?: number
+
+  /**
+   * 変換対象の MP4 ファイルに含まれるトラック毎の情報
+   *
+   * {@link CmafSegmenter.initSegment} などに指定するトラックのインデックスは、この配列内の位置に対応します
+   */
+  readonly tracks: CmafTrackInfo[]
+
+  private constructor(wasm: WebAssembly.Instance, segmenter: number, tracks: CmafTrackInfo[]) {
+    this.wasm = wasm
+    this.segmenter = segmenter
+    this.tracks = tracks
+  }
+
+  /**
+   * MP4 ファイルをロードして、セグメントの分割位置を決定します
+   *
+   * @param mp4 変換対象の MP4 ファイル
+   * @param options 変換オプション
+   *
+   * @returns 生成されたインスタンス
+   *
+   * @throws
+   * 指定された MP4 が不正であったり、非対応コーデックを含んでいる場合には例外が送出されます
+   */
+  static async load(mp4: Blob, options: CmafSegmenterOptions = {}): Promise<CmafSegmenter> {
+    const segmentDuration = options.segmentDuration ?? 2
+    if (!(segmentDuration > 0 && Number.isFinite(segmentDuration))) {
+      throw new Error(`Invalid segment duration: ${segmentDuration}`)
+    }
+
+    const wasm = await instantiateWasm()
+    const segmenter = (wasm.exports.newCmafSegmenter as CallableFunction)()
+    try {
+      const mp4Bytes = new Uint8Array(await mp4.arrayBuffer())
+      const resultWasmJson = (wasm.exports.cmafSegmenterLoad as CallableFunction)(
+        segmenter,
+        toWasmBytes(wasm, mp4Bytes),
+        segmentDuration * 1_000_000,
+      )
+      const tracks = wasmResultToValue(wasm, resultWasmJson) as CmafTrackInfo[]
+      for (const track of tracks) {
+        const codec = track.audioConfig?.codec ?? track.videoConfig?.codec
+        track.mimeType = `${track.kind}/mp4; codecs="${codec}"`
+        if (track.videoConfig !== undefined) {
+          // JSON.parse() の結果では description の型は number[] となって期待とは異なるので
+          // ここで適切な型に変換している
+          const config = track.videoConfig
+          config.description = new Uint8Array(config.description as object as number[])
+          if (config.description.byteLength === 0) {
+            config.description = undefined
+          }
+        }
+      }
+      return new CmafSegmenter(wasm, segmenter, tracks)
+    } catch (e) {
+      ;(wasm.exports.freeCmafSegmenter as CallableFunction)(segmenter)
+      throw e
+    }
+  }
+
+  /**
+   * 指定トラックの初期化セグメントを生成します
+   *
+   * @param trackIndex トラックのインデックス
+   *
+   * @returns 初期化セグメント（ftyp + moov）
+   */
+  initSegment(trackIndex: number): Uint8Array {
+    const resultWasmJson = (this.wasm.exports.cmafSegmenterInitSegment as CallableFunction)(
+      this.getSegmenter(),
+      trackIndex,
+    )
+    wasmResultToValue(this.wasm, resultWasmJson)
+    return this.takeOutput()
+  }
+
+  /**
+   * 指定トラックのメディアセグメントを生成します
+   *
+   * @param trackIndex トラックのインデックス
+   * @param segmentIndex セグメントのインデックス（{@link CmafTrackInfo.segments} 内の位置）
+   *
+   * @returns メディアセグメント（moof + mdat）
+   */
+  mediaSegment(trackIndex: number, segmentIndex: number): Uint8Array {
+    const resultWasmJson = (this.wasm.exports.cmafSegmenterMediaSegment as CallableFunction)(
+      this.getSegmenter(),
+      trackIndex,
+      segmentIndex,
+    )
+    wasmResultToValue(this.wasm, resultWasmJson)
+    return this.takeOutput()
+  }
+
+  /**
+   * セグメント群を静的なファイルサーバーから配信するための HLS のプレイリスト群を生成します
+   *
+   * 初期化セグメントやメディアセグメントは、オプションで指定されたテンプレートに従った名前で配置する必要があります。
+   *
+   * @param options 生成オプション
+   *
+   * @returns マスタープレイリストとトラック毎のメディアプレイリスト
+   */
+  hlsPlaylists(options: ManifestOptions = {}): HlsPlaylists {
+    const resultWasmJson = (this.wasm.exports.cmafSegmenterHlsPlaylists as CallableFunction)(
+      this.getSegmenter(),
+      valueToWasmJson(this.wasm, options),
+    )
+    return wasmResultToValue(this.wasm, resultWasmJson) as HlsPlaylists
+  }
+
+  /**
+   * セグメント群を静的なファイルサーバーから配信するための DASH の MPD を生成します
+   *
+   * 初期化セグメントやメディアセグメントは、オプションで指定されたテンプレートに従った名前で配置する必要があります。
+   *
+   * @param options 生成オプション（mediaPlaylistTemplate は使われません）
+   *
+   * @returns MPD の XML 文字列
+   */
+  dashMpd(options: ManifestOptions = {}): string {
+    const resultWasmJson = (this.wasm.exports.cmafSegmenterDashMpd as CallableFunction)(
+      this.getSegmenter(),
+      valueToWasmJson(this.wasm, options),
+    )
+    return wasmResultToValue(this.wasm, resultWasmJson) as object as string
+  }
+
+  /**
+   * 保持しているリソースを解放します
+   *
+   * このメソッドの呼び出し後には、このインスタンスは利用できなくなります
+   */
+  destroy() {
+    if (this.segmenter !== undefined) {
+      ;(this.wasm.exports.freeCmafSegmenter as CallableFunction)(this.segmenter)
+      this.segmenter = undefined
+    }
+  }
+
+  private takeOutput(): Uint8Array {
+    const outputWasmBytes = (this.wasm.exports.cmafSegmenterTakeOutput as CallableFunction)(
+      this.getSegmenter(),
+    )
+    return wasmBytesToUint8Array(this.wasm, outputWasmBytes)
+  }
+
+  private getSegmenter(): number {
+    if (this.segmenter === undefined) {
+      throw new Error('CmafSegmenter has already been destroyed')
+    }
+    return this.segmenter
+  }
+}
+
+/**
+ * {@link fragmentMp4} が返すトラック毎の変換結果
+ */
+interface CmafTrack {
+  /**
+   * トラックの情報
+   */
+  info: CmafTrackInfo
+
+  /**
+   * 初期化セグメント
+   */
+  initSegment: Uint8Array
+
+  /**
+   * メディアセグメント群（{@link CmafTrackInfo.segments} と同じ順番）
+   */
+  mediaSegments: Uint8Array[]
+}
+
+/**
+ * 通常の MP4 ファイルを、CMAF 形式のセグメント群に一括で変換します
+ *
+ * セグメントを個別に生成したい場合には {@link CmafSegmenter} を使用してください。
+ *
+ * @param mp4 変換対象の MP4 ファイル
+ * @param options 変換オプション
+ *
+ * @returns トラック毎の変換結果
+ *
+ * @throws
+ * 指定された MP4 が不正であったり、非対応コーデックを含んでいる場合には例外が送出されます
+ */
+async function fragmentMp4(mp4: Blob, options: CmafSegmenterOptions = {}): Promise<CmafTrack[]> {
+  const segmenter = await CmafSegmenter.load(mp4, options)
+  try {
+    return segmenter.tracks.map((info, trackIndex) => ({
+      info,
+      initSegment: segmenter.initSegment(trackIndex),
+      mediaSegments: info.segments.map((_, segmentIndex) =>
+        segmenter.mediaSegment(trackIndex, segmentIndex),
+      ),
+    }))
+  } finally {
+    segmenter.destroy()
+  }
+}
+
+// 埋め込まれている Wasm モジュールのインスタンスを生成する
+//
+// importObject が省略された場合には、インポート関数は全て何もしない関数で代用される
+// (再生を行わずに MP4 の加工などのみを行う場合には、インポート関数が呼ばれることはないため）
+async function instantiateWasm(
+  importObject: WebAssembly.Imports = { env: new Proxy({}, { get: () => () => {} }) },
+): Promise<WebAssembly.Instance> {
+  const wasmResults = await WebAssembly.instantiateStreaming(
+    fetch(`data:application/wasm;base64,${WASM_BASE64}`),
+    importObject,
+  )
+  return wasmResults.instance
+}
+
+function wasmJsonToValue(wasm: WebAssembly.Instance, wasmJson: number): object {
+  const offset = (wasm.exports.vecOffset as CallableFunction)(wasmJson)
+  const len = (wasm.exports.vecLen as CallableFunction)(wasmJson)
+  const memory = wasm.exports.memory as WebAssembly.Memory
+  const buffer = new Uint8Array(memory.buffer, offset, len)
+  const value = JSON.parse(new TextDecoder('utf-8').decode(buffer))
+
+  // Wasm 側で所有権は放棄されているので、解放するのは呼び出し側の責務
+  ;(wasm.exports.freeVec as CallableFunction)(wasmJson)
+
+  return value
+}
+
+function wasmResultToValue(wasm: WebAssembly.Instance, wasmResult: number): object {
+  const result = wasmJsonToValue(wasm, wasmResult) as { Ok?: object; Err?: { message: string } }
+  if (result.Err !== undefined) {
+    throw new Error(result.Err.message)
+  }
+  return result.Ok as object
+}
+
+function valueToWasmJson(wasm: WebAssembly.Instance, value: object): number {
+  const jsonBytes = new TextEncoder().encode(JSON.stringify(value))
+  return toWasmBytes(wasm, jsonBytes)
+}
+
+function toWasmBytes(wasm: WebAssembly.Instance, bytes: Uint8Array): number {
+  // ここで割り当てられたメモリ領域を解放するのは Wasm 側の責務
+  const wasmBytes = (wasm.exports.allocateVec as CallableFunction)(bytes.length)
+  const wasmBytesOffset = (wasm.exports.vecOffset as CallableFunction)(wasmBytes)
+  const memory = wasm.exports.memory as WebAssembly.Memory
+  new Uint8Array(memory.buffer, wasmBytesOffset, bytes.length).set(bytes)
+  return wasmBytes
+}
+
+function wasmBytesToUint8Array(wasm: WebAssembly.Instance, wasmBytes: number): Uint8Array {
+  const offset = (wasm.exports.vecOffset as CallableFunction)(wasmBytes)
+  const len = (wasm.exports.vecLen as CallableFunction)(wasmBytes)
+  const memory = wasm.exports.memory as WebAssembly.Memory
+  const bytes = new Uint8Array(memory.buffer, offset, len).slice()
+
+  // Wasm 側で所有権は放棄されているので、解放するのは呼び出し側の責務
+  ;(wasm.exports.freeVec as CallableFunction)(wasmBytes)
+
+  return bytes
+}
+
+export {
+  type BurnInCaptionOptions,
+  CmafSegmenter,
+  type CmafSegmenterOptions,
+  type CmafSegmentInfo,
+  type CmafTrack,
+  type CmafTrackInfo,
+  concatMp4,
+  faststartMp4,
+  fragmentMp4,
+  type HlsPlaylists,
+  inspectMp4,
+  type ManifestOptions,
+  type Mp4BoxReport,
+  type Mp4InspectionReport,
+  Mp4MediaStream,
+  Mp4Muxer,
+  type Mp4MuxerOptions,
+  type Mp4TrackReport,
+  type PlayOptions,
+  type PlaybackStats,
+  recoverMp4,
+  type SkippedTrack,
+  type SubtitleCue,
+  type SubtitleTrack,
+  type Timecode,
+  type TrackPlaybackStats,
+}
@@ -0,0 +1,86 @@
+use orfail::OrFail;
+
+use crate::mp4::{AudioDecoderConfig, AAC_SAMPLING_FREQUENCIES};
+
+// ADTS ヘッダー（CRC なし）のバイト数
+const HEADER_SIZE: usize = 7;
+
+// ADTS フレームに含まれる AAC の 1 フレームあたりのサンプル数
+pub const SAMPLES_PER_FRAME: u32 = 1024;
+
+// ADTS (Audio Data Transport Stream) ヘッダー
+//
+// ISO/IEC 13818-7 6.2 Audio Data Transport Stream, ADTS
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdtsHeader {
+    pub audio_object_type: u8,
+    pub sample_rate: u32,
+    pub channel_configuration: u8,
+
+    // ヘッダーを含むフレーム全体のバイト数
+    pub frame_length: usize,
+
+    // CRC を含むヘッダー部分のバイト数
+    pub header_length: usize,
+}
+
+impl AdtsHeader {
+    // バイト列の先頭が ADTS の同期ワードかどうかを判定する
+    pub fn is_sync_word(bytes: &[u8]) -> bool {
+        matches!(bytes, [0xFF, b, ..] if b & 0xF6 == 0xF0)
+    }
+
+    // バイト列の先頭から ADTS ヘッダーを読み込む（ヘッダー全体がない場合には None を返す）
+    pub fn parse(bytes: &[u8]) -> orfail::Result<Option<Self>> {
+        if bytes.len() < HEADER_SIZE {
+            return Ok(None);
+        }
+        Self::is_sync_word(bytes).or_fail_with(|()| "Invalid ADTS sync word".to_owned())?;
+
+        let protection_absent = bytes[1] & 1 == 1;
+        let profile = bytes[2] >> 6;
+        let frequency_index = (bytes[2] >> 2) & 0b1111;
+        let channel_configuration = ((bytes[2] & 1) << 2) | (bytes[3] >> 6);
+        let frame_length = ((bytes[3] as usize & 0b11) << 11)
+            | ((bytes[4] as usize) << 3)
+            | (bytes[5] as usize >> 5);
+        let raw_data_blocks = bytes[6] & 0b11;
+        let header_length = if protection_absent {
+            HEADER_SIZE
+        } else {
+            HEADER_SIZE + 2
+        };
+
+        let sample_rate = *AAC_SAMPLING_FREQUENCIES
+            .get(frequency_index as usize)
+            .or_fail_with(|()| {
+                format!("Invalid ADTS sampling frequency index: {frequency_index}")
+            })?;
+        (channel_configuration != 0).or_fail_with(|()| {
+            "ADTS streams with a program config element are not supported".to_owned()
+        })?;
+        (raw_data_blocks == 0).or_fail_with(|()| {
+            "ADTS frames with multiple raw data blocks are not supported".to_owned()
+        })?;
+        (frame_length > header_length)
+            .or_fail_with(|()| format!("Invalid ADTS frame length: {frame_length}"))?;
+
+        Ok(Some(Self {
+            audio_object_type: profile + 1,
+            sample_rate,
+            channel_configuration,
+            frame_length,
+            header_length,
+        }))
+    }
+
+    pub fn to_decoder_config(&self) -> AudioDecoderConfig {
+        AudioDecoderConfig {
+            codec: format!("mp4a.40.{}", self.audio_object_type),
+            sample_rate: self.sample_rate,
+            number_of_channels: self.channel_configuration,
+            // AudioSpecificConfig はサンプルエントリーの生成時に作られる
+            description: Vec::new(),
+        }
+    }
+}
@@ -0,0 +1,673 @@
+use std::num::NonZeroU32;
+
+use orfail::{Failure, OrFail};
+use shiguredo_mp4::{
+    boxes::{AvccBox, HvccBox, HvccNalUintArray},
+    Encode, Uint,
+};
+
+use crate::{
+    bits::BitReader,
+    mp4::{DecoderConfig, VideoDecoderConfig},
+};
+
+// H.264 の NAL ユニット種別
+const H264_NALU_TYPE_IDR: u8 = 5;
+const H264_NALU_TYPE_SPS: u8 = 7;
+const H264_NALU_TYPE_PPS: u8 = 8;
+const H264_NALU_TYPE_AUD: u8 = 9;
+
+// H.265 の NAL ユニット種別
+const H265_NALU_TYPE_BLA_W_LP: u8 = 16;
+const H265_NALU_TYPE_RSV_IRAP_23: u8 = 23;
+const H265_NALU_TYPE_VPS: u8 = 32;
+const H265_NALU_TYPE_SPS: u8 = 33;
+const H265_NALU_TYPE_PPS: u8 = 34;
+const H265_NALU_TYPE_AUD: u8 = 35;
+
+// avcC / hvcC のペイロードに格納する、NAL ユニットの長さフィールドのバイト数
+const NALU_LENGTH_SIZE: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum H26xCodec {
+    H264,
+    H265,
+}
+
+impl H26xCodec {
+    fn nalu_type(self, nalu: &[u8]) -> u8 {
+        match self {
+            Self::H264 => nalu[0] & 0b1_1111,
+            Self::H265 => (nalu[0] >> 1) & 0b11_1111,
+        }
+    }
+
+    fn is_key_nalu(self, nalu: &[u8]) -> bool {
+        let ty = self.nalu_type(nalu);
+        match self {
+            Self::H264 => ty == H264_NALU_TYPE_IDR,
+            Self::H265 => (H265_NALU_TYPE_BLA_W_LP..=H265_NALU_TYPE_RSV_IRAP_23).contains(&ty),
+        }
+    }
+
+    fn is_vcl_nalu(self, nalu: &[u8]) -> bool {
+        let ty = self.nalu_type(nalu);
+        match self {
+            Self::H264 => (1..=H264_NALU_TYPE_IDR).contains(&ty),
+            Self::H265 => ty < H265_NALU_TYPE_VPS,
+        }
+    }
+
+    // この NAL ユニットが（VCL NAL ユニットの後に出現した場合に）新しいアクセスユニットの先頭となるかどうか
+    fn is_access_unit_start(self, nalu: &[u8]) -> bool {
+        let ty = self.nalu_type(nalu);
+        match self {
+            // SEI / SPS / PPS / AUD / 予約済みの種別、またはピクチャの先頭のスライス（first_mb_in_slice == 0）
+            Self::H264 => {
+                matches!(ty, 6..=9 | 14..=18)
+                    || (self.is_vcl_nalu(nalu) && nalu.get(1).is_some_and(|b| b & 0x80 != 0))
+            }
+            // VPS / SPS / PPS / AUD / prefix SEI / 予約済みの種別、
+            // またはピクチャの先頭のスライスセグメント（first_slice_segment_in_pic_flag == 1）
+            Self::H265 => {
+                matches!(ty, 32..=35 | 39 | 41..=44 | 48..=55)
+                    || (self.is_vcl_nalu(nalu) && nalu.get(2).is_some_and(|b| b & 0x80 != 0))
+            }
+        }
+    }
+
+    fn is_aud_nalu(self, nalu: &[u8]) -> bool {
+        let ty = self.nalu_type(nalu);
+        match self {
+            Self::H264 => ty == H264_NALU_TYPE_AUD,
+            Self::H265 => ty == H265_NALU_TYPE_AUD,
+        }
+    }
+}
+
+// Annex-B 形式（スタートコード区切り）のバイト列を NAL ユニット群に分割する
+pub fn split_nalus(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    nalu_positions(data).into_iter().map(|(_, nalu)| nalu)
+}
+
+// 各 NAL ユニットを、その直前のスタートコードの位置と組にして返す
+fn nalu_positions(data: &[u8]) -> Vec<(usize, &[u8])> {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+            starts.push(i + 3);
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+
+    let ends = starts
+        .iter()
+        .skip(1)
+        .map(|&s| s - 3)
+        .chain(std::iter::once(data.len()))
+        .collect::<Vec<_>>();
+    starts
+        .into_iter()
+        .zip(ends)
+        .filter_map(|(start, end)| {
+            // 4 バイトのスタートコードの先頭の 0 や、末尾の trailing_zero_8bits を取り除く
+            let mut end = end;
+            while end > start && data[end - 1] == 0 {
+                end -= 1;
+            }
+            (start < end).then(|| (start - 3, &data[start..end]))
+        })
+        .collect()
+}
+
+// Annex-B 形式のバイト列をアクセスユニット単位に分割する
+//
+// アクセスユニットの境界は、スライス以外の特定の NAL ユニットや、ピクチャの先頭のスライスの出現位置で判定する
+// (ITU-T H.264 7.4.1.2.3 / ITU-T H.265 7.4.2.4.4）
+pub fn split_access_units(codec: H26xCodec, data: &[u8]) -> Vec<&[u8]> {
+    let mut units = Vec::new();
+    let mut start = 0;
+    let mut has_vcl = false;
+    for (position, nalu) in nalu_positions(data) {
+        if has_vcl && codec.is_access_unit_start(nalu) {
+            units.push(&data[start..position]);
+            start = position;
+            has_vcl = false;
+        }
+        has_vcl |= codec.is_vcl_nalu(nalu);
+    }
+    if has_vcl {
+        units.push(&data[start..]);
+    }
+    units
+}
+
+// Annex-B 形式のバイト列の先頭部分の NAL ユニットヘッダーから、H.264 と H.265 のどちらなのかを判定する
+pub fn detect_codec(data: &[u8]) -> Option<H26xCodec> {
+    split_nalus(data).take(16).find_map(|nalu| {
+        let h265_type = (nalu[0] >> 1) & 0b11_1111;
+        let h264_type = nalu[0] & 0b1_1111;
+        if nalu.get(1) == Some(&1) && (H265_NALU_TYPE_VPS..=H265_NALU_TYPE_AUD).contains(&h265_type)
+        {
+            // H.265 の場合には、二バイト目は nuh_layer_id=0 かつ nuh_temporal_id_plus1=1 となる
+            Some(H26xCodec::H265)
+        } else if nalu[0] & 0x80 == 0
+            && matches!(h264_type, H264_NALU_TYPE_SPS | H264_NALU_TYPE_AUD)
+        {
+            Some(H26xCodec::H264)
+        } else {
+            None
+        }
+    })
+}
+
+// Annex-B 形式の映像ストリームを MP4 形式（長さプレフィックス付き）のサンプルに変換するための構造体
+//
+// 変換の過程で見つかったパラメーターセット（SPS / PPS / VPS）はデコーダー設定の生成に使われる
+#[derive(Debug)]
+pub struct AnnexBConverter {
+    codec: H26xCodec,
+    vps_list: Vec<Vec<u8>>,
+    sps_list: Vec<Vec<u8>>,
+    pps_list: Vec<Vec<u8>>,
+}
+
+impl AnnexBConverter {
+    pub fn new(codec: H26xCodec) -> Self {
+        Self {
+            codec,
+            vps_list: Vec::new(),
+            sps_list: Vec::new(),
+            pps_list: Vec::new(),
+        }
+    }
+
+    // 一つのアクセスユニットを変換して output の末尾に追加し、キーフレームかどうかを返す
+    //
+    // アクセスユニットデリミターは取り除かれ、パラメーターセットはそのまま残される
+    // (途中で解像度などが変わるストリームに対応するため）
+    pub fn convert_access_unit(&mut self, data: &[u8], output: &mut Vec<u8>) -> bool {
+        let mut is_key = false;
+        for nalu in split_nalus(data) {
+            if self.codec.is_aud_nalu(nalu) {
+                continue;
+            }
+            is_key |= self.codec.is_key_nalu(nalu);
+            self.collect_parameter_set(nalu);
+            output.extend_from_slice(&(nalu.len() as u32).to_be_bytes());
+            output.extend_from_slice(nalu);
+        }
+        is_key
+    }
+
+    // 最初に見つかったパラメーターセットのみを保持する
+    fn collect_parameter_set(&mut self, nalu: &[u8]) {
+        let ty = self.codec.nalu_type(nalu);
+        let list = match (self.codec, ty) {
+            (H26xCodec::H264, H264_NALU_TYPE_SPS) => &mut self.sps_list,
+            (H26xCodec::H264, H264_NALU_TYPE_PPS) => &mut self.pps_list,
+            (H26xCodec::H265, H265_NALU_TYPE_VPS) => &mut self.vps_list,
+            (H26xCodec::H265, H265_NALU_TYPE_SPS) => &mut self.sps_list,
+            (H26xCodec::H265, H265_NALU_TYPE_PPS) => &mut self.pps_list,
+            _ => return,
+        };
+        if list.is_empty() {
+            list.push(nalu.to_vec());
+        }
+    }
+
+    // パラメーターセットのタイミング情報から、(タイムスケール, 1 フレームの尺) を求める
+    //
+    // タイミング情報が含まれていない場合には None が返される
+    pub fn frame_rate(&self) -> Option<(NonZeroU32, u32)> {
+        let (num_units_in_tick, time_scale) = match self.codec {
+            H26xCodec::H264 => {
+                let (num_units_in_tick, time_scale) =
+                    H264Sps::parse(self.sps_list.first()?).ok()?.timing?;
+                // H.264 の tick はフィールド単位なので、フレームの尺はその二倍となる
+                (num_units_in_tick.checked_mul(2)?, time_scale)
+            }
+            H26xCodec::H265 => parse_h265_vps_timing(self.vps_list.first()?)?,
+        };
+        let timescale = NonZeroU32::new(time_scale)?;
+        (num_units_in_tick > 0).then_some((timescale, num_units_in_tick))
+    }
+
+    // 収集済みのパラメーターセットから、デコーダー設定を生成する
+    pub fn decoder_config(&self) -> orfail::Result<VideoDecoderConfig> {
+        let sps = self
+            .sps_list
+            .first()
+            .or_fail_with(|()| format!("No SPS found in {:?} stream", self.codec))?;
+        (!self.pps_list.is_empty())
+            .or_fail_with(|()| format!("No PPS found in {:?} stream", self.codec))?;
+
+        // コーデック文字列はサンプルエントリーから作り直されるので、ここでは接頭辞のみを設定しておく
+        let (codec, mut description, sps_info) = match self.codec {
+            H26xCodec::H264 => {
+                let sps_info = H264Sps::parse(sps).or_fail()?;
+                let mut description = Vec::new();
+                sps_info
+                    .to_avcc_box(&self.sps_list, &self.pps_list)
+                    .encode(&mut description)
+                    .or_fail()?;
+                ("avc1.", description, sps_info.dimensions)
+            }
+            H26xCodec::H265 => {
+                (!self.vps_list.is_empty())
+                    .or_fail_with(|()| "No VPS found in H265 stream".to_owned())?;
+                let sps_info = H265Sps::parse(sps).or_fail()?;
+                let mut description = Vec::new();
+                sps_info
+                    .to_hvcc_box(&self.vps_list, &self.sps_list, &self.pps_list)
+                    .encode(&mut description)
+                    .or_fail()?;
+                ("hev1.", description, sps_info.dimensions)
+            }
+        };
+        description.drain(..8); // ボックスヘッダ部分を取り除く
+
+        let (width, height) = sps_info;
+        let config = VideoDecoderConfig {
+            codec: codec.to_owned(),
+            description,
+            coded_width: width,
+            coded_height: height,
+        };
+
+        // サンプルエントリーを経由して、正式なコーデック文字列を持つ設定を得る
+        let sample_entry = config.to_sample_entry().or_fail()?;
+        match DecoderConfig::from_sample_entry(&sample_entry) {
+            Some(DecoderConfig::Video(config)) => Ok(config),
+            _ => Err(Failure::new("Failed to build a video decoder config")),
+        }
+    }
+}
+
+// H.264 の SPS から取得した情報
+#[derive(Debug)]
+struct H264Sps {
+    profile_idc: u8,
+    constraint_flags: u8,
+    level_idc: u8,
+    chroma_format_idc: u8,
+    bit_depth_luma_minus8: u8,
+    bit_depth_chroma_minus8: u8,
+    dimensions: (u16, u16),
+
+    // VUI に含まれる (num_units_in_tick, time_scale)
+    timing: Option<(u32, u32)>,
+}
+
+impl H264Sps {
+    // ITU-T H.264 7.3.2.1.1 Sequence parameter set data syntax
+    fn parse(nalu: &[u8]) -> orfail::Result<Self> {
+        Self::parse_rbsp(&remove_emulation_prevention(&nalu[1..]))
+            .or_fail_with(|()| "Invalid H.264 SPS".to_owned())
+    }
+
+    fn parse_rbsp(rbsp: &[u8]) -> Option<Self> {
+        let mut r = BitReader::new(rbsp);
+        let profile_idc = r.read_bits(8)? as u8;
+        let constraint_flags = r.read_bits(8)? as u8;
+        let level_idc = r.read_bits(8)? as u8;
+        r.read_ue()?; // seq_parameter_set_id
+
+        let mut chroma_format_idc = 1;
+        let mut separate_colour_plane = false;
+        let mut bit_depth_luma_minus8 = 0;
+        let mut bit_depth_chroma_minus8 = 0;
+        if matches!(
+            profile_idc,
+            100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135
+        ) {
+            chroma_format_idc = r.read_ue()? as u8;
+            if chroma_format_idc == 3 {
+                separate_colour_plane = r.read_flag()?;
+            }
+            bit_depth_luma_minus8 = r.read_ue()? as u8;
+            bit_depth_chroma_minus8 = r.read_ue()? as u8;
+            r.read_flag()?; // qpprime_y_zero_transform_bypass_flag
+            if r.read_flag()? {
+                // seq_scaling_matrix_present_flag
+                let count = if chroma_format_idc == 3 { 12 } else { 8 };
+                for i in 0..count {
+                    if r.read_flag()? {
+                        skip_scaling_list(&mut r, if i < 6 { 16 } else { 64 })?;
+                    }
+                }
+            }
+        }
+
+        r.read_ue()?; // log2_max_frame_num_minus4
+        match r.read_ue()? {
+            // pic_order_cnt_type
+            0 => {
+                r.read_ue()?; // log2_max_pic_order_cnt_lsb_minus4
+            }
+            1 => {
+                r.read_flag()?; // delta_pic_order_always_zero_flag
+                r.read_se()?; // offset_for_non_ref_pic
+                r.read_se()?; // offset_for_top_to_bottom_field
+                for _ in 0..r.read_ue()? {
+                    r.read_se()?; // offset_for_ref_frame
+                }
+            }
+            _ => {}
+        }
+        r.read_ue()?; // max_num_ref_frames
+        r.read_flag()?; // gaps_in_frame_num_value_allowed_flag
+        let pic_width_in_mbs = r.read_ue()? + 1;
+        let pic_height_in_map_units = r.read_ue()? + 1;
+        let frame_mbs_only = r.read_flag()? as u32;
+        if frame_mbs_only == 0 {
+            r.read_flag()?; // mb_adaptive_frame_field_flag
+        }
+        r.read_flag()?; // direct_8x8_inference_flag
+
+        let mut width = pic_width_in_mbs * 16;
+        let mut height = (2 - frame_mbs_only) * pic_height_in_map_units * 16;
+        if r.read_flag()? {
+            // frame_cropping_flag
+            let chroma_array_type = if separate_colour_plane {
+                0
+            } else {
+                chroma_format_idc
+            };
+            let (crop_unit_x, crop_unit_y) = match chroma_array_type {
+                1 => (2, 2 * (2 - frame_mbs_only)),
+                2 => (2, 2 - frame_mbs_only),
+                _ => (1, 2 - frame_mbs_only),
+            };
+            let left = r.read_ue()?;
+            let right = r.read_ue()?;
+            let top = r.read_ue()?;
+            let bottom = r.read_ue()?;
+            width = width.checked_sub((left + right) * crop_unit_x)?;
+            height = height.checked_sub((top + bottom) * crop_unit_y)?;
+        }
+
+        // VUI の途中で読み込みに失敗しても、解像度などの情報は使えるようにする
+        let vui_parameters_present = r.read_flag() == Some(true);
+        let timing = vui_parameters_present
+            .then(|| Self::parse_vui_timing(&mut r))
+            .flatten();
+
+        Some(Self {
+            profile_idc,
+            constraint_flags,
+            level_idc,
+            chroma_format_idc,
+            bit_depth_luma_minus8,
+            bit_depth_chroma_minus8,
+            dimensions: (u16::try_from(width).ok()?, u16::try_from(height).ok()?),
+            timing,
+        })
+    }
+
+    // ITU-T H.264 E.1.1 VUI parameters syntax (timing_info_present_flag までの部分のみ)
+    fn parse_vui_timing(r: &mut BitReader) -> Option<(u32, u32)> {
+        if r.read_flag()? {
+            // aspect_ratio_info_present_flag
+            const EXTENDED_SAR: u32 = 255;
+            if r.read_bits(8)? == EXTENDED_SAR {
+                r.skip_bits(32)?; // sar_width, sar_height
+            }
+        }
+        if r.read_flag()? {
+            // overscan_info_present_flag
+            r.skip_bits(1)?;
+        }
+        if r.read_flag()? {
+            // video_signal_type_present_flag
+            r.skip_bits(4)?; // video_format, video_full_range_flag
+            if r.read_flag()? {
+                // colour_description_present_flag
+                r.skip_bits(24)?;
+            }
+        }
+        if r.read_flag()? {
+            // chroma_loc_info_present_flag
+            r.read_ue()?;
+            r.read_ue()?;
+        }
+        if !r.read_flag()? {
+            // timing_info_present_flag
+            return None;
+        }
+        let num_units_in_tick = r.read_bits(32)?;
+        let time_scale = r.read_bits(32)?;
+        Some((num_units_in_tick, time_scale))
+    }
+
+    fn to_avcc_box(&self, sps_list: &[Vec<u8>], pps_list: &[Vec<u8>]) -> AvccBox {
+        // Baseline / Main / Extended 以外のプロファイルでは、クロマフォーマットなどのフィールドが必要
+        let has_extensions = !matches!(self.profile_idc, 66 | 77 | 88);
+        AvccBox {
+            avc_profile_indication: self.profile_idc,
+            profile_compatibility: self.constraint_flags,
+            avc_level_indication: self.level_idc,
+            length_size_minus_one: Uint::new(NALU_LENGTH_SIZE as u8 - 1),
+            sps_list: sps_list.to_vec(),
+            pps_list: pps_list.to_vec(),
+            chroma_format: has_extensions.then(|| Uint::new(self.chroma_format_idc)),
+            bit_depth_luma_minus8: has_extensions.then(|| Uint::new(self.bit_depth_luma_minus8)),
+            bit_depth_chroma_minus8: has_extensions
+                .then(|| Uint::new(self.bit_depth_chroma_minus8)),
+            sps_ext_list: Vec::new(),
+        }
+    }
+}
+
+// H.265 の SPS から取得した情報
+#[derive(Debug)]
+struct H265Sps {
+    general_profile_space: u8,
+    general_tier_flag: u8,
+    general_profile_idc: u8,
+    general_profile_compatibility_flags: u32,
+    general_constraint_indicator_flags: u64,
+    general_level_idc: u8,
+    max_sub_layers: u8,
+    temporal_id_nesting: u8,
+    chroma_format_idc: u8,
+    bit_depth_luma_minus8: u8,
+    bit_depth_chroma_minus8: u8,
+    dimensions: (u16, u16),
+}
+
+impl H265Sps {
+    // ITU-T H.265 7.3.2.2.1 General sequence parameter set RBSP syntax
+    fn parse(nalu: &[u8]) -> orfail::Result<Self> {
+        (nalu.len() > 2).or_fail_with(|()| "Too short H.265 SPS".to_owned())?;
+        Self::parse_rbsp(&remove_emulation_prevention(&nalu[2..]))
+            .or_fail_with(|()| "Invalid H.265 SPS".to_owned())
+    }
+
+    fn parse_rbsp(rbsp: &[u8]) -> Option<Self> {
+        let mut r = BitReader::new(rbsp);
+        r.read_bits(4)?; // sps_video_parameter_set_id
+        let max_sub_layers_minus1 = r.read_bits(3)? as u8;
+        let temporal_id_nesting = r.read_bits(1)? as u8;
+
+        // profile_tier_level(1, sps_max_sub_layers_minus1)
+        let general_profile_space = r.read_bits(2)? as u8;
+        let general_tier_flag = r.read_bits(1)? as u8;
+        let general_profile_idc = r.read_bits(5)? as u8;
+        let general_profile_compatibility_flags = r.read_bits(32)?;
+        let general_constraint_indicator_flags =
+            ((r.read_bits(16)? as u64) << 32) | r.read_bits(32)? as u64;
+        let general_level_idc = r.read_bits(8)? as u8;
+        skip_sub_layer_profile_tier_levels(&mut r, max_sub_layers_minus1)?;
+
+        r.read_ue()?; // sps_seq_parameter_set_id
+        let chroma_format_idc = r.read_ue()? as u8;
+        if chroma_format_idc == 3 {
+            r.read_flag()?; // separate_colour_plane_flag
+        }
+        let mut width = r.read_ue()?;
+        let mut height = r.read_ue()?;
+        if r.read_flag()? {
+            // conformance_window_flag
+            let (sub_width, sub_height) = match chroma_format_idc {
+                1 => (2, 2),
+                2 => (2, 1),
+                _ => (1, 1),
+            };
+            let left = r.read_ue()?;
+            let right = r.read_ue()?;
+            let top = r.read_ue()?;
+            let bottom = r.read_ue()?;
+            width = width.checked_sub((left + right) * sub_width)?;
+            height = height.checked_sub((top + bottom) * sub_height)?;
+        }
+        let bit_depth_luma_minus8 = r.read_ue()? as u8;
+        let bit_depth_chroma_minus8 = r.read_ue()? as u8;
+
+        Some(Self {
+            general_profile_space,
+            general_tier_flag,
+            general_profile_idc,
+            general_profile_compatibility_flags,
+            general_constraint_indicator_flags,
+            general_level_idc,
+            max_sub_layers: max_sub_layers_minus1 + 1,
+            temporal_id_nesting,
+            chroma_format_idc,
+            bit_depth_luma_minus8,
+            bit_depth_chroma_minus8,
+            dimensions: (u16::try_from(width).ok()?, u16::try_from(height).ok()?),
+        })
+    }
+
+    fn to_hvcc_box(
+        &self,
+        vps_list: &[Vec<u8>],
+        sps_list: &[Vec<u8>],
+        pps_list: &[Vec<u8>],
+    ) -> HvccBox {
+        let nalu_array = |nal_unit_type: u8, nalus: &[Vec<u8>]| HvccNalUintArray {
+            array_completeness: Uint::new(0),
+            nal_unit_type: Uint::new(nal_unit_type),
+            nalus: nalus.to_vec(),
+        };
+        HvccBox {
+            general_profile_space: Uint::new(self.general_profile_space),
+            general_tier_flag: Uint::new(self.general_tier_flag),
+            general_profile_idc: Uint::new(self.general_profile_idc),
+            general_profile_compatibility_flags: self.general_profile_compatibility_flags,
+            general_constraint_indicator_flags: Uint::new(self.general_constraint_indicator_flags),
+            general_level_idc: self.general_level_idc,
+            min_spatial_segmentation_idc: Uint::new(0),
+            parallelism_type: Uint::new(0),
+            chroma_format_idc: Uint::new(self.chroma_format_idc),
+            bit_depth_luma_minus8: Uint::new(self.bit_depth_luma_minus8),
+            bit_depth_chroma_minus8: Uint::new(self.bit_depth_chroma_minus8),
+            avg_frame_rate: 0,
+            constant_frame_rate: Uint::new(0),
+            num_temporal_layers: Uint::new(self.max_sub_layers),
+            temporal_id_nested: Uint::new(self.temporal_id_nesting),
+            length_size_minus_one: Uint::new(NALU_LENGTH_SIZE as u8 - 1),
+            nalu_arrays: vec![
+                nalu_array(H265_NALU_TYPE_VPS, vps_list),
+                nalu_array(H265_NALU_TYPE_SPS, sps_list),
+                nalu_array(H265_NALU_TYPE_PPS, pps_list),
+            ],
+        }
+    }
+}
+
+// ITU-T H.265 7.3.3 Profile, tier and level syntax の、サブレイヤー部分を読み飛ばす
+fn skip_sub_layer_profile_tier_levels(r: &mut BitReader, max_sub_layers_minus1: u8) -> Option<()> {
+    let mut sub_layer_flags = Vec::new();
+    for _ in 0..max_sub_layers_minus1 {
+        let profile_present = r.read_flag()?;
+        let level_present = r.read_flag()?;
+        sub_layer_flags.push((profile_present, level_present));
+    }
+    if max_sub_layers_minus1 > 0 {
+        for _ in max_sub_layers_minus1..8 {
+            r.skip_bits(2)?; // reserved_zero_2bits
+        }
+    }
+    for (profile_present, level_present) in sub_layer_flags {
+        if profile_present {
+            r.skip_bits(88)?;
+        }
+        if level_present {
+            r.skip_bits(8)?;
+        }
+    }
+    Some(())
+}
+
+// H.265 の VPS から (vps_num_units_in_tick, vps_time_scale) を取得する
+//
+// ITU-T H.265 7.3.2.1 Video parameter set RBSP syntax
+fn parse_h265_vps_timing(nalu: &[u8]) -> Option<(u32, u32)> {
+    let rbsp = remove_emulation_prevention(nalu.get(2..)?);
+    let mut r = BitReader::new(&rbsp);
+    r.skip_bits(4 + 1 + 1 + 6)?; // vps_video_parameter_set_id ... vps_max_layers_minus1
+    let max_sub_layers_minus1 = r.read_bits(3)? as u8;
+    r.skip_bits(1 + 16)?; // vps_temporal_id_nesting_flag, vps_reserved_0xffff_16bits
+
+    // profile_tier_level(1, vps_max_sub_layers_minus1)
+    r.skip_bits(96)?; // general_profile_space ... general_level_idc
+    skip_sub_layer_profile_tier_levels(&mut r, max_sub_layers_minus1)?;
+
+    let sub_layer_ordering_info_present = r.read_flag()?;
+    let first_sub_layer = if sub_layer_ordering_info_present {
+        0
+    } else {
+        max_sub_layers_minus1
+    };
+    for _ in first_sub_layer..=max_sub_layers_minus1 {
+        r.read_ue()?; // vps_max_dec_pic_buffering_minus1
+        r.read_ue()?; // vps_max_num_reorder_pics
+        r.read_ue()?; // vps_max_latency_increase_plus1
+    }
+    let max_layer_id = r.read_bits(6)? as usize;
+    let num_layer_sets_minus1 = r.read_ue()? as usize;
+    r.skip_bits(num_layer_sets_minus1 * (max_layer_id + 1))?; // layer_id_included_flag
+    if !r.read_flag()? {
+        // vps_timing_info_present_flag
+        return None;
+    }
+    let num_units_in_tick = r.read_bits(32)?;
+    let time_scale = r.read_bits(32)?;
+    Some((num_units_in_tick, time_scale))
+}
+
+// NAL ユニットのペイロードからエミュレーション防止バイト（00 00 03 の 03）を取り除く
+fn remove_emulation_prevention(data: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(data.len());
+    let mut zeros = 0;
+    for &b in data {
+        if zeros >= 2 && b == 3 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if b == 0 { zeros + 1 } else { 0 };
+        rbsp.push(b);
+    }
+    rbsp
+}
+
+// ITU-T H.264 7.3.2.1.1.1 Scaling list syntax
+fn skip_scaling_list(r: &mut BitReader, size: usize) -> Option<()> {
+    let mut last_scale = 8;
+    let mut next_scale = 8;
+    for _ in 0..size {
+        if next_scale != 0 {
+            let delta_scale = r.read_se()?;
+            next_scale = (last_scale + delta_scale + 256) % 256;
+        }
+        if next_scale != 0 {
+            last_scale = next_scale;
+        }
+    }
+    Some(())
+}
@@ -0,0 +1,107 @@
+// MP4 ファイルの構造を調べて、ボックスツリーやサンプルテーブルの要約、仕様違反の一覧を表示するコマンド
+//
+// 使い方: mp4-inspect [--json] MP4_FILE
+//
+// 仕様違反が検出された場合には終了コードが 1 となる
+use mp4_media_stream::inspect::{inspect_mp4, BoxReport, Mp4Report};
+
+fn main() {
+    let mut json = false;
+    let mut path = None;
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--json" => json = true,
+            "-h" | "--help" => {
+                println!("Usage: mp4-inspect [--json] MP4_FILE");
+                return;
+            }
+            _ if path.is_none() => path = Some(arg),
+            _ => exit_with_usage(),
+        }
+    }
+    let Some(path) = path else {
+        exit_with_usage();
+    };
+
+    let mp4_bytes = match std::fs::read(&path) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("Failed to read {path}: {e}");
+            std::process::exit(2);
+        }
+    };
+    let report = inspect_mp4(&mp4_bytes);
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&report).expect("infallible")
+        );
+    } else {
+        print_report(&report);
+    }
+
+    if !report.violations.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+fn exit_with_usage() -> ! {
+    eprintln!("Usage: mp4-inspect [--json] MP4_FILE");
+    std::process::exit(2);
+}
+
+fn print_report(report: &Mp4Report) {
+    println!("File size: {}", report.file_size);
+
+    println!();
+    println!("Boxes:");
+    for b in &report.boxes {
+        print_box(b, 1);
+    }
+
+    println!();
+    println!("Tracks:");
+    for track in &report.tracks {
+        println!(
+            "  #{} handler={} timescale={} sample_entries=[{}]",
+            track.track_id,
+            track.handler_type,
+            track.timescale,
+            track.sample_entries.join(", ")
+        );
+        let sync_samples = track
+            .sync_sample_count
+            .map_or_else(|| "all (no stss)".to_owned(), |n| n.to_string());
+        println!(
+            "    samples={} chunks={} sync_samples={sync_samples} duration={}",
+            track.sample_count, track.chunk_count, track.total_duration
+        );
+        println!(
+            "    data_size={} sample_size=[{}, {}]",
+            track.total_data_size, track.min_sample_size, track.max_sample_size
+        );
+    }
+
+    println!();
+    if report.violations.is_empty() {
+        println!("Violations: none");
+    } else {
+        println!("Violations:");
+        for violation in &report.violations {
+            println!("  - {violation}");
+        }
+    }
+}
+
+fn print_box(b: &BoxReport, depth: usize) {
+    println!(
+        "{}{} offset={} size={}",
+        "  ".repeat(depth),
+        b.box_type,
+        b.offset,
+        b.size
+    );
+    for child in &b.children {
+        print_box(child, depth + 1);
+    }
+}
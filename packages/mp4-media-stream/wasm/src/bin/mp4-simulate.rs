@@ -0,0 +1,205 @@
+// ブラウザを使わずに、仮想時刻の上で MP4 の再生をシミュレートして、デコーダーへの入力スケジュールを表示するコマンド
+//
+// 使い方: mp4-simulate [--json] [--repeat] [--until SECONDS] [--timer-delay MILLIS] [--audio-latency MILLIS] MP4_FILE
+//
+// 繰り返し再生（--repeat）の場合には、終了時刻（--until）の指定が必須となる
+use std::time::Duration;
+
+use mp4_media_stream::{
+    mp4::TrackKind,
+    simulator::{
+        simulate_playback, SimulateOptions, SimulationEvent, SimulationEventKind, SimulationReport,
+    },
+};
+
+const USAGE: &str =
+    "Usage: mp4-simulate [--json] [--repeat] [--until SECONDS] [--timer-delay MILLIS] [--audio-latency MILLIS] MP4_FILE";
+
+fn main() {
+    let mut json = false;
+    let mut options = SimulateOptions {
+        repeat: false,
+        until: None,
+        timer_delay: Duration::ZERO,
+        audio_latency: None,
+    };
+    let mut path = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--repeat" => options.repeat = true,
+            "--until" => {
+                let secs = parse_number(args.next());
+                options.until = Some(Duration::from_secs_f64(secs));
+            }
+            "--timer-delay" => {
+                let millis = parse_number(args.next());
+                options.timer_delay = Duration::from_secs_f64(millis / 1000.0);
+            }
+            "--audio-latency" => {
+                let millis = parse_number(args.next());
+                options.audio_latency = Some(Duration::from_secs_f64(millis / 1000.0));
+            }
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return;
+            }
+            _ if path.is_none() => path = Some(arg),
+            _ => exit_with_usage(),
+        }
+    }
+    let Some(path) = path else {
+        exit_with_usage();
+    };
+    if options.repeat && options.until.is_none() {
+        exit_with_usage();
+    }
+
+    let mp4_bytes = match std::fs::read(&path) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("Failed to read {path}: {e}");
+            std::process::exit(2);
+        }
+    };
+    let report = match simulate_playback(mp4_bytes, options) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("Failed to simulate playback: {e}");
+            std::process::exit(1);
+        }
+    };
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&report).expect("infallible")
+        );
+    } else {
+        print_report(&report);
+    }
+}
+
+fn parse_number(arg: Option<String>) -> f64 {
+    match arg.and_then(|arg| arg.parse::<f64>().ok()) {
+        Some(n) if n.is_finite() && n >= 0.0 => n,
+        _ => exit_with_usage(),
+    }
+}
+
+fn exit_with_usage() -> ! {
+    eprintln!("{USAGE}");
+    std::process::exit(2);
+}
+
+fn print_report(report: &SimulationReport) {
+    for event in &report.events {
+        print_event(event);
+    }
+
+    let stats = &report.stats;
+    println!();
+    println!(
+        "late_samples={} max_lateness={} sleep_count={} max_sleep_overshoot={}",
+        stats.late_samples,
+        secs(stats.max_lateness),
+        stats.sleep_count,
+        secs(stats.max_sleep_overshoot)
+    );
+    println!(
+        "audio_clock_drift={} max_audio_clock_drift={} audio_clock_resyncs={}",
+        secs(stats.audio_clock_drift),
+        secs(stats.max_audio_clock_drift),
+        stats.audio_clock_resyncs
+    );
+    for track in &stats.tracks {
+        println!(
+            "{:?}: samples={} bytes={} decoder_configurations={} catch_ups={} skipped_samples={}",
+            track.kind,
+            track.samples,
+            track.bytes,
+            track.decoder_configurations,
+            track.catch_ups,
+            track.skipped_samples
+        );
+    }
+}
+
+fn print_event(event: &SimulationEvent) {
+    let time = secs(event.time);
+    match &event.kind {
+        SimulationEventKind::ConfigureAudioDecoder {
+            codec,
+            sample_rate,
+            number_of_channels,
+        } => {
+            println!("{time} configure audio codec={codec} sample_rate={sample_rate} channels={number_of_channels}");
+        }
+        SimulationEventKind::ConfigureVideoDecoder {
+            codec,
+            coded_width,
+            coded_height,
+        } => {
+            println!("{time} configure video codec={codec} size={coded_width}x{coded_height}");
+        }
+        SimulationEventKind::Decode {
+            track_kind,
+            timestamp,
+            duration,
+            is_key,
+            data_size,
+        } => {
+            println!(
+                "{time} decode    {} timestamp={} duration={} size={data_size}{}",
+                kind_name(*track_kind),
+                secs(*timestamp),
+                secs(*duration),
+                if *is_key { " key" } else { "" }
+            );
+        }
+        SimulationEventKind::OutputAudio {
+            timestamp,
+            number_of_samples,
+        } => {
+            println!(
+                "{time} output    audio timestamp={} samples={number_of_samples}",
+                secs(*timestamp)
+            );
+        }
+        SimulationEventKind::Cue {
+            track_id,
+            start,
+            end,
+            text,
+        } => {
+            println!(
+                "{time} cue       track={track_id} start={} end={} text={text:?}",
+                secs(*start),
+                secs(*end)
+            );
+        }
+        SimulationEventKind::ResetDecoder { track_kind } => {
+            println!("{time} reset     {}", kind_name(*track_kind));
+        }
+        SimulationEventKind::CloseDecoder { track_kind } => {
+            println!("{time} close     {}", kind_name(*track_kind));
+        }
+        SimulationEventKind::TimeUpdate { position } => {
+            println!("{time} time      position={}", secs(*position));
+        }
+        SimulationEventKind::Eos => {
+            println!("{time} eos");
+        }
+    }
+}
+
+fn kind_name(kind: TrackKind) -> &'static str {
+    match kind {
+        TrackKind::Audio => "audio",
+        TrackKind::Video => "video",
+    }
+}
+
+fn secs(duration: Duration) -> String {
+    format!("{:.6}", duration.as_secs_f64())
+}
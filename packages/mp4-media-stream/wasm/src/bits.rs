@@ -0,0 +1,43 @@
+// 指数ゴロム符号などを読み込むためのビット単位のリーダー
+#[derive(Debug)]
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    pub fn read_flag(&mut self) -> Option<bool> {
+        let byte = self.data.get(self.position / 8)?;
+        let bit = (byte >> (7 - self.position % 8)) & 1;
+        self.position += 1;
+        Some(bit == 1)
+    }
+
+    pub fn read_bits(&mut self, n: usize) -> Option<u32> {
+        (0..n).try_fold(0, |acc, _| Some((acc << 1) | self.read_flag()? as u32))
+    }
+
+    pub fn skip_bits(&mut self, n: usize) -> Option<()> {
+        (self.position + n <= self.data.len() * 8).then(|| self.position += n)
+    }
+
+    pub fn read_ue(&mut self) -> Option<u32> {
+        let mut leading_zeros = 0;
+        while !self.read_flag()? {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                return None;
+            }
+        }
+        Some((1 << leading_zeros) - 1 + self.read_bits(leading_zeros)?)
+    }
+
+    pub fn read_se(&mut self) -> Option<i32> {
+        let v = self.read_ue()? as i64;
+        Some(if v % 2 == 0 { -(v / 2) } else { (v + 1) / 2 } as i32)
+    }
+}
@@ -0,0 +1,567 @@
+use std::{ops::Range, time::Duration};
+
+use orfail::OrFail;
+use serde::Serialize;
+
+use crate::{
+    fmp4::{write_init_segment, write_media_segment, FragmentTrack},
+    manifest::{make_dash_mpd, make_hls_playlists, HlsPlaylists, ManifestOptions},
+    mp4::{AudioDecoderConfig, DecoderConfig, Mp4, TrackKind, VideoDecoderConfig},
+    muxer::{read_composition_offsets, to_timescale, MuxEdit, MuxSample, MuxTrack},
+    stats::serialize_micros,
+};
+
+// 通常の MP4 を、Media Source Extensions などで再生可能な CMAF 形式のセグメント群に変換するための構造体
+//
+// CMAF に合わせて、トラック毎に独立した初期化セグメントとメディアセグメントが生成される。
+// セグメントの区切りは映像トラックのキーフレームの位置となり（映像トラックがない場合には音声トラックの任意のサンプル）、
+// 他のトラックもその時刻に揃えて区切られる。
+//
+// 各セグメントはインデックスを指定して個別に生成できるので、全てを一度に変換する必要はない。
+//
+// 元の MP4 の編集リストは初期化セグメントの elst ボックスに引き継がれ、
+// セグメントの区切りも編集リストを適用した後の（全トラック共通の）時刻で揃えられる。
+#[derive(Debug, Default)]
+pub struct CmafSegmenter {
+    mp4_bytes: Vec<u8>,
+    tracks: Vec<SegmentedTrack>,
+    output: Vec<u8>,
+}
+
+impl CmafSegmenter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // 変換対象の MP4 をロードして、セグメントの分割位置を決定する
+    //
+    // 各セグメントの尺は、次のキーフレームが来るまでは segment_duration を超えることがある
+    pub fn load(
+        &mut self,
+        mp4_bytes: Vec<u8>,
+        segment_duration: Duration,
+    ) -> orfail::Result<Vec<CmafTrackInfo>> {
+        (!segment_duration.is_zero())
+            .or_fail_with(|()| "Segment duration must be positive".to_owned())?;
+        let mp4 = Mp4::load(&mp4_bytes).or_fail()?;
+
+        let mut tracks = Vec::new();
+        for track in &mp4.tracks {
+            let composition_offsets = read_composition_offsets(
+                track.sample_table.stbl_box(),
+                track.sample_table.sample_count() as usize,
+            )
+            .or_fail()?;
+            let mut mux_track = MuxTrack::new(track.kind, track.timescale);
+            let mut decode_times = Vec::new();
+            for (sample, composition_offset) in
+                track.sample_table.samples().zip(composition_offsets)
+            {
+                let sample_entry_index =
+                    mux_track.add_sample_entry(sample.chunk().sample_entry().clone());
+                mux_track.samples.push(MuxSample {
+                    sample_entry_index,
+                    duration: sample.duration(),
+                    is_sync: sample.is_sync_sample(),
+                    composition_offset,
+                    data_offset: sample.data_offset(),
+                    data_size: sample.data_size(),
+                });
+                decode_times.push(sample.timestamp());
+            }
+
+            // 先頭の空白区間はトラックの開始位置として扱う
+            let mut edits = track.edits.iter().peekable();
+            while let Some(MuxEdit::Empty(duration)) = edits.peek() {
+                mux_track.start_offset += *duration;
+                edits.next();
+            }
+            for edit in edits {
+                mux_track.push_edit(edit.clone());
+            }
+            if mux_track.edits == [MuxEdit::Media(0..mux_track.media_duration())] {
+                // 全体が表示されるだけの場合には、編集リストは不要
+                mux_track.edits.clear();
+            }
+            tracks.push(SegmentedTrack {
+                mux_track,
+                decode_times,
+                segments: Vec::new(),
+            });
+        }
+
+        // 区切りの基準となるトラックで分割位置（時刻）を決めてから、それを全トラックに適用する
+        let base_track = tracks
+            .iter()
+            .find(|t| t.mux_track.kind == TrackKind::Video)
+            .unwrap_or(&tracks[0]);
+        let cut_times = base_track.cut_times(segment_duration);
+        for track in &mut tracks {
+            track.split(&cut_times);
+        }
+
+        self.mp4_bytes = mp4_bytes;
+        self.tracks = tracks;
+        Ok(self.tracks.iter().map(|t| t.info()).collect())
+    }
+
+    // 指定トラックの初期化セグメントを生成する
+    //
+    // 生成結果は take_output() で取得できる
+    pub fn init_segment(&mut self, track_index: usize) -> orfail::Result<()> {
+        let track = self.get_track(track_index).or_fail()?;
+        self.output = write_init_segment(std::slice::from_ref(&track.mux_track)).or_fail()?;
+        Ok(())
+    }
+
+    // 指定トラックの segment_index 番目のメディアセグメントを生成する
+    //
+    // 生成結果は take_output() で取得できる
+    pub fn media_segment(
+        &mut self,
+        track_index: usize,
+        segment_index: usize,
+    ) -> orfail::Result<()> {
+        let track = self.get_track(track_index).or_fail()?;
+        let range = track
+            .segments
+            .get(segment_index)
+            .cloned()
+            .or_fail_with(|()| {
+                format!("Segment index out of range: track={track_index}, segment={segment_index}")
+            })?;
+        let fragment_track = FragmentTrack {
+            track_id: 1,
+            base_media_decode_time: track.decode_times[range.start],
+            samples: &track.mux_track.samples[range],
+        };
+
+        // シーケンス番号は 1 始まり
+        let sequence_number = segment_index as u32 + 1;
+        self.output =
+            write_media_segment(sequence_number, &[fragment_track], &self.mp4_bytes).or_fail()?;
+        Ok(())
+    }
+
+    // ロード済みの MP4 を配信するための HLS のプレイリスト群を生成する
+    pub fn hls_playlists(&self, options: &ManifestOptions) -> orfail::Result<HlsPlaylists> {
+        let tracks = self.track_infos().or_fail()?;
+        Ok(make_hls_playlists(&tracks, options))
+    }
+
+    // ロード済みの MP4 を配信するための DASH の MPD を生成する
+    pub fn dash_mpd(&self, options: &ManifestOptions) -> orfail::Result<String> {
+        let tracks = self.track_infos().or_fail()?;
+        Ok(make_dash_mpd(&tracks, options))
+    }
+
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    fn track_infos(&self) -> orfail::Result<Vec<CmafTrackInfo>> {
+        self.check_loaded().or_fail()?;
+        Ok(self.tracks.iter().map(|t| t.info()).collect())
+    }
+
+    fn get_track(&self, track_index: usize) -> orfail::Result<&SegmentedTrack> {
+        self.check_loaded().or_fail()?;
+        self.tracks
+            .get(track_index)
+            .or_fail_with(|()| format!("Track index out of range: {track_index}"))
+    }
+
+    fn check_loaded(&self) -> orfail::Result<()> {
+        (!self.tracks.is_empty()).or_fail_with(|()| "MP4 is not loaded".to_owned())
+    }
+}
+
+// CMAF 形式に変換されたトラックの情報
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CmafTrackInfo {
+    pub kind: TrackKind,
+    pub timescale: u32,
+
+    // トラックの先頭のサンプルエントリーに対応するデコーダーの設定
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audio_config: Option<AudioDecoderConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub video_config: Option<VideoDecoderConfig>,
+
+    pub segments: Vec<CmafSegmentInfo>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CmafSegmentInfo {
+    #[serde(rename = "startMicros", serialize_with = "serialize_micros")]
+    pub start: Duration,
+
+    #[serde(rename = "durationMicros", serialize_with = "serialize_micros")]
+    pub duration: Duration,
+
+    // セグメントに含まれるサンプルのデータサイズの合計（ビットレートの算出などに使える）
+    pub data_size: u64,
+
+    // トラックのタイムスケール単位での開始時刻と尺（マニフェストの生成時に使われる）
+    #[serde(skip)]
+    pub start_ticks: u64,
+    #[serde(skip)]
+    pub duration_ticks: u64,
+}
+
+#[derive(Debug)]
+struct SegmentedTrack {
+    // サンプルのデータ位置は、元の MP4 の先頭からのオフセット
+    mux_track: MuxTrack,
+
+    // 各サンプルのデコード時刻（トラックのタイムスケール単位）
+    decode_times: Vec<u64>,
+
+    // 各セグメントに含まれるサンプルのインデックスの範囲
+    segments: Vec<Range<usize>>,
+}
+
+impl SegmentedTrack {
+    // 編集リストで最初に表示されるメディア時刻（トラックのタイムスケール単位）
+    fn media_start(&self) -> u64 {
+        match self.mux_track.edits.first() {
+            Some(MuxEdit::Media(range)) => range.start,
+            _ => 0,
+        }
+    }
+
+    // トラックのタイムスケール単位のメディア時刻を、編集リストを適用した後の全トラック共通の時刻に変換する
+    fn to_time(&self, ticks: u64) -> Duration {
+        let timescale = self.mux_track.timescale;
+        let start_offset = to_timescale(self.mux_track.start_offset, timescale);
+        let ticks = (ticks + start_offset).saturating_sub(self.media_start());
+        Duration::from_secs(ticks) / timescale.get()
+    }
+
+    // to_time() の逆変換
+    fn to_ticks(&self, time: Duration) -> u64 {
+        let timescale = self.mux_track.timescale;
+        let start_offset = to_timescale(self.mux_track.start_offset, timescale);
+        (to_timescale(time, timescale) + self.media_start()).saturating_sub(start_offset)
+    }
+
+    // 直前の区切りから segment_duration 以上経過した後の、最初の同期サンプルの時刻を区切りとする
+    fn cut_times(&self, segment_duration: Duration) -> Vec<Duration> {
+        let timescale = self.mux_track.timescale;
+        let segment_ticks = to_timescale(segment_duration, timescale);
+        let mut cut_times = Vec::new();
+        let mut last_cut_ticks = self.decode_times[0];
+        for (sample, &ticks) in self.mux_track.samples.iter().zip(&self.decode_times) {
+            if sample.is_sync && ticks - last_cut_ticks >= segment_ticks {
+                cut_times.push(self.to_time(ticks));
+                last_cut_ticks = ticks;
+            }
+        }
+        cut_times
+    }
+
+    fn split(&mut self, cut_times: &[Duration]) {
+        let mut start = 0;
+        for &cut_time in cut_times {
+            let cut_ticks = self.to_ticks(cut_time);
+            let end = start + self.decode_times[start..].partition_point(|&t| t < cut_ticks);
+            // 他のトラックよりも先に終わっている場合などに生じる、空のセグメントは含めない
+            if start < end {
+                self.segments.push(start..end);
+            }
+            start = end;
+        }
+        if start < self.decode_times.len() {
+            self.segments.push(start..self.decode_times.len());
+        }
+    }
+
+    fn info(&self) -> CmafTrackInfo {
+        let timescale = self.mux_track.timescale;
+        let to_duration = |ticks: u64| Duration::from_secs(ticks) / timescale.get();
+        let (audio_config, video_config) =
+            match DecoderConfig::from_sample_entry(&self.mux_track.sample_entries[0]) {
+                Some(DecoderConfig::Audio(config)) => (Some(config), None),
+                Some(DecoderConfig::Video(config)) => (None, Some(config)),
+                None => (None, None),
+            };
+        let segments = self
+            .segments
+            .iter()
+            .map(|range| {
+                let samples = &self.mux_track.samples[range.clone()];
+                let start = self.decode_times[range.start];
+                let end =
+                    self.decode_times[range.end - 1] + samples[samples.len() - 1].duration as u64;
+                CmafSegmentInfo {
+                    start: to_duration(start),
+                    duration: to_duration(end) - to_duration(start),
+                    data_size: samples.iter().map(|s| s.data_size as u64).sum(),
+                    start_ticks: start,
+                    duration_ticks: end - start,
+                }
+            })
+            .collect();
+        CmafTrackInfo {
+            kind: self.mux_track.kind,
+            timescale: timescale.get(),
+            audio_config,
+            video_config,
+            segments,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mp4::{read_u32, split_box, Track},
+        muxer::{write_progressive_mp4, Mp4Muxer},
+    };
+
+    const AUDIO: usize = 0;
+    const VIDEO: usize = 1;
+
+    // 20 ms 毎の音声と、40 ms 遅れて始まる 100 ms 毎の映像（500 ms 毎にキーフレーム）を 2.4 秒分含む MP4 を生成する
+    fn make_input() -> Vec<u8> {
+        let mut muxer = Mp4Muxer::new(None);
+        muxer
+            .set_audio_config(&AudioDecoderConfig {
+                codec: "opus".to_owned(),
+                sample_rate: 48_000,
+                number_of_channels: 2,
+                description: Vec::new(),
+            })
+            .expect("set_audio_config");
+        muxer
+            .set_video_config(&VideoDecoderConfig {
+                codec: "vp8".to_owned(),
+                description: Vec::new(),
+                coded_width: 320,
+                coded_height: 240,
+            })
+            .expect("set_video_config");
+        for i in 0..120u64 {
+            let timestamp = Duration::from_millis(i * 20);
+            if i % 5 == 2 {
+                let frame = i / 5;
+                let video_timestamp = Duration::from_millis(frame * 100 + 40);
+                let data = [frame as u8; 8];
+                muxer
+                    .append_chunk(
+                        TrackKind::Video,
+                        video_timestamp,
+                        Duration::ZERO,
+                        Some(Duration::from_millis(100)),
+                        frame % 5 == 0,
+                        &data,
+                    )
+                    .expect("append_chunk");
+            }
+            muxer
+                .append_chunk(
+                    TrackKind::Audio,
+                    timestamp,
+                    Duration::ZERO,
+                    Some(Duration::from_millis(20)),
+                    true,
+                    &[i as u8; 3],
+                )
+                .expect("append_chunk");
+        }
+        muxer.finish().expect("finish");
+        muxer.take_output()
+    }
+
+    fn load(segment_duration: Duration) -> (CmafSegmenter, Vec<CmafTrackInfo>) {
+        let mut segmenter = CmafSegmenter::new();
+        let infos = segmenter
+            .load(make_input(), segment_duration)
+            .expect("load");
+        (segmenter, infos)
+    }
+
+    #[test]
+    fn segments_start_at_key_frames() {
+        let (segmenter, infos) = load(Duration::from_secs(1));
+        let video = &segmenter.tracks[VIDEO];
+        assert_eq!(video.segments, [0..10, 10..20, 20..24]);
+        for range in &video.segments {
+            assert!(video.mux_track.samples[range.start].is_sync);
+        }
+
+        // 短すぎる区切りは、次のキーフレームまで延長される
+        let (segmenter, _) = load(Duration::from_millis(300));
+        assert_eq!(
+            segmenter.tracks[VIDEO].segments,
+            [0..5, 5..10, 10..15, 15..20, 20..24]
+        );
+
+        let starts = infos[VIDEO]
+            .segments
+            .iter()
+            .map(|s| s.start)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            starts,
+            [0, 1000, 2000].map(Duration::from_millis),
+            "media decode times"
+        );
+    }
+
+    #[test]
+    fn other_tracks_are_split_at_the_same_presentation_time() {
+        let (mut segmenter, infos) = load(Duration::from_secs(1));
+        let video = &segmenter.tracks[VIDEO];
+        let cut_times = video.cut_times(Duration::from_secs(1));
+
+        // 映像は 40 ms 遅れて始まるので、キーフレームの表示時刻もその分だけ遅れる
+        assert_eq!(cut_times, [1040, 2040].map(Duration::from_millis));
+        let audio_starts = infos[AUDIO]
+            .segments
+            .iter()
+            .map(|s| s.start)
+            .collect::<Vec<_>>();
+        assert_eq!(audio_starts, [0, 1040, 2040].map(Duration::from_millis));
+        assert_eq!(segmenter.tracks[AUDIO].segments, [0..52, 52..102, 102..120]);
+
+        // 区切り位置より先に終わっているトラックには、空のセグメントは作られない
+        let audio = &mut segmenter.tracks[AUDIO];
+        audio.segments.clear();
+        audio.split(&[Duration::from_secs(5)]);
+        assert_eq!(audio.segments.len(), 1);
+        assert_eq!(audio.segments[0], 0..120);
+    }
+
+    // メディアセグメントの trun を読み込んで、サンプル群を復元する
+    //
+    // 戻り値のサンプルのデータ位置は、payload の先頭からのオフセットとなる
+    fn read_media_segment(segment: &[u8], payload: &mut Vec<u8>) -> (u64, Vec<MuxSample>) {
+        let (header, moof, _) = split_box(segment).expect("moof");
+        assert_eq!(header.box_type.as_bytes(), b"moof");
+        let mut base_media_decode_time = None;
+        let mut samples = Vec::new();
+        let mut bytes = moof;
+        while let Some((header, traf, rest)) = split_box(bytes) {
+            bytes = rest;
+            if header.box_type.as_bytes() != b"traf" {
+                continue;
+            }
+            let mut sample_entry_index = 0;
+            let mut children = traf;
+            while let Some((header, payload_bytes, rest)) = split_box(children) {
+                children = rest;
+                match header.box_type.as_bytes() {
+                    b"tfhd" => {
+                        sample_entry_index = read_u32(payload_bytes, 8).expect("index") as usize - 1
+                    }
+                    b"tfdt" => {
+                        let time =
+                            u64::from_be_bytes(payload_bytes[4..12].try_into().expect("u64"));
+                        base_media_decode_time.get_or_insert(time);
+                    }
+                    b"trun" => {
+                        let flags = read_u32(payload_bytes, 0).expect("flags") & 0xFFFFFF;
+                        let count = read_u32(payload_bytes, 4).expect("count") as usize;
+                        let mut data_offset = read_u32(payload_bytes, 8).expect("offset") as usize;
+                        let has_offsets = flags & 0x000800 != 0;
+                        let entry_size = if has_offsets { 16 } else { 12 };
+                        for i in 0..count {
+                            let entry = &payload_bytes[12 + i * entry_size..];
+                            let data_size = read_u32(entry, 4).expect("size");
+                            samples.push(MuxSample {
+                                sample_entry_index,
+                                duration: read_u32(entry, 0).expect("duration"),
+                                is_sync: read_u32(entry, 8).expect("flags") & 0x00010000 == 0,
+                                composition_offset: if has_offsets {
+                                    read_u32(entry, 12).expect("cto") as i32
+                                } else {
+                                    0
+                                },
+                                data_offset: payload.len() as u64,
+                                data_size,
+                            });
+                            payload.extend_from_slice(
+                                &segment[data_offset..data_offset + data_size as usize],
+                            );
+                            data_offset += data_size as usize;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        (base_media_decode_time.expect("tfdt"), samples)
+    }
+
+    #[test]
+    fn segments_round_trip_to_the_original_samples() {
+        let input = make_input();
+        let original = Mp4::load(&input).expect("load input");
+        let (mut segmenter, infos) = load(Duration::from_secs(1));
+
+        for (track_index, info) in infos.iter().enumerate() {
+            segmenter.init_segment(track_index).expect("init_segment");
+            let init_segment = segmenter.take_output();
+            let (moov_box, _) = Mp4::load_moov_box(&init_segment).expect("moov");
+            let trak_box = &moov_box.trak_boxes[0];
+
+            // 初期化セグメントのサンプルエントリーと、各メディアセグメントのサンプル群から MP4 を組み立て直す
+            let mut mux_track = MuxTrack::new(info.kind, trak_box.mdia_box.mdhd_box.timescale);
+            mux_track.sample_entries = trak_box.mdia_box.minf_box.stbl_box.stsd_box.entries.clone();
+            let mut payload = Vec::new();
+            for segment_index in 0..info.segments.len() {
+                segmenter
+                    .media_segment(track_index, segment_index)
+                    .expect("media_segment");
+                let segment = segmenter.take_output();
+                let (decode_time, samples) = read_media_segment(&segment, &mut payload);
+                assert_eq!(decode_time, mux_track.media_duration());
+                mux_track.samples.extend(samples);
+            }
+
+            // 映像トラックの先頭の空白区間は、初期化セグメントの編集リストに引き継がれる
+            let elst_box = trak_box.edts_box.as_ref().and_then(|b| b.elst_box.as_ref());
+            let start_offset = match elst_box.map(|b| &b.entries[..]) {
+                Some([empty, media]) => {
+                    assert_eq!(empty.media_time, -1);
+                    assert_eq!(media.media_time, 0);
+                    Duration::from_millis(empty.edit_duration)
+                }
+                None => Duration::ZERO,
+                entries => panic!("unexpected elst entries: {entries:?}"),
+            };
+            mux_track.start_offset = start_offset;
+
+            let rebuilt = write_progressive_mp4(&[mux_track], &payload).expect("write");
+            let rebuilt_track = &Mp4::load(&rebuilt).expect("load rebuilt").tracks[0];
+            let original_track = &original.tracks[track_index];
+            assert_eq!(rebuilt_track.start_offset(), original_track.start_offset());
+
+            let samples = |track: &Track, bytes: &[u8]| {
+                track
+                    .sample_table
+                    .samples()
+                    .map(|s| {
+                        let data = &bytes[s.data_offset() as usize..][..s.data_size() as usize];
+                        (
+                            s.timestamp(),
+                            s.duration(),
+                            s.is_sync_sample(),
+                            data.to_vec(),
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(
+                samples(rebuilt_track, &rebuilt),
+                samples(original_track, &input),
+                "{:?}",
+                info.kind
+            );
+        }
+    }
+}
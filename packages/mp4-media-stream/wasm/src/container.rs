@@ -0,0 +1,204 @@
+use orfail::{Failure, OrFail};
+
+use crate::{es, mp4::Mp4, ts, webm};
+
+// 形式の判定に使う MP4 のボックス種別（ファイルの先頭のボックスの種別がこれらのいずれかなら MP4 とみなす）
+const MP4_FTYP: &[u8] = b"ftyp";
+const MP4_STYP: &[u8] = b"styp";
+const MP4_MOOF: &[u8] = b"moof";
+
+// QuickTime 形式 (MOV) のファイルは ftyp ボックスを持たず、これらのボックスから始まることがある
+const QUICKTIME_LEADING_BOXES: &[&[u8]] = &[b"moov", b"mdat", b"wide", b"free", b"skip", b"pnot"];
+
+// エラーメッセージに含める先頭バイト列の長さ
+const ERROR_PREFIX_SIZE: usize = 8;
+
+// 再生に対応しているコンテナ（およびエレメンタリーストリーム）の形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContainerFormat {
+    Mp4,
+
+    // 初期化セグメント（moov ボックス）を含まない、フラグメント化された MP4 のメディアセグメント
+    Mp4Segment,
+
+    Webm,
+    MpegTs,
+    Ogg,
+    Ivf,
+    AnnexB,
+    Adts,
+}
+
+impl ContainerFormat {
+    // バイト列の先頭部分から形式を判定する（対応していない形式の場合には None を返す）
+    pub fn detect(bytes: &[u8]) -> Option<Self> {
+        match bytes.get(4..8) {
+            Some(MP4_FTYP) => return Some(Self::Mp4),
+            Some(MP4_STYP | MP4_MOOF) => return Some(Self::Mp4Segment),
+            Some(ty) if QUICKTIME_LEADING_BOXES.contains(&ty) => return Some(Self::Mp4),
+            _ => {}
+        }
+        if webm::is_webm(bytes) {
+            Some(Self::Webm)
+        } else if ts::is_ts(bytes) {
+            Some(Self::MpegTs)
+        } else if es::is_ogg(bytes) {
+            Some(Self::Ogg)
+        } else if es::is_ivf(bytes) {
+            Some(Self::Ivf)
+        } else if es::is_annexb(bytes) {
+            Some(Self::AnnexB)
+        } else if es::is_adts(bytes) {
+            Some(Self::Adts)
+        } else {
+            None
+        }
+    }
+}
+
+// 形式を判定した上で、対応するデマルチプレクサーを使ってバイト列を読み込む
+//
+// MPEG-TS などのようにサンプルデータの変換が必要な形式もあるので、
+// 返り値のトラックが参照するバイト列（変換が不要な場合には入力をそのまま返す）もあわせて返す。
+pub fn load(bytes: Vec<u8>) -> orfail::Result<(Mp4, Vec<u8>)> {
+    let format = ContainerFormat::detect(&bytes).or_fail_with(|()| {
+        format!(
+            "Unsupported container format (leading bytes: {:02x?})",
+            &bytes[..bytes.len().min(ERROR_PREFIX_SIZE)]
+        )
+    })?;
+    match format {
+        ContainerFormat::Mp4 => Ok((Mp4::load(&bytes).or_fail()?, bytes)),
+        ContainerFormat::Mp4Segment => Err(Failure::new(
+            "Fragmented MP4 media segments without an initialization segment are not supported",
+        )),
+        ContainerFormat::Webm => Ok((webm::load_webm(&bytes).or_fail()?, bytes)),
+        ContainerFormat::MpegTs => ts::load_ts(&bytes).or_fail(),
+        ContainerFormat::Ogg => es::load_ogg_opus(&bytes).or_fail(),
+        ContainerFormat::Ivf => Ok((es::load_ivf(&bytes).or_fail()?, bytes)),
+        ContainerFormat::AnnexB => es::load_annexb(&bytes).or_fail(),
+        ContainerFormat::Adts => Ok((es::load_adts(&bytes).or_fail()?, bytes)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TS_PACKET_SIZE: usize = 188;
+
+    // 指定の種別のボックスを先頭に持つバイト列
+    fn leading_box(box_type: &[u8; 4]) -> Vec<u8> {
+        [&[0, 0, 0, 16][..], box_type, &[0; 8]].concat()
+    }
+
+    fn ts_packets(count: usize) -> Vec<u8> {
+        let mut bytes = vec![0; TS_PACKET_SIZE * count];
+        for packet in bytes.chunks_mut(TS_PACKET_SIZE) {
+            packet[0] = 0x47;
+        }
+        bytes
+    }
+
+    // 44.1 kHz / 2ch の AAC-LC の ADTS フレーム
+    fn adts_frame(payload_size: usize) -> Vec<u8> {
+        let length = 7 + payload_size;
+        let mut frame = vec![
+            0xFF,
+            0xF1,
+            (1 << 6) | (4 << 2),
+            (2 << 6) | (length >> 11) as u8,
+            (length >> 3) as u8,
+            ((length & 0b111) << 5) as u8 | 0x1F,
+            0xFC,
+        ];
+        frame.resize(length, 0);
+        frame
+    }
+
+    #[test]
+    fn detect_table() {
+        let mut broken_ts = ts_packets(2);
+        broken_ts[TS_PACKET_SIZE] = 0;
+
+        let cases = [
+            ("ftyp", leading_box(b"ftyp"), Some(ContainerFormat::Mp4)),
+            (
+                "styp",
+                leading_box(b"styp"),
+                Some(ContainerFormat::Mp4Segment),
+            ),
+            (
+                "moof",
+                leading_box(b"moof"),
+                Some(ContainerFormat::Mp4Segment),
+            ),
+            ("moov", leading_box(b"moov"), Some(ContainerFormat::Mp4)),
+            ("mdat", leading_box(b"mdat"), Some(ContainerFormat::Mp4)),
+            ("wide", leading_box(b"wide"), Some(ContainerFormat::Mp4)),
+            ("free", leading_box(b"free"), Some(ContainerFormat::Mp4)),
+            ("skip", leading_box(b"skip"), Some(ContainerFormat::Mp4)),
+            ("pnot", leading_box(b"pnot"), Some(ContainerFormat::Mp4)),
+            ("uuid", leading_box(b"uuid"), None),
+            (
+                "EBML",
+                vec![0x1A, 0x45, 0xDF, 0xA3, 0x9F, 0x42, 0x86, 0x81],
+                Some(ContainerFormat::Webm),
+            ),
+            ("MPEG-TS", ts_packets(4), Some(ContainerFormat::MpegTs)),
+            ("broken MPEG-TS", broken_ts, None),
+            (
+                "Ogg",
+                b"OggS\0\x02\0\0\0\0\0\0\0\0".to_vec(),
+                Some(ContainerFormat::Ogg),
+            ),
+            (
+                "IVF",
+                b"DKIF\0\0\x20\0VP80".to_vec(),
+                Some(ContainerFormat::Ivf),
+            ),
+            (
+                "Annex-B H.264",
+                vec![0, 0, 0, 1, 0x67, 0x42, 0, 0x1E, 0, 0, 1, 0x68, 0xCE],
+                Some(ContainerFormat::AnnexB),
+            ),
+            (
+                "Annex-B H.265",
+                vec![0, 0, 0, 1, 0x40, 0x01, 0x0C, 0x01],
+                Some(ContainerFormat::AnnexB),
+            ),
+            // 開始コードがあっても、パラメーターセットなどが見つからなければ Annex-B とはみなさない
+            ("start code only", vec![0, 0, 1, 0x80, 0, 0], None),
+            (
+                "ADTS",
+                [adts_frame(10), adts_frame(20)].concat(),
+                Some(ContainerFormat::Adts),
+            ),
+            ("empty", Vec::new(), None),
+            ("text", b"hello, world".to_vec(), None),
+        ];
+        for (name, bytes, expected) in cases {
+            assert_eq!(ContainerFormat::detect(&bytes), expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn unsupported_formats_are_rejected_by_load() {
+        let e = load(b"hello, world".to_vec()).expect_err("unsupported format");
+        assert_eq!(
+            e.message,
+            format!(
+                "Unsupported container format (leading bytes: {:02x?})",
+                &b"hello, world"[..ERROR_PREFIX_SIZE]
+            )
+        );
+
+        // メディアセグメント単体は、形式としては判定できても読み込めない
+        let e = load(leading_box(b"moof")).expect_err("media segment");
+        assert!(
+            e.message.contains("without an initialization segment"),
+            "{}",
+            e.message
+        );
+    }
+}
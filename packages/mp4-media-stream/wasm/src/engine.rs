@@ -1,20 +1,27 @@
-use std::{collections::HashMap, rc::Rc};
+use std::{collections::HashMap, rc::Rc, time::Duration};
 
 use futures::{executor::LocalPool, future::RemoteHandle, task::LocalSpawnExt};
 use orfail::OrFail;
 
 use crate::{
-    mp4::{Mp4, Mp4Info, Track},
-    player::{PlayOptions, Player, PlayerId},
+    container,
+    mp4::{Mp4Info, Track},
+    player::{AudioClockReport, PlayOptions, Player, PlayerId, PlayerShared},
+    remux,
+    stats::PlayerStats,
+    subtitle::{self, SubtitleCue},
+    wasm::{DecoderId, WasmApi},
 };
 
 #[derive(Debug)]
 pub struct Engine {
     mp4_bytes: Rc<Vec<u8>>,
     tracks: Vec<Track>,
+    subtitle_cues: Rc<Vec<SubtitleCue>>,
     executor: LocalPool,
     executing: bool,
-    players: HashMap<PlayerId, RemoteHandle<()>>,
+    players: HashMap<PlayerId, PlayerHandle>,
+    output: Vec<u8>,
 }
 
 impl Engine {
@@ -23,37 +30,106 @@ impl Engine {
         Self {
             mp4_bytes: Rc::new(Vec::new()),
             tracks: Vec::new(),
+            subtitle_cues: Rc::new(Vec::new()),
             executor: LocalPool::new(),
             executing: false,
             players: HashMap::new(),
+            output: Vec::new(),
         }
     }
 
     pub fn load_mp4(&mut self, mp4_bytes: Vec<u8>) -> orfail::Result<Mp4Info> {
         (self.tracks.is_empty()).or_fail()?;
 
-        let mp4 = Mp4::load(&mp4_bytes).or_fail()?;
+        // MPEG-TS などの場合にはサンプルデータが変換されるので、変換後のバイト列を保持する
+        let (mp4, mp4_bytes) = container::load(mp4_bytes).or_fail()?;
         self.mp4_bytes = Rc::new(mp4_bytes);
         self.tracks = mp4.tracks;
+        self.subtitle_cues = Rc::new(subtitle::merge_cues(&mp4.subtitle_tracks));
 
         Ok(mp4.info)
     }
 
+    // ロード済みの MP4 の指定範囲を切り出した MP4 を生成する
+    //
+    // 生成結果は take_output() で取得できる
+    pub fn trim(&mut self, start: Duration, end: Duration) -> orfail::Result<()> {
+        (!self.tracks.is_empty()).or_fail_with(|()| "No MP4 loaded".to_owned())?;
+        self.output = remux::trim_mp4(&self.tracks, &self.mp4_bytes, start, end).or_fail()?;
+        Ok(())
+    }
+
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
     pub fn play(&mut self, player_id: PlayerId, options: PlayOptions) {
         // MP4 はロード済みであるのが前提
         assert!(!self.tracks.is_empty());
 
-        let player = Player::new(player_id, options, self.mp4_bytes.clone(), &self.tracks);
-        self.players.insert(
+        let shared = PlayerShared::new(&self.tracks, self.subtitle_cues.clone());
+        let player = Player::new(
             player_id,
-            self.executor
-                .spawner()
-                .spawn_local_with_handle(player.run())
-                .expect("unreachable"),
+            options,
+            self.mp4_bytes.clone(),
+            &self.tracks,
+            shared.clone(),
         );
+        let task = self
+            .executor
+            .spawner()
+            .spawn_local_with_handle(player.run())
+            .expect("unreachable");
+        self.players
+            .insert(player_id, PlayerHandle { task, shared });
         self.poll();
     }
 
+    // ロード済みのファイル全体の尺（全トラックの尺の最大値）
+    pub fn file_duration(&self) -> Duration {
+        self.tracks
+            .iter()
+            .map(|t| t.duration())
+            .max()
+            .unwrap_or_default()
+    }
+
+    pub fn current_time(&self, player_id: PlayerId) -> Option<Duration> {
+        self.players
+            .get(&player_id)
+            .map(|player| player.shared.current_time.get())
+    }
+
+    pub fn stats(&self, player_id: PlayerId) -> Option<PlayerStats> {
+        self.players
+            .get(&player_id)
+            .map(|player| player.shared.stats.borrow().clone())
+    }
+
+    pub fn notify_audio_position(&mut self, player_id: PlayerId, media_time: Duration) {
+        if let Some(player) = self.players.get(&player_id) {
+            player.shared.audio_clock.set(Some(AudioClockReport {
+                media_time,
+                reported_at: WasmApi::now(),
+            }));
+        }
+    }
+
+    pub fn notify_decoder_output(
+        &mut self,
+        player_id: PlayerId,
+        decoder: DecoderId,
+        timestamp_micros: u64,
+    ) {
+        if let Some(player) = self.players.get(&player_id) {
+            player.shared.stats.borrow_mut().on_decoder_output(
+                decoder,
+                timestamp_micros,
+                WasmApi::now(),
+            );
+        }
+    }
+
     pub fn stop(&mut self, player_id: PlayerId) {
         let _ = self.players.remove(&player_id);
         self.poll();
@@ -68,3 +144,12 @@ impl Engine {
         self.executing = false;
     }
 }
+
+// 実行中のプレイヤーと、その状態を外部から参照するためのハンドル
+#[derive(Debug)]
+struct PlayerHandle {
+    // このハンドルが破棄されるとプレイヤーも停止する
+    #[expect(dead_code)]
+    task: RemoteHandle<()>,
+    shared: PlayerShared,
+}
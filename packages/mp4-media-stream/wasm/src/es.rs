@@ -0,0 +1,749 @@
+use std::{num::NonZeroU32, ops::Range};
+
+use orfail::{Failure, OrFail};
+use shiguredo_mp4::boxes::SampleEntry;
+
+use crate::{
+    adts::{self, AdtsHeader},
+    annexb::{self, AnnexBConverter},
+    bits::BitReader,
+    mp4::{AudioDecoderConfig, Mp4, Track, TrackKind, VideoDecoderConfig},
+    muxer::{MuxSample, MuxTrack},
+    webm::{estimate_vp9_level, parse_vp9_profile_and_bit_depth, DEFAULT_VP9_FRAME_RATE},
+};
+
+// 形式の判定時に NAL ユニットヘッダーを確認する範囲
+const ANNEXB_PROBE_SIZE: usize = 64 * 1024;
+
+// Annex-B 形式のストリームにタイミング情報が含まれていない場合に使うフレームレート（30 fps）
+const DEFAULT_FRAME_RATE: (NonZeroU32, u32) = (NonZeroU32::MIN.saturating_add(30 - 1), 1);
+
+// https://wiki.multimedia.cx/index.php/Duck_IVF
+const IVF_SIGNATURE: &[u8] = b"DKIF";
+const IVF_FILE_HEADER_SIZE: usize = 32;
+const IVF_FRAME_HEADER_SIZE: usize = 12;
+
+// IVF のタイムベースをフレームレートとみなす上限（これを超える場合はミリ秒単位などのタイムベースとみなす）
+const MAX_IVF_FRAME_RATE: f64 = 240.0;
+
+// AV1 の OBU 種別（AV1 Bitstream & Decoding Process Specification 6.2.2）
+const OBU_SEQUENCE_HEADER: u8 = 1;
+const OBU_FRAME_HEADER: u8 = 3;
+const OBU_FRAME: u8 = 6;
+
+// https://www.xiph.org/ogg/doc/framing.html
+const OGG_CAPTURE_PATTERN: &[u8] = b"OggS";
+const OGG_PAGE_HEADER_SIZE: usize = 27;
+const OGG_HEADER_TYPE_BOS: u8 = 0x02;
+const OPUS_HEAD_SIGNATURE: &[u8] = b"OpusHead";
+const OPUS_TAGS_SIGNATURE: &[u8] = b"OpusTags";
+
+// Opus は常に 48 kHz でデコードされる
+const OPUS_TIMESCALE: NonZeroU32 = NonZeroU32::MIN.saturating_add(48_000 - 1);
+
+// 生のエレメンタリーストリーム（コンテナに格納されていない映像・音声のビットストリーム）のサンプル
+#[derive(Debug)]
+struct EsSample {
+    // トラックのタイムスケール単位の尺
+    duration: u32,
+    is_sync: bool,
+    data: Range<usize>,
+}
+
+fn make_track(
+    kind: TrackKind,
+    timescale: NonZeroU32,
+    sample_entry: SampleEntry,
+    samples: Vec<EsSample>,
+    data_size: usize,
+) -> orfail::Result<Mp4> {
+    (!samples.is_empty()).or_fail_with(|()| format!("No {kind:?} frame found"))?;
+    let mut mux_track = MuxTrack::new(kind, timescale);
+    let sample_entry_index = mux_track.add_sample_entry(sample_entry);
+    mux_track
+        .samples
+        .extend(samples.into_iter().map(|sample| MuxSample {
+            sample_entry_index,
+            duration: sample.duration,
+            is_sync: sample.is_sync,
+            composition_offset: 0,
+            data_offset: sample.data.start as u64,
+            data_size: sample.data.len() as u32,
+        }));
+    let track = Track::from_mux_track(&mux_track, 1, data_size).or_fail()?;
+    Mp4::from_tracks(vec![track]).or_fail()
+}
+
+// バイト列が Annex-B 形式の H.264 / H.265 ストリームかどうかを判定する
+pub fn is_annexb(bytes: &[u8]) -> bool {
+    (bytes.starts_with(&[0, 0, 1]) || bytes.starts_with(&[0, 0, 0, 1]))
+        && annexb::detect_codec(&bytes[..bytes.len().min(ANNEXB_PROBE_SIZE)]).is_some()
+}
+
+// Annex-B 形式の H.264 / H.265 ストリームを読み込んで、映像トラックのみを持つ MP4 と同じ形式に変換する
+//
+// 各サンプルは長さプレフィックス付きの形式に変換されるので、そのデータを格納したバイト列もあわせて返す。
+// デコーダー設定は最初に出現した SPS / PPS (と VPS) から生成され、
+// フレームレートは SPS の VUI (H.265 の場合は VPS) のタイミング情報から求める（ない場合には 30 fps とする）。
+//
+// [NOTE] タイムスタンプはデコード順に一定間隔で割り当てられるので、
+//        B フレームによる並べ替えがあるストリームでは、表示時刻が正確にはならない
+pub fn load_annexb(bytes: &[u8]) -> orfail::Result<(Mp4, Vec<u8>)> {
+    let codec = annexb::detect_codec(&bytes[..bytes.len().min(ANNEXB_PROBE_SIZE)])
+        .or_fail_with(|()| "Unknown Annex-B stream".to_owned())?;
+
+    let mut converter = AnnexBConverter::new(codec);
+    let mut media = Vec::new();
+    let mut units = Vec::new();
+    for unit in annexb::split_access_units(codec, bytes) {
+        let data_offset = media.len();
+        let is_key = converter.convert_access_unit(unit, &mut media);
+        if units.is_empty() && !is_key {
+            // 最初のキーフレームより前のアクセスユニットはデコードできないので取り除く
+            media.truncate(data_offset);
+            continue;
+        }
+        units.push((is_key, data_offset..media.len()));
+    }
+
+    let (timescale, frame_duration) = converter.frame_rate().unwrap_or(DEFAULT_FRAME_RATE);
+    let sample_entry = converter
+        .decoder_config()
+        .or_fail()?
+        .to_sample_entry()
+        .or_fail()?;
+    let samples = units
+        .into_iter()
+        .map(|(is_sync, data)| EsSample {
+            duration: frame_duration,
+            is_sync,
+            data,
+        })
+        .collect();
+    let mp4 = make_track(
+        TrackKind::Video,
+        timescale,
+        sample_entry,
+        samples,
+        media.len(),
+    )
+    .or_fail()?;
+    Ok((mp4, media))
+}
+
+// バイト列が IVF ファイルかどうかを判定する
+pub fn is_ivf(bytes: &[u8]) -> bool {
+    bytes.starts_with(IVF_SIGNATURE)
+}
+
+// IVF ファイル（VP8 / VP9 / AV1）を読み込んで、映像トラックのみを持つ MP4 と同じ形式に変換する
+//
+// フレームの時刻はヘッダーで宣言されているタイムベースを単位とし、
+// 各フレームの尺は次のフレームまでの時刻の差分とする。
+// 変換後の各サンプルのデータ位置は、IVF ファイルの先頭からのオフセットとなる。
+pub fn load_ivf(bytes: &[u8]) -> orfail::Result<Mp4> {
+    let header = bytes
+        .get(..IVF_FILE_HEADER_SIZE)
+        .or_fail_with(|()| "Too short IVF header".to_owned())?;
+    let u16_at = |i: usize| u16::from_le_bytes([header[i], header[i + 1]]);
+    let u32_at = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().expect("infallible"));
+    let header_size = u16_at(6) as usize;
+    let fourcc = &header[8..12];
+    let width = u16_at(12);
+    let height = u16_at(14);
+    let timebase_denominator = NonZeroU32::new(u32_at(16))
+        .or_fail_with(|()| "Invalid IVF timebase denominator".to_owned())?;
+    let timebase_numerator = u32_at(20).max(1);
+
+    let mut frames = Vec::new();
+    let mut position = header_size.max(IVF_FILE_HEADER_SIZE);
+    while let Some(frame_header) = bytes.get(position..position + IVF_FRAME_HEADER_SIZE) {
+        let size = u32::from_le_bytes(frame_header[..4].try_into().expect("infallible")) as usize;
+        let pts = u64::from_le_bytes(frame_header[4..].try_into().expect("infallible"));
+        let data = position + IVF_FRAME_HEADER_SIZE..position + IVF_FRAME_HEADER_SIZE + size;
+        if data.end > bytes.len() {
+            // 末尾が欠けているフレームは無視する
+            break;
+        }
+        frames.push((pts, data.clone()));
+        position = data.end;
+    }
+    (!frames.is_empty()).or_fail_with(|()| "No IVF frame found".to_owned())?;
+
+    let (codec, description) = match fourcc {
+        b"VP80" => ("vp8".to_owned(), Vec::new()),
+        b"VP90" => {
+            let (profile, bit_depth) = frames
+                .iter()
+                .find_map(|(_, data)| parse_vp9_profile_and_bit_depth(&bytes[data.clone()]))
+                .or_fail_with(|()| "No VP9 key frame found".to_owned())?;
+            // IVF のヘッダーのタイムベースは、多くの場合フレームレートの逆数となっている
+            let frame_rate = timebase_denominator.get() as f64 / timebase_numerator as f64;
+            let frame_rate = if frame_rate <= MAX_IVF_FRAME_RATE {
+                frame_rate
+            } else {
+                DEFAULT_VP9_FRAME_RATE
+            };
+            let level = estimate_vp9_level(width as u64, height as u64, frame_rate);
+            (
+                format!("vp09.{profile:02}.{level:02}.{bit_depth:02}"),
+                Vec::new(),
+            )
+        }
+        b"AV01" => {
+            let (codec, description) = frames
+                .iter()
+                .find_map(|(_, data)| av1_codec_and_description(&bytes[data.clone()]))
+                .or_fail_with(|()| "No AV1 sequence header found".to_owned())?;
+            (codec, description)
+        }
+        _ => {
+            return Err(Failure::new(format!(
+                "Unsupported IVF codec: {}",
+                String::from_utf8_lossy(fourcc)
+            )));
+        }
+    };
+    let is_key_frame: fn(&[u8]) -> bool = match fourcc {
+        b"VP80" => is_vp8_key_frame,
+        b"VP90" => is_vp9_key_frame,
+        _ => is_av1_key_frame,
+    };
+    let sample_entry = VideoDecoderConfig {
+        codec,
+        description,
+        coded_width: width,
+        coded_height: height,
+    }
+    .to_sample_entry()
+    .or_fail()?;
+
+    let mut samples = Vec::<EsSample>::new();
+    for (i, (pts, data)) in frames.iter().enumerate() {
+        let is_sync = is_key_frame(&bytes[data.clone()]);
+        if samples.is_empty() && !is_sync {
+            // 最初のキーフレームより前のフレームはデコードできないので取り除く
+            continue;
+        }
+        let ticks = frames
+            .get(i + 1)
+            .and_then(|(next_pts, _)| next_pts.checked_sub(*pts))
+            .filter(|&ticks| ticks > 0)
+            .unwrap_or(1);
+        let duration = ticks * u64::from(timebase_numerator);
+        samples.push(EsSample {
+            duration: u32::try_from(duration)
+                .or_fail_with(|_| format!("Too long IVF frame duration: {duration}"))?,
+            is_sync,
+            data: data.clone(),
+        });
+    }
+    make_track(
+        TrackKind::Video,
+        timebase_denominator,
+        sample_entry,
+        samples,
+        bytes.len(),
+    )
+    .or_fail()
+}
+
+// VP8 のフレームタグの先頭ビットが 0 ならキーフレーム（RFC 6386 9.1）
+pub(crate) fn is_vp8_key_frame(frame: &[u8]) -> bool {
+    frame.first().is_some_and(|b| b & 1 == 0)
+}
+
+// VP9 の非圧縮ヘッダーの frame_type が KEY_FRAME ならキーフレーム
+pub(crate) fn is_vp9_key_frame(frame: &[u8]) -> bool {
+    parse_vp9_profile_and_bit_depth(frame).is_some()
+}
+
+// 一つのテンポラルユニットに含まれる OBU 群を (種別, ペイロード) の組として返す
+//
+// AV1 Bitstream & Decoding Process Specification 5.3 OBU syntax
+fn av1_obus(mut data: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    std::iter::from_fn(move || {
+        let header = *data.first()?;
+        let obu_type = (header >> 3) & 0b1111;
+        let has_extension = header & 0b100 != 0;
+        let has_size_field = header & 0b10 != 0;
+        let mut position = 1 + usize::from(has_extension);
+        let size = if has_size_field {
+            // leb128()
+            let mut size = 0;
+            for i in 0..8 {
+                let b = *data.get(position)?;
+                position += 1;
+                size |= ((b & 0x7F) as usize) << (i * 7);
+                if b & 0x80 == 0 {
+                    break;
+                }
+            }
+            size
+        } else {
+            data.len().checked_sub(position)?
+        };
+        let payload = data.get(position..position + size)?;
+        data = &data[position + size..];
+        Some((obu_type, payload))
+    })
+}
+
+// 最初のフレームヘッダーの frame_type が KEY_FRAME ならキーフレーム
+pub(crate) fn is_av1_key_frame(temporal_unit: &[u8]) -> bool {
+    av1_obus(temporal_unit)
+        .find(|(obu_type, _)| matches!(*obu_type, OBU_FRAME_HEADER | OBU_FRAME))
+        .and_then(|(_, payload)| payload.first())
+        .is_some_and(|b| {
+            // show_existing_frame == 0 かつ frame_type == KEY_FRAME (0)
+            b & 0x80 == 0 && (b >> 5) & 0b11 == 0
+        })
+}
+
+// シーケンスヘッダー OBU から、コーデック文字列と av1C ボックスのペイロードを生成する
+fn av1_codec_and_description(temporal_unit: &[u8]) -> Option<(String, Vec<u8>)> {
+    let (_, payload) =
+        av1_obus(temporal_unit).find(|(obu_type, _)| *obu_type == OBU_SEQUENCE_HEADER)?;
+    let seq = Av1SequenceHeader::parse(payload)?;
+    let codec = format!(
+        "av01.{}.{:02}{}.{:02}",
+        seq.profile,
+        seq.level,
+        if seq.tier == 0 { 'M' } else { 'H' },
+        seq.bit_depth
+    );
+
+    // AV1 Codec ISO Media File Format Binding 2.3.3 AV1CodecConfigurationBox syntax
+    let mut description = vec![
+        0x81, // marker, version
+        (seq.profile << 5) | seq.level,
+        (seq.tier << 7)
+            | (u8::from(seq.bit_depth > 8) << 6)
+            | (u8::from(seq.bit_depth == 12) << 5)
+            | (seq.monochrome << 4)
+            | (seq.chroma_subsampling_x << 3)
+            | (seq.chroma_subsampling_y << 2)
+            | seq.chroma_sample_position,
+        0, // initial_presentation_delay_present = 0
+    ];
+
+    // configOBUs にはシーケンスヘッダー OBU を（サイズフィールド付きで）格納する
+    description.push((OBU_SEQUENCE_HEADER << 3) | 0b10);
+    let mut size = payload.len();
+    loop {
+        let b = (size & 0x7F) as u8;
+        size >>= 7;
+        if size == 0 {
+            description.push(b);
+            break;
+        }
+        description.push(b | 0x80);
+    }
+    description.extend_from_slice(payload);
+    Some((codec, description))
+}
+
+#[derive(Debug)]
+struct Av1SequenceHeader {
+    profile: u8,
+    level: u8,
+    tier: u8,
+    bit_depth: u8,
+    monochrome: u8,
+    chroma_subsampling_x: u8,
+    chroma_subsampling_y: u8,
+    chroma_sample_position: u8,
+}
+
+impl Av1SequenceHeader {
+    // AV1 Bitstream & Decoding Process Specification 5.5 Sequence header OBU syntax
+    fn parse(payload: &[u8]) -> Option<Self> {
+        let mut r = BitReader::new(payload);
+        let profile = r.read_bits(3)? as u8;
+        r.skip_bits(1)?; // still_picture
+        let reduced_still_picture_header = r.read_flag()?;
+
+        let level;
+        let mut tier = 0;
+        if reduced_still_picture_header {
+            level = r.read_bits(5)? as u8;
+        } else {
+            let mut decoder_model_info_present = false;
+            let mut buffer_delay_length = 0;
+            if r.read_flag()? {
+                // timing_info_present_flag
+                r.skip_bits(64)?; // num_units_in_display_tick, time_scale
+                if r.read_flag()? {
+                    // equal_picture_interval
+                    read_uvlc(&mut r)?; // num_ticks_per_picture_minus_1
+                }
+                decoder_model_info_present = r.read_flag()?;
+                if decoder_model_info_present {
+                    buffer_delay_length = r.read_bits(5)? as usize + 1;
+                    r.skip_bits(32 + 5 + 5)?;
+                }
+            }
+            let initial_display_delay_present = r.read_flag()?;
+            let operating_points = r.read_bits(5)? + 1;
+            let mut first_level_and_tier = None;
+            for _ in 0..operating_points {
+                r.skip_bits(12)?; // operating_point_idc
+                let seq_level_idx = r.read_bits(5)? as u8;
+                let seq_tier = if seq_level_idx > 7 {
+                    r.read_bits(1)? as u8
+                } else {
+                    0
+                };
+                first_level_and_tier.get_or_insert((seq_level_idx, seq_tier));
+                if decoder_model_info_present && r.read_flag()? {
+                    // decoder_buffer_delay, encoder_buffer_delay, low_delay_mode_flag
+                    r.skip_bits(buffer_delay_length * 2 + 1)?;
+                }
+                if initial_display_delay_present && r.read_flag()? {
+                    r.skip_bits(4)?; // initial_display_delay_minus_1
+                }
+            }
+            (level, tier) = first_level_and_tier?;
+        }
+
+        let frame_width_bits = r.read_bits(4)? as usize + 1;
+        let frame_height_bits = r.read_bits(4)? as usize + 1;
+        r.skip_bits(frame_width_bits + frame_height_bits)?;
+        if !reduced_still_picture_header && r.read_flag()? {
+            // frame_id_numbers_present_flag
+            r.skip_bits(4 + 3)?;
+        }
+        r.skip_bits(3)?; // use_128x128_superblock, enable_filter_intra, enable_intra_edge_filter
+        if !reduced_still_picture_header {
+            r.skip_bits(4)?; // enable_interintra_compound ... enable_dual_filter
+            let enable_order_hint = r.read_flag()?;
+            if enable_order_hint {
+                r.skip_bits(2)?; // enable_jnt_comp, enable_ref_frame_mvs
+            }
+            let seq_force_screen_content_tools = if r.read_flag()? {
+                // seq_choose_screen_content_tools
+                2
+            } else {
+                r.read_bits(1)?
+            };
+            if seq_force_screen_content_tools > 0 && !r.read_flag()? {
+                // seq_choose_integer_mv
+                r.skip_bits(1)?; // seq_force_integer_mv
+            }
+            if enable_order_hint {
+                r.skip_bits(3)?; // order_hint_bits_minus_1
+            }
+        }
+        r.skip_bits(3)?; // enable_superres, enable_cdef, enable_restoration
+
+        // 5.5.2 Color config syntax
+        let high_bitdepth = r.read_flag()?;
+        let bit_depth = match (profile, high_bitdepth) {
+            (2, true) if r.read_flag()? => 12,
+            (_, true) => 10,
+            (_, false) => 8,
+        };
+        let monochrome = if profile == 1 {
+            0
+        } else {
+            r.read_bits(1)? as u8
+        };
+        let (mut color_primaries, mut transfer_characteristics, mut matrix_coefficients) =
+            (2, 2, 2);
+        if r.read_flag()? {
+            // color_description_present_flag
+            color_primaries = r.read_bits(8)?;
+            transfer_characteristics = r.read_bits(8)?;
+            matrix_coefficients = r.read_bits(8)?;
+        }
+        let (chroma_subsampling_x, chroma_subsampling_y, chroma_sample_position) =
+            if monochrome == 1 {
+                (1, 1, 0)
+            } else if (
+                color_primaries,
+                transfer_characteristics,
+                matrix_coefficients,
+            ) == (1, 13, 0)
+            {
+                // sRGB
+                (0, 0, 0)
+            } else {
+                r.skip_bits(1)?; // color_range
+                let (x, y) = match profile {
+                    0 => (1, 1),
+                    1 => (0, 0),
+                    _ if bit_depth == 12 => {
+                        let x = r.read_bits(1)? as u8;
+                        let y = if x == 1 { r.read_bits(1)? as u8 } else { 0 };
+                        (x, y)
+                    }
+                    _ => (1, 0),
+                };
+                let position = if x == 1 && y == 1 {
+                    r.read_bits(2)? as u8
+                } else {
+                    0
+                };
+                (x, y, position)
+            };
+
+        Some(Self {
+            profile,
+            level,
+            tier,
+            bit_depth,
+            monochrome,
+            chroma_subsampling_x,
+            chroma_subsampling_y,
+            chroma_sample_position,
+        })
+    }
+}
+
+// AV1 Bitstream & Decoding Process Specification 4.10.3 uvlc()
+fn read_uvlc(r: &mut BitReader) -> Option<u32> {
+    let mut leading_zeros = 0;
+    while !r.read_flag()? {
+        leading_zeros += 1;
+    }
+    if leading_zeros >= 32 {
+        return Some(u32::MAX);
+    }
+    Some(r.read_bits(leading_zeros)? + ((1u64 << leading_zeros) - 1) as u32)
+}
+
+// バイト列が ADTS 形式の AAC ストリームかどうかを判定する
+//
+// 先頭の ID3 タグは読み飛ばし、誤判定を避けるために二つ目のフレームの同期ワードも確認する
+pub fn is_adts(bytes: &[u8]) -> bool {
+    let bytes = &bytes[id3_tag_size(bytes).min(bytes.len())..];
+    let Ok(Some(header)) = AdtsHeader::parse(bytes) else {
+        return false;
+    };
+    let next = &bytes[header.frame_length.min(bytes.len())..];
+    next.is_empty() || AdtsHeader::is_sync_word(next)
+}
+
+// ADTS 形式の AAC ストリームを読み込んで、音声トラックのみを持つ MP4 と同じ形式に変換する
+//
+// 変換後の各サンプルのデータ位置は、ADTS ヘッダーを除いたフレームの、元のバイト列の先頭からのオフセットとなる。
+// デコーダー設定は最初のフレームの ADTS ヘッダーから生成する。
+pub fn load_adts(bytes: &[u8]) -> orfail::Result<Mp4> {
+    let mut position = id3_tag_size(bytes);
+    let mut config = None;
+    let mut samples = Vec::new();
+    while position < bytes.len() {
+        let data = &bytes[position..];
+        if !AdtsHeader::is_sync_word(data) {
+            // 同期が外れている場合には、次の同期ワードまで読み飛ばす
+            position += 1;
+            continue;
+        }
+        let Some(header) = AdtsHeader::parse(data).or_fail()? else {
+            break;
+        };
+        if data.len() < header.frame_length {
+            // 末尾が欠けているフレームは無視する
+            break;
+        }
+        let header_config = config.get_or_insert(header);
+        (header_config.sample_rate == header.sample_rate
+            && header_config.channel_configuration == header.channel_configuration)
+            .or_fail_with(|()| "ADTS stream parameters changed midway".to_owned())?;
+        samples.push(EsSample {
+            duration: adts::SAMPLES_PER_FRAME,
+            is_sync: true,
+            data: position + header.header_length..position + header.frame_length,
+        });
+        position += header.frame_length;
+    }
+
+    let header = config.or_fail_with(|()| "No ADTS frame found".to_owned())?;
+    let timescale = NonZeroU32::new(header.sample_rate).or_fail()?;
+    let sample_entry = header.to_decoder_config().to_sample_entry().or_fail()?;
+    make_track(
+        TrackKind::Audio,
+        timescale,
+        sample_entry,
+        samples,
+        bytes.len(),
+    )
+    .or_fail()
+}
+
+// 先頭に ID3v2 タグがある場合には、そのバイト数を返す
+fn id3_tag_size(bytes: &[u8]) -> usize {
+    let [b'I', b'D', b'3', _, _, flags, s0, s1, s2, s3, ..] = *bytes else {
+        return 0;
+    };
+    let size = (s0 as usize & 0x7F) << 21
+        | (s1 as usize & 0x7F) << 14
+        | (s2 as usize & 0x7F) << 7
+        | (s3 as usize & 0x7F);
+    let footer_size = if flags & 0x10 != 0 { 10 } else { 0 };
+    10 + size + footer_size
+}
+
+// バイト列が Ogg ファイルかどうかを判定する
+pub fn is_ogg(bytes: &[u8]) -> bool {
+    bytes.starts_with(OGG_CAPTURE_PATTERN)
+}
+
+// Ogg Opus ファイルを読み込んで、音声トラックのみを持つ MP4 と同じ形式に変換する
+//
+// Opus パケットはページをまたいで格納されていることがあるため、
+// パケットを連結したバイト列もあわせて返す（サンプルのデータ位置はこのバイト列の先頭からのオフセットとなる）。
+// 各パケットの尺は TOC バイトから求め、OpusHead はデコーダー設定の description として使われる。
+//
+// [NOTE] 最初の Opus の論理ストリームのみを扱い、それ以外の論理ストリームは無視する。
+//        また、グラニュール位置による末尾のトリミングは行わない。
+pub fn load_ogg_opus(bytes: &[u8]) -> orfail::Result<(Mp4, Vec<u8>)> {
+    let mut serial = None;
+    let mut packet = Vec::new();
+    let mut packets = Vec::new();
+    let mut position = 0;
+    while let Some(header) = bytes.get(position..position + OGG_PAGE_HEADER_SIZE) {
+        header
+            .starts_with(OGG_CAPTURE_PATTERN)
+            .or_fail_with(|()| format!("Invalid Ogg page at offset {position}"))?;
+        let header_type = header[5];
+        let page_serial = u32::from_le_bytes(header[14..18].try_into().expect("infallible"));
+        let segment_count = header[26] as usize;
+        let Some(lacing_values) = bytes
+            .get(position + OGG_PAGE_HEADER_SIZE..position + OGG_PAGE_HEADER_SIZE + segment_count)
+        else {
+            break;
+        };
+        let data_start = position + OGG_PAGE_HEADER_SIZE + segment_count;
+        let data_end = data_start + lacing_values.iter().map(|&v| v as usize).sum::<usize>();
+        let Some(mut data) = bytes.get(data_start..data_end) else {
+            // 末尾が欠けているページは無視する
+            break;
+        };
+        position = data_end;
+
+        if serial.is_none()
+            && header_type & OGG_HEADER_TYPE_BOS != 0
+            && data.starts_with(OPUS_HEAD_SIGNATURE)
+        {
+            serial = Some(page_serial);
+        }
+        if serial != Some(page_serial) {
+            continue;
+        }
+
+        // 255 未満のレーシング値でパケットが終端する（255 の場合は次のセグメントに続く）
+        for &lacing_value in lacing_values {
+            let (segment, rest) = data.split_at(lacing_value as usize);
+            packet.extend_from_slice(segment);
+            data = rest;
+            if lacing_value < 255 {
+                packets.push(std::mem::take(&mut packet));
+            }
+        }
+    }
+
+    let mut packets = packets.into_iter();
+    let opus_head = packets
+        .next()
+        .or_fail_with(|()| "No Opus stream found in Ogg".to_owned())?;
+    let channels = *opus_head
+        .get(9)
+        .or_fail_with(|()| "Too short OpusHead".to_owned())?;
+    let sample_entry = AudioDecoderConfig {
+        codec: "opus".to_owned(),
+        sample_rate: OPUS_TIMESCALE.get(),
+        number_of_channels: channels,
+        description: opus_head,
+    }
+    .to_sample_entry()
+    .or_fail()?;
+
+    let mut media = Vec::new();
+    let mut samples = Vec::new();
+    for packet in packets {
+        if packet.starts_with(OPUS_TAGS_SIGNATURE) || packet.is_empty() {
+            continue;
+        }
+        let data_offset = media.len();
+        media.extend_from_slice(&packet);
+        samples.push(EsSample {
+            duration: opus_packet_samples(&packet) as u32,
+            is_sync: true,
+            data: data_offset..media.len(),
+        });
+    }
+    let mp4 = make_track(
+        TrackKind::Audio,
+        OPUS_TIMESCALE,
+        sample_entry,
+        samples,
+        media.len(),
+    )
+    .or_fail()?;
+    Ok((mp4, media))
+}
+
+// Opus パケットの TOC バイトから、パケットに含まれるサンプル数（48 kHz 単位）を求める
+//
+// RFC 6716 3.1. The TOC Byte
+pub(crate) fn opus_packet_samples(packet: &[u8]) -> u64 {
+    let Some(&toc) = packet.first() else {
+        return 0;
+    };
+    let config = toc >> 3;
+    let frame_samples = match config {
+        0..=11 => [480, 960, 1920, 2880][config as usize % 4],
+        12..=15 => [480, 960][config as usize % 2],
+        _ => [120, 240, 480, 960][config as usize % 4],
+    };
+    let frame_count = match toc & 0b11 {
+        0 => 1,
+        1 | 2 => 2,
+        _ => packet.get(1).map_or(0, |b| b & 0b11_1111) as u64,
+    };
+    frame_samples * frame_count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 6716 Table 2 のフレーム長（1/10 ms 単位）
+    fn frame_duration_tenth_ms(config: u8) -> u64 {
+        match config {
+            0..=11 => [100, 200, 400, 600][config as usize % 4],
+            12..=15 => [100, 200][config as usize % 2],
+            _ => [25, 50, 100, 200][config as usize % 4],
+        }
+    }
+
+    #[test]
+    fn opus_frame_size_depends_on_toc_config() {
+        for config in 0..32 {
+            let expected = frame_duration_tenth_ms(config) * 48_000 / 10_000;
+            assert_eq!(
+                opus_packet_samples(&[config << 3]),
+                expected,
+                "config={config}"
+            );
+        }
+    }
+
+    #[test]
+    fn opus_frame_count_depends_on_toc_code() {
+        // CELT FB 20 ms（config 31）のフレームを使う
+        let toc = 31 << 3;
+        assert_eq!(opus_packet_samples(&[toc]), 960);
+        assert_eq!(opus_packet_samples(&[toc | 1]), 2 * 960);
+        assert_eq!(opus_packet_samples(&[toc | 2]), 2 * 960);
+
+        // code 3 では、次のバイトの下位 6 ビットがフレーム数となる（上位ビットは VBR / パディングのフラグ）
+        assert_eq!(opus_packet_samples(&[toc | 3, 0b1000_0011]), 3 * 960);
+        assert_eq!(opus_packet_samples(&[16 << 3 | 3, 48]), 48 * 120);
+
+        // フレーム数のバイトが欠けている・空のパケット
+        assert_eq!(opus_packet_samples(&[toc | 3]), 0);
+        assert_eq!(opus_packet_samples(&[]), 0);
+    }
+}
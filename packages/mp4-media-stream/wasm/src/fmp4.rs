@@ -0,0 +1,306 @@
+use orfail::OrFail;
+use shiguredo_mp4::{
+    boxes::{Brand, FtypBox, MdatBox, UnknownBox},
+    BoxHeader, BoxSize, BoxType, Encode,
+};
+
+use crate::muxer::{make_moov_box, MuxSample, MuxTrack};
+
+// Fragmented MP4 (fMP4) の初期化セグメントとメディアセグメントの生成処理
+//
+// shiguredo_mp4 は moof / mvex 配下のボックスに未対応なので、それらはここで直接エンコードしている
+
+// tfhd ボックスのフラグ
+const TFHD_FLAG_SAMPLE_DESCRIPTION_INDEX_PRESENT: u32 = 0x000002;
+const TFHD_FLAG_DEFAULT_BASE_IS_MOOF: u32 = 0x020000;
+
+// trun ボックスのフラグ
+const TRUN_FLAG_DATA_OFFSET_PRESENT: u32 = 0x000001;
+const TRUN_FLAG_SAMPLE_DURATION_PRESENT: u32 = 0x000100;
+const TRUN_FLAG_SAMPLE_SIZE_PRESENT: u32 = 0x000200;
+const TRUN_FLAG_SAMPLE_FLAGS_PRESENT: u32 = 0x000400;
+const TRUN_FLAG_SAMPLE_COMPOSITION_TIME_OFFSET_PRESENT: u32 = 0x000800;
+
+// trun ボックスのサンプル毎のフラグ
+//
+// 同期サンプルは「他のサンプルに依存しない」、それ以外は「他のサンプルに依存する非同期サンプル」とする
+const SAMPLE_FLAGS_SYNC: u32 = 0x02000000;
+const SAMPLE_FLAGS_NON_SYNC: u32 = 0x01010000;
+
+// メディアセグメントに含めるトラックの情報
+#[derive(Debug)]
+pub struct FragmentTrack<'a> {
+    // 初期化セグメント内でのトラック ID
+    pub track_id: u32,
+
+    // トラックのタイムスケール単位での、フラグメント内の最初のサンプルのデコード時刻
+    pub base_media_decode_time: u64,
+
+    // サンプルのデータ位置は write_media_segment() に渡される payload 先頭からのオフセットとして扱われる
+    pub samples: &'a [MuxSample],
+}
+
+pub fn make_fragmented_ftyp_box() -> FtypBox {
+    FtypBox {
+        major_brand: Brand::ISO5,
+        minor_version: 512,
+        compatible_brands: vec![Brand::ISO5, Brand::ISO6, Brand::MP41],
+    }
+}
+
+// ftyp / moov からなる初期化セグメントを生成する
+//
+// トラックのサンプル群は参照されず、サンプルエントリーと編集リスト（start_offset と edits）のみが使われる
+// (トラック ID は tracks 内の位置に一を足した値となる）
+pub fn write_init_segment(tracks: &[MuxTrack]) -> orfail::Result<Vec<u8>> {
+    let tracks = tracks
+        .iter()
+        .map(|t| MuxTrack {
+            sample_entries: t.sample_entries.clone(),
+            start_offset: t.start_offset,
+            edits: t.edits.clone(),
+            ..MuxTrack::new(t.kind, t.timescale)
+        })
+        .collect::<Vec<_>>();
+
+    let mut mvex_payload = Vec::new();
+    for track_id in 1..=tracks.len() as u32 {
+        write_full_box(&mut mvex_payload, *b"trex", 0, 0, |buf| {
+            buf.extend_from_slice(&track_id.to_be_bytes());
+            buf.extend_from_slice(&1u32.to_be_bytes()); // default_sample_description_index
+            buf.extend_from_slice(&0u32.to_be_bytes()); // default_sample_duration
+            buf.extend_from_slice(&0u32.to_be_bytes()); // default_sample_size
+            buf.extend_from_slice(&0u32.to_be_bytes()); // default_sample_flags
+        });
+    }
+    let mvex_type = BoxType::Normal(*b"mvex");
+    let mut moov_box = make_moov_box(&tracks, 0);
+    moov_box.unknown_boxes.push(UnknownBox {
+        box_type: mvex_type,
+        box_size: BoxSize::with_payload_size(mvex_type, mvex_payload.len() as u64),
+        payload: mvex_payload,
+    });
+
+    let mut bytes = Vec::new();
+    make_fragmented_ftyp_box().encode(&mut bytes).or_fail()?;
+    moov_box.encode(&mut bytes).or_fail()?;
+    Ok(bytes)
+}
+
+// moof / mdat からなるメディアセグメントを生成する
+//
+// 各トラックのサンプルのデータは、トラック毎に連続するように並べ直して mdat に格納される
+pub fn write_media_segment(
+    sequence_number: u32,
+    tracks: &[FragmentTrack],
+    payload: &[u8],
+) -> orfail::Result<Vec<u8>> {
+    // tfhd で指定可能なサンプルエントリーは一つだけなので、
+    // サンプルエントリーが切り替わる位置で traf ボックスを分割する
+    let mut runs = Vec::new();
+    for track in tracks {
+        let mut decode_time = track.base_media_decode_time;
+        for run in track
+            .samples
+            .chunk_by(|a, b| a.sample_entry_index == b.sample_entry_index)
+        {
+            runs.push(TrackRun {
+                track_id: track.track_id,
+                base_media_decode_time: decode_time,
+                samples: run,
+                data_offset: 0,
+            });
+            decode_time += run.iter().map(|s| s.duration as u64).sum::<u64>();
+        }
+    }
+    (!runs.is_empty()).or_fail_with(|()| "No samples in the fragment".to_owned())?;
+
+    let mut mdat_payload = Vec::new();
+    let mut run_payload_offsets = Vec::with_capacity(runs.len());
+    for run in &runs {
+        run_payload_offsets.push(mdat_payload.len() as u64);
+        for sample in run.samples {
+            let start = sample.data_offset as usize;
+            let end = start + sample.data_size as usize;
+            let data = payload
+                .get(start..end)
+                .or_fail_with(|()| "Sample data is out of range".to_owned())?;
+            mdat_payload.extend_from_slice(data);
+        }
+    }
+
+    let mdat_header = BoxHeader {
+        box_type: MdatBox::TYPE,
+        box_size: BoxSize::with_payload_size(MdatBox::TYPE, mdat_payload.len() as u64),
+    };
+
+    // trun のデータオフセットは moof の先頭を基準とするので、
+    // 一度仮の値でエンコードして moof のサイズを求めてから、正しい値を設定する
+    let moof_size = encode_moof_box(sequence_number, &runs).len() as u64;
+    for (run, payload_offset) in runs.iter_mut().zip(run_payload_offsets) {
+        let offset = moof_size + mdat_header.external_size() as u64 + payload_offset;
+        run.data_offset = i32::try_from(offset)
+            .ok()
+            .or_fail_with(|()| format!("Too large fragment: data offset {offset}"))?;
+    }
+
+    let mut bytes = encode_moof_box(sequence_number, &runs);
+    mdat_header.encode(&mut bytes).or_fail()?;
+    bytes.extend_from_slice(&mdat_payload);
+    Ok(bytes)
+}
+
+// 同じトラック・同じサンプルエントリーのサンプルが連続する区間（一つの traf ボックスに対応する）
+#[derive(Debug)]
+struct TrackRun<'a> {
+    track_id: u32,
+    base_media_decode_time: u64,
+    samples: &'a [MuxSample],
+    data_offset: i32,
+}
+
+fn encode_moof_box(sequence_number: u32, runs: &[TrackRun]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    write_box(&mut bytes, *b"moof", |buf| {
+        write_full_box(buf, *b"mfhd", 0, 0, |buf| {
+            buf.extend_from_slice(&sequence_number.to_be_bytes());
+        });
+        for run in runs {
+            write_box(buf, *b"traf", |buf| encode_traf_payload(buf, run));
+        }
+    });
+    bytes
+}
+
+fn encode_traf_payload(buf: &mut Vec<u8>, run: &TrackRun) {
+    let tfhd_flags = TFHD_FLAG_SAMPLE_DESCRIPTION_INDEX_PRESENT | TFHD_FLAG_DEFAULT_BASE_IS_MOOF;
+    write_full_box(buf, *b"tfhd", 0, tfhd_flags, |buf| {
+        let sample_description_index = run.samples[0].sample_entry_index as u32 + 1;
+        buf.extend_from_slice(&run.track_id.to_be_bytes());
+        buf.extend_from_slice(&sample_description_index.to_be_bytes());
+    });
+    write_full_box(buf, *b"tfdt", 1, 0, |buf| {
+        buf.extend_from_slice(&run.base_media_decode_time.to_be_bytes());
+    });
+
+    // B フレームを含む場合には、負の値も扱えるようにバージョン 1 でコンポジション時間オフセットを書き込む
+    let has_composition_offsets = run.samples.iter().any(|s| s.composition_offset != 0);
+    let mut trun_flags = TRUN_FLAG_DATA_OFFSET_PRESENT
+        | TRUN_FLAG_SAMPLE_DURATION_PRESENT
+        | TRUN_FLAG_SAMPLE_SIZE_PRESENT
+        | TRUN_FLAG_SAMPLE_FLAGS_PRESENT;
+    if has_composition_offsets {
+        trun_flags |= TRUN_FLAG_SAMPLE_COMPOSITION_TIME_OFFSET_PRESENT;
+    }
+    let trun_version = u8::from(has_composition_offsets);
+    write_full_box(buf, *b"trun", trun_version, trun_flags, |buf| {
+        buf.extend_from_slice(&(run.samples.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&run.data_offset.to_be_bytes());
+        for sample in run.samples {
+            let flags = if sample.is_sync {
+                SAMPLE_FLAGS_SYNC
+            } else {
+                SAMPLE_FLAGS_NON_SYNC
+            };
+            buf.extend_from_slice(&sample.duration.to_be_bytes());
+            buf.extend_from_slice(&sample.data_size.to_be_bytes());
+            buf.extend_from_slice(&flags.to_be_bytes());
+            if has_composition_offsets {
+                buf.extend_from_slice(&sample.composition_offset.to_be_bytes());
+            }
+        }
+    });
+}
+
+// ボックスを書き込む（サイズはペイロードを書き込んだ後に確定させる）
+fn write_box(buf: &mut Vec<u8>, box_type: [u8; 4], write_payload: impl FnOnce(&mut Vec<u8>)) {
+    let start = buf.len();
+    buf.extend_from_slice(&[0; 4]);
+    buf.extend_from_slice(&box_type);
+    write_payload(buf);
+    let size = (buf.len() - start) as u32;
+    buf[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+fn write_full_box(
+    buf: &mut Vec<u8>,
+    box_type: [u8; 4],
+    version: u8,
+    flags: u32,
+    write_payload: impl FnOnce(&mut Vec<u8>),
+) {
+    write_box(buf, box_type, |buf| {
+        buf.extend_from_slice(&((version as u32) << 24 | flags).to_be_bytes());
+        write_payload(buf);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mp4::{read_u32, split_box};
+
+    // 指定の経路にある子ボックスのペイロードを返す（フルボックスのヘッダーは含む）
+    fn find_box<'a>(mut bytes: &'a [u8], path: &[&[u8; 4]]) -> Option<&'a [u8]> {
+        let (first, rest) = path.split_first()?;
+        while let Some((header, payload, remaining)) = split_box(bytes) {
+            if header.box_type.as_bytes() == *first {
+                return if rest.is_empty() {
+                    Some(payload)
+                } else {
+                    // moof と traf の子ボックスは、ペイロードの先頭から並んでいる
+                    find_box(payload, rest)
+                };
+            }
+            bytes = remaining;
+        }
+        None
+    }
+
+    fn sample(composition_offset: i32, is_sync: bool) -> MuxSample {
+        MuxSample {
+            sample_entry_index: 0,
+            duration: 3000,
+            is_sync,
+            composition_offset,
+            data_offset: 0,
+            data_size: 4,
+        }
+    }
+
+    fn trun_payload(samples: &[MuxSample]) -> Vec<u8> {
+        let track = FragmentTrack {
+            track_id: 1,
+            base_media_decode_time: 0,
+            samples,
+        };
+        let segment = write_media_segment(1, &[track], &[0; 4]).expect("write_media_segment");
+        find_box(&segment, &[b"moof", b"traf", b"trun"])
+            .expect("trun")
+            .to_vec()
+    }
+
+    #[test]
+    fn trun_has_signed_composition_offsets() {
+        let payload = trun_payload(&[sample(0, true), sample(6000, false), sample(-3000, false)]);
+        assert_eq!(payload[0], 1);
+        let flags = read_u32(&payload, 0).expect("flags") & 0xFFFFFF;
+        assert_eq!(flags, 0x000F01);
+        assert_eq!(read_u32(&payload, 4), Some(3));
+
+        // sample_count と data_offset の後に、16 バイトずつのサンプル情報が続く
+        let offsets = (0..3)
+            .map(|i| read_u32(&payload, 12 + i * 16 + 12).expect("offset") as i32)
+            .collect::<Vec<_>>();
+        assert_eq!(offsets, [0, 6000, -3000]);
+    }
+
+    #[test]
+    fn trun_omits_zero_composition_offsets() {
+        let payload = trun_payload(&[sample(0, true), sample(0, false)]);
+        assert_eq!(payload[0], 0);
+        let flags = read_u32(&payload, 0).expect("flags") & 0xFFFFFF;
+        assert_eq!(flags, 0x000701);
+        assert_eq!(payload.len(), 4 + 4 + 4 + 2 * 12);
+    }
+}
@@ -0,0 +1,515 @@
+use serde::Serialize;
+use shiguredo_mp4::{
+    aux::SampleTableAccessor,
+    boxes::{HdlrBox, StszBox, TrakBox},
+    BaseBox, BoxHeader, BoxType, Decode, Either,
+};
+
+use crate::mp4::{
+    read_u16, Mp4, AUDIO_SAMPLE_ENTRY_FIELDS_SIZE, SOUND_DESCRIPTION_V1_EXTRA_SIZE,
+    SOUND_DESCRIPTION_V2_EXTRA_SIZE,
+};
+
+// 子ボックスを持つコンテナボックスと、ペイロードの先頭から最初の子ボックスまでのバイト数
+//
+// サンプルエントリーは、固定長のフィールドの後に avcC などの子ボックスが続く。
+// 音声のサンプルエントリーについては、QuickTime のサウンドデスクリプションのバージョンに応じた追加フィールドの分が
+// container_payload_skip() で加算される
+const CONTAINER_BOXES: &[([u8; 4], usize)] = &[
+    (*b"moov", 0),
+    (*b"trak", 0),
+    (*b"edts", 0),
+    (*b"mdia", 0),
+    (*b"minf", 0),
+    (*b"dinf", 0),
+    (*b"stbl", 0),
+    (*b"mvex", 0),
+    (*b"moof", 0),
+    (*b"traf", 0),
+    (*b"mfra", 0),
+    (*b"udta", 0),
+    (*b"sinf", 0),
+    (*b"schi", 0),
+    (*b"meta", 4),
+    (*b"dref", 8),
+    (*b"stsd", 8),
+    (*b"avc1", 78),
+    (*b"avc3", 78),
+    (*b"hev1", 78),
+    (*b"hvc1", 78),
+    (*b"vp08", 78),
+    (*b"vp09", 78),
+    (*b"av01", 78),
+    (*b"encv", 78),
+    (*b"mp4a", AUDIO_SAMPLE_ENTRY_FIELDS_SIZE),
+    (*b"Opus", AUDIO_SAMPLE_ENTRY_FIELDS_SIZE),
+    (*b".mp3", AUDIO_SAMPLE_ENTRY_FIELDS_SIZE),
+    (*b"fLaC", AUDIO_SAMPLE_ENTRY_FIELDS_SIZE),
+    (*b"enca", AUDIO_SAMPLE_ENTRY_FIELDS_SIZE),
+];
+
+// MP4 ファイルの構造と、仕様違反（あるいは再生時に問題となり得る箇所）の調査結果
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Mp4Report {
+    pub file_size: u64,
+    pub boxes: Vec<BoxReport>,
+    pub tracks: Vec<TrackReport>,
+    pub violations: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BoxReport {
+    #[serde(rename = "type")]
+    pub box_type: String,
+
+    // ファイル先頭からのオフセットと、ヘッダーを含むボックス全体のサイズ
+    pub offset: u64,
+    pub size: u64,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<BoxReport>,
+}
+
+// サンプルテーブルの要約
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrackReport {
+    pub track_id: u32,
+    pub handler_type: String,
+    pub timescale: u32,
+    pub sample_entries: Vec<String>,
+    pub sample_count: u32,
+    pub chunk_count: u32,
+
+    // stss ボックスが存在しない場合（全てのサンプルが同期サンプル扱い）には None
+    pub sync_sample_count: Option<u32>,
+
+    // stts ボックスの値の合計（トラックのタイムスケール単位）
+    pub total_duration: u64,
+    pub total_data_size: u64,
+    pub min_sample_size: u32,
+    pub max_sample_size: u32,
+}
+
+// MP4 ファイルの構造を調べて、その結果を返す
+//
+// 不正なファイルであっても失敗はせず、検出された問題は violations に格納される
+pub fn inspect_mp4(mp4_bytes: &[u8]) -> Mp4Report {
+    let mut violations = Vec::new();
+    let boxes = inspect_boxes(mp4_bytes, 0, &mut violations);
+
+    let mut tracks = Vec::new();
+    match Mp4::load_moov_box(mp4_bytes) {
+        Ok((moov_box, _)) => {
+            for trak_box in &moov_box.trak_boxes {
+                tracks.push(inspect_track(trak_box, mp4_bytes.len(), &mut violations));
+            }
+        }
+        Err(e) => violations.push(format!("Failed to decode 'moov' box: {}", e.message)),
+    }
+
+    Mp4Report {
+        file_size: mp4_bytes.len() as u64,
+        boxes,
+        tracks,
+        violations,
+    }
+}
+
+// bytes 内のボックス群を再帰的に走査する（base_offset は bytes の先頭のファイル内での位置）
+fn inspect_boxes(bytes: &[u8], base_offset: u64, violations: &mut Vec<String>) -> Vec<BoxReport> {
+    let mut boxes = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let absolute_offset = base_offset + offset as u64;
+        let header = match BoxHeader::decode(&mut &bytes[offset..]) {
+            Ok(header) => header,
+            Err(e) => {
+                violations.push(format!(
+                    "Failed to decode box header at offset {absolute_offset}: {}",
+                    e.io_error
+                ));
+                break;
+            }
+        };
+
+        let header_size = header.external_size();
+        let remaining = (bytes.len() - offset) as u64;
+        let mut size = match header.box_size.get() {
+            // サイズが 0 の場合は、親ボックス（あるいはファイル）の末尾までがボックスとなる
+            0 => remaining,
+            size => size,
+        };
+        if size < header_size as u64 {
+            violations.push(format!(
+                "Box '{}' at offset {absolute_offset} has too small size: {size}",
+                header.box_type
+            ));
+            break;
+        }
+        if size > remaining {
+            violations.push(format!(
+                "Box '{}' at offset {absolute_offset} exceeds its parent or the file: size={size}, available={remaining}",
+                header.box_type
+            ));
+            size = remaining;
+        }
+
+        let end = offset + size as usize;
+        let payload = bytes.get(offset + header_size..end).unwrap_or_default();
+        let children = match container_payload_skip(header.box_type, payload) {
+            Some(skip) if offset + header_size + skip <= end => inspect_boxes(
+                &bytes[offset + header_size + skip..end],
+                absolute_offset + (header_size + skip) as u64,
+                violations,
+            ),
+            _ => Vec::new(),
+        };
+        boxes.push(BoxReport {
+            box_type: header.box_type.to_string(),
+            offset: absolute_offset,
+            size,
+            children,
+        });
+        offset = end;
+    }
+    boxes
+}
+
+fn container_payload_skip(box_type: BoxType, payload: &[u8]) -> Option<usize> {
+    let BoxType::Normal(ty) = box_type else {
+        return None;
+    };
+    let skip = CONTAINER_BOXES
+        .iter()
+        .find(|(t, _)| *t == ty)
+        .map(|(_, skip)| *skip)?;
+    if skip != AUDIO_SAMPLE_ENTRY_FIELDS_SIZE {
+        return Some(skip);
+    }
+
+    // 音声のサンプルエントリーの場合は、バージョンフィールド（ISO BMFF では予約領域）を参照する
+    let extra_size = match read_u16(payload, 8)? {
+        0 => 0,
+        1 => SOUND_DESCRIPTION_V1_EXTRA_SIZE,
+        2 => SOUND_DESCRIPTION_V2_EXTRA_SIZE,
+        _ => return None,
+    };
+    Some(skip + extra_size)
+}
+
+fn inspect_track(trak_box: &TrakBox, mp4_size: usize, violations: &mut Vec<String>) -> TrackReport {
+    let track_id = trak_box.tkhd_box.track_id;
+    let handler_type = trak_box.mdia_box.hdlr_box.handler_type;
+    let stbl_box = &trak_box.mdia_box.minf_box.stbl_box;
+    let mut violation = |message: String| violations.push(format!("Track {track_id}: {message}"));
+
+    // 不正なファイルで巨大な sample_count が指定されている場合に備えて、固定サイズの場合は展開せずに集計する
+    let (sample_count, total_data_size, min_sample_size, max_sample_size) = match &stbl_box.stsz_box
+    {
+        StszBox::Fixed {
+            sample_size,
+            sample_count,
+        } => (
+            *sample_count as u64,
+            sample_size.get() as u64 * *sample_count as u64,
+            sample_size.get(),
+            sample_size.get(),
+        ),
+        StszBox::Variable { entry_sizes } => (
+            entry_sizes.len() as u64,
+            entry_sizes.iter().map(|&s| s as u64).sum(),
+            entry_sizes.iter().copied().min().unwrap_or_default(),
+            entry_sizes.iter().copied().max().unwrap_or_default(),
+        ),
+    };
+    let stts_sample_count = stbl_box
+        .stts_box
+        .entries
+        .iter()
+        .map(|e| e.sample_count as u64)
+        .sum::<u64>();
+    let chunk_count = match &stbl_box.stco_or_co64_box {
+        Either::A(b) => b.chunk_offsets.len(),
+        Either::B(b) => b.chunk_offsets.len(),
+    };
+
+    let mut report = TrackReport {
+        track_id,
+        handler_type: String::from_utf8_lossy(&handler_type).into_owned(),
+        timescale: trak_box.mdia_box.mdhd_box.timescale.get(),
+        sample_entries: stbl_box
+            .stsd_box
+            .entries
+            .iter()
+            .map(|e| e.box_type().to_string())
+            .collect(),
+        sample_count: sample_count as u32,
+        chunk_count: chunk_count as u32,
+        sync_sample_count: stbl_box
+            .stss_box
+            .as_ref()
+            .map(|b| b.sample_numbers.len() as u32),
+        total_duration: stbl_box
+            .stts_box
+            .entries
+            .iter()
+            .map(|e| e.sample_count as u64 * e.sample_delta as u64)
+            .sum(),
+        total_data_size,
+        min_sample_size,
+        max_sample_size,
+    };
+
+    if sample_count == 0 {
+        violation("Empty track".to_owned());
+    }
+    if stts_sample_count != sample_count {
+        violation(format!(
+            "Mismatched sample counts: stts={stts_sample_count}, stsz={sample_count}"
+        ));
+        // サンプル数が一致しない場合には、以降のサンプル単位のチェックは行えない
+        return report;
+    }
+    if handler_type == HdlrBox::HANDLER_TYPE_VIDE && stbl_box.stss_box.is_none() {
+        violation(
+            "Missing 'stss' box in video track (all samples are treated as sync samples)"
+                .to_owned(),
+        );
+    }
+
+    // stts の sample_delta が 0 のサンプルは、尺が 0 で、次のサンプルとデコード時刻が同じになる
+    let mut sample_index = 0;
+    let mut zero_duration_samples = 0;
+    let mut first_zero_duration_sample = None;
+    let mut is_last_sample_zero_duration = false;
+    for entry in &stbl_box.stts_box.entries {
+        if entry.sample_delta == 0 && entry.sample_count > 0 {
+            zero_duration_samples += entry.sample_count as u64;
+            first_zero_duration_sample.get_or_insert(sample_index);
+        }
+        sample_index += entry.sample_count as u64;
+        is_last_sample_zero_duration = entry.sample_delta == 0;
+    }
+    if let Some(first) = first_zero_duration_sample {
+        violation(format!(
+            "{zero_duration_samples} zero-duration samples (first sample index: {first})"
+        ));
+
+        // 最後のサンプル以外で尺が 0 のものがあれば、その次のサンプルのデコード時刻は増加していない
+        let non_increasing_samples = zero_duration_samples - is_last_sample_zero_duration as u64;
+        if non_increasing_samples > 0 {
+            violation(format!(
+                "{non_increasing_samples} samples have non-increasing decoding timestamps (first sample index: {})",
+                first + 1
+            ));
+        }
+    }
+
+    let sample_table = match SampleTableAccessor::new(stbl_box) {
+        Ok(sample_table) => sample_table,
+        Err(e) => {
+            violation(format!("Invalid sample table: {e}"));
+            return report;
+        }
+    };
+    report.chunk_count = sample_table.chunk_count();
+
+    // Track::new() では最後のサンプルのみを確認しているが、ここでは全サンプルを確認する
+    let out_of_range_samples = sample_table
+        .samples()
+        .filter(|s| s.data_offset() + s.data_size() as u64 > mp4_size as u64)
+        .map(|s| s.index().get() - 1)
+        .collect::<Vec<_>>();
+    if let Some(&first) = out_of_range_samples.first() {
+        violation(format!(
+            "{} samples' data is out of range of the file (first sample index: {first})",
+            out_of_range_samples.len()
+        ));
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use shiguredo_mp4::{
+        boxes::{MoovBox, StblBox, SttsEntry},
+        Encode,
+    };
+
+    use super::*;
+    use crate::{
+        mp4::{TrackKind, VideoDecoderConfig},
+        muxer::Mp4Muxer,
+    };
+
+    // 3 フレームごとにキーフレームを含む、映像トラックのみの MP4 を生成する（moov は末尾に置かれる）
+    fn make_mp4() -> Vec<u8> {
+        let mut muxer = Mp4Muxer::new(None);
+        muxer
+            .set_video_config(&VideoDecoderConfig {
+                codec: "vp8".to_owned(),
+                description: Vec::new(),
+                coded_width: 320,
+                coded_height: 240,
+            })
+            .expect("set_video_config");
+        for i in 0..6 {
+            muxer
+                .append_chunk(
+                    TrackKind::Video,
+                    Duration::from_millis(i * 100),
+                    Duration::ZERO,
+                    Some(Duration::from_millis(100)),
+                    i % 3 == 0,
+                    &[i as u8; 10],
+                )
+                .expect("append_chunk");
+        }
+        muxer.finish().expect("finish");
+        muxer.take_output()
+    }
+
+    // moov ボックスを書き換えた MP4 の調査結果の violations を返す
+    fn violations_after(modify: impl FnOnce(&mut MoovBox)) -> Vec<String> {
+        let mp4_bytes = make_mp4();
+        let (mut moov_box, moov_range) = Mp4::load_moov_box(&mp4_bytes).expect("moov");
+        assert_eq!(moov_range.end, mp4_bytes.len());
+        modify(&mut moov_box);
+
+        let mut modified = mp4_bytes[..moov_range.start].to_vec();
+        moov_box.encode(&mut modified).expect("encode");
+        inspect_mp4(&modified).violations
+    }
+
+    fn stbl(moov_box: &mut MoovBox) -> &mut StblBox {
+        &mut moov_box.trak_boxes[0].mdia_box.minf_box.stbl_box
+    }
+
+    #[test]
+    fn valid_file_has_no_violations() {
+        let report = inspect_mp4(&make_mp4());
+        assert_eq!(report.violations, Vec::<String>::new());
+
+        let [track] = &report.tracks[..] else {
+            panic!("one track expected");
+        };
+        assert_eq!(track.sample_count, 6);
+        assert_eq!(track.sync_sample_count, Some(2));
+        assert_eq!(track.total_data_size, 60);
+    }
+
+    #[test]
+    fn zero_duration_samples_are_reported() {
+        let violations = violations_after(|moov_box| {
+            stbl(moov_box).stts_box.entries = vec![
+                SttsEntry {
+                    sample_count: 2,
+                    sample_delta: 100,
+                },
+                SttsEntry {
+                    sample_count: 2,
+                    sample_delta: 0,
+                },
+                SttsEntry {
+                    sample_count: 2,
+                    sample_delta: 100,
+                },
+            ];
+        });
+        assert_eq!(
+            violations,
+            [
+                "Track 1: 2 zero-duration samples (first sample index: 2)",
+                "Track 1: 2 samples have non-increasing decoding timestamps (first sample index: 3)",
+            ]
+        );
+
+        // 最後のサンプルの尺が 0 なのは、デコード時刻の逆転にはならない
+        let violations = violations_after(|moov_box| {
+            stbl(moov_box).stts_box.entries = vec![
+                SttsEntry {
+                    sample_count: 5,
+                    sample_delta: 100,
+                },
+                SttsEntry {
+                    sample_count: 1,
+                    sample_delta: 0,
+                },
+            ];
+        });
+        assert_eq!(
+            violations,
+            ["Track 1: 1 zero-duration samples (first sample index: 5)"]
+        );
+    }
+
+    #[test]
+    fn missing_stss_in_video_track_is_reported() {
+        let violations = violations_after(|moov_box| stbl(moov_box).stss_box = None);
+        assert_eq!(
+            violations,
+            ["Track 1: Missing 'stss' box in video track (all samples are treated as sync samples)"]
+        );
+    }
+
+    #[test]
+    fn mismatched_sample_counts_are_reported() {
+        let violations = violations_after(|moov_box| {
+            stbl(moov_box).stts_box.entries[0].sample_count += 1;
+        });
+        assert_eq!(
+            violations,
+            ["Track 1: Mismatched sample counts: stts=7, stsz=6"]
+        );
+    }
+
+    #[test]
+    fn out_of_range_sample_data_is_reported() {
+        let violations = violations_after(|moov_box| {
+            let Either::A(stco_box) = &mut stbl(moov_box).stco_or_co64_box else {
+                panic!("stco box expected");
+            };
+            // 全サンプルが一つのチャンクに入っているので、全てのサンプルがファイルの外を指すことになる
+            stco_box.chunk_offsets[0] += 1000;
+        });
+        assert_eq!(
+            violations,
+            ["Track 1: 6 samples' data is out of range of the file (first sample index: 0)"]
+        );
+    }
+
+    // QuickTime のサウンドデスクリプションのバージョンに応じて、子ボックスの位置が変わる
+    #[test]
+    fn sound_description_children_follow_version_specific_fields() {
+        for (version, extra_size) in [
+            (0, 0),
+            (1, SOUND_DESCRIPTION_V1_EXTRA_SIZE),
+            (2, SOUND_DESCRIPTION_V2_EXTRA_SIZE),
+        ] {
+            let mut payload = vec![0; AUDIO_SAMPLE_ENTRY_FIELDS_SIZE + extra_size];
+            payload[8..10].copy_from_slice(&(version as u16).to_be_bytes());
+            payload.extend_from_slice(&[0, 0, 0, 12, b'e', b's', b'd', b's', 0, 0, 0, 0]);
+            let mut mp4a = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+            mp4a.extend_from_slice(b"mp4a");
+            mp4a.extend_from_slice(&payload);
+
+            let mut violations = Vec::new();
+            let boxes = inspect_boxes(&mp4a, 0, &mut violations);
+            assert_eq!(violations, Vec::<String>::new(), "version={version}");
+            let [mp4a_box] = &boxes[..] else {
+                panic!("one box expected");
+            };
+            let [esds_box] = &mp4a_box.children[..] else {
+                panic!("one child expected: version={version}");
+            };
+            assert_eq!(esds_box.box_type, "esds");
+            assert_eq!(esds_box.offset, 8 + payload.len() as u64 - 12);
+        }
+    }
+}
@@ -1,4 +1,23 @@
+pub mod adts;
+pub mod annexb;
+pub mod bits;
+pub mod cmaf;
+pub mod container;
 pub mod engine;
+pub mod es;
+pub mod fmp4;
+pub mod inspect;
+pub mod manifest;
 pub mod mp4;
+pub mod muxer;
+pub mod pcm;
 pub mod player;
+pub mod recover;
+pub mod remux;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod simulator;
+pub mod stats;
+pub mod subtitle;
+pub mod ts;
 pub mod wasm;
+pub mod webm;
@@ -0,0 +1,501 @@
+use std::fmt::Write;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{cmaf::CmafTrackInfo, mp4::TrackKind};
+
+// マニフェスト内のセグメントの URI の指定に使われるテンプレート文字列の識別子
+//
+// DASH の SegmentTemplate と同じ形式で、HLS の場合にはここで実際の値に置換される
+const REPRESENTATION_ID_IDENTIFIER: &str = "$RepresentationID$";
+const NUMBER_IDENTIFIER: &str = "$Number$";
+
+// HLS / DASH のマニフェスト生成時のオプション
+//
+// 各テンプレート内の $RepresentationID$ はトラックのインデックス、
+// $Number$ はメディアセグメントのインデックス（0 始まり）に置換される
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ManifestOptions {
+    pub init_segment_template: String,
+    pub media_segment_template: String,
+
+    // HLS のメディアプレイリストの URI（DASH では使われない）
+    pub media_playlist_template: String,
+}
+
+impl Default for ManifestOptions {
+    fn default() -> Self {
+        Self {
+            init_segment_template: "init-$RepresentationID$.mp4".to_owned(),
+            media_segment_template: "segment-$RepresentationID$-$Number$.m4s".to_owned(),
+            media_playlist_template: "playlist-$RepresentationID$.m3u8".to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HlsPlaylists {
+    pub master_playlist: String,
+
+    // トラック毎のメディアプレイリスト（トラックと同じ順番）
+    pub media_playlists: Vec<String>,
+}
+
+// CMAF 形式のトラック群を配信するための HLS のマスタープレイリストとメディアプレイリストを生成する
+//
+// 音声トラックは、映像トラックとは別の代替音声（EXT-X-MEDIA）として扱われる
+pub fn make_hls_playlists(tracks: &[CmafTrackInfo], options: &ManifestOptions) -> HlsPlaylists {
+    let media_playlists = tracks
+        .iter()
+        .enumerate()
+        .map(|(i, track)| make_hls_media_playlist(i, track, options))
+        .collect();
+
+    let mut master = String::new();
+    let _ = writeln!(master, "#EXTM3U");
+    let _ = writeln!(master, "#EXT-X-VERSION:7");
+    let _ = writeln!(master, "#EXT-X-INDEPENDENT-SEGMENTS");
+
+    let audio = tracks
+        .iter()
+        .enumerate()
+        .find(|(_, t)| t.kind == TrackKind::Audio);
+    let video = tracks
+        .iter()
+        .enumerate()
+        .find(|(_, t)| t.kind == TrackKind::Video);
+    if let (Some((audio_index, audio_track)), Some(_)) = (audio, video) {
+        let channels = audio_track
+            .audio_config
+            .as_ref()
+            .map(|c| format!(",CHANNELS=\"{}\"", c.number_of_channels))
+            .unwrap_or_default();
+        let _ = writeln!(
+            master,
+            "#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"audio\",NAME=\"audio\",DEFAULT=YES,AUTOSELECT=YES{channels},URI=\"{}\"",
+            apply_template(&options.media_playlist_template, audio_index, None)
+        );
+    }
+
+    // 再生時に必要となる帯域は、映像と音声の合計となる
+    let stream_tracks = [video, audio].into_iter().flatten().collect::<Vec<_>>();
+    let peak_bitrate = stream_tracks
+        .iter()
+        .map(|(_, t)| peak_bitrate(t))
+        .sum::<u64>();
+    let average_bitrate = stream_tracks
+        .iter()
+        .map(|(_, t)| average_bitrate(t))
+        .sum::<u64>();
+    let codecs = stream_tracks
+        .iter()
+        .filter_map(|(_, t)| codec(t))
+        .collect::<Vec<_>>()
+        .join(",");
+    let mut attributes =
+        format!("BANDWIDTH={peak_bitrate},AVERAGE-BANDWIDTH={average_bitrate},CODECS=\"{codecs}\"");
+    if let Some(config) = video.and_then(|(_, t)| t.video_config.as_ref()) {
+        if config.coded_width > 0 && config.coded_height > 0 {
+            let _ = write!(
+                attributes,
+                ",RESOLUTION={}x{}",
+                config.coded_width, config.coded_height
+            );
+        }
+    }
+    let stream_index = match (video, audio) {
+        (Some((video_index, _)), Some(_)) => {
+            attributes.push_str(",AUDIO=\"audio\"");
+            video_index
+        }
+        (Some((i, _)), None) | (None, Some((i, _))) => i,
+        (None, None) => 0,
+    };
+    let _ = writeln!(master, "#EXT-X-STREAM-INF:{attributes}");
+    let _ = writeln!(
+        master,
+        "{}",
+        apply_template(&options.media_playlist_template, stream_index, None)
+    );
+
+    HlsPlaylists {
+        master_playlist: master,
+        media_playlists,
+    }
+}
+
+fn make_hls_media_playlist(
+    track_index: usize,
+    track: &CmafTrackInfo,
+    options: &ManifestOptions,
+) -> String {
+    // EXTINF の値を四捨五入した値が、EXT-X-TARGETDURATION 以下である必要がある
+    let target_duration = track
+        .segments
+        .iter()
+        .map(|s| s.duration.as_secs_f64().round() as u64)
+        .max()
+        .unwrap_or_default()
+        .max(1);
+
+    let mut playlist = String::new();
+    let _ = writeln!(playlist, "#EXTM3U");
+    let _ = writeln!(playlist, "#EXT-X-VERSION:7");
+    let _ = writeln!(playlist, "#EXT-X-TARGETDURATION:{target_duration}");
+    let _ = writeln!(playlist, "#EXT-X-MEDIA-SEQUENCE:0");
+    let _ = writeln!(playlist, "#EXT-X-PLAYLIST-TYPE:VOD");
+    let _ = writeln!(playlist, "#EXT-X-INDEPENDENT-SEGMENTS");
+    let _ = writeln!(
+        playlist,
+        "#EXT-X-MAP:URI=\"{}\"",
+        apply_template(&options.init_segment_template, track_index, None)
+    );
+    for (segment_index, segment) in track.segments.iter().enumerate() {
+        let _ = writeln!(playlist, "#EXTINF:{:.6},", segment.duration.as_secs_f64());
+        let _ = writeln!(
+            playlist,
+            "{}",
+            apply_template(
+                &options.media_segment_template,
+                track_index,
+                Some(segment_index)
+            )
+        );
+    }
+    let _ = writeln!(playlist, "#EXT-X-ENDLIST");
+    playlist
+}
+
+// CMAF 形式のトラック群を配信するための DASH の MPD を生成する
+//
+// 各トラックは個別の AdaptationSet となり、セグメントの位置は SegmentTimeline で指定される
+pub fn make_dash_mpd(tracks: &[CmafTrackInfo], options: &ManifestOptions) -> String {
+    let presentation_duration = tracks
+        .iter()
+        .filter_map(|t| t.segments.last().map(|s| s.start + s.duration))
+        .max()
+        .unwrap_or_default();
+    let max_segment_duration = tracks
+        .iter()
+        .flat_map(|t| t.segments.iter().map(|s| s.duration))
+        .max()
+        .unwrap_or_default();
+
+    let mut mpd = String::new();
+    let _ = writeln!(mpd, r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    let _ = writeln!(
+        mpd,
+        r#"<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" profiles="urn:mpeg:dash:profile:isoff-live:2011" type="static" mediaPresentationDuration="PT{:.3}S" minBufferTime="PT{:.3}S">"#,
+        presentation_duration.as_secs_f64(),
+        max_segment_duration.as_secs_f64()
+    );
+    let _ = writeln!(mpd, r#"  <Period id="0" start="PT0S">"#);
+    for (track_index, track) in tracks.iter().enumerate() {
+        let content_type = match track.kind {
+            TrackKind::Audio => "audio",
+            TrackKind::Video => "video",
+        };
+        let _ = writeln!(
+            mpd,
+            r#"    <AdaptationSet id="{track_index}" contentType="{content_type}" mimeType="{content_type}/mp4" segmentAlignment="true" startWithSAP="1">"#
+        );
+
+        let mut attributes = format!(r#"id="{track_index}" bandwidth="{}""#, peak_bitrate(track));
+        if let Some(codec) = codec(track) {
+            let _ = write!(attributes, r#" codecs="{}""#, escape_xml(codec));
+        }
+        if let Some(config) = &track.video_config {
+            if config.coded_width > 0 && config.coded_height > 0 {
+                let _ = write!(
+                    attributes,
+                    r#" width="{}" height="{}""#,
+                    config.coded_width, config.coded_height
+                );
+            }
+        }
+        if let Some(config) = &track.audio_config {
+            let _ = write!(attributes, r#" audioSamplingRate="{}""#, config.sample_rate);
+        }
+        let _ = writeln!(mpd, r#"      <Representation {attributes}>"#);
+        if let Some(config) = &track.audio_config {
+            let _ = writeln!(
+                mpd,
+                r#"        <AudioChannelConfiguration schemeIdUri="urn:mpeg:dash:23003:3:audio_channel_configuration:2011" value="{}"/>"#,
+                config.number_of_channels
+            );
+        }
+        let _ = writeln!(
+            mpd,
+            r#"        <SegmentTemplate timescale="{}" initialization="{}" media="{}" startNumber="0">"#,
+            track.timescale,
+            escape_xml(&options.init_segment_template),
+            escape_xml(&options.media_segment_template)
+        );
+        let _ = writeln!(mpd, r#"          <SegmentTimeline>"#);
+        write_segment_timeline(&mut mpd, track);
+        let _ = writeln!(mpd, r#"          </SegmentTimeline>"#);
+        let _ = writeln!(mpd, r#"        </SegmentTemplate>"#);
+        let _ = writeln!(mpd, r#"      </Representation>"#);
+        let _ = writeln!(mpd, r#"    </AdaptationSet>"#);
+    }
+    let _ = writeln!(mpd, r#"  </Period>"#);
+    let _ = writeln!(mpd, r#"</MPD>"#);
+    mpd
+}
+
+// 同じ尺のセグメントが連続する場合には、一つの S 要素の r 属性（繰り返し回数）にまとめる
+fn write_segment_timeline(mpd: &mut String, track: &CmafTrackInfo) {
+    let mut next_start = None;
+    let mut segments = track.segments.iter().peekable();
+    while let Some(segment) = segments.next() {
+        let mut repeat = 0;
+        while segments
+            .next_if(|s| s.duration_ticks == segment.duration_ticks)
+            .is_some()
+        {
+            repeat += 1;
+        }
+
+        // 直前の S 要素から連続している場合には t 属性は省略可能
+        let mut attributes = String::new();
+        if next_start != Some(segment.start_ticks) {
+            let _ = write!(attributes, r#" t="{}""#, segment.start_ticks);
+        }
+        let _ = write!(attributes, r#" d="{}""#, segment.duration_ticks);
+        if repeat > 0 {
+            let _ = write!(attributes, r#" r="{repeat}""#);
+        }
+        let _ = writeln!(mpd, r#"            <S{attributes}/>"#);
+        next_start = Some(segment.start_ticks + segment.duration_ticks * (repeat + 1));
+    }
+}
+
+fn codec(track: &CmafTrackInfo) -> Option<&str> {
+    track
+        .audio_config
+        .as_ref()
+        .map(|c| c.codec.as_str())
+        .or_else(|| track.video_config.as_ref().map(|c| c.codec.as_str()))
+}
+
+// セグメント単位でのビットレート（bps）の最大値
+fn peak_bitrate(track: &CmafTrackInfo) -> u64 {
+    track
+        .segments
+        .iter()
+        .filter(|s| !s.duration.is_zero())
+        .map(|s| (s.data_size as f64 * 8.0 / s.duration.as_secs_f64()).ceil() as u64)
+        .max()
+        .unwrap_or_default()
+}
+
+// トラック全体での平均ビットレート（bps）
+fn average_bitrate(track: &CmafTrackInfo) -> u64 {
+    let data_size = track.segments.iter().map(|s| s.data_size).sum::<u64>();
+    let duration = track
+        .segments
+        .iter()
+        .map(|s| s.duration.as_secs_f64())
+        .sum::<f64>();
+    if duration == 0.0 {
+        return 0;
+    }
+    (data_size as f64 * 8.0 / duration).ceil() as u64
+}
+
+fn apply_template(template: &str, track_index: usize, segment_index: Option<usize>) -> String {
+    let uri = template.replace(REPRESENTATION_ID_IDENTIFIER, &track_index.to_string());
+    match segment_index {
+        Some(i) => uri.replace(NUMBER_IDENTIFIER, &i.to_string()),
+        None => uri,
+    }
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        cmaf::CmafSegmentInfo,
+        mp4::{AudioDecoderConfig, VideoDecoderConfig},
+    };
+
+    fn segments(timescale: u32, durations: &[(u64, u64)]) -> Vec<CmafSegmentInfo> {
+        let mut start_ticks = 0;
+        durations
+            .iter()
+            .map(|&(duration_ticks, data_size)| {
+                let segment = CmafSegmentInfo {
+                    start: Duration::from_secs(start_ticks) / timescale,
+                    duration: Duration::from_secs(duration_ticks) / timescale,
+                    data_size,
+                    start_ticks,
+                    duration_ticks,
+                };
+                start_ticks += duration_ticks;
+                segment
+            })
+            .collect()
+    }
+
+    fn tracks() -> Vec<CmafTrackInfo> {
+        vec![
+            CmafTrackInfo {
+                kind: TrackKind::Video,
+                timescale: 90_000,
+                audio_config: None,
+                video_config: Some(VideoDecoderConfig {
+                    codec: "avc1.64001f".to_owned(),
+                    description: Vec::new(),
+                    coded_width: 1280,
+                    coded_height: 720,
+                }),
+                segments: segments(
+                    90_000,
+                    &[(180_000, 500_000), (180_000, 250_000), (135_000, 150_000)],
+                ),
+            },
+            CmafTrackInfo {
+                kind: TrackKind::Audio,
+                timescale: 48_000,
+                audio_config: Some(AudioDecoderConfig {
+                    codec: "mp4a.40.2".to_owned(),
+                    sample_rate: 48_000,
+                    number_of_channels: 2,
+                    description: Vec::new(),
+                }),
+                video_config: None,
+                segments: segments(
+                    48_000,
+                    &[(96_000, 32_000), (96_000, 32_000), (72_000, 24_000)],
+                ),
+            },
+        ]
+    }
+
+    #[test]
+    fn hls_playlists() {
+        let playlists = make_hls_playlists(&tracks(), &ManifestOptions::default());
+        assert_eq!(
+            playlists.master_playlist,
+            r#"#EXTM3U
+#EXT-X-VERSION:7
+#EXT-X-INDEPENDENT-SEGMENTS
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID="audio",NAME="audio",DEFAULT=YES,AUTOSELECT=YES,CHANNELS="2",URI="playlist-1.m3u8"
+#EXT-X-STREAM-INF:BANDWIDTH=2128000,AVERAGE-BANDWIDTH=1437091,CODECS="avc1.64001f,mp4a.40.2",RESOLUTION=1280x720,AUDIO="audio"
+playlist-0.m3u8
+"#
+        );
+        assert_eq!(
+            playlists.media_playlists,
+            [
+                r#"#EXTM3U
+#EXT-X-VERSION:7
+#EXT-X-TARGETDURATION:2
+#EXT-X-MEDIA-SEQUENCE:0
+#EXT-X-PLAYLIST-TYPE:VOD
+#EXT-X-INDEPENDENT-SEGMENTS
+#EXT-X-MAP:URI="init-0.mp4"
+#EXTINF:2.000000,
+segment-0-0.m4s
+#EXTINF:2.000000,
+segment-0-1.m4s
+#EXTINF:1.500000,
+segment-0-2.m4s
+#EXT-X-ENDLIST
+"#,
+                r#"#EXTM3U
+#EXT-X-VERSION:7
+#EXT-X-TARGETDURATION:2
+#EXT-X-MEDIA-SEQUENCE:0
+#EXT-X-PLAYLIST-TYPE:VOD
+#EXT-X-INDEPENDENT-SEGMENTS
+#EXT-X-MAP:URI="init-1.mp4"
+#EXTINF:2.000000,
+segment-1-0.m4s
+#EXTINF:2.000000,
+segment-1-1.m4s
+#EXTINF:1.500000,
+segment-1-2.m4s
+#EXT-X-ENDLIST
+"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn hls_playlists_without_video() {
+        let options = ManifestOptions {
+            init_segment_template: "audio/init.mp4".to_owned(),
+            media_segment_template: "audio/$Number$.m4s".to_owned(),
+            media_playlist_template: "audio.m3u8".to_owned(),
+        };
+        let playlists = make_hls_playlists(&tracks()[1..], &options);
+        assert_eq!(
+            playlists.master_playlist,
+            r#"#EXTM3U
+#EXT-X-VERSION:7
+#EXT-X-INDEPENDENT-SEGMENTS
+#EXT-X-STREAM-INF:BANDWIDTH=128000,AVERAGE-BANDWIDTH=128000,CODECS="mp4a.40.2"
+audio.m3u8
+"#
+        );
+        assert!(playlists.media_playlists[0].contains("#EXT-X-MAP:URI=\"audio/init.mp4\"\n"));
+        assert!(playlists.media_playlists[0].contains("\naudio/2.m4s\n"));
+    }
+
+    #[test]
+    fn dash_mpd() {
+        let mpd = make_dash_mpd(&tracks(), &ManifestOptions::default());
+        assert_eq!(
+            mpd,
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" profiles="urn:mpeg:dash:profile:isoff-live:2011" type="static" mediaPresentationDuration="PT5.500S" minBufferTime="PT2.000S">
+  <Period id="0" start="PT0S">
+    <AdaptationSet id="0" contentType="video" mimeType="video/mp4" segmentAlignment="true" startWithSAP="1">
+      <Representation id="0" bandwidth="2000000" codecs="avc1.64001f" width="1280" height="720">
+        <SegmentTemplate timescale="90000" initialization="init-$RepresentationID$.mp4" media="segment-$RepresentationID$-$Number$.m4s" startNumber="0">
+          <SegmentTimeline>
+            <S t="0" d="180000" r="1"/>
+            <S d="135000"/>
+          </SegmentTimeline>
+        </SegmentTemplate>
+      </Representation>
+    </AdaptationSet>
+    <AdaptationSet id="1" contentType="audio" mimeType="audio/mp4" segmentAlignment="true" startWithSAP="1">
+      <Representation id="1" bandwidth="128000" codecs="mp4a.40.2" audioSamplingRate="48000">
+        <AudioChannelConfiguration schemeIdUri="urn:mpeg:dash:23003:3:audio_channel_configuration:2011" value="2"/>
+        <SegmentTemplate timescale="48000" initialization="init-$RepresentationID$.mp4" media="segment-$RepresentationID$-$Number$.m4s" startNumber="0">
+          <SegmentTimeline>
+            <S t="0" d="96000" r="1"/>
+            <S d="72000"/>
+          </SegmentTimeline>
+        </SegmentTemplate>
+      </Representation>
+    </AdaptationSet>
+  </Period>
+</MPD>
+"#
+        );
+    }
+
+    #[test]
+    fn dash_mpd_escapes_templates() {
+        let options = ManifestOptions {
+            init_segment_template: "init.mp4?a=1&b=\"2\"".to_owned(),
+            ..Default::default()
+        };
+        let mpd = make_dash_mpd(&tracks()[..1], &options);
+        assert!(mpd.contains(r#"initialization="init.mp4?a=1&amp;b=&quot;2&quot;""#));
+    }
+}
@@ -1,22 +1,519 @@
-use std::{collections::HashSet, num::NonZeroU32, rc::Rc};
+use std::{collections::HashSet, num::NonZeroU32, ops::Range, rc::Rc, time::Duration};
 
 use orfail::{Failure, OrFail};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use shiguredo_mp4::{
     aux::SampleTableAccessor,
     boxes::{
-        Av01Box, Avc1Box, FtypBox, HdlrBox, Hev1Box, IgnoredBox, MoovBox, Mp4aBox, OpusBox,
-        SampleEntry, StblBox, TrakBox, Vp08Box, Vp09Box,
+        AudioSampleEntryFields, Av01Box, Av1cBox, Avc1Box, AvccBox, Co64Box, DopsBox, EdtsBox,
+        EsdsBox, HdlrBox, Hev1Box, HvccBox, IgnoredBox, MdiaBox, MinfBox, MoovBox, Mp4aBox,
+        OpusBox, SampleEntry, StblBox, StcoBox, StsdBox, StszBox, TkhdBox, TrakBox, UnknownBox,
+        VisualSampleEntryFields, Vp08Box, Vp09Box, VpccBox,
     },
-    BaseBox, Decode, Either, Encode,
+    descriptors::{DecoderConfigDescriptor, DecoderSpecificInfo, EsDescriptor, SlConfigDescriptor},
+    BaseBox, BoxHeader, BoxSize, BoxType, Decode, Encode, FixedPointNumber, Uint,
 };
 
-#[derive(Debug, Serialize)]
+use crate::{
+    muxer::{MuxEdit, MuxTrack, MOVIE_TIMESCALE},
+    pcm::PcmFormat,
+    subtitle::{SubtitleTrack, SubtitleTrackInfo, SUBTITLE_HANDLER_TYPES},
+};
+
+// AAC の AudioSpecificConfig で使われるサンプリング周波数のテーブル
+pub(crate) const AAC_SAMPLING_FREQUENCIES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+// esds の objectTypeIndication のうち MP3 を示す値（MPEG-1 Audio と MPEG-2 Audio (ISO/IEC 13818-3)）
+const OBJECT_TYPE_INDICATION_MPEG1_AUDIO: u8 = 0x6B;
+const OBJECT_TYPE_INDICATION_MPEG2_AUDIO: u8 = 0x69;
+
+// shiguredo_mp4 が個別に対応していない音声のサンプルエントリー
+//
+// これらは SampleEntry::Unknown としてデコードされるので、ペイロードを直接解釈する
+const MP3_BOX_TYPE: BoxType = BoxType::Normal(*b".mp3");
+const FLAC_BOX_TYPE: BoxType = BoxType::Normal(*b"fLaC");
+const DFLA_BOX_TYPE: BoxType = BoxType::Normal(*b"dfLa");
+
+// QuickTime (iPhone など) で使われる HEVC のサンプルエントリー
+//
+// パラメーターセットを hvcC にのみ格納するという違いを除けば hev1 と同じ構造なので、hev1 としてデコードする
+const HVC1_BOX_TYPE: BoxType = BoxType::Normal(*b"hvc1");
+
+// 音声のサンプルエントリーで子ボックスの前に置かれる AudioSampleEntryFields のバイト数
+pub(crate) const AUDIO_SAMPLE_ENTRY_FIELDS_SIZE: usize = 28;
+
+// FLAC の STREAMINFO メタデータブロックのペイロードサイズ
+const FLAC_STREAMINFO_SIZE: usize = 34;
+
+// デコーダー設定の description のようにヘッダー部分が取り除かれたボックスのペイロードをデコードする
+pub(crate) fn decode_box_payload<B: Decode>(
+    box_type: BoxType,
+    payload: &[u8],
+) -> orfail::Result<B> {
+    let header = BoxHeader {
+        box_type,
+        box_size: BoxSize::with_payload_size(box_type, payload.len() as u64),
+    };
+    let mut bytes = Vec::with_capacity(header.external_size() + payload.len());
+    header.encode(&mut bytes).or_fail()?;
+    bytes.extend_from_slice(payload);
+    B::decode(&mut &bytes[..]).or_fail()
+}
+
+// hvc1 のサンプルエントリーを、同じ構造の hev1 としてデコードする（hvc1 以外の場合には None を返す）
+pub(crate) fn decode_hvc1_box(b: &UnknownBox) -> Option<Hev1Box> {
+    if b.box_type != HVC1_BOX_TYPE {
+        return None;
+    }
+    decode_box_payload(Hev1Box::TYPE, &b.payload).ok()
+}
+
+// shiguredo_mp4 は hvc1 のエンコードに未対応なので、hev1 としてエンコードしてからボックス種別を差し替える
+fn encode_hvc1_box(b: &Hev1Box) -> orfail::Result<UnknownBox> {
+    let mut bytes = Vec::new();
+    b.encode(&mut bytes).or_fail()?;
+    let (_, payload, _) = split_box(&bytes).or_fail()?;
+    Ok(UnknownBox {
+        box_type: HVC1_BOX_TYPE,
+        box_size: BoxSize::with_payload_size(HVC1_BOX_TYPE, payload.len() as u64),
+        payload: payload.to_vec(),
+    })
+}
+
+// ボックス群の中から、指定の種別の最初のボックスのペイロードを探す
+pub(crate) fn find_child_box_payload(mut bytes: &[u8], box_type: BoxType) -> Option<&[u8]> {
+    while !bytes.is_empty() {
+        let (header, payload, remaining) = split_box(bytes)?;
+        if header.box_type == box_type {
+            return Some(payload);
+        }
+        bytes = remaining;
+    }
+    None
+}
+
+// バイト列の先頭のボックスを、ヘッダー・ペイロード・残りのバイト列に分割する
+pub(crate) fn split_box(mut bytes: &[u8]) -> Option<(BoxHeader, &[u8], &[u8])> {
+    let header = BoxHeader::decode(&mut bytes).ok()?;
+    let payload_size = if header.box_size.get() == 0 {
+        // サイズが 0 の場合には、末尾までがペイロードとなる
+        bytes.len()
+    } else {
+        (header.box_size.get() as usize).checked_sub(header.external_size())?
+    };
+    let payload = bytes.get(..payload_size)?;
+    Some((header, payload, &bytes[payload_size..]))
+}
+
+// ペイロードにヘッダーを付与したボックスを書き込む
+fn write_box(box_type: BoxType, payload: &[u8], output: &mut Vec<u8>) -> Option<()> {
+    let header = BoxHeader {
+        box_type,
+        box_size: BoxSize::with_payload_size(box_type, payload.len() as u64),
+    };
+    header.encode(&mut *output).ok()?;
+    output.extend_from_slice(payload);
+    Some(())
+}
+
+pub(crate) fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(
+        bytes.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+pub(crate) fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        bytes.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+// DecoderSpecificInfo を含む esds ボックスまでの経路となるボックスと、ペイロードの先頭から最初の子ボックスまでのバイト数
+const ESDS_ANCESTOR_BOXES: &[([u8; 4], usize)] = &[
+    (*b"moov", 0),
+    (*b"trak", 0),
+    (*b"mdia", 0),
+    (*b"minf", 0),
+    (*b"stbl", 0),
+    (*b"stsd", 8),
+    (*b"mp4a", AUDIO_SAMPLE_ENTRY_FIELDS_SIZE),
+];
+
+// MPEG-4 記述子のタグ (ISO/IEC 14496-1 7.2.2.1)
+const ES_DESCRIPTOR_TAG: u8 = 0x03;
+const DECODER_CONFIG_DESCRIPTOR_TAG: u8 = 0x04;
+const DECODER_SPECIFIC_INFO_TAG: u8 = 0x05;
+
+// DecoderConfigDescriptor の DecoderSpecificInfo より前の固定長部分のバイト数
+const DECODER_CONFIG_DESCRIPTOR_FIXED_SIZE: usize = 13;
+
+// MP3 などでは esds の DecoderConfigDescriptor に DecoderSpecificInfo が含まれないことがあるが、
+// shiguredo_mp4 はそれを必須として扱うため、そのままだと moov ボックスのデコードに失敗してしまう。
+// そのため、空の DecoderSpecificInfo を補ったボックス群のバイト列を生成する。
+//
+// 解釈できない構造が含まれる場合には None が返される
+fn complement_decoder_specific_info(mut bytes: &[u8], output: &mut Vec<u8>) -> Option<()> {
+    while !bytes.is_empty() {
+        let (header, payload, remaining) = split_box(bytes)?;
+        bytes = remaining;
+
+        let mut new_payload = Vec::with_capacity(payload.len() + 2);
+        let ancestor = ESDS_ANCESTOR_BOXES
+            .iter()
+            .find(|(ty, _)| header.box_type == BoxType::Normal(*ty));
+        if header.box_type == EsdsBox::TYPE {
+            // 先頭の 4 バイトはフルボックスのバージョンとフラグ
+            new_payload.extend_from_slice(payload.get(..4)?);
+            let (tag, es) = read_descriptor(&mut payload.get(4..)?)?;
+            (tag == ES_DESCRIPTOR_TAG).then_some(())?;
+            write_descriptor(tag, &complement_es_descriptor(es)?, &mut new_payload);
+        } else if let Some(&(_, children_offset)) = ancestor {
+            new_payload.extend_from_slice(payload.get(..children_offset)?);
+            complement_decoder_specific_info(&payload[children_offset..], &mut new_payload)?;
+        } else {
+            new_payload.extend_from_slice(payload);
+        }
+        write_box(header.box_type, &new_payload, output)?;
+    }
+    Some(())
+}
+
+// ISO/IEC 14496-1 7.2.6.5 ES_Descriptor
+fn complement_es_descriptor(es: &[u8]) -> Option<Vec<u8>> {
+    let flags = *es.get(2)?;
+    let mut header_size = 3;
+    if flags & 0b1000_0000 != 0 {
+        // dependsOn_ES_ID
+        header_size += 2;
+    }
+    if flags & 0b0100_0000 != 0 {
+        // URLstring
+        header_size += 1 + *es.get(header_size)? as usize;
+    }
+    if flags & 0b0010_0000 != 0 {
+        // OCR_ES_Id
+        header_size += 2;
+    }
+    let mut output = es.get(..header_size)?.to_vec();
+    let mut reader = &es[header_size..];
+
+    while !reader.is_empty() {
+        let (tag, payload) = read_descriptor(&mut reader)?;
+        let has_specific_info =
+            payload.get(DECODER_CONFIG_DESCRIPTOR_FIXED_SIZE) == Some(&DECODER_SPECIFIC_INFO_TAG);
+        if tag == DECODER_CONFIG_DESCRIPTOR_TAG && !has_specific_info {
+            let mut new_payload = payload
+                .get(..DECODER_CONFIG_DESCRIPTOR_FIXED_SIZE)?
+                .to_vec();
+            write_descriptor(DECODER_SPECIFIC_INFO_TAG, &[], &mut new_payload);
+            new_payload.extend_from_slice(&payload[DECODER_CONFIG_DESCRIPTOR_FIXED_SIZE..]);
+            write_descriptor(tag, &new_payload, &mut output);
+        } else {
+            write_descriptor(tag, payload, &mut output);
+        }
+    }
+    Some(output)
+}
+
+// ISO/IEC 14496-1 8.3.3 Expandable classes
+fn read_descriptor<'a>(reader: &mut &'a [u8]) -> Option<(u8, &'a [u8])> {
+    let (&tag, mut bytes) = reader.split_first()?;
+    let mut size = 0;
+    loop {
+        let (&b, remaining) = bytes.split_first()?;
+        bytes = remaining;
+        size = (size << 7) | (b & 0b0111_1111) as usize;
+        if b & 0b1000_0000 == 0 {
+            break;
+        }
+    }
+    let payload = bytes.get(..size)?;
+    *reader = &bytes[size..];
+    Some((tag, payload))
+}
+
+fn write_descriptor(tag: u8, payload: &[u8], output: &mut Vec<u8>) {
+    output.push(tag);
+    let mut size_bytes = vec![(payload.len() & 0b0111_1111) as u8];
+    let mut size = payload.len() >> 7;
+    while size != 0 {
+        size_bytes.push((size & 0b0111_1111) as u8 | 0b1000_0000);
+        size >>= 7;
+    }
+    output.extend(size_bytes.iter().rev());
+    output.extend_from_slice(payload);
+}
+
+// QuickTime 形式 (MOV) の moov ボックスを正規化する際に、子ボックスを辿る対象となるボックスと、
+// ペイロードの先頭から最初の子ボックスまでのバイト数
+const QUICKTIME_ANCESTOR_BOXES: &[([u8; 4], usize)] = &[
+    (*b"moov", 0),
+    (*b"trak", 0),
+    (*b"mdia", 0),
+    (*b"minf", 0),
+    (*b"stbl", 0),
+    (*b"stsd", 8),
+];
+
+// 再生対象となるトラックのハンドラー種別
+const PLAYABLE_HANDLER_TYPES: [[u8; 4]; 2] =
+    [HdlrBox::HANDLER_TYPE_SOUN, HdlrBox::HANDLER_TYPE_VIDE];
+
+// 音声・映像・字幕以外のトラックを除外した理由
+const NON_MEDIA_TRACK_REASON: &str = "Not an audio, video or subtitle track";
+
+// 同じ種類のトラックが複数ある場合に、二つ目以降のトラックを除外した理由
+const MULTIPLE_AUDIO_TRACKS_REASON: &str = "Only the first audio track is played";
+const MULTIPLE_VIDEO_TRACKS_REASON: &str = "Only the first video track is played";
+
+// QuickTime のタイムコードトラックのハンドラー種別およびサンプルエントリー
+const TMCD_HANDLER_TYPE: [u8; 4] = *b"tmcd";
+const TMCD_BOX_TYPE: BoxType = BoxType::Normal(*b"tmcd");
+
+// tmcd サンプルエントリーの flags
+const TMCD_FLAG_DROP_FRAME: u32 = 1 << 0;
+const TMCD_FLAG_COUNTER: u32 = 1 << 3;
+
+// QuickTime のサウンドデスクリプション内で、esds などを格納するボックス
+const WAVE_BOX_TYPE: BoxType = BoxType::Normal(*b"wave");
+
+// QuickTime のサウンドデスクリプションのバージョン 1 / 2 で、バージョン 0 のフィールドの後に追加されるバイト数
+pub(crate) const SOUND_DESCRIPTION_V1_EXTRA_SIZE: usize = 16;
+pub(crate) const SOUND_DESCRIPTION_V2_EXTRA_SIZE: usize = 36;
+
+// QuickTime 形式 (MOV) の moov ボックスを、shiguredo_mp4 でデコードできる形に変換したバイト列を生成する
+//
+// - 音声・映像以外のトラック（字幕やタイムコードなど）を取り除いて、そのペイロードを removed_traks に追加する
+//   （これらは gmhd のような shiguredo_mp4 が対応していないメディアヘッダーを持ち、MinfBox のデコードに失敗するため）
+// - mp4a のバージョン 1 / 2 のサウンドデスクリプションをバージョン 0 の形式に変換する
+//
+// 解釈できない構造が含まれる場合には None が返される
+fn normalize_quicktime_boxes<'a>(
+    mut bytes: &'a [u8],
+    output: &mut Vec<u8>,
+    removed_traks: &mut Vec<&'a [u8]>,
+) -> Option<()> {
+    while !bytes.is_empty() {
+        let (header, payload, remaining) = split_box(bytes)?;
+        bytes = remaining;
+
+        if header.box_type == TrakBox::TYPE
+            && !trak_handler_type(payload).is_some_and(|ty| PLAYABLE_HANDLER_TYPES.contains(&ty))
+        {
+            removed_traks.push(payload);
+            continue;
+        }
+
+        let ancestor = QUICKTIME_ANCESTOR_BOXES
+            .iter()
+            .find(|(ty, _)| header.box_type == BoxType::Normal(*ty));
+        let new_payload = if header.box_type == Mp4aBox::TYPE {
+            normalize_mp4a_payload(payload)?
+        } else if let Some(&(_, children_offset)) = ancestor {
+            let mut new_payload = payload.get(..children_offset)?.to_vec();
+            normalize_quicktime_boxes(
+                &payload[children_offset..],
+                &mut new_payload,
+                removed_traks,
+            )?;
+            new_payload
+        } else {
+            payload.to_vec()
+        };
+        write_box(header.box_type, &new_payload, output)?;
+    }
+    Some(())
+}
+
+// trak ボックスのペイロードから、hdlr ボックスのハンドラー種別を取得する
+fn trak_handler_type(trak_payload: &[u8]) -> Option<[u8; 4]> {
+    let mdia_payload = find_child_box_payload(trak_payload, MdiaBox::TYPE)?;
+    let hdlr_payload = find_child_box_payload(mdia_payload, HdlrBox::TYPE)?;
+
+    // フルボックスのバージョンとフラグ (4 バイト) と pre_defined (4 バイト) の後にハンドラー種別が続く
+    hdlr_payload.get(8..12)?.try_into().ok()
+}
+
+// trak ボックスのペイロードから、tkhd ボックスのトラック ID を取得する
+fn trak_track_id(trak_payload: &[u8]) -> Option<u32> {
+    let tkhd_payload = find_child_box_payload(trak_payload, TkhdBox::TYPE)?;
+
+    // バージョン 1 では、トラック ID の前にある作成・更新時刻がそれぞれ 64 ビットになる
+    let offset = if tkhd_payload.first() == Some(&1) {
+        20
+    } else {
+        12
+    };
+    read_u32(tkhd_payload, offset)
+}
+
+// QuickTime File Format "Sound Sample Description (Version 1 / Version 2)"
+//
+// 追加フィールドを取り除いてバージョン 0 の形式にした上で、
+// wave ボックス内に置かれている esds ボックスを mp4a ボックスの直下に移動する
+fn normalize_mp4a_payload(payload: &[u8]) -> Option<Vec<u8>> {
+    let mut new_payload = payload.get(..AUDIO_SAMPLE_ENTRY_FIELDS_SIZE)?.to_vec();
+    let extra_size = match read_u16(payload, 8)? {
+        0 => 0,
+        1 => SOUND_DESCRIPTION_V1_EXTRA_SIZE,
+        2 => {
+            // バージョン 2 では、サンプリングレートとチャンネル数は追加フィールドの方に格納される
+            //
+            // 16.16 固定小数点数に収まらないサンプリングレートの場合には 0 を格納し、
+            // 実際の値は esds 内の AudioSpecificConfig などから取得する（to_sample_entry() と同様）
+            let sample_rate = f64::from_be_bytes(payload.get(32..40)?.try_into().ok()?);
+            (sample_rate.fract() == 0.0).then_some(())?;
+            let sample_rate = u16::try_from(sample_rate as u64).unwrap_or(0);
+            let number_of_channels = u16::try_from(read_u32(payload, 40)?).ok()?;
+            new_payload[16..18].copy_from_slice(&number_of_channels.to_be_bytes());
+            new_payload[24..28].copy_from_slice(&(u32::from(sample_rate) << 16).to_be_bytes());
+            SOUND_DESCRIPTION_V2_EXTRA_SIZE
+        }
+        _ => return None,
+    };
+
+    // バージョン (2 バイト) と、その後に続く revision level と vendor をゼロにする
+    new_payload[8..16].fill(0);
+
+    // compression ID と packet size もバージョン 0 では使われないのでゼロにする
+    new_payload[20..24].fill(0);
+
+    let mut children = payload.get(AUDIO_SAMPLE_ENTRY_FIELDS_SIZE + extra_size..)?;
+    while !children.is_empty() {
+        let (header, child_payload, remaining) = split_box(children)?;
+        children = remaining;
+
+        if header.box_type == WAVE_BOX_TYPE {
+            // wave には frma / mp4a / esds / 終端ボックスなどが含まれるが、デコードに必要なのは esds のみ
+            if let Some(esds_payload) = find_child_box_payload(child_payload, EsdsBox::TYPE) {
+                write_box(EsdsBox::TYPE, esds_payload, &mut new_payload)?;
+            }
+        } else {
+            write_box(header.box_type, child_payload, &mut new_payload)?;
+        }
+    }
+    Some(new_payload)
+}
+
+// QuickTime のタイムコードトラック (tmcd) から取得した、先頭フレームのタイムコード
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Timecode {
+    // "HH:MM:SS:FF" 形式の文字列（ドロップフレームの場合には "HH:MM:SS;FF" となる）
+    pub start: String,
+    pub frame_number: u32,
+    pub frame_rate: f64,
+    pub drop_frame: bool,
+}
+
+impl Timecode {
+    // moov ボックス内の最初のタイムコードトラックから、その先頭サンプルのタイムコードを取得する
+    //
+    // タイムコードトラックが存在しない場合や、解釈できない場合には None が返される
+    fn from_moov_bytes(moov_bytes: &[u8], mp4_bytes: &[u8]) -> Option<Self> {
+        let (_, mut bytes, _) = split_box(moov_bytes)?;
+        while !bytes.is_empty() {
+            let (header, payload, remaining) = split_box(bytes)?;
+            bytes = remaining;
+            if header.box_type == TrakBox::TYPE
+                && trak_handler_type(payload) == Some(TMCD_HANDLER_TYPE)
+            {
+                return Self::from_trak_payload(payload, mp4_bytes);
+            }
+        }
+        None
+    }
+
+    fn from_trak_payload(trak_payload: &[u8], mp4_bytes: &[u8]) -> Option<Self> {
+        let stbl_payload = [MdiaBox::TYPE, MinfBox::TYPE, StblBox::TYPE]
+            .into_iter()
+            .try_fold(trak_payload, find_child_box_payload)?;
+
+        // stsd のフルボックスのヘッダー (4 バイト) とエントリー数 (4 バイト) の後に、最初のサンプルエントリーが続く
+        let stsd_payload = find_child_box_payload(stbl_payload, StsdBox::TYPE)?;
+        let (header, entry, _) = split_box(stsd_payload.get(8..)?)?;
+        (header.box_type == TMCD_BOX_TYPE).then_some(())?;
+
+        // reserved (6 バイト) / data_reference_index (2 バイト) / reserved (4 バイト) の後に各フィールドが続く
+        let flags = read_u32(entry, 12)?;
+        let timescale = read_u32(entry, 16)?;
+        let frame_duration = read_u32(entry, 20)?;
+        let frames_per_second = *entry.get(24)?;
+        (flags & TMCD_FLAG_COUNTER == 0).then_some(())?;
+        (timescale > 0 && frame_duration > 0 && frames_per_second > 0).then_some(())?;
+
+        // 先頭サンプルのデータは、フレーム番号を表す 32 ビットの整数
+        let offset = if let Some(stco_payload) = find_child_box_payload(stbl_payload, StcoBox::TYPE)
+        {
+            u64::from(read_u32(stco_payload, 8)?)
+        } else {
+            let co64_payload = find_child_box_payload(stbl_payload, Co64Box::TYPE)?;
+            u64::from_be_bytes(co64_payload.get(8..16)?.try_into().ok()?)
+        };
+        let frame_number = read_u32(mp4_bytes, usize::try_from(offset).ok()?)?;
+
+        let drop_frame = flags & TMCD_FLAG_DROP_FRAME != 0;
+        Some(Self {
+            start: format_timecode(frame_number, frames_per_second, drop_frame),
+            frame_number,
+            frame_rate: timescale as f64 / frame_duration as f64,
+            drop_frame,
+        })
+    }
+}
+
+// フレーム番号を "HH:MM:SS:FF" 形式の文字列に変換する
+//
+// ドロップフレームの場合には、10 分毎を除く各分の先頭で（29.97 fps なら 2 つの）フレーム番号が飛ばされる
+fn format_timecode(frame_number: u32, frames_per_second: u8, drop_frame: bool) -> String {
+    let fps = u64::from(frames_per_second);
+    let mut frame = u64::from(frame_number);
+    let mut separator = ':';
+    if drop_frame {
+        separator = ';';
+        let dropped = fps / 15;
+        let frames_per_minute = fps * 60 - dropped;
+        let frames_per_ten_minutes = frames_per_minute * 10 + dropped;
+        let rest = frame % frames_per_ten_minutes;
+        frame += dropped * 9 * (frame / frames_per_ten_minutes);
+        if rest > dropped {
+            frame += dropped * ((rest - dropped) / frames_per_minute);
+        }
+    }
+    format!(
+        "{:02}:{:02}:{:02}{separator}{:02}",
+        frame / (fps * 3600) % 24,
+        frame / (fps * 60) % 60,
+        frame / fps % 60,
+        frame % fps
+    )
+}
+
+// "vp09.00.10.08" のようなコーデック文字列を "." で区切った数値列として解釈する
+fn parse_codec_params(codec: &str, prefix: &str) -> orfail::Result<Vec<u8>> {
+    codec
+        .strip_prefix(prefix)
+        .or_fail_with(|()| format!("Invalid codec string: {codec}"))?
+        .split('.')
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            s.parse::<u8>()
+                .or_fail_with(|e| format!("Invalid codec string: {codec} ({e})"))
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct VideoDecoderConfig {
     pub codec: String,
+    #[serde(default)]
     pub description: Vec<u8>,
+    #[serde(default)]
     pub coded_width: u16,
+    #[serde(default)]
     pub coded_height: u16,
 }
 
@@ -57,7 +554,7 @@ impl VideoDecoderConfig {
         Self {
             // ISO / IEC 14496-15 E.3
             codec: format!(
-                "hev1.{}.{:X}.{}.{}",
+                "hev1.{}.{:X}.{}{}.{}",
                 match b.hvcc_box.general_profile_space.get() {
                     1 => format!("A{}", b.hvcc_box.general_profile_idc.get()),
                     2 => format!("B{}", b.hvcc_box.general_profile_idc.get()),
@@ -67,15 +564,12 @@ impl VideoDecoderConfig {
                 b.hvcc_box
                     .general_profile_compatibility_flags
                     .reverse_bits(),
-                format!(
-                    "{}{}",
-                    if b.hvcc_box.general_tier_flag.get() == 0 {
-                        'L'
-                    } else {
-                        'H'
-                    },
-                    b.hvcc_box.general_level_idc
-                ),
+                if b.hvcc_box.general_tier_flag.get() == 0 {
+                    'L'
+                } else {
+                    'H'
+                },
+                b.hvcc_box.general_level_idc,
                 constraints
                     .into_iter()
                     .map(|b| format!("{:02X}", b))
@@ -88,6 +582,13 @@ impl VideoDecoderConfig {
         }
     }
 
+    pub fn from_hvc1_box(b: &UnknownBox) -> Option<Self> {
+        let hev1_box = decode_hvc1_box(b)?;
+        let mut config = Self::from_hev1_box(&hev1_box);
+        config.codec.replace_range(.."hev1".len(), "hvc1");
+        Some(config)
+    }
+
     pub fn from_vp08_box(b: &Vp08Box) -> Self {
         Self {
             codec: "vp8".to_owned(),
@@ -111,6 +612,118 @@ impl VideoDecoderConfig {
         }
     }
 
+    // WebCodecs のエンコーダーが出力したデコーダー設定から、MP4 に格納するためのサンプルエントリーを作る
+    pub fn to_sample_entry(&self) -> orfail::Result<SampleEntry> {
+        let visual = VisualSampleEntryFields {
+            data_reference_index: VisualSampleEntryFields::DEFAULT_DATA_REFERENCE_INDEX,
+            width: self.coded_width,
+            height: self.coded_height,
+            horizresolution: VisualSampleEntryFields::DEFAULT_HORIZRESOLUTION,
+            vertresolution: VisualSampleEntryFields::DEFAULT_VERTRESOLUTION,
+            frame_count: VisualSampleEntryFields::DEFAULT_FRAME_COUNT,
+            compressorname: VisualSampleEntryFields::NULL_COMPRESSORNAME,
+            depth: VisualSampleEntryFields::DEFAULT_DEPTH,
+        };
+        let codec = self.codec.as_str();
+        if codec.starts_with("avc1.") || codec.starts_with("avc3.") {
+            (!self.description.is_empty())
+                .or_fail_with(|()| "Missing avcC description for H.264".to_owned())?;
+            Ok(SampleEntry::Avc1(Avc1Box {
+                visual,
+                avcc_box: decode_box_payload(AvccBox::TYPE, &self.description).or_fail()?,
+                unknown_boxes: Vec::new(),
+            }))
+        } else if codec.starts_with("hev1.") || codec.starts_with("hvc1.") {
+            (!self.description.is_empty())
+                .or_fail_with(|()| "Missing hvcC description for H.265".to_owned())?;
+            let hev1_box = Hev1Box {
+                visual,
+                hvcc_box: decode_box_payload(HvccBox::TYPE, &self.description).or_fail()?,
+                unknown_boxes: Vec::new(),
+            };
+            if codec.starts_with("hvc1.") {
+                Ok(SampleEntry::Unknown(encode_hvc1_box(&hev1_box).or_fail()?))
+            } else {
+                Ok(SampleEntry::Hev1(hev1_box))
+            }
+        } else if codec == "vp8" {
+            Ok(SampleEntry::Vp08(Vp08Box {
+                visual,
+                vpcc_box: VpccBox {
+                    profile: 0,
+                    level: 0,
+                    bit_depth: Uint::new(8),
+                    chroma_subsampling: Uint::new(1), // 4:2:0 colocated with luma
+                    video_full_range_flag: Uint::new(0),
+                    colour_primaries: 1,
+                    transfer_characteristics: 1,
+                    matrix_coefficients: 1,
+                    codec_initialization_data: Vec::new(),
+                },
+                unknown_boxes: Vec::new(),
+            }))
+        } else if codec.starts_with("vp09.") {
+            // https://www.webmproject.org/vp9/mp4/#codecs-parameter-string
+            let params = parse_codec_params(codec, "vp09.").or_fail()?;
+            (params.len() >= 3).or_fail_with(|()| format!("Invalid codec string: {codec}"))?;
+            let param = |i: usize, default: u8| params.get(i).copied().unwrap_or(default);
+            Ok(SampleEntry::Vp09(Vp09Box {
+                visual,
+                vpcc_box: VpccBox {
+                    profile: params[0],
+                    level: params[1],
+                    bit_depth: Uint::new(params[2]),
+                    chroma_subsampling: Uint::new(param(3, 1)),
+                    colour_primaries: param(4, 1),
+                    transfer_characteristics: param(5, 1),
+                    matrix_coefficients: param(6, 1),
+                    video_full_range_flag: Uint::new(param(7, 0)),
+                    codec_initialization_data: Vec::new(),
+                },
+                unknown_boxes: Vec::new(),
+            }))
+        } else if let Some(params) = codec.strip_prefix("av01.") {
+            // https://aomediacodec.github.io/av1-isobmff/#codecsparam
+            let invalid = || format!("Invalid codec string: {codec}");
+            let mut params = params.split('.');
+            let profile = params
+                .next()
+                .and_then(|s| s.parse::<u8>().ok())
+                .or_fail_with(|()| invalid())?;
+            let level_and_tier = params.next().or_fail_with(|()| invalid())?;
+            (level_and_tier.len() == 3).or_fail_with(|()| invalid())?;
+            let level = level_and_tier[..2]
+                .parse::<u8>()
+                .ok()
+                .or_fail_with(|()| invalid())?;
+            let tier = u8::from(level_and_tier.ends_with('H'));
+            let bit_depth = params
+                .next()
+                .and_then(|s| s.parse::<u8>().ok())
+                .or_fail_with(|()| invalid())?;
+            Ok(SampleEntry::Av01(Av01Box {
+                visual,
+                av1c_box: Av1cBox {
+                    seq_profile: Uint::new(profile),
+                    seq_level_idx_0: Uint::new(level),
+                    seq_tier_0: Uint::new(tier),
+                    high_bitdepth: Uint::new(u8::from(bit_depth > 8)),
+                    twelve_bit: Uint::new(u8::from(bit_depth == 12)),
+                    monochrome: Uint::new(0),
+                    chroma_subsampling_x: Uint::new(1),
+                    chroma_subsampling_y: Uint::new(1),
+                    chroma_sample_position: Uint::new(0),
+                    initial_presentation_delay_minus_one: None,
+                    // description が存在する場合には av1C の中身が入っているので、その末尾の configOBUs を使う
+                    config_obus: self.description.get(4..).unwrap_or_default().to_vec(),
+                },
+                unknown_boxes: Vec::new(),
+            }))
+        } else {
+            Err(Failure::new(format!("Unsupported video codec: {codec}")))
+        }
+    }
+
     pub fn from_av01_box(b: &Av01Box) -> Self {
         Self {
             // https://aomediacodec.github.io/av1-isobmff/#codecsparam
@@ -141,46 +754,221 @@ impl VideoDecoderConfig {
     }
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct AudioDecoderConfig {
     pub codec: String,
-    pub sample_rate: u16,
+    pub sample_rate: u32,
     pub number_of_channels: u8,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub description: Vec<u8>,
 }
 
 impl AudioDecoderConfig {
     pub fn from_opus_box(b: &OpusBox) -> Self {
         Self {
             codec: "opus".to_owned(),
-            sample_rate: b.audio.samplerate.integer,
+            sample_rate: b.audio.samplerate.integer.into(),
             number_of_channels: b.audio.channelcount as u8,
+            description: Vec::new(),
         }
     }
 
     pub fn from_mp4a_box(b: &Mp4aBox) -> Self {
+        if matches!(
+            b.esds_box.es.dec_config_descr.object_type_indication,
+            OBJECT_TYPE_INDICATION_MPEG1_AUDIO | OBJECT_TYPE_INDICATION_MPEG2_AUDIO
+        ) {
+            return Self::from_mp3_audio_fields(&b.audio);
+        }
+
         let mut codec = format!(
             "mp4a.{:02X}",
             b.esds_box.es.dec_config_descr.object_type_indication
         );
+        let audio_specific_config = &b.esds_box.es.dec_config_descr.dec_specific_info.payload;
+        let mut sample_rate = u32::from(b.audio.samplerate.integer);
         if b.esds_box.es.dec_config_descr.object_type_indication == 0x40 {
-            if let Some(b) = b
-                .esds_box
-                .es
-                .dec_config_descr
-                .dec_specific_info
-                .payload
-                .get(0)
-            {
+            if let Some(b) = audio_specific_config.first() {
                 let audio_object_type = b >> 3;
                 codec.push_str(&format!(".{audio_object_type}"));
             }
+
+            // 16 bit に収まらないサンプリングレート (88.2 / 96 kHz) の場合には、
+            // サンプルエントリーの値は 0 となっているので AudioSpecificConfig から取得する
+            if sample_rate == 0 {
+                sample_rate = aac_sample_rate(audio_specific_config).unwrap_or_default();
+            }
         };
         Self {
             codec,
-            sample_rate: b.audio.samplerate.integer,
+            sample_rate,
             number_of_channels: b.audio.channelcount as u8,
+            description: Vec::new(),
+        }
+    }
+
+    // QuickTime 形式の '.mp3' サンプルエントリーの場合
+    pub fn from_mp3_box(b: &UnknownBox) -> Option<Self> {
+        let audio = AudioSampleEntryFields::decode(&b.payload[..]).ok()?;
+        Some(Self::from_mp3_audio_fields(&audio))
+    }
+
+    fn from_mp3_audio_fields(audio: &AudioSampleEntryFields) -> Self {
+        Self {
+            codec: "mp3".to_owned(),
+            sample_rate: audio.samplerate.integer.into(),
+            number_of_channels: audio.channelcount as u8,
+            description: Vec::new(),
+        }
+    }
+
+    // FLAC の場合には、ストリームマーカー ("fLaC") と dfLa ボックス内のメタデータブロック群を description とする
+    //
+    // 不正なボックスの場合には None が返される
+    pub fn from_flac_box(b: &UnknownBox) -> Option<Self> {
+        // 子ボックスの前にある AudioSampleEntryFields は、このコーデックでは参照しない
+        let children = b.payload.get(AUDIO_SAMPLE_ENTRY_FIELDS_SIZE..)?;
+        let dfla_payload = find_child_box_payload(children, DFLA_BOX_TYPE)?;
+
+        // 先頭の 4 バイトはフルボックスのバージョンとフラグ
+        let metadata_blocks = dfla_payload.get(4..)?;
+
+        // 最初のメタデータブロックは STREAMINFO でなければならない
+        let block_type = metadata_blocks.first()? & 0b0111_1111;
+        let streaminfo = metadata_blocks.get(4..4 + FLAC_STREAMINFO_SIZE)?;
+        if block_type != 0 {
+            return None;
         }
+
+        // サンプルエントリーのサンプルレートは 16 bit に収まらない場合があるので STREAMINFO の値を使う
+        let sample_rate = (u32::from(streaminfo[10]) << 12)
+            | (u32::from(streaminfo[11]) << 4)
+            | (u32::from(streaminfo[12]) >> 4);
+        let number_of_channels = ((streaminfo[12] >> 1) & 0b111) + 1;
+
+        let mut description = b"fLaC".to_vec();
+        description.extend_from_slice(metadata_blocks);
+        Some(Self {
+            codec: "flac".to_owned(),
+            sample_rate,
+            number_of_channels,
+            description,
+        })
+    }
+
+    // WebCodecs のエンコーダーが出力したデコーダー設定から、MP4 に格納するためのサンプルエントリーを作る
+    pub fn to_sample_entry(&self) -> orfail::Result<SampleEntry> {
+        let audio = AudioSampleEntryFields {
+            data_reference_index: 1,
+            channelcount: self.number_of_channels as u16,
+            samplesize: AudioSampleEntryFields::DEFAULT_SAMPLESIZE,
+            // 16 bit に収まらない場合は 0 とする（デコード時にはコーデック固有の設定の値が使われる）
+            samplerate: FixedPointNumber::new(u16::try_from(self.sample_rate).unwrap_or(0), 0),
+        };
+        let codec = self.codec.as_str();
+        if codec == "opus" {
+            // description が存在する場合には OpusHead (RFC 7845) が入っている
+            let opus_head = self
+                .description
+                .starts_with(b"OpusHead")
+                .then_some(&self.description[..]);
+            let pre_skip = opus_head
+                .and_then(|b| b.get(10..12))
+                .map(|b| u16::from_le_bytes([b[0], b[1]]))
+                .unwrap_or(0);
+            let input_sample_rate = opus_head
+                .and_then(|b| b.get(12..16))
+                .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .unwrap_or(self.sample_rate);
+            Ok(SampleEntry::Opus(OpusBox {
+                audio: AudioSampleEntryFields {
+                    samplerate: FixedPointNumber::new(48000, 0),
+                    ..audio
+                },
+                dops_box: DopsBox {
+                    output_channel_count: self.number_of_channels,
+                    pre_skip,
+                    input_sample_rate,
+                    output_gain: 0,
+                },
+                unknown_boxes: Vec::new(),
+            }))
+        } else if let Some(audio_object_type) = codec.strip_prefix("mp4a.40.") {
+            let audio_specific_config = if self.description.is_empty() {
+                let audio_object_type = audio_object_type
+                    .parse::<u8>()
+                    .or_fail_with(|e| format!("Invalid codec string: {codec} ({e})"))?;
+                self.make_audio_specific_config(audio_object_type)
+                    .or_fail()?
+            } else {
+                self.description.clone()
+            };
+            Ok(SampleEntry::Mp4a(Mp4aBox {
+                audio,
+                esds_box: EsdsBox {
+                    es: EsDescriptor {
+                        es_id: 0,
+                        stream_priority: Uint::new(0),
+                        depends_on_es_id: None,
+                        url_string: None,
+                        ocr_es_id: None,
+                        dec_config_descr: DecoderConfigDescriptor {
+                            object_type_indication: 0x40, // MPEG-4 Audio
+                            stream_type: Uint::new(0x05), // AudioStream
+                            up_stream: Uint::new(0),
+                            buffer_size_db: Uint::new(0),
+                            max_bitrate: 0,
+                            avg_bitrate: 0,
+                            dec_specific_info: DecoderSpecificInfo {
+                                payload: audio_specific_config,
+                            },
+                        },
+                        sl_config_descr: SlConfigDescriptor,
+                    },
+                },
+                unknown_boxes: Vec::new(),
+            }))
+        } else {
+            Err(Failure::new(format!("Unsupported audio codec: {codec}")))
+        }
+    }
+
+    // ISO/IEC 14496-3 1.6.2.1 AudioSpecificConfig
+    fn make_audio_specific_config(&self, audio_object_type: u8) -> orfail::Result<Vec<u8>> {
+        let frequency_index = AAC_SAMPLING_FREQUENCIES
+            .iter()
+            .position(|&f| f == self.sample_rate)
+            .or_fail_with(|()| format!("Unsupported AAC sample rate: {}", self.sample_rate))?
+            as u8;
+        let channel_configuration = self.number_of_channels;
+        (channel_configuration <= 7)
+            .or_fail_with(|()| format!("Unsupported AAC channel count: {channel_configuration}"))?;
+        Ok(vec![
+            (audio_object_type << 3) | (frequency_index >> 1),
+            (frequency_index << 7) | (channel_configuration << 3),
+        ])
+    }
+}
+
+// AudioSpecificConfig からサンプリングレートを取得する
+//
+// ISO/IEC 14496-3 1.6.2.1 AudioSpecificConfig
+fn aac_sample_rate(audio_specific_config: &[u8]) -> Option<u32> {
+    let mut buf = [0; 8];
+    let len = audio_specific_config.len().min(buf.len());
+    buf[..len].copy_from_slice(&audio_specific_config[..len]);
+    let bits = u64::from_be_bytes(buf);
+    let read = |offset: usize, size: usize| {
+        (offset + size <= len * 8).then(|| ((bits << offset) >> (64 - size)) as u32)
+    };
+
+    // audioObjectType が 31 の場合には、続く 6 ビットに拡張された値が格納されている
+    let offset = if read(0, 5)? == 31 { 11 } else { 5 };
+    match read(offset, 4)? {
+        // 24 ビットの値が直接格納されている
+        0xF => read(offset + 4, 24),
+        index => AAC_SAMPLING_FREQUENCIES.get(index as usize).copied(),
     }
 }
 
@@ -189,45 +977,165 @@ impl AudioDecoderConfig {
 pub struct Mp4Info {
     pub audio_configs: Vec<AudioDecoderConfig>,
     pub video_configs: Vec<VideoDecoderConfig>,
+
+    // QuickTime 形式 (MOV) のタイムコードトラックが存在する場合の、先頭フレームのタイムコード
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timecode: Option<Timecode>,
+
+    // 再生対象から除外されたトラック群
+    pub skipped_tracks: Vec<SkippedTrack>,
+
+    // 再生時にキューが TypeScript 側に渡される字幕トラック群
+    pub subtitle_tracks: Vec<SubtitleTrackInfo>,
+}
+
+// 音声・映像以外のトラックや、未対応コーデックのトラックのように、再生対象から除外されたトラックの情報
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SkippedTrack {
+    pub track_id: u32,
+
+    // hdlr ボックスのハンドラー種別 ("tmcd" や "meta" など）
+    pub handler_type: String,
+
+    pub reason: String,
+}
+
+impl SkippedTrack {
+    fn new(track_id: u32, handler_type: [u8; 4], reason: impl Into<String>) -> Self {
+        Self {
+            track_id,
+            handler_type: String::from_utf8_lossy(&handler_type).into_owned(),
+            reason: reason.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum DecoderConfig {
+    Audio(AudioDecoderConfig),
+    Video(VideoDecoderConfig),
+}
+
+impl DecoderConfig {
+    // 未対応のサンプルエントリーの場合には None が返される
+    pub fn from_sample_entry(sample_entry: &SampleEntry) -> Option<Self> {
+        let config = match sample_entry {
+            SampleEntry::Avc1(b) => Self::Video(VideoDecoderConfig::from_avc1_box(b)),
+            SampleEntry::Hev1(b) => Self::Video(VideoDecoderConfig::from_hev1_box(b)),
+            SampleEntry::Vp08(b) => Self::Video(VideoDecoderConfig::from_vp08_box(b)),
+            SampleEntry::Vp09(b) => Self::Video(VideoDecoderConfig::from_vp09_box(b)),
+            SampleEntry::Av01(b) => Self::Video(VideoDecoderConfig::from_av01_box(b)),
+            SampleEntry::Opus(b) => Self::Audio(AudioDecoderConfig::from_opus_box(b)),
+            SampleEntry::Mp4a(b) => Self::Audio(AudioDecoderConfig::from_mp4a_box(b)),
+            SampleEntry::Unknown(b) if b.box_type == HVC1_BOX_TYPE => {
+                Self::Video(VideoDecoderConfig::from_hvc1_box(b)?)
+            }
+            SampleEntry::Unknown(b) if b.box_type == MP3_BOX_TYPE => {
+                Self::Audio(AudioDecoderConfig::from_mp3_box(b)?)
+            }
+            SampleEntry::Unknown(b) if b.box_type == FLAC_BOX_TYPE => {
+                Self::Audio(AudioDecoderConfig::from_flac_box(b)?)
+            }
+            _ => {
+                // PCM の場合には WebCodecs のデコーダーは使われないが、Mp4Info には他の音声と同様に含める
+                let format = PcmFormat::from_sample_entry(sample_entry)?;
+                Self::Audio(format.to_decoder_config())
+            }
+        };
+        Some(config)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TrackKind {
+    Audio,
+    Video,
 }
 
 #[derive(Debug, Clone)]
 pub struct Track {
+    // MP4 以外のコンテナから変換されたトラックの場合には、そのコンテナでのトラック番号など
+    pub track_id: u32,
+    pub kind: TrackKind,
     pub sample_table: Rc<SampleTableAccessor<StblBox>>,
     pub timescale: NonZeroU32,
+
+    // 編集リスト（存在しない場合には空）
+    //
+    // 再生時には先頭の空白区間のみが考慮される（start_offset() を参照）
+    pub edits: Vec<MuxEdit>,
 }
 
 impl Track {
-    pub fn new(trak_box: TrakBox, mp4_size: usize) -> orfail::Result<Self> {
+    // トラックを再生対象から除外すべき場合に、その理由を返す
+    //
+    // ここでチェックされるのはトラック単位で無視できる問題のみで、
+    // サンプルデータの範囲外参照のようなファイル自体の破損は `Track::new()` でエラーとなる
+    pub fn unplayable_reason(trak_box: &TrakBox) -> Option<String> {
+        let kind = match trak_box.mdia_box.hdlr_box.handler_type {
+            HdlrBox::HANDLER_TYPE_SOUN => "audio ",
+            HdlrBox::HANDLER_TYPE_VIDE => "video ",
+            ty if SUBTITLE_HANDLER_TYPES.contains(&ty) => {
+                // moov ボックスの正規化ができなかった場合にのみここに来る
+                return Some("Subtitle tracks in this file cannot be parsed".to_owned());
+            }
+            _ => return Some(NON_MEDIA_TRACK_REASON.to_owned()),
+        };
+
+        let stbl_box = &trak_box.mdia_box.minf_box.stbl_box;
+        let sample_count = match &stbl_box.stsz_box {
+            StszBox::Fixed { sample_count, .. } => *sample_count as usize,
+            StszBox::Variable { entry_sizes } => entry_sizes.len(),
+        };
+        if sample_count == 0 {
+            return Some(format!("Empty {kind}track"));
+        }
+
+        // 途中のチャンクで未対応のサンプルエントリーに切り替わる場合も、トラック全体を除外する
+        stbl_box
+            .stsd_box
+            .entries
+            .iter()
+            .find(|entry| DecoderConfig::from_sample_entry(entry).is_none())
+            .map(|entry| format!("Unsupported {kind}codec: {}", entry.box_type()))
+    }
+
+    pub fn new(
+        trak_box: TrakBox,
+        movie_timescale: NonZeroU32,
+        mp4_size: usize,
+    ) -> orfail::Result<Self> {
         let kind = match trak_box.mdia_box.hdlr_box.handler_type {
             HdlrBox::HANDLER_TYPE_SOUN => "audio ",
             HdlrBox::HANDLER_TYPE_VIDE => "video ",
             _ => "",
         };
 
+        let track_id = trak_box.tkhd_box.track_id;
         let timescale = trak_box.mdia_box.mdhd_box.timescale;
+        let edts_box = trak_box.edts_box.clone();
         let sample_table =
             SampleTableAccessor::new(trak_box.mdia_box.minf_box.stbl_box).or_fail()?;
         (sample_table.sample_count() > 0).or_fail_with(|()| format!("Empty {kind}track"))?;
 
-        match sample_table.stbl_box().stsd_box.entries.first() {
-            Some(SampleEntry::Avc1(_)) => (),
-            Some(SampleEntry::Hev1(_)) => (),
-            Some(SampleEntry::Vp08(_)) => (),
-            Some(SampleEntry::Vp09(_)) => (),
-            Some(SampleEntry::Av01(_)) => (),
-            Some(SampleEntry::Opus(_)) => (),
-            Some(SampleEntry::Mp4a(_)) => (),
-            Some(b) => {
+        // MP4 デコード時にチェック済みなので、サンプルエントリーが存在しないことはない
+        let sample_entry = sample_table
+            .stbl_box()
+            .stsd_box
+            .entries
+            .first()
+            .expect("unreachable");
+        let track_kind = match DecoderConfig::from_sample_entry(sample_entry) {
+            Some(DecoderConfig::Audio(_)) => TrackKind::Audio,
+            Some(DecoderConfig::Video(_)) => TrackKind::Video,
+            None => {
                 return Err(Failure::new(format!(
                     "Unsupported {kind}codec: {}",
-                    b.box_type()
+                    sample_entry.box_type()
                 )));
             }
-            None => {
-                // MP4 デコード時にチェック済みなので、ここには来ない
-                unreachable!()
-            }
         };
 
         if let Some(last) = sample_table.samples().last() {
@@ -236,61 +1144,240 @@ impl Track {
                 .or_fail_with(|()| format!("Last {kind}sample's data is out of range"))?;
         }
 
+        let media_duration = sample_table
+            .samples()
+            .last()
+            .map_or(0, |s| s.timestamp() + s.duration() as u64);
+        let edits = edts_box.map_or_else(Vec::new, |b| {
+            edits_from_edts_box(&b, movie_timescale, timescale, media_duration)
+        });
+
         Ok(Self {
+            track_id,
+            kind: track_kind,
             sample_table: Rc::new(sample_table),
             timescale,
+            edits,
         })
     }
+
+    // 先頭の空白区間を含めた、ファイル先頭から最後のサンプルの終端までの尺
+    pub fn duration(&self) -> Duration {
+        let media_duration = self
+            .sample_table
+            .samples()
+            .last()
+            .map_or(0, |s| s.timestamp() + s.duration() as u64);
+        self.start_offset() + Duration::from_secs(media_duration) / self.timescale.get()
+    }
+
+    // 編集リストの先頭の空白区間の尺（ファイル先頭から、このトラックの最初のサンプルまでの時間）
+    pub fn start_offset(&self) -> Duration {
+        self.edits
+            .iter()
+            .map_while(|e| match e {
+                MuxEdit::Empty(d) => Some(*d),
+                MuxEdit::Media(_) => None,
+            })
+            .sum()
+    }
+
+    // MuxTrack のサンプルのデータ位置は、元のファイルの先頭からのオフセットとして扱われる
+    pub fn from_mux_track(
+        track: &MuxTrack,
+        track_id: u32,
+        file_size: usize,
+    ) -> orfail::Result<Self> {
+        Self::new(track.to_trak_box(track_id, 0), MOVIE_TIMESCALE, file_size).or_fail()
+    }
+}
+
+// elst ボックスの各エントリーを、トラックのタイムスケール単位の表示区間に変換する
+//
+// 尺が 0 のエントリーは、メディアの末尾までを表すものとして扱う（断片化 MP4 などで使われる）。
+// 再生速度（media_rate）は考慮しない。
+fn edits_from_edts_box(
+    edts_box: &EdtsBox,
+    movie_timescale: NonZeroU32,
+    timescale: NonZeroU32,
+    media_duration: u64,
+) -> Vec<MuxEdit> {
+    let Some(elst_box) = &edts_box.elst_box else {
+        return Vec::new();
+    };
+    elst_box
+        .entries
+        .iter()
+        .map(|entry| {
+            if entry.media_time < 0 {
+                return MuxEdit::Empty(
+                    Duration::from_secs(entry.edit_duration) / movie_timescale.get(),
+                );
+            }
+            let start = entry.media_time as u64;
+            let end = if entry.edit_duration == 0 {
+                media_duration.max(start)
+            } else {
+                let ticks = entry.edit_duration as u128 * timescale.get() as u128
+                    / movie_timescale.get() as u128;
+                start + ticks as u64
+            };
+            MuxEdit::Media(start..end)
+        })
+        .collect()
 }
 
 #[derive(Debug)]
 pub struct Mp4 {
     pub info: Mp4Info,
     pub tracks: Vec<Track>,
+
+    // 音声・映像トラックとは別に扱われる字幕トラック群
+    pub subtitle_tracks: Vec<SubtitleTrack>,
 }
 
 impl Mp4 {
     pub fn load(mp4_bytes: &[u8]) -> orfail::Result<Self> {
-        let moov_box = Self::load_moov_box(mp4_bytes).or_fail()?;
+        let moov_range = Self::find_moov_box(mp4_bytes).or_fail()?;
+        let moov_bytes = &mp4_bytes[moov_range];
+
+        // QuickTime 形式 (MOV) の場合に備えて、デコード前に moov ボックスを正規化しておく
+        // （正規化できない構造を含む場合には、元のバイト列をそのままデコードする）
+        let mut normalized = Vec::new();
+        let mut removed_traks = Vec::new();
+        let moov_box = if normalize_quicktime_boxes(moov_bytes, &mut normalized, &mut removed_traks)
+            .is_some()
+        {
+            Self::decode_moov_box(&normalized).or_fail()?
+        } else {
+            removed_traks.clear();
+            Self::decode_moov_box(moov_bytes).or_fail()?
+        };
+
+        // 音声・映像以外のトラックのうち、字幕トラックは別途読み込んで、それ以外は除外する
+        let mut subtitle_tracks = Vec::new();
+        let mut skipped_tracks = Vec::new();
+        for trak_payload in removed_traks {
+            let track_id = trak_track_id(trak_payload).unwrap_or_default();
+            let handler_type = trak_handler_type(trak_payload).unwrap_or_default();
+            if !SUBTITLE_HANDLER_TYPES.contains(&handler_type) {
+                skipped_tracks.push(SkippedTrack::new(
+                    track_id,
+                    handler_type,
+                    NON_MEDIA_TRACK_REASON,
+                ));
+                continue;
+            }
+            match SubtitleTrack::new(track_id, trak_payload, mp4_bytes) {
+                Ok(track) => subtitle_tracks.push(track),
+                Err(e) => skipped_tracks.push(SkippedTrack::new(track_id, handler_type, e.message)),
+            }
+        }
 
-        let mut audio_track_count = 0;
-        let mut video_track_count = 0;
+        // 再生できないトラックはファイル全体のエラーとはせずに、除外した上で残りのトラックを再生する
         let mut tracks = Vec::new();
         for trak_box in moov_box.trak_boxes {
-            if trak_box.mdia_box.hdlr_box.handler_type == HdlrBox::HANDLER_TYPE_SOUN {
-                audio_track_count += 1;
-            } else if trak_box.mdia_box.hdlr_box.handler_type == HdlrBox::HANDLER_TYPE_VIDE {
-                video_track_count += 1;
+            if let Some(reason) = Track::unplayable_reason(&trak_box) {
+                skipped_tracks.push(SkippedTrack::new(
+                    trak_box.tkhd_box.track_id,
+                    trak_box.mdia_box.hdlr_box.handler_type,
+                    reason,
+                ));
+                continue;
             }
-
-            tracks.push(Track::new(trak_box, mp4_bytes.len()).or_fail()?);
+            let movie_timescale = moov_box.mvhd_box.timescale;
+            tracks.push(Track::new(trak_box, movie_timescale, mp4_bytes.len()).or_fail()?);
         }
+        (!tracks.is_empty() || skipped_tracks.is_empty()).or_fail_with(|()| {
+            let reasons = skipped_tracks
+                .iter()
+                .map(|t| format!("track {}: {}", t.track_id, t.reason))
+                .collect::<Vec<_>>();
+            format!(
+                "No playable video or audio tracks found ({})",
+                reasons.join(", ")
+            )
+        })?;
+
+        let mut mp4 = Self::from_tracks(tracks).or_fail()?;
+        mp4.info.timecode = Timecode::from_moov_bytes(moov_bytes, mp4_bytes);
+        skipped_tracks.append(&mut mp4.info.skipped_tracks);
+        skipped_tracks.sort_by_key(|t| t.track_id);
+        mp4.info.skipped_tracks = skipped_tracks;
+        mp4.info.subtitle_tracks = subtitle_tracks.iter().map(|t| t.info.clone()).collect();
+        mp4.subtitle_tracks = subtitle_tracks;
+        Ok(mp4)
+    }
+
+    // MP4 以外のコンテナ（WebM など）から変換されたトラック群からも作れるようにしている
+    //
+    // 同じ種類のトラックが複数ある場合には最初のもののみを再生対象とし、残りは除外したトラックとして扱う
+    pub fn from_tracks(tracks: Vec<Track>) -> orfail::Result<Self> {
         (!tracks.is_empty()).or_fail_with(|()| "No video or audio tracks found".to_owned())?;
-        (audio_track_count <= 1)
-            .or_fail_with(|()| "Unsupported: multiple audio tracks".to_owned())?;
-        (video_track_count <= 1)
-            .or_fail_with(|()| "Unsupported: multiple video tracks".to_owned())?;
 
+        let mut playable_tracks = Vec::<Track>::new();
+        let mut skipped_tracks = Vec::new();
+        for track in tracks {
+            if playable_tracks.iter().any(|t| t.kind == track.kind) {
+                let (handler_type, reason) = match track.kind {
+                    TrackKind::Audio => (HdlrBox::HANDLER_TYPE_SOUN, MULTIPLE_AUDIO_TRACKS_REASON),
+                    TrackKind::Video => (HdlrBox::HANDLER_TYPE_VIDE, MULTIPLE_VIDEO_TRACKS_REASON),
+                };
+                skipped_tracks.push(SkippedTrack::new(track.track_id, handler_type, reason));
+                continue;
+            }
+            playable_tracks.push(track);
+        }
+
+        let mut info = Self::get_mp4_info(&playable_tracks);
+        info.skipped_tracks = skipped_tracks;
         Ok(Self {
-            info: Self::get_mp4_info(&tracks),
-            tracks,
+            info,
+            tracks: playable_tracks,
+            subtitle_tracks: Vec::new(),
         })
     }
 
-    fn load_moov_box(mut reader: &[u8]) -> orfail::Result<MoovBox> {
-        FtypBox::decode(&mut reader).or_fail()?;
+    // moov ボックスをデコードして、そのファイル内での位置と一緒に返す
+    pub fn load_moov_box(mp4_bytes: &[u8]) -> orfail::Result<(MoovBox, Range<usize>)> {
+        let moov_range = Self::find_moov_box(mp4_bytes).or_fail()?;
+        let moov_box = Self::decode_moov_box(&mp4_bytes[moov_range.clone()]).or_fail()?;
+        Ok((moov_box, moov_range))
+    }
+
+    // トップレベルのボックス群の中から moov ボックスを探して、そのファイル内での位置を返す
+    //
+    // QuickTime 形式 (MOV) では ftyp ボックスが省略されていることもあるので、先頭のボックスの種別は問わない
+    fn find_moov_box(mp4_bytes: &[u8]) -> orfail::Result<Range<usize>> {
+        let mut reader = mp4_bytes;
         loop {
             if reader.is_empty() {
                 return Err(Failure::new("No 'moov' box found"));
             }
-            if let Either::A(moov_box) =
-                IgnoredBox::decode_or_ignore(&mut reader, |ty| ty == MoovBox::TYPE).or_fail()?
-            {
-                return Ok(moov_box);
+            let start = mp4_bytes.len() - reader.len();
+            let ignored = IgnoredBox::decode(&mut reader).or_fail()?;
+            if ignored.box_type == MoovBox::TYPE {
+                let end = mp4_bytes.len() - reader.len();
+                return Ok(start..end);
             }
         }
     }
 
+    fn decode_moov_box(moov_bytes: &[u8]) -> orfail::Result<MoovBox> {
+        let error = match MoovBox::decode(moov_bytes) {
+            Ok(moov_box) => return Ok(moov_box),
+            Err(e) => e,
+        };
+
+        // DecoderSpecificInfo が省略された esds を含む場合には、それを補った上でデコードし直す
+        // （補えなかった場合や、それでも失敗した場合には、元のエラーを返す）
+        let mut complemented = Vec::new();
+        complement_decoder_specific_info(moov_bytes, &mut complemented)
+            .and_then(|()| MoovBox::decode(&complemented[..]).ok())
+            .ok_or(error)
+            .or_fail()
+    }
+
     fn get_mp4_info(tracks: &[Track]) -> Mp4Info {
         let mut audio_configs = Vec::new();
         let mut video_configs = Vec::new();
@@ -302,32 +1389,10 @@ impl Mp4 {
                 }
                 known_sample_entries.insert(chunk.sample_entry().clone());
 
-                match chunk.sample_entry() {
-                    SampleEntry::Avc1(b) => {
-                        video_configs.push(VideoDecoderConfig::from_avc1_box(b));
-                    }
-                    SampleEntry::Hev1(b) => {
-                        video_configs.push(VideoDecoderConfig::from_hev1_box(b));
-                    }
-                    SampleEntry::Vp08(b) => {
-                        video_configs.push(VideoDecoderConfig::from_vp08_box(b));
-                    }
-                    SampleEntry::Vp09(b) => {
-                        video_configs.push(VideoDecoderConfig::from_vp09_box(b));
-                    }
-                    SampleEntry::Av01(b) => {
-                        video_configs.push(VideoDecoderConfig::from_av01_box(b));
-                    }
-                    SampleEntry::Opus(b) => {
-                        audio_configs.push(AudioDecoderConfig::from_opus_box(b));
-                    }
-                    SampleEntry::Mp4a(b) => {
-                        audio_configs.push(AudioDecoderConfig::from_mp4a_box(b));
-                    }
-                    _ => {
-                        // `Track` 作成時にチェックしているのでここには来ない
-                        unreachable!()
-                    }
+                // `Track` 作成時にチェックしているので、未対応のサンプルエントリーはここには来ない
+                match DecoderConfig::from_sample_entry(chunk.sample_entry()).expect("unreachable") {
+                    DecoderConfig::Audio(config) => audio_configs.push(config),
+                    DecoderConfig::Video(config) => video_configs.push(config),
                 }
             }
         }
@@ -335,6 +1400,312 @@ impl Mp4 {
         Mp4Info {
             audio_configs,
             video_configs,
+            timecode: None,
+            skipped_tracks: Vec::new(),
+            subtitle_tracks: Vec::new(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::muxer::MuxSample;
+
+    fn boxes(children: &[(BoxType, &[u8])]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for (box_type, payload) in children {
+            write_box(*box_type, payload, &mut bytes).expect("failed to write box");
+        }
+        bytes
+    }
+
+    #[test]
+    fn non_drop_frame_timecodes_count_whole_frames() {
+        assert_eq!(format_timecode(0, 30, false), "00:00:00:00");
+        assert_eq!(format_timecode(30 * 3661 + 5, 30, false), "01:01:01:05");
+        assert_eq!(format_timecode(25 * 60 - 1, 25, false), "00:00:59:24");
+
+        // 24 時間で一周する
+        assert_eq!(
+            format_timecode(30 * 3600 * 24 + 1, 30, false),
+            "00:00:00:01"
+        );
+    }
+
+    #[test]
+    fn drop_frame_timecodes_skip_labels_at_minute_boundaries() {
+        // 29.97 fps では 10 分毎を除く各分の先頭で 2 フレーム分、59.94 fps では 4 フレーム分のラベルが飛ばされる
+        for (fps, dropped) in [(30, 2), (60, 4)] {
+            let mut previous = format_timecode(0, fps, true);
+            assert_eq!(previous, "00:00:00;00");
+            for frame_number in 1..fps as u32 * 60 * 20 {
+                let timecode = format_timecode(frame_number, fps, true);
+                let (_, frames) = timecode.split_once(';').expect("drop frame separator");
+                let minute = &timecode[3..5];
+                let expected_first_frame = if previous[3..5] == *minute {
+                    None
+                } else if minute.ends_with('0') {
+                    Some("00".to_owned())
+                } else {
+                    Some(format!("{dropped:02}"))
+                };
+                if let Some(expected) = expected_first_frame {
+                    assert_eq!(frames, expected, "fps={fps}, {previous} -> {timecode}");
+                }
+                previous = timecode;
+            }
+        }
+
+        assert_eq!(format_timecode(1799, 30, true), "00:00:59;29");
+        assert_eq!(format_timecode(1800, 30, true), "00:01:00;02");
+        assert_eq!(format_timecode(17982, 30, true), "00:10:00;00");
+        assert_eq!(format_timecode(17982 * 6, 30, true), "01:00:00;00");
+        assert_eq!(format_timecode(35964, 60, true), "00:10:00;00");
+    }
+
+    // mp4a のサウンドデスクリプションのペイロード（子ボックスを除く）を生成する
+    fn sound_description(version: u16, channels: u16, sample_rate: u16) -> Vec<u8> {
+        let mut payload = vec![0; AUDIO_SAMPLE_ENTRY_FIELDS_SIZE];
+        payload[6..8].copy_from_slice(&1u16.to_be_bytes()); // data_reference_index
+        payload[8..10].copy_from_slice(&version.to_be_bytes());
+        payload[12..16].copy_from_slice(b"appl"); // vendor
+        payload[16..18].copy_from_slice(&channels.to_be_bytes());
+        payload[18..20].copy_from_slice(&16u16.to_be_bytes()); // samplesize
+        payload[20..22].copy_from_slice(&0xFFFEu16.to_be_bytes()); // compression ID
+        payload[24..28].copy_from_slice(&(u32::from(sample_rate) << 16).to_be_bytes());
+        payload
+    }
+
+    // バージョン 2 のサウンドデスクリプションの追加フィールド（サンプリングレートと 2 チャンネル）
+    fn sound_description_v2_extra(sample_rate: f64) -> Vec<u8> {
+        let mut extra = vec![0; SOUND_DESCRIPTION_V2_EXTRA_SIZE];
+        extra[4..12].copy_from_slice(&sample_rate.to_be_bytes());
+        extra[12..16].copy_from_slice(&2u32.to_be_bytes());
+        extra
+    }
+
+    const ESDS_PAYLOAD: [u8; 8] = [0, 0, 0, 0, 3, 1, 2, 3];
+
+    // wave 内に esds が置かれている QuickTime 形式の子ボックス
+    fn wave_with_esds() -> Vec<u8> {
+        let frma = BoxType::Normal(*b"frma");
+        let wave = boxes(&[(frma, b"mp4a"), (EsdsBox::TYPE, &ESDS_PAYLOAD)]);
+        boxes(&[(WAVE_BOX_TYPE, &wave)])
+    }
+
+    // 正規化後のバージョン 0 のペイロード
+    fn normalized_v0(sample_rate: u16, children: &[u8]) -> Vec<u8> {
+        let mut payload = sound_description(0, 2, sample_rate);
+        payload[12..16].fill(0);
+        payload[20..22].fill(0);
+        payload.extend_from_slice(children);
+        payload
+    }
+
+    #[test]
+    fn sound_description_v1_drops_extra_fields_and_unwraps_esds() {
+        let payload = [
+            &sound_description(1, 2, 44100)[..],
+            &[0; SOUND_DESCRIPTION_V1_EXTRA_SIZE],
+            &wave_with_esds(),
+        ]
+        .concat();
+        let esds = boxes(&[(EsdsBox::TYPE, &ESDS_PAYLOAD)]);
+        assert_eq!(
+            normalize_mp4a_payload(&payload),
+            Some(normalized_v0(44100, &esds))
+        );
+
+        // 途中で切れている場合は変換できない
+        assert_eq!(normalize_mp4a_payload(&payload[..20]), None);
+        assert_eq!(normalize_mp4a_payload(&payload[..30]), None);
+    }
+
+    #[test]
+    fn sound_description_v2_takes_format_from_extra_fields() {
+        let payload = [
+            &sound_description(2, 3, 1)[..],
+            &sound_description_v2_extra(44100.0),
+            &wave_with_esds(),
+        ]
+        .concat();
+        let esds = boxes(&[(EsdsBox::TYPE, &ESDS_PAYLOAD)]);
+        assert_eq!(
+            normalize_mp4a_payload(&payload),
+            Some(normalized_v0(44100, &esds))
+        );
+
+        // 16 ビットに収まらないサンプリングレートは 0 となり、実際の値は esds から取得される
+        let high_sample_rate = [
+            &sound_description(2, 3, 1)[..],
+            &sound_description_v2_extra(96000.0),
+            &wave_with_esds(),
+        ]
+        .concat();
+        assert_eq!(
+            normalize_mp4a_payload(&high_sample_rate),
+            Some(normalized_v0(0, &esds))
+        );
+
+        // 整数でないサンプリングレートはバージョン 0 では表現できない
+        let fractional = [
+            &sound_description(2, 3, 1)[..],
+            &sound_description_v2_extra(44100.5),
+        ]
+        .concat();
+        assert_eq!(normalize_mp4a_payload(&fractional), None);
+    }
+
+    #[test]
+    fn sound_description_v0_children_are_kept() {
+        let btrt = BoxType::Normal(*b"btrt");
+        let children = boxes(&[(EsdsBox::TYPE, &ESDS_PAYLOAD), (btrt, &[0; 12])]);
+        let payload = [&sound_description(0, 2, 44100)[..], &children].concat();
+        assert_eq!(
+            normalize_mp4a_payload(&payload),
+            Some(normalized_v0(44100, &children))
+        );
+
+        let unknown_version = [&sound_description(3, 2, 44100)[..], &[0; 64]].concat();
+        assert_eq!(normalize_mp4a_payload(&unknown_version), None);
+    }
+
+    #[test]
+    fn aac_sample_rate_table() {
+        let cases: [(&[u8], Option<u32>); 7] = [
+            (&[0x12, 0x10], Some(44_100)),
+            (&[0x10, 0x10], Some(96_000)),
+            // サンプリング周波数インデックス 15 の後に 24 ビットの値が続く
+            (&[0x17, 0x80, 0xBB, 0x80, 0x10], Some(96_000)),
+            // audioObjectType が 31 による拡張 (42: USAC)
+            (&[0xF9, 0x46, 0x40], Some(48_000)),
+            // 予約済みのインデックス
+            (&[0x16, 0x90], None),
+            (&[0x12], None),
+            (&[], None),
+        ];
+        for (config, expected) in cases {
+            assert_eq!(aac_sample_rate(config), expected, "{config:02x?}");
+        }
+    }
+
+    #[test]
+    fn aac_sample_rates_above_u16_are_kept() {
+        for sample_rate in [88_200, 96_000] {
+            let config = AudioDecoderConfig {
+                codec: "mp4a.40.2".to_owned(),
+                sample_rate,
+                number_of_channels: 2,
+                description: Vec::new(),
+            };
+            let entry = config
+                .to_sample_entry()
+                .expect("failed to make sample entry");
+            let SampleEntry::Mp4a(b) = &entry else {
+                panic!("unexpected sample entry: {entry:?}");
+            };
+            assert_eq!(b.audio.samplerate.integer, 0);
+            let Some(DecoderConfig::Audio(decoded)) = DecoderConfig::from_sample_entry(&entry)
+            else {
+                panic!("failed to decode sample entry");
+            };
+            assert_eq!(decoded, config);
+        }
+    }
+
+    #[test]
+    fn flac_sample_rate_is_read_from_streaminfo() {
+        let mut streaminfo = [0; FLAC_STREAMINFO_SIZE];
+        // 96 kHz (20 ビット), 2 チャンネル, 24 ビット
+        streaminfo[10..13].copy_from_slice(&[0x17, 0x70, 0x03]);
+        streaminfo[13] = 0x70;
+        let dfla = [
+            &[0, 0, 0, 0][..],
+            &[0x80, 0, 0, FLAC_STREAMINFO_SIZE as u8],
+            &streaminfo,
+        ]
+        .concat();
+        let payload = [
+            &[0; AUDIO_SAMPLE_ENTRY_FIELDS_SIZE][..],
+            &boxes(&[(DFLA_BOX_TYPE, &dfla)]),
+        ]
+        .concat();
+        let b = UnknownBox {
+            box_type: FLAC_BOX_TYPE,
+            box_size: BoxSize::with_payload_size(FLAC_BOX_TYPE, payload.len() as u64),
+            payload,
+        };
+        let config = AudioDecoderConfig::from_flac_box(&b).expect("failed to parse fLaC box");
+        assert_eq!(config.sample_rate, 96_000);
+        assert_eq!(config.number_of_channels, 2);
+        assert!(config.description.starts_with(b"fLaC\x80"));
+    }
+
+    fn make_track(track_id: u32, kind: TrackKind) -> Track {
+        let sample_entry = match kind {
+            TrackKind::Audio => AudioDecoderConfig {
+                codec: "opus".to_owned(),
+                sample_rate: 48_000,
+                number_of_channels: 2,
+                description: Vec::new(),
+            }
+            .to_sample_entry(),
+            TrackKind::Video => VideoDecoderConfig {
+                codec: "vp8".to_owned(),
+                description: Vec::new(),
+                coded_width: 320,
+                coded_height: 240,
+            }
+            .to_sample_entry(),
+        };
+        let mut mux_track = MuxTrack::new(kind, NonZeroU32::MIN.saturating_add(999));
+        let sample_entry_index =
+            mux_track.add_sample_entry(sample_entry.expect("failed to make sample entry"));
+        mux_track.samples.push(MuxSample {
+            sample_entry_index,
+            duration: 20,
+            is_sync: true,
+            composition_offset: 0,
+            data_offset: 0,
+            data_size: 10,
+        });
+        Track::from_mux_track(&mux_track, track_id, 10).expect("failed to make track")
+    }
+
+    #[test]
+    fn only_first_track_of_each_kind_is_played() {
+        let mp4 = Mp4::from_tracks(vec![
+            make_track(1, TrackKind::Audio),
+            make_track(2, TrackKind::Video),
+            make_track(3, TrackKind::Audio),
+            make_track(4, TrackKind::Video),
+            make_track(5, TrackKind::Audio),
+        ])
+        .expect("failed to make Mp4");
+
+        let tracks = mp4
+            .tracks
+            .iter()
+            .map(|t| (t.track_id, t.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(tracks, [(1, TrackKind::Audio), (2, TrackKind::Video)]);
+        assert_eq!(mp4.info.audio_configs.len(), 1);
+        assert_eq!(mp4.info.video_configs.len(), 1);
+
+        let skipped = mp4
+            .info
+            .skipped_tracks
+            .iter()
+            .map(|t| (t.track_id, t.handler_type.as_str(), t.reason.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            skipped,
+            [
+                (3, "soun", MULTIPLE_AUDIO_TRACKS_REASON),
+                (4, "vide", MULTIPLE_VIDEO_TRACKS_REASON),
+                (5, "soun", MULTIPLE_AUDIO_TRACKS_REASON),
+            ]
+        );
+    }
+}
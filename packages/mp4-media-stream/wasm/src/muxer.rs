@@ -0,0 +1,1042 @@
+use std::{num::NonZeroU32, ops::Range, time::Duration};
+
+use orfail::OrFail;
+use shiguredo_mp4::{
+    boxes::{
+        Brand, Co64Box, DinfBox, EdtsBox, ElstBox, ElstEntry, FtypBox, HdlrBox, MdatBox, MdhdBox,
+        MdiaBox, MinfBox, MoovBox, MvhdBox, SampleEntry, SmhdBox, StblBox, StcoBox, StscBox,
+        StscEntry, StsdBox, StssBox, StszBox, SttsBox, TkhdBox, TrakBox, UnknownBox, VmhdBox,
+    },
+    BoxHeader, BoxSize, BoxType, Either, Encode, FixedPointNumber, Mp4FileTime,
+};
+
+use crate::{
+    fmp4,
+    mp4::{decode_hvc1_box, read_u32, AudioDecoderConfig, TrackKind, VideoDecoderConfig},
+};
+
+// 映像トラックのタイムスケール（一般的な 90 kHz を使う）
+pub const VIDEO_TIMESCALE: NonZeroU32 = NonZeroU32::MIN.saturating_add(90_000 - 1);
+
+// mvhd / tkhd / elst で使われるタイムスケール
+pub const MOVIE_TIMESCALE: NonZeroU32 = NonZeroU32::MIN.saturating_add(1_000 - 1);
+
+// 尺が不明な最後のサンプルに使うデフォルトの尺
+const DEFAULT_LAST_SAMPLE_DURATION: Duration = Duration::from_millis(20);
+
+// shiguredo_mp4 が未対応の ctts ボックス（コンポジション時間オフセット）
+pub const CTTS_BOX_TYPE: BoxType = BoxType::Normal(*b"ctts");
+
+// MP4 に書き込む個々のサンプルの情報
+#[derive(Debug, Clone)]
+pub struct MuxSample {
+    // MuxTrack::sample_entries 内の位置
+    pub sample_entry_index: usize,
+
+    // トラックのタイムスケール単位の尺
+    pub duration: u32,
+
+    pub is_sync: bool,
+
+    // トラックのタイムスケール単位の、表示時刻とデコード時刻の差（B フレームを含まない場合は 0）
+    pub composition_offset: i32,
+
+    // mdat ボックスのペイロード先頭からのバイト位置とサイズ
+    pub data_offset: u64,
+    pub data_size: u32,
+}
+
+// MP4 に書き込むトラックの情報
+//
+// MP4 レコーダーだけでなく、既存の MP4 を加工して新しい MP4 を生成する処理でも共通で使われる
+#[derive(Debug, Clone)]
+pub struct MuxTrack {
+    pub kind: TrackKind,
+    pub timescale: NonZeroU32,
+    pub sample_entries: Vec<SampleEntry>,
+    pub samples: Vec<MuxSample>,
+
+    // ファイル先頭（全トラック共通の時刻 0）から、このトラックの最初のサンプルまでの空白時間
+    pub start_offset: Duration,
+
+    // start_offset 以降に、順番に表示される区間群（編集リストのエントリーに対応する）
+    //
+    // 空の場合にはサンプル群の全体が表示される。
+    // どの区間にも含まれないサンプルはデコードには使われるが表示はされない（キーフレームからのプリロールなど）。
+    pub edits: Vec<MuxEdit>,
+}
+
+// 編集リストの一つのエントリーに対応する表示区間
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MuxEdit {
+    // 何も表示されない空白区間
+    Empty(Duration),
+
+    // サンプル群の中で実際に表示される区間（トラックのタイムスケール単位の表示時刻）
+    Media(Range<u64>),
+}
+
+impl MuxTrack {
+    pub fn new(kind: TrackKind, timescale: NonZeroU32) -> Self {
+        Self {
+            kind,
+            timescale,
+            sample_entries: Vec::new(),
+            samples: Vec::new(),
+            start_offset: Duration::ZERO,
+            edits: Vec::new(),
+        }
+    }
+
+    // 表示区間を末尾に追加する
+    //
+    // 直前の区間と連続している場合には、一つの区間にまとめられる
+    pub fn push_edit(&mut self, edit: MuxEdit) {
+        match (self.edits.last_mut(), edit) {
+            (Some(MuxEdit::Empty(last)), MuxEdit::Empty(d)) => *last += d,
+            (Some(MuxEdit::Media(last)), MuxEdit::Media(r)) if last.end == r.start => {
+                last.end = r.end;
+            }
+            (_, edit) => self.edits.push(edit),
+        }
+    }
+
+    // 同じサンプルエントリーが既に存在する場合にはそれを、存在しない場合には追加してその位置を返す
+    pub fn add_sample_entry(&mut self, entry: SampleEntry) -> usize {
+        if let Some(i) = self.sample_entries.iter().position(|e| *e == entry) {
+            i
+        } else {
+            self.sample_entries.push(entry);
+            self.sample_entries.len() - 1
+        }
+    }
+
+    // トラックのタイムスケール単位での尺を返す
+    pub fn media_duration(&self) -> u64 {
+        self.samples.iter().map(|s| s.duration as u64).sum()
+    }
+
+    // 表示される区間の尺を返す（start_offset は含まない）
+    pub fn duration(&self) -> Duration {
+        if self.edits.is_empty() {
+            return self.ticks_to_duration(self.media_duration());
+        }
+        self.edits.iter().map(|e| self.edit_duration(e)).sum()
+    }
+
+    fn edit_duration(&self, edit: &MuxEdit) -> Duration {
+        match edit {
+            MuxEdit::Empty(d) => *d,
+            MuxEdit::Media(r) => self.ticks_to_duration(r.end.saturating_sub(r.start)),
+        }
+    }
+
+    fn ticks_to_duration(&self, ticks: u64) -> Duration {
+        Duration::from_secs(ticks) / self.timescale.get()
+    }
+
+    // 空白時間を含めた、ムービータイムスケール単位での尺を返す
+    fn movie_duration(&self) -> u64 {
+        to_timescale(self.start_offset + self.duration(), MOVIE_TIMESCALE)
+    }
+
+    pub fn to_trak_box(&self, track_id: u32, mdat_payload_offset: u64) -> TrakBox {
+        let (width, height) = match self.sample_entries.first() {
+            Some(SampleEntry::Avc1(b)) => (b.visual.width, b.visual.height),
+            Some(SampleEntry::Hev1(b)) => (b.visual.width, b.visual.height),
+            Some(SampleEntry::Vp08(b)) => (b.visual.width, b.visual.height),
+            Some(SampleEntry::Vp09(b)) => (b.visual.width, b.visual.height),
+            Some(SampleEntry::Av01(b)) => (b.visual.width, b.visual.height),
+            Some(SampleEntry::Unknown(b)) => {
+                decode_hvc1_box(b).map_or((0, 0), |b| (b.visual.width, b.visual.height))
+            }
+            _ => (0, 0),
+        };
+
+        let tkhd_box = TkhdBox {
+            flag_track_enabled: true,
+            flag_track_in_movie: true,
+            flag_track_in_preview: false,
+            flag_track_size_is_aspect_ratio: false,
+            creation_time: Mp4FileTime::default(),
+            modification_time: Mp4FileTime::default(),
+            track_id,
+            duration: self.movie_duration(),
+            layer: TkhdBox::DEFAULT_LAYER,
+            alternate_group: TkhdBox::DEFAULT_ALTERNATE_GROUP,
+            volume: match self.kind {
+                TrackKind::Audio => TkhdBox::DEFAULT_AUDIO_VOLUME,
+                TrackKind::Video => TkhdBox::DEFAULT_VIDEO_VOLUME,
+            },
+            matrix: TkhdBox::DEFAULT_MATRIX,
+            width: FixedPointNumber::new(width as i16, 0),
+            height: FixedPointNumber::new(height as i16, 0),
+        };
+
+        let needs_edts = !self.start_offset.is_zero() || !self.edits.is_empty();
+        let edts_box = needs_edts.then(|| {
+            let mut edits = Vec::new();
+            if !self.start_offset.is_zero() {
+                // 先頭の空白部分
+                edits.push(MuxEdit::Empty(self.start_offset));
+            }
+            if self.edits.is_empty() {
+                edits.push(MuxEdit::Media(0..self.media_duration()));
+            } else {
+                edits.extend(self.edits.iter().cloned());
+            }
+            let entries = edits
+                .iter()
+                .map(|edit| ElstEntry {
+                    edit_duration: to_timescale(self.edit_duration(edit), MOVIE_TIMESCALE),
+                    media_time: match edit {
+                        MuxEdit::Empty(_) => -1,
+                        MuxEdit::Media(r) => r.start as i64,
+                    },
+                    media_rate: FixedPointNumber::new(1, 0),
+                })
+                .collect();
+            EdtsBox {
+                elst_box: Some(ElstBox { entries }),
+                unknown_boxes: Vec::new(),
+            }
+        });
+
+        let (handler_type, handler_name, smhd_or_vmhd_box) = match self.kind {
+            TrackKind::Audio => (
+                HdlrBox::HANDLER_TYPE_SOUN,
+                &b"SoundHandler\0"[..],
+                Either::A(SmhdBox::default()),
+            ),
+            TrackKind::Video => (
+                HdlrBox::HANDLER_TYPE_VIDE,
+                &b"VideoHandler\0"[..],
+                Either::B(VmhdBox::default()),
+            ),
+        };
+
+        TrakBox {
+            tkhd_box,
+            edts_box,
+            mdia_box: MdiaBox {
+                mdhd_box: MdhdBox {
+                    creation_time: Mp4FileTime::default(),
+                    modification_time: Mp4FileTime::default(),
+                    timescale: self.timescale,
+                    duration: self.media_duration(),
+                    language: MdhdBox::LANGUAGE_UNDEFINED,
+                },
+                hdlr_box: HdlrBox {
+                    handler_type,
+                    name: handler_name.to_vec(),
+                },
+                minf_box: MinfBox {
+                    smhd_or_vmhd_box,
+                    dinf_box: DinfBox::LOCAL_FILE,
+                    stbl_box: self.to_stbl_box(mdat_payload_offset),
+                    unknown_boxes: Vec::new(),
+                },
+                unknown_boxes: Vec::new(),
+            },
+            unknown_boxes: Vec::new(),
+        }
+    }
+
+    fn to_stbl_box(&self, mdat_payload_offset: u64) -> StblBox {
+        // サンプルエントリーが同じで、かつ、データが連続しているサンプル群を一つのチャンクにまとめる
+        let mut chunk_offsets = Vec::new();
+        let mut stsc_entries = Vec::<StscEntry>::new();
+        let mut chunk_sample_count = 0;
+        let mut prev: Option<&MuxSample> = None;
+        for sample in &self.samples {
+            let is_new_chunk = prev.is_none_or(|prev| {
+                prev.sample_entry_index != sample.sample_entry_index
+                    || prev.data_offset + prev.data_size as u64 != sample.data_offset
+            });
+            if is_new_chunk {
+                if let Some(prev) = prev {
+                    push_stsc_entry(
+                        &mut stsc_entries,
+                        chunk_offsets.len() as u32,
+                        chunk_sample_count,
+                        prev.sample_entry_index,
+                    );
+                }
+                chunk_offsets.push(mdat_payload_offset + sample.data_offset);
+                chunk_sample_count = 0;
+            }
+            chunk_sample_count += 1;
+            prev = Some(sample);
+        }
+        if let Some(prev) = prev {
+            push_stsc_entry(
+                &mut stsc_entries,
+                chunk_offsets.len() as u32,
+                chunk_sample_count,
+                prev.sample_entry_index,
+            );
+        }
+
+        let stco_or_co64_box = make_stco_or_co64_box(chunk_offsets);
+
+        // 全てが同期サンプルの場合には stss ボックスは省略する
+        let stss_box = (!self.samples.iter().all(|s| s.is_sync)).then(|| StssBox {
+            sample_numbers: self
+                .samples
+                .iter()
+                .enumerate()
+                .filter(|(_, s)| s.is_sync)
+                .map(|(i, _)| NonZeroU32::MIN.saturating_add(i as u32))
+                .collect(),
+        });
+
+        let mut unknown_boxes = Vec::new();
+        if self.samples.iter().any(|s| s.composition_offset != 0) {
+            unknown_boxes.push(self.to_ctts_box());
+        }
+
+        StblBox {
+            stsd_box: StsdBox {
+                entries: self.sample_entries.clone(),
+            },
+            stts_box: SttsBox::from_sample_deltas(self.samples.iter().map(|s| s.duration)),
+            stsc_box: StscBox {
+                entries: stsc_entries,
+            },
+            stsz_box: StszBox::Variable {
+                entry_sizes: self.samples.iter().map(|s| s.data_size).collect(),
+            },
+            stco_or_co64_box,
+            stss_box,
+            unknown_boxes,
+        }
+    }
+
+    // ISO/IEC 14496-12 8.6.1.3 Composition Time to Sample Box
+    //
+    // 負のオフセットを含む場合にはバージョン 1 とする
+    fn to_ctts_box(&self) -> UnknownBox {
+        let mut entries = Vec::<(u32, i32)>::new();
+        for sample in &self.samples {
+            match entries.last_mut() {
+                Some((count, offset)) if *offset == sample.composition_offset => *count += 1,
+                _ => entries.push((1, sample.composition_offset)),
+            }
+        }
+        let version = u8::from(entries.iter().any(|&(_, offset)| offset < 0));
+
+        let mut payload = vec![version, 0, 0, 0];
+        payload.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+        for (count, offset) in entries {
+            payload.extend_from_slice(&count.to_be_bytes());
+            payload.extend_from_slice(&offset.to_be_bytes());
+        }
+        UnknownBox {
+            box_type: CTTS_BOX_TYPE,
+            box_size: BoxSize::with_payload_size(CTTS_BOX_TYPE, payload.len() as u64),
+            payload,
+        }
+    }
+}
+
+// stbl ボックス内の ctts ボックスから、各サンプルのコンポジション時間オフセットを取得する
+//
+// ctts ボックスが存在しない場合には、全てのサンプルのオフセットが 0 となる。
+// バージョン 0 のオフセットは符号なしだが、実際には負の値を意図して書き込むエンコーダーもあるため、
+// バージョンに関わらず符号付きとして扱う。
+pub fn read_composition_offsets(
+    stbl_box: &StblBox,
+    sample_count: usize,
+) -> orfail::Result<Vec<i32>> {
+    let Some(ctts_box) = stbl_box
+        .unknown_boxes
+        .iter()
+        .find(|b| b.box_type == CTTS_BOX_TYPE)
+    else {
+        return Ok(vec![0; sample_count]);
+    };
+
+    let payload = &ctts_box.payload;
+    let entry_count = read_u32(payload, 4).or_fail_with(|()| "Too short ctts box".to_owned())?;
+    let mut offsets = Vec::with_capacity(sample_count);
+    for i in 0..entry_count as usize {
+        let (count, offset) = read_u32(payload, 8 + i * 8)
+            .zip(read_u32(payload, 12 + i * 8))
+            .or_fail_with(|()| "Too short ctts box".to_owned())?;
+        let count = (count as usize).min(sample_count - offsets.len());
+        offsets.extend(std::iter::repeat_n(offset as i32, count));
+    }
+    (offsets.len() == sample_count).or_fail_with(|()| {
+        format!(
+            "ctts box covers only {} of {sample_count} samples",
+            offsets.len()
+        )
+    })?;
+    Ok(offsets)
+}
+
+fn push_stsc_entry(
+    entries: &mut Vec<StscEntry>,
+    chunk_index: u32,
+    sample_count: u32,
+    sample_entry_index: usize,
+) {
+    let sample_description_index = NonZeroU32::MIN.saturating_add(sample_entry_index as u32);
+    if entries.last().is_some_and(|e| {
+        e.sample_per_chunk == sample_count && e.sample_description_index == sample_description_index
+    }) {
+        return;
+    }
+    entries.push(StscEntry {
+        first_chunk: NonZeroU32::new(chunk_index).expect("unreachable"),
+        sample_per_chunk: sample_count,
+        sample_description_index,
+    });
+}
+
+// 時間を指定のタイムスケール単位の値に変換する
+pub fn to_timescale(duration: Duration, timescale: NonZeroU32) -> u64 {
+    (duration.as_nanos() * timescale.get() as u128 / 1_000_000_000) as u64
+}
+
+// 全てのチャンクオフセットが 32 ビットに収まる場合には stco ボックスを、そうでなければ co64 ボックスを生成する
+pub fn make_stco_or_co64_box(chunk_offsets: Vec<u64>) -> Either<StcoBox, Co64Box> {
+    if chunk_offsets.iter().all(|&o| o <= u32::MAX as u64) {
+        Either::A(StcoBox {
+            chunk_offsets: chunk_offsets.into_iter().map(|o| o as u32).collect(),
+        })
+    } else {
+        Either::B(Co64Box { chunk_offsets })
+    }
+}
+
+pub fn make_ftyp_box() -> FtypBox {
+    FtypBox {
+        major_brand: Brand::ISOM,
+        minor_version: 512,
+        compatible_brands: vec![Brand::ISOM, Brand::ISO2, Brand::MP41],
+    }
+}
+
+pub fn make_moov_box(tracks: &[MuxTrack], mdat_payload_offset: u64) -> MoovBox {
+    MoovBox {
+        mvhd_box: MvhdBox {
+            creation_time: Mp4FileTime::default(),
+            modification_time: Mp4FileTime::default(),
+            timescale: MOVIE_TIMESCALE,
+            duration: tracks.iter().map(|t| t.movie_duration()).max().unwrap_or(0),
+            rate: MvhdBox::DEFAULT_RATE,
+            volume: MvhdBox::DEFAULT_VOLUME,
+            matrix: MvhdBox::DEFAULT_MATRIX,
+            next_track_id: tracks.len() as u32 + 1,
+        },
+        trak_boxes: tracks
+            .iter()
+            .enumerate()
+            .map(|(i, t)| t.to_trak_box(i as u32 + 1, mdat_payload_offset))
+            .collect(),
+        unknown_boxes: Vec::new(),
+    }
+}
+
+// ftyp / mdat / moov の順にボックスを並べた MP4 ファイルを生成する
+//
+// トラック内のサンプルのデータ位置は mdat_payload 先頭からのオフセットとして扱われる
+pub fn write_progressive_mp4(tracks: &[MuxTrack], mdat_payload: &[u8]) -> orfail::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    make_ftyp_box().encode(&mut bytes).or_fail()?;
+
+    let mdat_header = BoxHeader {
+        box_type: MdatBox::TYPE,
+        box_size: BoxSize::with_payload_size(MdatBox::TYPE, mdat_payload.len() as u64),
+    };
+    mdat_header.encode(&mut bytes).or_fail()?;
+    let mdat_payload_offset = bytes.len() as u64;
+    bytes.extend_from_slice(mdat_payload);
+
+    make_moov_box(tracks, mdat_payload_offset)
+        .encode(&mut bytes)
+        .or_fail()?;
+    Ok(bytes)
+}
+
+// WebCodecs のエンコーダーが出力したチャンク群から MP4 を生成するためのマルチプレクサー
+//
+// 通常の MP4 を生成する場合には、全てのサンプルを保持しておいて finish() の呼び出し時に出力する。
+// Fragmented MP4 を生成する場合には、フラグメントの区切りに達する度に初期化セグメントやメディアセグメントを出力する。
+#[derive(Debug)]
+pub struct Mp4Muxer {
+    audio: Option<MuxerTrack>,
+    video: Option<MuxerTrack>,
+    mdat_payload: Vec<u8>,
+    fragmenter: Option<Fragmenter>,
+    output: Vec<u8>,
+    finished: bool,
+}
+
+impl Mp4Muxer {
+    // fragment_duration が指定された場合には Fragmented MP4 を生成する
+    //
+    // フラグメントは映像トラックがある場合にはキーフレームの位置で、ない場合には音声サンプルの境界で区切られる。
+    // そのため、実際のフラグメントの尺は fragment_duration よりも長くなることがある。
+    pub fn new(fragment_duration: Option<Duration>) -> Self {
+        Self {
+            audio: None,
+            video: None,
+            mdat_payload: Vec::new(),
+            fragmenter: fragment_duration.map(Fragmenter::new),
+            output: Vec::new(),
+            finished: false,
+        }
+    }
+
+    // 以降に追加される映像チャンクのデコーダー設定を指定する
+    //
+    // 途中で設定が変わった場合には、新しいサンプルエントリーが追加される
+    // (Fragmented MP4 の場合には、初期化セグメントの出力後に新しい設定を追加することはできない）
+    pub fn set_video_config(&mut self, config: &VideoDecoderConfig) -> orfail::Result<()> {
+        (!self.finished).or_fail_with(|()| "Muxer already finished".to_owned())?;
+        let entry = config.to_sample_entry().or_fail()?;
+        let frozen = self.is_init_segment_written();
+        if self.video.is_none() {
+            (!frozen).or_fail_with(|()| NEW_TRACK_AFTER_INIT_SEGMENT_ERROR.to_owned())?;
+        }
+        self.video
+            .get_or_insert_with(|| MuxerTrack::new(TrackKind::Video, VIDEO_TIMESCALE))
+            .set_sample_entry(entry, frozen)
+            .or_fail()?;
+        Ok(())
+    }
+
+    // 以降に追加される音声チャンクのデコーダー設定を指定する
+    pub fn set_audio_config(&mut self, config: &AudioDecoderConfig) -> orfail::Result<()> {
+        (!self.finished).or_fail_with(|()| "Muxer already finished".to_owned())?;
+        let entry = config.to_sample_entry().or_fail()?;
+        let timescale = NonZeroU32::new(config.sample_rate)
+            .or_fail_with(|()| "Invalid audio sample rate: 0".to_owned())?;
+        let frozen = self.is_init_segment_written();
+        if self.audio.is_none() {
+            (!frozen).or_fail_with(|()| NEW_TRACK_AFTER_INIT_SEGMENT_ERROR.to_owned())?;
+        }
+        let track = self
+            .audio
+            .get_or_insert_with(|| MuxerTrack::new(TrackKind::Audio, timescale));
+        (track.track.timescale == timescale).or_fail_with(|()| {
+            format!(
+                "Changing audio sample rate is not supported: {} => {timescale}",
+                track.track.timescale
+            )
+        })?;
+        track.set_sample_entry(entry, frozen).or_fail()?;
+        Ok(())
+    }
+
+    // エンコード済みのチャンクを追加する
+    //
+    // timestamp はチャンクの表示時刻で、composition_offset はそこからデコード時刻を引いた値
+    // (B フレームを含むストリームの場合には、デコード時刻が単調増加となるように指定する必要がある）
+    pub fn append_chunk(
+        &mut self,
+        kind: TrackKind,
+        timestamp: Duration,
+        composition_offset: Duration,
+        duration: Option<Duration>,
+        is_key: bool,
+        data: &[u8],
+    ) -> orfail::Result<()> {
+        (!self.finished).or_fail_with(|()| "Muxer already finished".to_owned())?;
+        let decode_timestamp = match kind {
+            TrackKind::Audio => self.audio.as_ref(),
+            TrackKind::Video => self.video.as_ref(),
+        }
+        .or_fail_with(|()| format!("No decoder configuration for {kind:?} track"))?
+        .decode_timestamp(timestamp, composition_offset)
+        .or_fail()?;
+        if self.should_cut_fragment(kind, decode_timestamp, is_key) {
+            self.write_fragment(Some(decode_timestamp)).or_fail()?;
+        }
+
+        let track = match kind {
+            TrackKind::Audio => self.audio.as_mut(),
+            TrackKind::Video => self.video.as_mut(),
+        }
+        .or_fail()?;
+        track
+            .append_sample(
+                timestamp,
+                composition_offset,
+                duration,
+                is_key,
+                self.mdat_payload.len() as u64,
+                data.len() as u32,
+            )
+            .or_fail()?;
+        self.mdat_payload.extend_from_slice(data);
+        Ok(())
+    }
+
+    // 全てのチャンクの追加が終わったことを通知して、MP4 ファイル（あるいは最後のフラグメント）を生成する
+    //
+    // 生成結果は take_output() で取得できる
+    pub fn finish(&mut self) -> orfail::Result<()> {
+        (!self.finished).or_fail_with(|()| "Muxer already finished".to_owned())?;
+        self.finished = true;
+
+        if self.fragmenter.is_some() {
+            let has_pending_samples = self.tracks().any(|t| !t.timestamps.is_empty());
+            if has_pending_samples || !self.is_init_segment_written() {
+                self.write_fragment(None).or_fail()?;
+            }
+            return Ok(());
+        }
+
+        let tracks = self.finish_tracks().or_fail()?;
+        self.output = write_progressive_mp4(&tracks, &self.mdat_payload).or_fail()?;
+        self.mdat_payload = Vec::new();
+        Ok(())
+    }
+
+    // 各トラックのサンプルの尺と開始位置を確定させる
+    fn finish_tracks(&mut self) -> orfail::Result<Vec<MuxTrack>> {
+        let base_timestamp = self.base_timestamp().or_fail()?;
+
+        let mut tracks = Vec::new();
+        for track in [self.audio.take(), self.video.take()].into_iter().flatten() {
+            if track.timestamps.is_empty() {
+                continue;
+            }
+            tracks.push(track.finish(base_timestamp));
+        }
+        Ok(tracks)
+    }
+
+    // これまでに生成された MP4 のバイト列を取り出す
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    fn tracks(&self) -> impl Iterator<Item = &MuxerTrack> {
+        [&self.audio, &self.video].into_iter().flatten()
+    }
+
+    // 全トラックの中で最も早いサンプルのタイムスタンプ（これが MP4 内での時刻 0 となる）
+    fn base_timestamp(&self) -> orfail::Result<Duration> {
+        self.tracks()
+            .filter_map(|t| t.timestamps.first().copied())
+            .min()
+            .or_fail_with(|()| "No chunks appended".to_owned())
+    }
+
+    fn is_init_segment_written(&self) -> bool {
+        self.fragmenter
+            .as_ref()
+            .is_some_and(|f| f.base_timestamp.is_some())
+    }
+
+    // 新しいチャンクの追加前に、それまでのサンプル群をフラグメントとして出力すべきかどうかを判定する
+    fn should_cut_fragment(&self, kind: TrackKind, timestamp: Duration, is_key: bool) -> bool {
+        let Some(fragmenter) = &self.fragmenter else {
+            return false;
+        };
+
+        // 映像トラックがある場合には、デコードの起点となるキーフレームでのみ区切る
+        let cut_track = if self.video.is_some() {
+            &self.video
+        } else {
+            &self.audio
+        };
+        let Some(cut_track) = cut_track.as_ref().filter(|t| t.track.kind == kind) else {
+            return false;
+        };
+        let Some(&fragment_start) = cut_track.timestamps.first() else {
+            return false;
+        };
+        is_key && timestamp.saturating_sub(fragment_start) >= fragmenter.fragment_duration
+    }
+
+    // 保持中のサンプル群をメディアセグメントとして出力する（初回は初期化セグメントも出力する）
+    //
+    // next_timestamp は、区切りとなったキーフレーム（あるいは音声サンプル）のタイムスタンプ
+    fn write_fragment(&mut self, next_timestamp: Option<Duration>) -> orfail::Result<()> {
+        let fragmenter = self.fragmenter.as_ref().or_fail()?;
+        let base_timestamp = if let Some(base_timestamp) = fragmenter.base_timestamp {
+            base_timestamp
+        } else {
+            let base_timestamp = self.base_timestamp().or_fail()?;
+            let init_tracks = self.tracks().map(|t| t.track.clone()).collect::<Vec<_>>();
+            let init_segment = fmp4::write_init_segment(&init_tracks).or_fail()?;
+            self.output.extend_from_slice(&init_segment);
+            self.fragmenter.as_mut().or_fail()?.base_timestamp = Some(base_timestamp);
+            base_timestamp
+        };
+
+        let cut_kind = if self.video.is_some() {
+            TrackKind::Video
+        } else {
+            TrackKind::Audio
+        };
+        let mut fragment_samples = Vec::new();
+        for (i, track) in [&mut self.audio, &mut self.video]
+            .into_iter()
+            .flatten()
+            .enumerate()
+        {
+            if track.timestamps.is_empty() {
+                continue;
+            }
+
+            // 区切りのタイムスタンプが分かるのは区切りの判定対象のトラックのみで、
+            // それ以外のトラックの最後のサンプルの尺は推定値となる
+            // (ズレは次のフラグメントの tfdt の値で補正される）
+            let next_timestamp = next_timestamp.filter(|_| track.track.kind == cut_kind);
+            let (base_media_decode_time, samples) =
+                track.take_samples(base_timestamp, next_timestamp);
+            fragment_samples.push((i as u32 + 1, base_media_decode_time, samples));
+        }
+        let fragment_tracks = fragment_samples
+            .iter()
+            .map(
+                |(track_id, base_media_decode_time, samples)| fmp4::FragmentTrack {
+                    track_id: *track_id,
+                    base_media_decode_time: *base_media_decode_time,
+                    samples,
+                },
+            )
+            .collect::<Vec<_>>();
+
+        let fragmenter = self.fragmenter.as_mut().or_fail()?;
+        if !fragment_tracks.is_empty() {
+            let segment = fmp4::write_media_segment(
+                fragmenter.sequence_number,
+                &fragment_tracks,
+                &self.mdat_payload,
+            )
+            .or_fail()?;
+            self.output.extend_from_slice(&segment);
+            fragmenter.sequence_number += 1;
+        }
+        self.mdat_payload.clear();
+        Ok(())
+    }
+}
+
+const NEW_TRACK_AFTER_INIT_SEGMENT_ERROR: &str =
+    "Adding a new track after the initialization segment is written is not supported";
+
+// Fragmented MP4 生成時の状態
+#[derive(Debug)]
+struct Fragmenter {
+    fragment_duration: Duration,
+
+    // 初期化セグメントの出力時に確定する、MP4 内での時刻 0 に対応するタイムスタンプ
+    base_timestamp: Option<Duration>,
+
+    // 次に出力する moof ボックスのシーケンス番号（1 始まり）
+    sequence_number: u32,
+}
+
+impl Fragmenter {
+    fn new(fragment_duration: Duration) -> Self {
+        Self {
+            fragment_duration,
+            base_timestamp: None,
+            sequence_number: 1,
+        }
+    }
+}
+
+// 追記中のトラック
+//
+// 各サンプルの尺は次のサンプルのタイムスタンプが判明するまで確定しないので、
+// タイムスタンプのまま保持しておいて、出力時にまとめて尺に変換する
+//
+// デコード時刻は、最初のサンプルのデコード時刻が表示時刻と一致するようにずらして扱う
+// (その分、以降のサンプルのコンポジション時間オフセットは負になり得るが、編集リストなしで先頭から表示できる）
+#[derive(Debug)]
+struct MuxerTrack {
+    track: MuxTrack,
+    current_sample_entry_index: Option<usize>,
+
+    // 未出力のサンプルのデコード時刻と表示時刻（track.samples と同じ順番・個数）
+    timestamps: Vec<Duration>,
+    presentation_timestamps: Vec<Duration>,
+
+    // 最初のチャンクに指定されたコンポジション時間オフセット
+    first_composition_offset: Option<Duration>,
+
+    last_timestamp: Option<Duration>,
+    last_duration: Option<Duration>,
+}
+
+impl MuxerTrack {
+    fn new(kind: TrackKind, timescale: NonZeroU32) -> Self {
+        Self {
+            track: MuxTrack::new(kind, timescale),
+            current_sample_entry_index: None,
+            timestamps: Vec::new(),
+            presentation_timestamps: Vec::new(),
+            first_composition_offset: None,
+            last_timestamp: None,
+            last_duration: None,
+        }
+    }
+
+    // frozen が true の場合には、既存のサンプルエントリーへの切り替えのみを許可する
+    fn set_sample_entry(&mut self, entry: SampleEntry, frozen: bool) -> orfail::Result<()> {
+        if frozen {
+            let i = self.track.sample_entries.iter().position(|e| *e == entry);
+            self.current_sample_entry_index = Some(i.or_fail_with(|()| {
+                "Changing decoder configuration after the initialization segment is written is not supported"
+                    .to_owned()
+            })?);
+        } else {
+            self.current_sample_entry_index = Some(self.track.add_sample_entry(entry));
+        }
+        Ok(())
+    }
+
+    // 表示時刻とコンポジション時間オフセットから、このトラック内でのデコード時刻を求める
+    fn decode_timestamp(
+        &self,
+        timestamp: Duration,
+        composition_offset: Duration,
+    ) -> orfail::Result<Duration> {
+        let first_composition_offset = self.first_composition_offset.unwrap_or(composition_offset);
+        (timestamp + first_composition_offset)
+            .checked_sub(composition_offset)
+            .or_fail_with(|()| {
+                format!(
+                    "Too large {:?} chunk composition offset: {composition_offset:?}",
+                    self.track.kind
+                )
+            })
+    }
+
+    fn append_sample(
+        &mut self,
+        timestamp: Duration,
+        composition_offset: Duration,
+        duration: Option<Duration>,
+        is_key: bool,
+        data_offset: u64,
+        data_size: u32,
+    ) -> orfail::Result<()> {
+        let sample_entry_index = self.current_sample_entry_index.or_fail()?;
+        let decode_timestamp = self
+            .decode_timestamp(timestamp, composition_offset)
+            .or_fail()?;
+        if let Some(last) = self.last_timestamp {
+            // B フレームを含む場合には、コンポジション時間オフセットの指定が必要
+            (last <= decode_timestamp).or_fail_with(|()| {
+                format!(
+                    "Non-monotonic {:?} chunk decode timestamp: {last:?} => {decode_timestamp:?}",
+                    self.track.kind
+                )
+            })?;
+        } else {
+            if self.track.kind == TrackKind::Video {
+                is_key.or_fail_with(|()| "The first video chunk must be a key frame".to_owned())?;
+            }
+            self.first_composition_offset = Some(composition_offset);
+        }
+
+        self.timestamps.push(decode_timestamp);
+        self.presentation_timestamps.push(timestamp);
+        self.last_timestamp = Some(decode_timestamp);
+        self.last_duration = duration;
+        self.track.samples.push(MuxSample {
+            sample_entry_index,
+            duration: 0,           // take_samples() の中で確定する
+            composition_offset: 0, // take_samples() の中で確定する
+            is_sync: is_key,
+            data_offset,
+            data_size,
+        });
+        Ok(())
+    }
+
+    // 未出力のサンプル群の尺を確定させて取り出す
+    //
+    // 返り値の一つ目は、base_timestamp を 0 とした場合の先頭サンプルのデコード時刻（トラックのタイムスケール単位）。
+    // next_timestamp が指定された場合には、それが最後のサンプルの終端として扱われる。
+    fn take_samples(
+        &mut self,
+        base_timestamp: Duration,
+        next_timestamp: Option<Duration>,
+    ) -> (u64, Vec<MuxSample>) {
+        let timescale = self.track.timescale;
+
+        // 丸め誤差が蓄積しないように、基準時刻からの累積時間をタイムスケール単位に変換してから差分を取る
+        let to_ticks = |t: Duration| to_timescale(t.saturating_sub(base_timestamp), timescale);
+        let ticks = self
+            .timestamps
+            .iter()
+            .map(|&t| to_ticks(t))
+            .collect::<Vec<_>>();
+        let mut samples = std::mem::take(&mut self.track.samples);
+        for (i, sample) in samples.iter_mut().enumerate() {
+            let end = if let Some(&next) = ticks.get(i + 1) {
+                next
+            } else if let Some(next) = next_timestamp {
+                to_ticks(next)
+            } else {
+                let duration = self
+                    .last_duration
+                    .filter(|d| !d.is_zero())
+                    .or_else(|| (i > 0).then(|| self.timestamps[i] - self.timestamps[i - 1]))
+                    .filter(|d| !d.is_zero())
+                    .unwrap_or(DEFAULT_LAST_SAMPLE_DURATION);
+                ticks[i] + to_timescale(duration, timescale)
+            };
+            sample.duration = end.saturating_sub(ticks[i]) as u32;
+            sample.composition_offset =
+                (to_ticks(self.presentation_timestamps[i]) as i64 - ticks[i] as i64) as i32;
+        }
+        let first_tick = ticks.first().copied().unwrap_or(0);
+        self.timestamps.clear();
+        self.presentation_timestamps.clear();
+        (first_tick, samples)
+    }
+
+    fn finish(mut self, base_timestamp: Duration) -> MuxTrack {
+        let first = self.timestamps[0];
+        let (_, samples) = self.take_samples(base_timestamp, None);
+        self.track.start_offset = first - base_timestamp;
+        self.track.samples = samples;
+        self.track
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shiguredo_mp4::{BaseBox, Decode, Mp4File};
+
+    use super::*;
+    use crate::mp4::DecoderConfig;
+
+    fn append_video(muxer: &mut Mp4Muxer, pts_ms: u64, offset_ms: u64, is_key: bool) {
+        muxer
+            .append_chunk(
+                TrackKind::Video,
+                Duration::from_millis(pts_ms),
+                Duration::from_millis(offset_ms),
+                Some(Duration::from_millis(33)),
+                is_key,
+                &[0; 10],
+            )
+            .expect("append_chunk");
+    }
+
+    fn decode_mp4(bytes: &[u8]) -> Mp4File {
+        Mp4File::decode(&mut &bytes[..]).expect("decode MP4")
+    }
+
+    fn ctts_entries(stbl_box: &StblBox) -> Option<(u8, Vec<(u32, i32)>)> {
+        let ctts_box = stbl_box
+            .unknown_boxes
+            .iter()
+            .find(|b| b.box_type == CTTS_BOX_TYPE)?;
+        let payload = &ctts_box.payload;
+        let count = read_u32(payload, 4)? as usize;
+        let entries = (0..count)
+            .map(|i| {
+                let count = read_u32(payload, 8 + i * 8).expect("sample count");
+                let offset = read_u32(payload, 12 + i * 8).expect("offset") as i32;
+                (count, offset)
+            })
+            .collect();
+        Some((payload[0], entries))
+    }
+
+    #[test]
+    fn b_frames_are_written_with_composition_offsets() {
+        let mut muxer = Mp4Muxer::new(None);
+        muxer
+            .set_video_config(&VideoDecoderConfig {
+                codec: "vp8".to_owned(),
+                description: Vec::new(),
+                coded_width: 320,
+                coded_height: 240,
+            })
+            .expect("set_video_config");
+
+        // 表示順は I0 B1 B2 P3 で、デコード順は I0 P3 B1 B2
+        append_video(&mut muxer, 0, 33, true);
+        append_video(&mut muxer, 99, 99, false);
+        append_video(&mut muxer, 33, 0, false);
+        append_video(&mut muxer, 66, 0, false);
+        muxer.finish().expect("finish");
+
+        let file = decode_mp4(&muxer.take_output());
+        let stbl_box = &file.boxes.iter().find_map(|b| match b {
+            shiguredo_mp4::boxes::RootBox::Moov(b) => Some(b.clone()),
+            _ => None,
+        });
+        let stbl_box = &stbl_box.as_ref().expect("moov").trak_boxes[0]
+            .mdia_box
+            .minf_box
+            .stbl_box;
+
+        // 先頭サンプルのデコード時刻が表示時刻と一致するようにずらされるので、オフセットは負になり得る
+        let to_ticks = |ms: i32| ms * VIDEO_TIMESCALE.get() as i32 / 1000;
+        assert_eq!(
+            ctts_entries(stbl_box),
+            Some((1, vec![(1, 0), (1, to_ticks(66)), (2, to_ticks(-33))]))
+        );
+        let deltas = stbl_box
+            .stts_box
+            .entries
+            .iter()
+            .flat_map(|e| std::iter::repeat_n(e.sample_delta, e.sample_count as usize))
+            .collect::<Vec<_>>();
+        assert_eq!(deltas, [to_ticks(33) as u32; 4]);
+    }
+
+    #[test]
+    fn non_monotonic_decode_timestamps_are_rejected() {
+        let mut muxer = Mp4Muxer::new(None);
+        muxer
+            .set_video_config(&VideoDecoderConfig {
+                codec: "vp8".to_owned(),
+                description: Vec::new(),
+                coded_width: 320,
+                coded_height: 240,
+            })
+            .expect("set_video_config");
+        append_video(&mut muxer, 33, 0, true);
+        let result = muxer.append_chunk(
+            TrackKind::Video,
+            Duration::ZERO,
+            Duration::ZERO,
+            None,
+            false,
+            &[0; 10],
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn hvc1_sample_entry_type_is_preserved() {
+        let hvcc = [
+            1, 0x01, 0x60, 0, 0, 0, 0x90, 0, 0, 0, 0, 0, 93, 0xF0, 0, 0xFC, 0xFD, 0xF8, 0xF8, 0, 0,
+            0x0F, 0,
+        ];
+        for codec in ["hvc1.1.6.L93.90", "hev1.1.6.L93.90"] {
+            let config = VideoDecoderConfig {
+                codec: codec.to_owned(),
+                description: hvcc.to_vec(),
+                coded_width: 320,
+                coded_height: 240,
+            };
+            let entry = config.to_sample_entry().expect("to_sample_entry");
+            assert_eq!(entry.box_type().as_bytes(), &codec.as_bytes()[..4]);
+
+            let Some(DecoderConfig::Video(decoded)) = DecoderConfig::from_sample_entry(&entry)
+            else {
+                panic!("not a video sample entry: {codec}");
+            };
+            assert!(decoded.codec.starts_with(&codec[..5]), "{}", decoded.codec);
+            assert_eq!((decoded.coded_width, decoded.coded_height), (320, 240));
+        }
+    }
+}
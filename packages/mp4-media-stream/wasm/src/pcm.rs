@@ -0,0 +1,386 @@
+use shiguredo_mp4::{
+    boxes::{SampleEntry, UnknownBox},
+    BoxType,
+};
+
+use crate::mp4::{find_child_box_payload, read_u16, read_u32, AudioDecoderConfig};
+
+// ISO/IEC 23003-5 の整数・浮動小数点 PCM のサンプルエントリーと、その設定を保持する pcmC ボックス
+const IPCM_BOX_TYPE: BoxType = BoxType::Normal(*b"ipcm");
+const FPCM_BOX_TYPE: BoxType = BoxType::Normal(*b"fpcm");
+const PCMC_BOX_TYPE: BoxType = BoxType::Normal(*b"pcmC");
+
+// ISO/IEC 14496-12 12.2.3 Sampling rate box
+//
+// サンプリングレートが 16 bit に収まらない場合には、こちらに実際の値が格納される
+const SRAT_BOX_TYPE: BoxType = BoxType::Normal(*b"srat");
+
+// QuickTime の PCM のサンプルエントリー
+//
+// sowt / twos はそれぞれリトルエンディアン・ビッグエンディアンの符号付き整数で、
+// lpcm はバージョン 2 のサウンドデスクリプションの中で形式が指定される
+const LPCM_BOX_TYPE: BoxType = BoxType::Normal(*b"lpcm");
+const SOWT_BOX_TYPE: BoxType = BoxType::Normal(*b"sowt");
+const TWOS_BOX_TYPE: BoxType = BoxType::Normal(*b"twos");
+
+// QuickTime のサウンドデスクリプションのバージョン毎の、子ボックスの前に置かれるフィールドのバイト数
+// (バージョン 0 のものは ISO の AudioSampleEntry と同じレイアウト）
+const SOUND_DESCRIPTION_V0_SIZE: usize = 28;
+const SOUND_DESCRIPTION_V2_SIZE: usize = 64;
+
+// lpcm の formatSpecificFlags (Core Audio の AudioStreamBasicDescription と同じ値)
+const LPCM_FLAG_IS_FLOAT: u32 = 1 << 0;
+const LPCM_FLAG_IS_BIG_ENDIAN: u32 = 1 << 1;
+
+// pcmC の format_flags で、リトルエンディアンであることを示すビット
+const PCMC_FLAG_LITTLE_ENDIAN: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PcmSampleFormat {
+    I8,
+    I16,
+    I24,
+    I32,
+    F32,
+    F64,
+}
+
+impl PcmSampleFormat {
+    fn from_bits(bits: u32, is_float: bool) -> Option<Self> {
+        match (bits, is_float) {
+            (8, false) => Some(Self::I8),
+            (16, false) => Some(Self::I16),
+            (24, false) => Some(Self::I24),
+            (32, false) => Some(Self::I32),
+            (32, true) => Some(Self::F32),
+            (64, true) => Some(Self::F64),
+            _ => None,
+        }
+    }
+
+    fn bytes(self) -> usize {
+        match self {
+            Self::I8 => 1,
+            Self::I16 => 2,
+            Self::I24 => 3,
+            Self::I32 | Self::F32 => 4,
+            Self::F64 => 8,
+        }
+    }
+
+    // WebCodecs の PCM 用のコーデック文字列に合わせた名前
+    // (ただし、実際に WebCodecs のデコーダーに渡されることはない）
+    fn codec(self) -> &'static str {
+        match self {
+            Self::I8 => "pcm-s8",
+            Self::I16 => "pcm-s16",
+            Self::I24 => "pcm-s24",
+            Self::I32 => "pcm-s32",
+            Self::F32 => "pcm-f32",
+            Self::F64 => "pcm-f64",
+        }
+    }
+}
+
+// 非圧縮 PCM の形式
+//
+// WebCodecs には PCM 用のデコーダーがないので、PCM のトラックは Rust 側で f32 に変換して
+// TypeScript 側の音声出力（AudioWorklet）に直接渡す
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PcmFormat {
+    pub sample_format: PcmSampleFormat,
+    pub little_endian: bool,
+    pub sample_rate: u32,
+    pub number_of_channels: u8,
+}
+
+impl PcmFormat {
+    // PCM 以外のサンプルエントリーや、未対応の形式の場合には None が返される
+    pub fn from_sample_entry(sample_entry: &SampleEntry) -> Option<Self> {
+        let SampleEntry::Unknown(b) = sample_entry else {
+            return None;
+        };
+        let format = match b.box_type {
+            IPCM_BOX_TYPE | FPCM_BOX_TYPE => Self::from_iso_box(b)?,
+            SOWT_BOX_TYPE | TWOS_BOX_TYPE => Self::from_quicktime_box(b)?,
+            LPCM_BOX_TYPE => Self::from_lpcm_box(b)?,
+            _ => return None,
+        };
+        (format.number_of_channels > 0 && format.sample_rate > 0).then_some(format)
+    }
+
+    // ISO/IEC 23003-5 5.1 PCM configuration
+    fn from_iso_box(b: &UnknownBox) -> Option<Self> {
+        let (sample_rate, number_of_channels) = read_sound_description_v0(&b.payload)?;
+        let children = b.payload.get(SOUND_DESCRIPTION_V0_SIZE..)?;
+        let pcmc_payload = find_child_box_payload(children, PCMC_BOX_TYPE)?;
+
+        // srat ボックスの先頭の 4 バイトもフルボックスのバージョンとフラグ
+        let sample_rate = find_child_box_payload(children, SRAT_BOX_TYPE)
+            .and_then(|payload| read_u32(payload, 4))
+            .unwrap_or(sample_rate);
+
+        // 先頭の 4 バイトはフルボックスのバージョンとフラグ
+        let &[format_flags, sample_size] = pcmc_payload.get(4..6)? else {
+            return None;
+        };
+        let is_float = b.box_type == FPCM_BOX_TYPE;
+        Some(Self {
+            sample_format: PcmSampleFormat::from_bits(sample_size.into(), is_float)?,
+            little_endian: format_flags & PCMC_FLAG_LITTLE_ENDIAN != 0,
+            sample_rate,
+            number_of_channels,
+        })
+    }
+
+    // バージョン 0 あるいは 1 のサウンドデスクリプション
+    //
+    // 量子化ビット数は samplesize フィールドの値を使う（バージョン 1 で追加されたフィールドは参照しない）
+    fn from_quicktime_box(b: &UnknownBox) -> Option<Self> {
+        let version = read_u16(&b.payload, 8)?;
+        (version <= 1).then_some(())?;
+        let (sample_rate, number_of_channels) = read_sound_description_v0(&b.payload)?;
+        let sample_size = read_u16(&b.payload, 18)?;
+        Some(Self {
+            sample_format: PcmSampleFormat::from_bits(sample_size.into(), false)?,
+            little_endian: b.box_type == SOWT_BOX_TYPE,
+            sample_rate,
+            number_of_channels,
+        })
+    }
+
+    // QuickTime File Format "Sound Sample Description (Version 2)"
+    fn from_lpcm_box(b: &UnknownBox) -> Option<Self> {
+        let version = read_u16(&b.payload, 8)?;
+        (version == 2 && b.payload.len() >= SOUND_DESCRIPTION_V2_SIZE).then_some(())?;
+        let sample_rate = f64::from_be_bytes(b.payload[32..40].try_into().ok()?);
+        let number_of_channels = read_u32(&b.payload, 40)?;
+        let bits_per_channel = read_u32(&b.payload, 48)?;
+        let flags = read_u32(&b.payload, 52)?;
+
+        (sample_rate.fract() == 0.0).then_some(())?;
+        Some(Self {
+            sample_format: PcmSampleFormat::from_bits(
+                bits_per_channel,
+                flags & LPCM_FLAG_IS_FLOAT != 0,
+            )?,
+            little_endian: flags & LPCM_FLAG_IS_BIG_ENDIAN == 0,
+            sample_rate: u32::try_from(sample_rate as u64).ok()?,
+            number_of_channels: u8::try_from(number_of_channels).ok()?,
+        })
+    }
+
+    // PCM の 1 フレーム（全チャンネル分のサンプル）のバイト数
+    pub fn bytes_per_frame(&self) -> usize {
+        self.sample_format.bytes() * self.number_of_channels as usize
+    }
+
+    pub fn to_decoder_config(&self) -> AudioDecoderConfig {
+        AudioDecoderConfig {
+            codec: self.sample_format.codec().to_owned(),
+            sample_rate: self.sample_rate,
+            number_of_channels: self.number_of_channels,
+            description: Vec::new(),
+        }
+    }
+
+    // PCM データを、AudioData.copyTo() で format='f32' を指定した場合と同じ、
+    // チャンネルがインターリーブされた f32 のサンプル列に変換する
+    //
+    // 末尾にフレームに満たない端数がある場合には、その部分は無視される
+    pub fn convert(&self, data: &[u8], output: &mut Vec<f32>) {
+        let sample_bytes = self.sample_format.bytes();
+        let frames = data.len() / self.bytes_per_frame();
+        let samples = &data[..frames * self.bytes_per_frame()];
+        output.reserve(frames * self.number_of_channels as usize);
+        for sample in samples.chunks_exact(sample_bytes) {
+            output.push(self.convert_sample(sample));
+        }
+    }
+
+    fn convert_sample(&self, sample: &[u8]) -> f32 {
+        // ビッグエンディアンに揃えてから解釈する
+        let mut bytes = [0; 8];
+        let bytes = &mut bytes[..sample.len()];
+        bytes.copy_from_slice(sample);
+        if self.little_endian {
+            bytes.reverse();
+        }
+
+        match self.sample_format {
+            PcmSampleFormat::I8 => bytes[0] as i8 as f32 / 128.0,
+            PcmSampleFormat::I16 => i16::from_be_bytes([bytes[0], bytes[1]]) as f32 / 32768.0,
+            PcmSampleFormat::I24 => {
+                // 上位 24 bit に詰めて i32 として扱う
+                i32::from_be_bytes([bytes[0], bytes[1], bytes[2], 0]) as f32 / 2147483648.0
+            }
+            PcmSampleFormat::I32 => {
+                i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f32 / 2147483648.0
+            }
+            PcmSampleFormat::F32 => f32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            PcmSampleFormat::F64 => f64::from_be_bytes([
+                bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7],
+            ]) as f32,
+        }
+    }
+}
+
+// バージョン 0 のサウンドデスクリプション（AudioSampleEntry と共通部分）からサンプリングレートとチャンネル数を読み込む
+fn read_sound_description_v0(payload: &[u8]) -> Option<(u32, u8)> {
+    (payload.len() >= SOUND_DESCRIPTION_V0_SIZE).then_some(())?;
+    let number_of_channels = read_u16(payload, 16)?;
+    let sample_rate = read_u16(payload, 24)?;
+    Some((sample_rate.into(), u8::try_from(number_of_channels).ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use shiguredo_mp4::BoxSize;
+
+    use super::*;
+    use PcmSampleFormat::*;
+
+    fn unknown_box(box_type: BoxType, payload: Vec<u8>) -> SampleEntry {
+        SampleEntry::Unknown(UnknownBox {
+            box_type,
+            box_size: BoxSize::with_payload_size(box_type, payload.len() as u64),
+            payload,
+        })
+    }
+
+    fn child_box(box_type: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        [
+            &(8 + payload.len() as u32).to_be_bytes()[..],
+            box_type,
+            payload,
+        ]
+        .concat()
+    }
+
+    // バージョン 0 のサウンドデスクリプション（ISO の AudioSampleEntry と共通部分）
+    fn sound_description_v0(version: u16, channels: u16, bits: u16, sample_rate: u16) -> Vec<u8> {
+        let mut payload = vec![0; SOUND_DESCRIPTION_V0_SIZE];
+        payload[8..10].copy_from_slice(&version.to_be_bytes());
+        payload[16..18].copy_from_slice(&channels.to_be_bytes());
+        payload[18..20].copy_from_slice(&bits.to_be_bytes());
+        payload[24..26].copy_from_slice(&sample_rate.to_be_bytes());
+        payload
+    }
+
+    fn lpcm_sample_entry(sample_rate: f64) -> SampleEntry {
+        let mut payload = sound_description_v0(2, 3, 16, 1);
+        payload.resize(SOUND_DESCRIPTION_V2_SIZE, 0);
+        payload[32..40].copy_from_slice(&sample_rate.to_be_bytes());
+        payload[40..44].copy_from_slice(&2u32.to_be_bytes());
+        payload[48..52].copy_from_slice(&24u32.to_be_bytes());
+        unknown_box(LPCM_BOX_TYPE, payload)
+    }
+
+    fn ipcm_sample_entry(sample_rate: u16, srat: Option<u32>) -> SampleEntry {
+        let mut payload = sound_description_v0(0, 2, 16, sample_rate);
+        payload.extend(child_box(
+            b"pcmC",
+            &[0, 0, 0, 0, PCMC_FLAG_LITTLE_ENDIAN, 32],
+        ));
+        if let Some(srat) = srat {
+            payload.extend(child_box(
+                b"srat",
+                &[&[0; 4][..], &srat.to_be_bytes()].concat(),
+            ));
+        }
+        unknown_box(IPCM_BOX_TYPE, payload)
+    }
+
+    #[test]
+    fn sample_rates_above_u16_are_supported() {
+        let cases = [
+            (lpcm_sample_entry(44_100.0), Some((I24, 44_100))),
+            (lpcm_sample_entry(96_000.0), Some((I24, 96_000))),
+            (lpcm_sample_entry(192_000.0), Some((I24, 192_000))),
+            (lpcm_sample_entry(44_100.5), None),
+            (ipcm_sample_entry(48_000, None), Some((I32, 48_000))),
+            (ipcm_sample_entry(0, Some(192_000)), Some((I32, 192_000))),
+            (
+                unknown_box(SOWT_BOX_TYPE, sound_description_v0(0, 2, 16, 44_100)),
+                Some((I16, 44_100)),
+            ),
+        ];
+        for (entry, expected) in cases {
+            let actual = PcmFormat::from_sample_entry(&entry);
+            assert_eq!(
+                actual.map(|f| (f.sample_format, f.sample_rate)),
+                expected,
+                "{entry:?}"
+            );
+            if let Some(format) = actual {
+                assert_eq!(format.to_decoder_config().sample_rate, format.sample_rate);
+            }
+        }
+    }
+
+    // 既存の出力の末尾に追加されることも合わせて確認するために、ダミーの値を一つ入れた状態で変換する
+    fn convert(
+        sample_format: PcmSampleFormat,
+        little_endian: bool,
+        number_of_channels: u8,
+        data: &[u8],
+    ) -> Vec<f32> {
+        let format = PcmFormat {
+            sample_format,
+            little_endian,
+            sample_rate: 48_000,
+            number_of_channels,
+        };
+        let mut output = vec![1.0];
+        format.convert(data, &mut output);
+        assert_eq!(output[0], 1.0);
+        output.split_off(1)
+    }
+
+    #[test]
+    fn integer_samples_are_scaled_to_unit_range() {
+        assert_eq!(convert(I8, false, 1, &[0x80, 0x00, 0x40]), [-1.0, 0.0, 0.5]);
+        assert_eq!(
+            convert(I16, false, 1, &[0x80, 0x00, 0x40, 0x00]),
+            [-1.0, 0.5]
+        );
+        assert_eq!(convert(I24, false, 1, &[0xC0, 0x00, 0x00]), [-0.5]);
+        assert_eq!(convert(I32, false, 1, &[0x80, 0, 0, 0]), [-1.0]);
+
+        // リトルエンディアンの場合は、バイト順を逆にしたものと同じ値になる
+        for (sample_format, big_endian) in [
+            (I16, &[0x40, 0x00][..]),
+            (I16, &[0x80, 0x00]),
+            (I24, &[0x40, 0x00, 0x00]),
+            (I32, &[0x40, 0, 0, 0]),
+        ] {
+            let little_endian = big_endian.iter().rev().copied().collect::<Vec<_>>();
+            assert_eq!(
+                convert(sample_format, true, 1, &little_endian),
+                convert(sample_format, false, 1, big_endian),
+                "{sample_format:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn float_samples_are_kept_as_is() {
+        assert_eq!(convert(F32, false, 1, &0.25f32.to_be_bytes()), [0.25]);
+        assert_eq!(convert(F32, true, 1, &(-0.25f32).to_le_bytes()), [-0.25]);
+        assert_eq!(convert(F64, false, 1, &0.75f64.to_be_bytes()), [0.75]);
+        assert_eq!(convert(F64, true, 1, &0.75f64.to_le_bytes()), [0.75]);
+    }
+
+    #[test]
+    fn only_whole_frames_are_converted_in_interleaved_order() {
+        let data = [0x00, 0x40, 0x00, 0xC0, 0x00, 0x00, 0x00, 0x80];
+        assert_eq!(convert(I16, true, 2, &data), [0.5, -0.5, 0.0, -1.0]);
+
+        // 1 フレームに満たない末尾の端数は無視される
+        assert_eq!(
+            convert(I16, false, 2, &[0x40, 0x00, 0xC0, 0x00, 0x40]),
+            [0.5, -0.5]
+        );
+        assert!(convert(I24, false, 2, &[0x40, 0x00, 0x00]).is_empty());
+    }
+}
@@ -1,18 +1,79 @@
-use std::{num::NonZeroU32, rc::Rc, time::Duration};
+use std::{
+    cell::{Cell, RefCell},
+    num::NonZeroU32,
+    rc::Rc,
+    time::Duration,
+};
 
 use serde::Deserialize;
 use shiguredo_mp4::{
     aux::{SampleAccessor, SampleTableAccessor},
-    boxes::{SampleEntry, StblBox},
+    boxes::StblBox,
 };
 
 use crate::{
-    mp4::{AudioDecoderConfig, Track, VideoDecoderConfig},
+    mp4::{DecoderConfig, Track, TrackKind},
+    pcm::PcmFormat,
+    stats::PlayerStats,
+    subtitle::SubtitleCue,
     wasm::{DecoderId, WasmApi},
 };
 
 pub type PlayerId = u32;
 
+// 再生位置を TypeScript 側に通知する間隔
+// (HTMLMediaElement の timeupdate イベントの最大間隔に合わせている）
+const TIME_UPDATE_INTERVAL: Duration = Duration::from_millis(250);
+
+// 映像トラックの再生がこれ以上遅れた場合には、次の同期サンプルまでスキップして追いつく
+// (タブがバックグラウンドにあってタイマーが間引かれた場合などを想定している）
+const CATCH_UP_THRESHOLD: Duration = Duration::from_millis(500);
+
+// PCM のサンプル群をまとめて音声出力に渡す際の尺の上限
+// (AAC の 1 フレーム分程度にしておけば、他のコーデックと同じ間隔で処理できる）
+const PCM_BATCH_DURATION: Duration = Duration::from_millis(20);
+
+// 音声の再生位置と映像のスケジューリングに使う時計のズレがこれ以上になったら、
+// 徐々に補正するのではなく、一気に音声側の時計に合わせる
+const AUDIO_CLOCK_RESYNC_THRESHOLD: Duration = Duration::from_millis(200);
+
+// 音声の再生位置と時計のズレがこれ以下なら補正しない（通知のジッターを無視するため）
+const AUDIO_CLOCK_DEADBAND: Duration = Duration::from_millis(2);
+
+// 一回の補正でズレの何分の一を解消するか
+const AUDIO_CLOCK_SLEW_DIVISOR: u32 = 16;
+
+// これ以上古い音声の再生位置の通知は無視する（音声の再生が止まっているとみなす）
+const AUDIO_CLOCK_STALE_THRESHOLD: Duration = Duration::from_secs(1);
+
+// 最初の通知を受け取ってからこの期間は補正を行わずに、音声の出力経路の遅延の計測のみを行う
+const AUDIO_LATENCY_CALIBRATION_PERIOD: Duration = Duration::from_secs(1);
+
+// TypeScript 側（AudioWorklet）から通知された音声の再生位置
+#[derive(Debug, Clone, Copy)]
+pub struct AudioClockReport {
+    // 再生中の音声データのタイムスタンプ（繰り返し再生時のオフセットを含む）
+    pub media_time: Duration,
+
+    // 通知を受け取った時刻（WasmApi::now() 基準）
+    pub reported_at: Duration,
+}
+
+// 音声の出力経路（デコーダーや AudioWorklet のバッファ）による遅延の計測結果
+//
+// 音声データは映像と同じスケジュールでデコーダーに渡されるので、
+// 通知される再生位置は常にこの遅延分だけ経過時間よりも遅れている
+#[derive(Debug, Clone, Copy)]
+struct AudioLatency {
+    // 計測期間中に観測された、経過時間に対する音声の再生位置の遅れの最小値（マイクロ秒）
+    //
+    // デコーダーの起動直後などの一時的な遅れを含めないように、平均ではなく最小値を使う
+    lag_micros: i64,
+
+    // 計測期間の終了時刻（WasmApi::now() 基準）
+    calibration_end: Duration,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PlayOptions {
@@ -20,6 +81,48 @@ pub struct PlayOptions {
     pub repeat: bool,
 }
 
+// デコーダーに渡したタイムスタンプ（繰り返し再生の場合には周回毎のオフセットを含む）を、ファイル先頭からの位置に変換する
+//
+// タイムスタンプはマイクロ秒単位に切り捨てて TypeScript 側に渡されるので、
+// 周回の先頭の 1 マイクロ秒手前までは、その周回の先頭とみなす
+pub fn media_time(timestamp: Duration, file_duration: Duration) -> Duration {
+    if file_duration.is_zero() {
+        return timestamp;
+    }
+    let loops = (timestamp + Duration::from_micros(1)).as_nanos() / file_duration.as_nanos();
+    timestamp.saturating_sub(file_duration * loops as u32)
+}
+
+// Engine と Player の間で共有される状態
+//
+// Engine 側はこれを経由して、再生中のプレイヤーの状態の参照や音声の再生位置の通知を行う
+#[derive(Debug, Clone)]
+pub struct PlayerShared {
+    // ファイル内での現在の再生位置
+    pub current_time: Rc<Cell<Duration>>,
+
+    pub stats: Rc<RefCell<PlayerStats>>,
+
+    // TypeScript 側から最後に通知された音声の再生位置
+    pub audio_clock: Rc<Cell<Option<AudioClockReport>>>,
+
+    // 全ての字幕トラックのキューを開始時刻順に並べたもの
+    pub subtitle_cues: Rc<Vec<SubtitleCue>>,
+}
+
+impl PlayerShared {
+    pub fn new(tracks: &[Track], subtitle_cues: Rc<Vec<SubtitleCue>>) -> Self {
+        Self {
+            current_time: Rc::new(Cell::new(Duration::ZERO)),
+            stats: Rc::new(RefCell::new(PlayerStats::new(
+                tracks.iter().map(|t| t.kind),
+            ))),
+            audio_clock: Rc::new(Cell::new(None)),
+            subtitle_cues,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Player {
     player_id: PlayerId,
@@ -28,6 +131,12 @@ pub struct Player {
     tracks: Vec<TrackPlayer>,
     repeat: bool,
     timestamp_offset: Duration,
+    shared: PlayerShared,
+    last_time_update: Option<Duration>,
+    audio_latency: Option<AudioLatency>,
+
+    // 次に TypeScript 側に渡す字幕のキューの位置
+    next_cue_index: usize,
 }
 
 impl Player {
@@ -36,6 +145,7 @@ impl Player {
         options: PlayOptions,
         mp4_bytes: Rc<Vec<u8>>,
         tracks: &[Track],
+        shared: PlayerShared,
     ) -> Self {
         Self {
             player_id,
@@ -43,6 +153,10 @@ impl Player {
             mp4_bytes,
             repeat: options.repeat,
             timestamp_offset: Duration::ZERO,
+            shared,
+            last_time_update: None,
+            audio_latency: None,
+            next_cue_index: 0,
             tracks: tracks
                 .iter()
                 .map(|track| TrackPlayer::new(player_id, track))
@@ -56,14 +170,103 @@ impl Player {
         WasmApi::now().saturating_sub(self.start_time)
     }
 
-    // 再生時刻が一番近いサンプルのタイムスタンプを返す
+    // 音声トラックが存在する場合には、映像のスケジューリングに使う時計（start_time）を
+    // TypeScript 側から通知された音声の再生位置に追従させる
+    //
+    // AudioWorklet は AudioContext の時計で動いているため、WasmApi::now() だけを基準にしていると
+    // 長時間の再生で映像と音声のズレが蓄積してしまう
+    //
+    // 補正の対象は、再生開始直後に計測した出力経路の遅延を差し引いた分のズレのみとする
+    // (遅延そのものを補正しようとすると、start_time を遅らせるたびに音声の供給も遅れて、
+    // 遅延が縮まらないまま再生がどんどん遅くなってしまう）
+    fn sync_with_audio_clock(&mut self, now: Duration) {
+        let Some(report) = self.shared.audio_clock.get() else {
+            return;
+        };
+        if now.saturating_sub(report.reported_at) > AUDIO_CLOCK_STALE_THRESHOLD {
+            return;
+        }
+        let Some(audio_position) = report.media_time.checked_sub(self.timestamp_offset) else {
+            // 繰り返し再生の前の周回の通知なので無視する
+            return;
+        };
+
+        // 通知を受け取ってからの経過時間分は WasmApi::now() で補う
+        let audio_elapsed = audio_position + now.saturating_sub(report.reported_at);
+        let elapsed = now.saturating_sub(self.start_time);
+        let lag_micros = elapsed.as_micros() as i64 - audio_elapsed.as_micros() as i64;
+        let latency_micros = match &mut self.audio_latency {
+            None => {
+                self.audio_latency = Some(AudioLatency {
+                    lag_micros,
+                    calibration_end: now + AUDIO_LATENCY_CALIBRATION_PERIOD,
+                });
+                return;
+            }
+            Some(latency) if now < latency.calibration_end => {
+                latency.lag_micros = latency.lag_micros.min(lag_micros);
+                return;
+            }
+            Some(latency) => latency.lag_micros,
+        };
+        let drift_micros = lag_micros - latency_micros;
+        let drift = Duration::from_micros(drift_micros.unsigned_abs());
+        let audio_is_ahead = drift_micros < 0;
+
+        let correction = if drift >= AUDIO_CLOCK_RESYNC_THRESHOLD {
+            self.shared.stats.borrow_mut().on_audio_clock_resync();
+            drift
+        } else if drift > AUDIO_CLOCK_DEADBAND {
+            drift / AUDIO_CLOCK_SLEW_DIVISOR
+        } else {
+            Duration::ZERO
+        };
+        self.shared.stats.borrow_mut().on_audio_clock_drift(drift);
+
+        if audio_is_ahead {
+            self.start_time = self.start_time.saturating_sub(correction);
+        } else {
+            self.start_time += correction;
+        }
+    }
+
+    // 再生時刻が一番近いサンプル（あるいは字幕のキュー）のタイムスタンプを返す
     // 全てのトラックが終端に達している場合には None が返される
+    //
+    // 字幕のキューは音声・映像トラックが終端に達するまでの間のみ対象となる
     fn next_timestamp(&self) -> Option<Duration> {
-        self.tracks
+        let next = self
+            .tracks
             .iter()
             .filter(|t| !t.eos())
             .map(|t| t.current_timestamp())
-            .min()
+            .min()?;
+        Some(match self.shared.subtitle_cues.get(self.next_cue_index) {
+            Some(cue) => next.min(cue.start),
+            None => next,
+        })
+    }
+
+    // ファイル内での現在の再生位置を返す
+    fn position(&self) -> Duration {
+        self.elapsed().min(self.file_duration())
+    }
+
+    // 再生位置を更新して、前回の通知から一定時間が経過していれば TypeScript 側にも伝える
+    fn update_current_time(&mut self, force_notify: bool) {
+        let position = self.position();
+        self.shared.current_time.set(position);
+
+        let now = WasmApi::now();
+        if !force_notify
+            && self
+                .last_time_update
+                .is_some_and(|t| now.saturating_sub(t) < TIME_UPDATE_INTERVAL)
+        {
+            return;
+        }
+        self.last_time_update = Some(now);
+        WasmApi::notify_time_update(self.player_id, position);
     }
 
     // ファイル全体の尺を返す
@@ -77,14 +280,18 @@ impl Player {
 
     pub async fn run(mut self) {
         loop {
-            while let Some(wait) = self
-                .next_timestamp()
-                .map(|t| t.saturating_sub(self.elapsed()))
-            {
+            while let Some(next_timestamp) = self.next_timestamp() {
                 // 次のサンプルのタイムスタンプまで待つ
+                let wait = next_timestamp.saturating_sub(self.elapsed());
                 WasmApi::sleep(wait).await;
+                self.shared
+                    .stats
+                    .borrow_mut()
+                    .on_sleep(self.elapsed().saturating_sub(next_timestamp));
+                self.sync_with_audio_clock(WasmApi::now());
 
                 self.run_one().await;
+                self.update_current_time(false);
             }
 
             if !self.repeat {
@@ -94,122 +301,279 @@ impl Player {
             // 繰り返し再生を行う場合には、開始時刻を調整する
             self.timestamp_offset += self.file_duration();
             self.start_time = WasmApi::now();
+            self.shared.audio_clock.set(None);
+            self.audio_latency = None;
+            self.next_cue_index = 0;
             for track in &mut self.tracks {
                 track.current_sample_index = NonZeroU32::MIN;
             }
         }
 
+        // 終端に達した時点の再生位置は必ず通知する
+        self.update_current_time(true);
         WasmApi::notify_eos(self.player_id);
     }
 
     async fn run_one(&mut self) {
         let now = self.elapsed();
+        self.output_due_cues(now);
 
-        for track in &mut self.tracks {
-            track.maybe_setup_decoder().await;
-        }
-
-        for track in &self.tracks {
-            if track.eos() || now < track.current_timestamp() {
-                continue;
+        // 音声は常に全てのサンプルを再生し、映像の方を音声の再生時刻に追いつかせる
+        for (i, track) in self.tracks.iter_mut().enumerate() {
+            if let Some(skipped) = track.maybe_catch_up(now) {
+                self.shared.stats.borrow_mut().on_catch_up(i, skipped);
             }
-            self.decode_sample(track);
-            break;
         }
 
-        for track in &mut self.tracks {
-            if track.eos() || now < track.current_timestamp() {
-                continue;
+        for (i, track) in self.tracks.iter_mut().enumerate() {
+            if let Some(decoder) = track.maybe_setup_decoder().await {
+                self.shared
+                    .stats
+                    .borrow_mut()
+                    .on_decoder_configured(i, decoder);
             }
+        }
 
-            track.current_sample_index = track.current_sample_index.saturating_add(1);
+        let Some((i, consumed)) = self
+            .tracks
+            .iter()
+            .enumerate()
+            .find(|(_, track)| !track.eos() && track.current_timestamp() <= now)
+            .map(|(i, track)| (i, self.decode_sample(i, track, now)))
+        else {
             return;
+        };
+        let track = &mut self.tracks[i];
+        track.current_sample_index = track.current_sample_index.saturating_add(consumed);
+    }
+
+    // 開始時刻に達した字幕のキューを TypeScript 側に渡す
+    //
+    // 再生の遅れなどで、渡す前に表示の終了時刻を過ぎてしまったキューは読み飛ばす
+    fn output_due_cues(&mut self, now: Duration) {
+        while let Some(cue) = self.shared.subtitle_cues.get(self.next_cue_index) {
+            if cue.start > now {
+                break;
+            }
+            if cue.end > now {
+                WasmApi::output_cue(self.player_id, cue);
+            }
+            self.next_cue_index += 1;
         }
     }
 
-    fn decode_sample(&self, track: &TrackPlayer) {
+    // 処理したサンプルの数を返す（PCM 以外の場合は常に 1）
+    fn decode_sample(&self, track_index: usize, track: &TrackPlayer, now: Duration) -> u32 {
+        if let Some(format) = &track.pcm_format {
+            return self.output_pcm_samples(track_index, track, format, now);
+        }
+
         let sample = track.current_sample();
         let data = &self.mp4_bytes[sample.data_offset() as usize..][..sample.data_size() as usize];
 
+        let timestamp = track.current_timestamp();
+        let end_timestamp =
+            timestamp + Duration::from_secs(sample.duration() as u64) / track.timescale.get();
+        self.shared.stats.borrow_mut().on_sample_submitted(
+            track_index,
+            data.len(),
+            now.saturating_sub(timestamp),
+            timestamp + self.timestamp_offset,
+            WasmApi::now(),
+            self.start_time + end_timestamp,
+        );
+
         // Rust 側で関与するのはデコードのトリガーを引くところまでで、その先の処理は TypeScript 側で行われる
         WasmApi::decode(
             self.player_id,
             track.decoder.expect("unreachable"),
             track.timescale,
-            self.timestamp_offset,
+            self.timestamp_offset + track.start_offset,
             sample,
             data,
         );
+        1
+    }
+
+    // PCM の場合にはデコーダーを使わずに、f32 に変換したデータを TypeScript 側の音声出力に直接渡す
+    //
+    // PCM では 1 フレーム毎に 1 サンプルとなっていることが多いので、
+    // ファイル内で連続しているサンプル群を PCM_BATCH_DURATION 分までまとめて処理する
+    fn output_pcm_samples(
+        &self,
+        track_index: usize,
+        track: &TrackPlayer,
+        format: &PcmFormat,
+        now: Duration,
+    ) -> u32 {
+        let first = track.current_sample();
+        let batch_duration =
+            PCM_BATCH_DURATION.as_micros() as u64 * track.timescale.get() as u64 / 1_000_000;
+        let mut count = 1;
+        let mut end_offset = first.data_offset() + first.data_size() as u64;
+        let mut duration = first.duration() as u64;
+        while let Some(next) = NonZeroU32::new(first.index().get() + count)
+            .and_then(|i| track.sample_table.get_sample(i))
+        {
+            if duration >= batch_duration
+                || next.data_offset() != end_offset
+                || next.chunk().sample_entry() != first.chunk().sample_entry()
+            {
+                break;
+            }
+            end_offset += next.data_size() as u64;
+            duration += next.duration() as u64;
+            count += 1;
+        }
+        let data = &self.mp4_bytes[first.data_offset() as usize..end_offset as usize];
+
+        let timestamp = track.current_timestamp();
+        let end_timestamp = timestamp + Duration::from_secs(duration) / track.timescale.get();
+        self.shared.stats.borrow_mut().on_sample_submitted(
+            track_index,
+            data.len(),
+            now.saturating_sub(timestamp),
+            timestamp + self.timestamp_offset,
+            WasmApi::now(),
+            self.start_time + end_timestamp,
+        );
+
+        let mut samples = Vec::new();
+        format.convert(data, &mut samples);
+        WasmApi::output_audio(self.player_id, timestamp + self.timestamp_offset, &samples);
+        count
     }
 }
 
 #[derive(Debug, Clone)]
 struct TrackPlayer {
     player_id: PlayerId,
+    kind: TrackKind,
     sample_table: Rc<SampleTableAccessor<StblBox>>,
     decoder: Option<DecoderId>,
+
+    // 現在のサンプルエントリーが PCM の場合には、デコーダーの代わりにこちらが使われる
+    pcm_format: Option<PcmFormat>,
+
     timescale: NonZeroU32,
     current_sample_index: NonZeroU32,
+
+    // ファイル先頭から、このトラックの最初のサンプルまでの空白時間
+    start_offset: Duration,
 }
 
 impl TrackPlayer {
     fn new(player_id: PlayerId, track: &Track) -> orfail::Result<Self> {
         Ok(TrackPlayer {
             player_id,
+            kind: track.kind,
             sample_table: track.sample_table.clone(),
             decoder: None,
+            pcm_format: None,
             timescale: track.timescale,
             current_sample_index: NonZeroU32::MIN,
+            start_offset: track.start_offset(),
         })
     }
 
-    async fn maybe_setup_decoder(&mut self) {
-        if self.eos() {
-            return;
+    // 再生時刻に対して遅れすぎている映像トラックを、次の同期サンプルまでスキップさせる
+    //
+    // スキップを行った場合には、読み飛ばしたサンプルの数を返す
+    fn maybe_catch_up(&mut self, now: Duration) -> Option<u32> {
+        if self.kind != TrackKind::Video || self.eos() {
+            return None;
         }
-        if self.decoder.is_some() && !self.is_sample_entry_changed() {
-            return;
+        if now.saturating_sub(self.current_timestamp()) < CATCH_UP_THRESHOLD {
+            return None;
         }
 
-        if let Some(decoder) = self.decoder {
-            WasmApi::close_decoder(self.player_id, decoder);
+        let target = self.catch_up_target(now)?;
+        if target.get() + 1 >= self.sample_table.sample_count() {
+            // 終端に達してしまう場合にはスキップしない
+            return None;
         }
 
-        let decoder = match self.current_sample().chunk().sample_entry() {
-            SampleEntry::Avc1(b) => {
-                let config = VideoDecoderConfig::from_avc1_box(b);
-                WasmApi::create_video_decoder(self.player_id, config).await
-            }
-            SampleEntry::Hev1(b) => {
-                let config = VideoDecoderConfig::from_hev1_box(b);
-                WasmApi::create_video_decoder(self.player_id, config).await
-            }
-            SampleEntry::Vp08(b) => {
-                let config = VideoDecoderConfig::from_vp08_box(b);
-                WasmApi::create_video_decoder(self.player_id, config).await
-            }
-            SampleEntry::Vp09(b) => {
-                let config = VideoDecoderConfig::from_vp09_box(b);
-                WasmApi::create_video_decoder(self.player_id, config).await
-            }
-            SampleEntry::Av01(b) => {
-                let config = VideoDecoderConfig::from_av01_box(b);
-                WasmApi::create_video_decoder(self.player_id, config).await
+        let prev_sample_entry = self.current_sample().chunk().sample_entry();
+        let target_sample_entry = self
+            .sample_table
+            .get_sample(target)
+            .expect("unreachable")
+            .chunk()
+            .sample_entry();
+        if let Some(decoder) = self.decoder {
+            if prev_sample_entry == target_sample_entry {
+                // デコード待ちのフレームを破棄する
+                WasmApi::reset_decoder(self.player_id, decoder);
+            } else {
+                // サンプルエントリーが変わる場合には、次の maybe_setup_decoder() で作り直してもらう
+                WasmApi::close_decoder(self.player_id, decoder);
+                self.decoder = None;
             }
-            SampleEntry::Opus(b) => {
-                let config = AudioDecoderConfig::from_opus_box(b);
-                WasmApi::create_audio_decoder(self.player_id, config).await
+        }
+
+        let skipped = target.get() - self.current_sample_index.get();
+        self.current_sample_index = target;
+        Some(skipped)
+    }
+
+    // スキップ先の同期サンプルを決定する
+    //
+    // 再生時刻以前の最後の同期サンプルが現在位置より先にあればそれを、
+    // そうでなければ現在位置の次の同期サンプルを対象とする
+    fn catch_up_target(&self, now: Duration) -> Option<NonZeroU32> {
+        let now = now.saturating_sub(self.start_offset);
+        let now_timestamp = (now.as_micros() * self.timescale.get() as u128 / 1_000_000) as u64;
+        let sample = self
+            .sample_table
+            .get_sample_by_timestamp(now_timestamp)
+            .or_else(|| self.sample_table.samples().last())?;
+        if let Some(sync_sample) = sample.sync_sample() {
+            if sync_sample.index() > self.current_sample_index {
+                return Some(sync_sample.index());
             }
-            SampleEntry::Mp4a(b) => {
-                let config = AudioDecoderConfig::from_mp4a_box(b);
+        }
+
+        let stss_box = self.sample_table.stbl_box().stss_box.as_ref()?;
+        stss_box
+            .sample_numbers
+            .iter()
+            .copied()
+            .find(|&i| i > self.current_sample_index)
+    }
+
+    // デコーダーを新たに生成した場合にはその ID を返す
+    async fn maybe_setup_decoder(&mut self) -> Option<DecoderId> {
+        if self.eos() {
+            return None;
+        }
+        if (self.decoder.is_some() || self.pcm_format.is_some()) && !self.is_sample_entry_changed()
+        {
+            return None;
+        }
+
+        if let Some(decoder) = self.decoder.take() {
+            WasmApi::close_decoder(self.player_id, decoder);
+        }
+
+        self.pcm_format =
+            PcmFormat::from_sample_entry(self.current_sample().chunk().sample_entry());
+        if self.pcm_format.is_some() {
+            return None;
+        }
+
+        // MP4::load() の中で非対応コーデックのチェックは行っているので、失敗することはない
+        let config = DecoderConfig::from_sample_entry(self.current_sample().chunk().sample_entry())
+            .expect("unreachable");
+        let decoder = match config {
+            DecoderConfig::Audio(config) => {
                 WasmApi::create_audio_decoder(self.player_id, config).await
             }
-            _ => {
-                // MP4::load() の中で非対応コーデックのチェックは行っているので、ここに来ることはない
-                unreachable!()
+            DecoderConfig::Video(config) => {
+                WasmApi::create_video_decoder(self.player_id, config).await
             }
         };
         self.decoder = Some(decoder);
+        Some(decoder)
     }
 
     fn is_sample_entry_changed(&self) -> bool {
@@ -228,7 +592,7 @@ impl TrackPlayer {
         prev_sample.chunk().sample_entry() != current_sample.chunk().sample_entry()
     }
 
-    fn current_sample(&self) -> SampleAccessor<StblBox> {
+    fn current_sample(&self) -> SampleAccessor<'_, StblBox> {
         self.sample_table
             .get_sample(self.current_sample_index)
             .expect("unreachable")
@@ -236,16 +600,18 @@ impl TrackPlayer {
 
     fn current_timestamp(&self) -> Duration {
         let sample = self.current_sample();
-        Duration::from_secs(sample.timestamp()) / self.timescale.get()
+        self.start_offset + Duration::from_secs(sample.timestamp()) / self.timescale.get()
     }
 
     fn duration(&self) -> Duration {
         let last = self.sample_table.samples().last().expect("unreachable");
-        Duration::from_secs(last.timestamp() + last.duration() as u64) / self.timescale.get()
+        self.start_offset
+            + Duration::from_secs(last.timestamp() + last.duration() as u64) / self.timescale.get()
     }
 
     fn eos(&self) -> bool {
-        self.current_sample_index.get() + 1 == self.sample_table.sample_count()
+        // PCM の場合には複数のサンプルをまとめて進めることがあるので、等値ではなく大小で比較する
+        self.current_sample_index.get() + 1 >= self.sample_table.sample_count()
     }
 }
 
@@ -256,3 +622,258 @@ impl Drop for TrackPlayer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mp4::{AudioDecoderConfig, VideoDecoderConfig},
+        muxer::{MuxSample, MuxTrack},
+    };
+
+    // テスト用のトラックのサンプル数（各サンプルの尺は 100 ms）
+    const SAMPLE_COUNT: u32 = 20;
+
+    // sync_samples には 1 始まりのサンプル番号を指定する
+    //
+    // entry_change_at が指定された場合には、そのサンプル以降のサンプルエントリーが切り替わる
+    fn make_track(kind: TrackKind, sync_samples: &[u32], entry_change_at: Option<u32>) -> Track {
+        let video_entry = |coded_width| {
+            VideoDecoderConfig {
+                codec: "vp8".to_owned(),
+                description: Vec::new(),
+                coded_width,
+                coded_height: 240,
+            }
+            .to_sample_entry()
+            .expect("failed to make sample entry")
+        };
+        let mut mux_track = MuxTrack::new(kind, NonZeroU32::new(1000).expect("unreachable"));
+        let first_entry = match kind {
+            TrackKind::Audio => mux_track.add_sample_entry(
+                AudioDecoderConfig {
+                    codec: "opus".to_owned(),
+                    sample_rate: 48_000,
+                    number_of_channels: 2,
+                    description: Vec::new(),
+                }
+                .to_sample_entry()
+                .expect("failed to make sample entry"),
+            ),
+            TrackKind::Video => mux_track.add_sample_entry(video_entry(320)),
+        };
+        let second_entry = mux_track.add_sample_entry(video_entry(640));
+        for i in 1..=SAMPLE_COUNT {
+            mux_track.samples.push(MuxSample {
+                sample_entry_index: if entry_change_at.is_some_and(|n| i >= n) {
+                    second_entry
+                } else {
+                    first_entry
+                },
+                duration: 100,
+                is_sync: kind == TrackKind::Audio || sync_samples.contains(&i),
+                composition_offset: 0,
+                data_offset: (i - 1) as u64,
+                data_size: 1,
+            });
+        }
+        Track::from_mux_track(&mux_track, 1, SAMPLE_COUNT as usize).expect("failed to make track")
+    }
+
+    fn track_player_at(track: &Track, sample_index: u32) -> TrackPlayer {
+        let mut player = TrackPlayer::new(0, track).expect("failed to make track player");
+        player.current_sample_index = NonZeroU32::new(sample_index).expect("unreachable");
+        player
+    }
+
+    #[test]
+    fn catch_up_requires_the_threshold_lag() {
+        let track = make_track(TrackKind::Video, &[1, 6, 11, 16], None);
+        let mut player = track_player_at(&track, 2);
+
+        // サンプル 2 の表示時刻は 100 ms
+        let now = Duration::from_millis(100) + CATCH_UP_THRESHOLD;
+        assert_eq!(player.maybe_catch_up(now - Duration::from_millis(1)), None);
+        assert_eq!(player.current_sample_index.get(), 2);
+
+        // 600 ms 時点のサンプル 7 を含む GOP の先頭（サンプル 6）まで進む
+        assert_eq!(player.maybe_catch_up(now), Some(4));
+        assert_eq!(player.current_sample_index.get(), 6);
+    }
+
+    #[test]
+    fn catch_up_skips_to_the_next_sync_sample_within_a_long_gop() {
+        let track = make_track(TrackKind::Video, &[1, 11], None);
+
+        // 再生時刻以前の最後の同期サンプル（サンプル 1）は現在位置より前なので、次の同期サンプルまで進む
+        let mut player = track_player_at(&track, 2);
+        assert_eq!(player.maybe_catch_up(Duration::from_millis(700)), Some(9));
+        assert_eq!(player.current_sample_index.get(), 11);
+    }
+
+    #[test]
+    fn catch_up_does_nothing_without_a_later_sync_sample() {
+        let track = make_track(TrackKind::Video, &[1, 11], None);
+        let mut player = track_player_at(&track, 12);
+        assert_eq!(player.maybe_catch_up(Duration::from_millis(1900)), None);
+        assert_eq!(player.current_sample_index.get(), 12);
+
+        // スキップ先が終端の場合も同様
+        let track = make_track(TrackKind::Video, &[1, SAMPLE_COUNT], None);
+        let mut player = track_player_at(&track, 2);
+        assert_eq!(player.maybe_catch_up(Duration::from_millis(1950)), None);
+        assert_eq!(player.current_sample_index.get(), 2);
+    }
+
+    #[test]
+    fn audio_tracks_never_catch_up() {
+        let track = make_track(TrackKind::Audio, &[], None);
+        let mut player = track_player_at(&track, 1);
+        assert_eq!(player.maybe_catch_up(Duration::from_secs(1)), None);
+        assert_eq!(player.current_sample_index.get(), 1);
+    }
+
+    #[test]
+    fn catch_up_keeps_the_decoder_only_for_the_same_sample_entry() {
+        let track = make_track(TrackKind::Video, &[1, 6, 11, 16], Some(11));
+
+        let mut player = track_player_at(&track, 2);
+        player.decoder = Some(1);
+        assert_eq!(player.maybe_catch_up(Duration::from_millis(700)), Some(4));
+        assert_eq!(player.decoder, Some(1));
+
+        // スキップ先でサンプルエントリーが変わる場合には、デコーダーを作り直す必要がある
+        let mut player = track_player_at(&track, 7);
+        player.decoder = Some(1);
+        assert_eq!(player.maybe_catch_up(Duration::from_millis(1200)), Some(4));
+        assert_eq!(player.decoder, None);
+    }
+
+    fn audio_only_player() -> Player {
+        let shared = PlayerShared::new(&[], Rc::new(Vec::new()));
+        let options = PlayOptions { repeat: false };
+        let mut player = Player::new(0, options, Rc::new(Vec::new()), &[], shared);
+        player.start_time = PLAYER_START_TIME;
+        player
+    }
+
+    const PLAYER_START_TIME: Duration = Duration::from_secs(10);
+
+    // 再生開始時刻を基準とした時刻で、音声の再生位置を通知する
+    fn report_audio_position(player: &mut Player, at: Duration, position: Duration) {
+        player.shared.audio_clock.set(Some(AudioClockReport {
+            media_time: position,
+            reported_at: PLAYER_START_TIME + at,
+        }));
+        player.sync_with_audio_clock(PLAYER_START_TIME + at);
+    }
+
+    #[test]
+    fn audio_clock_corrections() {
+        let ms = |millis: u64| Duration::from_millis(millis);
+        let shift = |base: Duration, micros: i64| {
+            if micros < 0 {
+                base - Duration::from_micros(micros.unsigned_abs())
+            } else {
+                base + Duration::from_micros(micros as u64)
+            }
+        };
+        let slewed = |micros: i64| micros / AUDIO_CLOCK_SLEW_DIVISOR as i64;
+
+        // (音声の再生位置の遅れ（マイクロ秒、負なら進み）, 時計の補正量, 一気に合わせたかどうか)
+        let cases = [
+            (0, 0, false),
+            // 不感帯の内側
+            (1_000, 0, false),
+            (-2_000, 0, false),
+            // ズレの一部ずつ補正する
+            (4_000, slewed(4_000), false),
+            (32_000, slewed(32_000), false),
+            (-32_000, slewed(-32_000), false),
+            (192_000, slewed(192_000), false),
+            // 一気に合わせる
+            (200_000, 200_000, true),
+            (-250_000, -250_000, true),
+        ];
+        for (lag, correction, resync) in cases {
+            let mut player = audio_only_player();
+
+            // 計測期間中の最小の遅れ（20 ms）が出力経路の遅延として扱われる
+            report_audio_position(&mut player, ms(100), ms(80));
+            report_audio_position(&mut player, ms(500), ms(470));
+            report_audio_position(&mut player, ms(900), ms(860));
+            assert_eq!(player.start_time, PLAYER_START_TIME, "calibration");
+
+            let now = AUDIO_LATENCY_CALIBRATION_PERIOD + ms(1000);
+            report_audio_position(&mut player, now, shift(now, -20_000 - lag));
+
+            let stats = player.shared.stats.borrow();
+            assert_eq!(
+                player.start_time,
+                shift(PLAYER_START_TIME, correction),
+                "lag={lag}us"
+            );
+            assert_eq!(
+                stats.audio_clock_drift,
+                Duration::from_micros(lag.unsigned_abs()),
+                "lag={lag}us"
+            );
+            assert_eq!(stats.audio_clock_resyncs, resync as u64, "lag={lag}us");
+        }
+    }
+
+    #[test]
+    fn stale_and_previous_loop_audio_clock_reports_are_ignored() {
+        let mut player = audio_only_player();
+        report_audio_position(&mut player, Duration::ZERO, Duration::ZERO);
+        let now = PLAYER_START_TIME + Duration::from_secs(5);
+
+        // 音声の再生が止まっている
+        player.shared.audio_clock.set(Some(AudioClockReport {
+            media_time: Duration::from_secs(1),
+            reported_at: now - AUDIO_CLOCK_STALE_THRESHOLD - Duration::from_millis(1),
+        }));
+        player.sync_with_audio_clock(now);
+        assert_eq!(player.start_time, PLAYER_START_TIME);
+
+        // 繰り返し再生の前の周回の位置
+        player.timestamp_offset = Duration::from_secs(10);
+        player.shared.audio_clock.set(Some(AudioClockReport {
+            media_time: Duration::from_secs(9),
+            reported_at: now,
+        }));
+        player.sync_with_audio_clock(now);
+        assert_eq!(player.start_time, PLAYER_START_TIME);
+
+        let stats = player.shared.stats.borrow();
+        assert_eq!(stats.audio_clock_drift, Duration::ZERO);
+        assert_eq!(stats.max_audio_clock_drift, Duration::ZERO);
+    }
+
+    #[test]
+    fn media_time_table() {
+        let ms = Duration::from_millis;
+        let us = Duration::from_micros;
+        let ns = Duration::from_nanos;
+        let cases = [
+            (ms(0), ms(1000), ms(0)),
+            (ms(500), ms(1000), ms(500)),
+            (ms(999), ms(1000), ms(999)),
+            (ms(1000), ms(1000), ms(0)),
+            (ms(2500), ms(1000), ms(500)),
+            // 周回の先頭が切り捨てによって僅かに手前になっている場合
+            (us(1_000_000), ms(1000) + ns(500), ms(0)),
+            (us(3_000_001), ms(1000) + ns(500), ms(0)),
+            (us(1_500_000), ms(1000) + ns(500), ms(500) - ns(500)),
+            // 尺が 0 の場合には変換しない
+            (ms(10), ms(0), ms(10)),
+        ];
+        for (timestamp, file_duration, expected) in cases {
+            assert_eq!(
+                media_time(timestamp, file_duration),
+                expected,
+                "timestamp={timestamp:?}, file_duration={file_duration:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,579 @@
+use std::{num::NonZeroU32, ops::Range};
+
+use orfail::{Failure, OrFail};
+use shiguredo_mp4::{
+    boxes::{MdatBox, SampleEntry},
+    BaseBox, BoxHeader, Decode,
+};
+
+use crate::{
+    mp4::{decode_hvc1_box, Mp4, Track, TrackKind},
+    muxer::{write_progressive_mp4, MuxSample, MuxTrack},
+};
+
+// 参照用 MP4 のサンプルサイズの範囲に対して、復旧時に許容するサイズの倍率
+// (ビットレートの違いなどで参照用 MP4 よりも大きい・小さいサンプルが存在する可能性があるため）
+const SAMPLE_SIZE_TOLERANCE: u32 = 2;
+
+// 音声データの分割時に、次の映像のアクセスユニットを探す範囲（音声フレームの最大サイズの倍数）
+const MAX_AUDIO_FRAMES_BETWEEN_VIDEO: usize = 16;
+
+// 音声データを映像のアクセスユニットの位置までちょうど分割する際に考慮する、区切り位置の候補数の上限
+// （これを超える場合には分割の計算量が大きくなりすぎるので、一フレームずつ推定する）
+const MAX_AUDIO_FRAME_BOUNDARIES: usize = 1024;
+
+// 録画の途中でクラッシュするなどして moov ボックスが書き込まれなかった MP4 を復旧する
+//
+// mdat ボックスのペイロードを先頭から走査して、H.264 / H.265 のアクセスユニットと音声フレームを切り出し、
+// 新しいサンプルテーブルを構築する。
+// サンプルエントリーと音声サンプルの尺には、同じ設定で録画された正常な参照用 MP4 の値が使われる。
+// 映像サンプルの尺は、GOP 単位で音声サンプルの進み具合から推定する（詳細は set_video_durations() を参照）。
+//
+// [NOTE]
+// 音声フレームには長さ情報が含まれないので、参照用 MP4 のサンプルサイズや先頭バイトの傾向を元にした推定で区切っている。
+// そのため、可変ビットレートの音声では区切り位置を誤る可能性がある。
+pub fn recover_mp4(truncated_bytes: &[u8], reference_bytes: &[u8]) -> orfail::Result<Vec<u8>> {
+    let reference = Mp4::load(reference_bytes).or_fail()?;
+    let mut video = None;
+    let mut audio = None;
+    for track in &reference.tracks {
+        let reference_track = ReferenceTrack::new(track, reference_bytes).or_fail()?;
+        match track.kind {
+            TrackKind::Video => video = Some(VideoScanner::new(reference_track).or_fail()?),
+            TrackKind::Audio => audio = Some(reference_track),
+        }
+    }
+
+    let payload_range = find_mdat_payload(truncated_bytes).or_fail()?;
+    let payload = &truncated_bytes[payload_range];
+
+    let mut video_track = video.as_ref().map(|v| v.reference.to_mux_track());
+    let mut audio_track = audio.as_ref().map(|a| a.to_mux_track());
+    let mut offset = 0;
+    while offset < payload.len() {
+        let data = &payload[offset..];
+        if let Some((size, is_key)) = video.as_ref().and_then(|v| v.parse_access_unit(data)) {
+            let track = video_track.as_mut().or_fail()?;
+            track.samples.push(recovered_sample(offset, size, is_key));
+            offset += size;
+            continue;
+        }
+
+        let sizes = audio
+            .as_ref()
+            .map(|a| split_audio_frames(a, video.as_ref(), data))
+            .unwrap_or_default();
+        if sizes.is_empty() {
+            // 末尾の書き込み途中のサンプルなど、これ以上は解釈できないデータ
+            break;
+        }
+        let track = audio_track.as_mut().or_fail()?;
+        for size in sizes {
+            track.samples.push(recovered_sample(offset, size, true));
+            offset += size;
+        }
+    }
+
+    if let (Some(track), Some(reference)) = (&mut audio_track, &audio) {
+        for sample in &mut track.samples {
+            sample.duration = reference.sample_duration;
+        }
+    }
+    if let (Some(track), Some(video)) = (&mut video_track, &video) {
+        set_video_durations(track, &video.reference, audio_track.as_ref());
+    }
+
+    let tracks = [audio_track, video_track]
+        .into_iter()
+        .flatten()
+        .filter(|t| !t.samples.is_empty())
+        .collect::<Vec<_>>();
+    (!tracks.is_empty()).or_fail_with(|()| "No recoverable samples found".to_owned())?;
+
+    write_progressive_mp4(&tracks, payload).or_fail()
+}
+
+fn recovered_sample(data_offset: usize, data_size: usize, is_sync: bool) -> MuxSample {
+    MuxSample {
+        sample_entry_index: 0,
+        duration: 0, // 全サンプルを切り出した後で設定する
+        is_sync,
+        composition_offset: 0,
+        data_offset: data_offset as u64,
+        data_size: data_size as u32,
+    }
+}
+
+// 映像サンプルの尺を、GOP 単位で同じファイル内の音声サンプルの進み具合から推定して設定する
+//
+// 録画時には音声と映像のサンプルがほぼリアルタイムに交互に書き込まれるので、
+// キーフレームよりも前に書き込まれた音声サンプルの総尺は、そのキーフレームの時刻の近似となる。
+// GOP 内の各フレームには、次のキーフレームまでの時間を均等に割り当てる。
+//
+// 音声トラックがない場合や、最初のキーフレームより前・最後のキーフレーム以降のフレームには、
+// 参照用 MP4 の映像サンプルの尺の中央値が使われる。
+fn set_video_durations(video: &mut MuxTrack, reference: &ReferenceTrack, audio: Option<&MuxTrack>) {
+    for sample in &mut video.samples {
+        sample.duration = reference.sample_duration;
+    }
+    let Some(audio) = audio else {
+        return;
+    };
+
+    // 音声サンプルはデータ位置の順に並んでいるので、終了時刻の累積値と二分探索で求められる
+    let audio_end_times = audio
+        .samples
+        .iter()
+        .scan(0, |t, s| {
+            *t += s.duration as u64;
+            Some(*t)
+        })
+        .collect::<Vec<_>>();
+    let video_timescale = video.timescale.get() as u64;
+    let audio_timescale = audio.timescale.get() as u64;
+    let audio_time_before = |data_offset: u64| {
+        let count = audio
+            .samples
+            .partition_point(|s| s.data_offset < data_offset);
+        let ticks = count.checked_sub(1).map_or(0, |i| audio_end_times[i]);
+        ticks * video_timescale / audio_timescale
+    };
+    let keyframes = video
+        .samples
+        .iter()
+        .enumerate()
+        .filter(|(_, s)| s.is_sync)
+        .map(|(i, s)| (i, audio_time_before(s.data_offset)))
+        .collect::<Vec<_>>();
+
+    let mut current = 0;
+    while current < keyframes.len() {
+        let (start_index, start_time) = keyframes[current];
+
+        // 間に音声が書き込まれていないキーフレームは時刻が決まらないので、次の GOP とまとめて扱う
+        let Some(next) = (current + 1..keyframes.len()).find(|&i| keyframes[i].1 > start_time)
+        else {
+            break;
+        };
+        let (end_index, end_time) = keyframes[next];
+        let frames = (end_index - start_index) as u64;
+        let total = end_time - start_time;
+        if total >= frames {
+            // 割り切れない分は、各フレームに一ティックずつ配分する
+            for (i, sample) in video.samples[start_index..end_index].iter_mut().enumerate() {
+                let i = i as u64;
+                sample.duration = (total * (i + 1) / frames - total * i / frames) as u32;
+            }
+        }
+        current = next;
+    }
+}
+
+// mdat ボックスのペイロードの位置を返す
+//
+// ファイルが途中で切れている場合には、ボックスヘッダーのサイズではなくファイル末尾までをペイロードとして扱う
+fn find_mdat_payload(bytes: &[u8]) -> orfail::Result<Range<usize>> {
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let header = BoxHeader::decode(&mut &bytes[offset..]).or_fail()?;
+        let payload_start = offset + header.external_size();
+        let box_end = if header.box_size.get() == 0 {
+            // サイズが 0 の場合はファイル末尾までがボックスとなる
+            bytes.len()
+        } else {
+            (offset as u64)
+                .saturating_add(header.box_size.get())
+                .min(bytes.len() as u64) as usize
+        };
+        (payload_start <= box_end).or_fail_with(|()| {
+            format!(
+                "Invalid box size: type={}, offset={offset}",
+                header.box_type
+            )
+        })?;
+
+        if header.box_type == MdatBox::TYPE {
+            return Ok(payload_start..box_end);
+        }
+        offset = box_end;
+    }
+    Err(Failure::new("No 'mdat' box found"))
+}
+
+// 参照用 MP4 から取得した、復旧に必要となるトラックの情報
+#[derive(Debug)]
+struct ReferenceTrack {
+    kind: TrackKind,
+    timescale: NonZeroU32,
+    sample_entry: SampleEntry,
+
+    // サンプルの尺の中央値
+    sample_duration: u32,
+
+    // サンプルサイズの中央値と、許容するサイズの範囲（音声フレームの区切り位置の推定に使われる）
+    typical_sample_size: usize,
+    min_sample_size: usize,
+    max_sample_size: usize,
+
+    // サンプルの先頭バイトとして出現した値
+    first_bytes: [bool; 256],
+}
+
+impl ReferenceTrack {
+    fn new(track: &Track, mp4_bytes: &[u8]) -> orfail::Result<Self> {
+        let sample_table = &track.sample_table;
+        let sample_entry = sample_table
+            .stbl_box()
+            .stsd_box
+            .entries
+            .first()
+            .or_fail()?
+            .clone();
+
+        let mut durations = sample_table
+            .samples()
+            .map(|s| s.duration())
+            .collect::<Vec<_>>();
+        durations.sort_unstable();
+        let mut sizes = sample_table
+            .samples()
+            .map(|s| s.data_size())
+            .collect::<Vec<_>>();
+        sizes.sort_unstable();
+
+        // Track::new() で空のトラックは弾かれているので、ここに来る時点で一つ以上のサンプルが存在する
+        let min_size = sizes[0] / SAMPLE_SIZE_TOLERANCE;
+        let max_size = sizes[sizes.len() - 1] * SAMPLE_SIZE_TOLERANCE;
+
+        let mut first_bytes = [false; 256];
+        for sample in sample_table.samples() {
+            if let Some(&b) = mp4_bytes.get(sample.data_offset() as usize) {
+                first_bytes[b as usize] = true;
+            }
+        }
+
+        Ok(Self {
+            kind: track.kind,
+            timescale: track.timescale,
+            sample_entry,
+            sample_duration: durations[durations.len() / 2],
+            typical_sample_size: sizes[sizes.len() / 2] as usize,
+            min_sample_size: min_size.max(1) as usize,
+            max_sample_size: max_size as usize,
+            first_bytes,
+        })
+    }
+
+    fn to_mux_track(&self) -> MuxTrack {
+        let mut track = MuxTrack::new(self.kind, self.timescale);
+        track.add_sample_entry(self.sample_entry.clone());
+        track
+    }
+}
+
+// 音声データの先頭から、音声フレーム群を切り出して、それぞれのサイズを返す
+//
+// 音声フレームの区切りとして妥当なのは、次のフレームの先頭バイトが参照用 MP4 の音声サンプルの先頭バイトとして
+// 出現したことがある値で、かつフレームのサイズが参照用 MP4 のサンプルサイズの範囲内に収まる位置となる。
+//
+// 近くに映像のアクセスユニットが見つかった場合には、そこまでの区間を過不足なく分割できる区切り方の中で、
+// 各フレームのサイズが参照用 MP4 のサンプルサイズの中央値に最も近くなるものを選ぶ。
+// 見つからない場合には、次の区切りとして妥当な位置のうち、サイズが中央値に最も近いものを選ぶ。
+fn split_audio_frames(
+    audio: &ReferenceTrack,
+    video: Option<&VideoScanner>,
+    data: &[u8],
+) -> Vec<usize> {
+    let search_end = data
+        .len()
+        .min(audio.max_sample_size * MAX_AUDIO_FRAMES_BETWEEN_VIDEO);
+    let video_start = video.and_then(|v| {
+        (audio.min_sample_size..search_end).find(|&p| v.parse_access_unit(&data[p..]).is_some())
+    });
+    if let Some(end) = video_start {
+        if let Some(sizes) = split_audio_frames_until(audio, data, end) {
+            return sizes;
+        }
+    }
+
+    let max = audio.max_sample_size.min(data.len());
+    (audio.min_sample_size..=max)
+        .filter(|&size| {
+            size == data.len()
+                || Some(size) == video_start
+                || audio.first_bytes[data[size] as usize]
+        })
+        .min_by_key(|&size| size.abs_diff(audio.typical_sample_size))
+        .into_iter()
+        .collect()
+}
+
+// data[..end] をちょうど音声フレーム群に分割する区切り方のうち、
+// 中央値からのサイズの差の合計が最小となるものを動的計画法で求める
+//
+// 区切り位置の候補は、先頭バイトが参照用 MP4 の音声サンプルの先頭バイトとして出現したことがある位置に限られる。
+// 候補が多すぎる場合には None が返される。
+fn split_audio_frames_until(audio: &ReferenceTrack, data: &[u8], end: usize) -> Option<Vec<usize>> {
+    let mut boundaries = (audio.min_sample_size..end)
+        .filter(|&p| audio.first_bytes[data[p] as usize])
+        .collect::<Vec<_>>();
+    if boundaries.len() > MAX_AUDIO_FRAME_BOUNDARIES {
+        return None;
+    }
+    boundaries.insert(0, 0);
+    boundaries.push(end);
+
+    // best[i] は data[..boundaries[i]] を分割した際の最小コストと、その最後のフレームの開始位置の候補番号
+    let mut best = vec![None::<(usize, usize)>; boundaries.len()];
+    best[0] = Some((0, 0));
+    for i in 0..boundaries.len() {
+        let Some((cost, _)) = best[i] else {
+            continue;
+        };
+        let start = boundaries[i];
+        let first = boundaries.partition_point(|&p| p < start + audio.min_sample_size);
+        for j in first..boundaries.len() {
+            let size = boundaries[j] - start;
+            if size > audio.max_sample_size {
+                break;
+            }
+            let cost = cost + size.abs_diff(audio.typical_sample_size);
+            if best[j].is_none_or(|(c, _)| cost < c) {
+                best[j] = Some((cost, i));
+            }
+        }
+    }
+
+    let mut sizes = Vec::new();
+    let mut i = boundaries.len() - 1;
+    while i > 0 {
+        let (_, prev) = best[i]?;
+        sizes.push(boundaries[i] - boundaries[prev]);
+        i = prev;
+    }
+    sizes.reverse();
+    Some(sizes)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VideoCodec {
+    H264,
+    H265,
+}
+
+// 長さプレフィックス形式の NAL ユニット列から、映像のアクセスユニットを切り出すための構造体
+#[derive(Debug)]
+struct VideoScanner {
+    reference: ReferenceTrack,
+    codec: VideoCodec,
+    nalu_length_size: usize,
+}
+
+impl VideoScanner {
+    fn new(reference: ReferenceTrack) -> orfail::Result<Self> {
+        let (codec, nalu_length_size) = match &reference.sample_entry {
+            SampleEntry::Avc1(b) => (
+                VideoCodec::H264,
+                b.avcc_box.length_size_minus_one.get() as usize + 1,
+            ),
+            SampleEntry::Hev1(b) => (
+                VideoCodec::H265,
+                b.hvcc_box.length_size_minus_one.get() as usize + 1,
+            ),
+            SampleEntry::Unknown(b) if decode_hvc1_box(b).is_some() => (
+                VideoCodec::H265,
+                decode_hvc1_box(b)
+                    .map(|b| b.hvcc_box.length_size_minus_one.get() as usize + 1)
+                    .expect("unreachable"),
+            ),
+            b => {
+                return Err(Failure::new(format!(
+                    "Unsupported video codec for recovery: {}",
+                    b.box_type()
+                )));
+            }
+        };
+        Ok(Self {
+            reference,
+            codec,
+            nalu_length_size,
+        })
+    }
+
+    // データの先頭から一つのアクセスユニットを切り出して、そのサイズとキーフレームかどうかを返す
+    //
+    // 先頭が妥当な NAL ユニットではない場合には None が返される
+    fn parse_access_unit(&self, data: &[u8]) -> Option<(usize, bool)> {
+        let mut offset = 0;
+        let mut has_vcl = false;
+        let mut is_key = false;
+        while let Some(length_bytes) = data.get(offset..offset + self.nalu_length_size) {
+            let nalu_size = length_bytes
+                .iter()
+                .fold(0usize, |acc, &b| (acc << 8) | b as usize);
+            let nalu_start = offset + self.nalu_length_size;
+            let Some(nalu) = data.get(nalu_start..nalu_start + nalu_size) else {
+                break;
+            };
+            let Some(nalu) = self.classify_nalu(nalu) else {
+                break;
+            };
+            if has_vcl && nalu.starts_access_unit {
+                break;
+            }
+            has_vcl |= nalu.is_vcl;
+            is_key |= nalu.is_key;
+            offset = nalu_start + nalu_size;
+        }
+        has_vcl.then_some((offset, is_key))
+    }
+
+    fn classify_nalu(&self, nalu: &[u8]) -> Option<NaluInfo> {
+        match self.codec {
+            VideoCodec::H264 => {
+                let header = *nalu.first()?;
+                let forbidden_zero_bit = header >> 7;
+                let nal_ref_idc = (header >> 5) & 0b11;
+                let nal_unit_type = header & 0b1_1111;
+                if forbidden_zero_bit != 0 || !matches!(nal_unit_type, 1..=12) {
+                    return None;
+                }
+                // 参照されない種別の NAL ユニットでは nal_ref_idc は 0 でなければならない
+                if matches!(nal_unit_type, 6 | 9..=12) && nal_ref_idc != 0 {
+                    return None;
+                }
+                if nal_unit_type == 5 && nal_ref_idc == 0 {
+                    return None;
+                }
+
+                let is_vcl = matches!(nal_unit_type, 1..=5);
+                // スライスの場合は first_mb_in_slice が 0 （ue(v) の先頭ビットが 1）なら新しいピクチャの先頭
+                let first_slice = is_vcl && nalu.get(1).is_some_and(|b| b & 0x80 != 0);
+                Some(NaluInfo {
+                    is_vcl,
+                    is_key: nal_unit_type == 5,
+                    starts_access_unit: first_slice || matches!(nal_unit_type, 6..=9),
+                })
+            }
+            VideoCodec::H265 => {
+                let header = nalu.get(..2)?;
+                let forbidden_zero_bit = header[0] >> 7;
+                let nal_unit_type = (header[0] >> 1) & 0b11_1111;
+                let nuh_layer_id = ((header[0] & 1) << 5) | (header[1] >> 3);
+                let nuh_temporal_id_plus1 = header[1] & 0b111;
+                if forbidden_zero_bit != 0 || nuh_layer_id != 0 || nuh_temporal_id_plus1 == 0 {
+                    return None;
+                }
+                if !matches!(nal_unit_type, 0..=9 | 16..=21 | 32..=40) {
+                    return None;
+                }
+
+                let is_vcl = nal_unit_type < 32;
+                // スライスの場合は first_slice_segment_in_pic_flag が 1 なら新しいピクチャの先頭
+                let first_slice = is_vcl && nalu.get(2).is_some_and(|b| b & 0x80 != 0);
+                Some(NaluInfo {
+                    is_vcl,
+                    is_key: matches!(nal_unit_type, 16..=21),
+                    starts_access_unit: first_slice || matches!(nal_unit_type, 32..=35 | 39),
+                })
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct NaluInfo {
+    is_vcl: bool,
+    is_key: bool,
+    starts_access_unit: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reference_track(kind: TrackKind, timescale: u32, sample_duration: u32) -> ReferenceTrack {
+        ReferenceTrack {
+            kind,
+            timescale: NonZeroU32::new(timescale).expect("non zero"),
+            sample_entry: SampleEntry::Unknown(shiguredo_mp4::boxes::UnknownBox {
+                box_type: shiguredo_mp4::BoxType::Normal(*b"test"),
+                box_size: shiguredo_mp4::BoxSize::U32(8),
+                payload: Vec::new(),
+            }),
+            sample_duration,
+            typical_sample_size: 10,
+            min_sample_size: 5,
+            max_sample_size: 20,
+            first_bytes: [false; 256],
+        }
+    }
+
+    #[test]
+    fn video_durations_follow_interleaved_audio() {
+        let video_reference = reference_track(TrackKind::Video, 90_000, 3000);
+        let audio_reference = reference_track(TrackKind::Audio, 48_000, 1024);
+        let mut video = video_reference.to_mux_track();
+        let mut audio = audio_reference.to_mux_track();
+
+        // 一つ目の GOP は 15 fps（3 フレームで 0.2 秒）、二つ目の GOP は 30 fps（3 フレームで 0.1 秒）
+        let mut offset = 0;
+        for (frames, audio_frames) in [(3, 9600), (3, 4687), (2, 0)] {
+            for i in 0..frames {
+                video.samples.push(recovered_sample(offset, 1, i == 0));
+                offset += 1;
+            }
+            // 音声は 1 サンプル（1 / 48000 秒）単位のフレームとして書き込む
+            for _ in 0..audio_frames {
+                audio.samples.push(MuxSample {
+                    duration: 1,
+                    ..recovered_sample(offset, 1, true)
+                });
+                offset += 1;
+            }
+        }
+
+        set_video_durations(&mut video, &video_reference, Some(&audio));
+        let durations = video.samples.iter().map(|s| s.duration).collect::<Vec<_>>();
+        // 二つ目の GOP は 4687 / 48000 秒 = 8788.125 ティックで、余りが各フレームに配分される
+        assert_eq!(durations, [6000, 6000, 6000, 2929, 2929, 2930, 3000, 3000]);
+    }
+
+    #[test]
+    fn video_durations_fall_back_to_reference_without_audio() {
+        let video_reference = reference_track(TrackKind::Video, 90_000, 3000);
+        let mut video = video_reference.to_mux_track();
+        for i in 0..4 {
+            video.samples.push(recovered_sample(i, 1, i % 2 == 0));
+        }
+        set_video_durations(&mut video, &video_reference, None);
+        assert!(video.samples.iter().all(|s| s.duration == 3000));
+    }
+
+    #[test]
+    fn audio_frames_are_split_at_plausible_boundaries() {
+        let mut audio = reference_track(TrackKind::Audio, 48_000, 1024);
+        audio.first_bytes[0xFF] = true;
+
+        // 先頭バイトが 0xFF の位置だけが区切り位置の候補となる
+        let mut data = vec![0; 30];
+        data[0] = 0xFF;
+        data[9] = 0xFF;
+        data[12] = 0xFF;
+        data[21] = 0xFF;
+        assert_eq!(
+            split_audio_frames_until(&audio, &data, 30),
+            Some(vec![9, 12, 9])
+        );
+
+        // 候補の位置ではサイズの範囲（5..=20 バイト）に収まるように区切れない
+        assert_eq!(
+            split_audio_frames_until(&audio, &data[..9], 9),
+            Some(vec![9])
+        );
+        assert_eq!(split_audio_frames_until(&audio, &data[..2], 2), None);
+        data[9] = 0;
+        data[12] = 0;
+        assert_eq!(split_audio_frames_until(&audio, &data, 30), None);
+    }
+}
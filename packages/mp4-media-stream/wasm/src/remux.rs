@@ -0,0 +1,890 @@
+use std::{num::NonZeroU32, ops::Range, time::Duration};
+
+use orfail::{Failure, OrFail};
+use shiguredo_mp4::{
+    aux::SampleAccessor,
+    boxes::{FtypBox, IgnoredBox, MoovBox, StblBox},
+    Decode, Either, Encode,
+};
+
+use crate::{
+    mp4::{DecoderConfig, Mp4, Track, TrackKind},
+    muxer::{
+        make_stco_or_co64_box, read_composition_offsets, to_timescale, write_progressive_mp4,
+        MuxEdit, MuxSample, MuxTrack,
+    },
+};
+
+// 入力の編集リストの丸め誤差とみなす、表示区間同士のずれの上限
+const EDIT_ROUNDING_TOLERANCE: Duration = Duration::from_millis(2);
+
+// 再エンコードを行わずに、ロード済みの MP4 の指定範囲を切り出した MP4 を生成する
+//
+// start と end は入力の編集リストを適用した後の表示時刻（全トラック共通）で、
+// 各トラックでは、start の直前のキーフレームから end の直前のサンプルまでが出力対象となる。
+// B フレームを含むトラックでは、範囲の判定には表示時刻が使われ、コンポジション時間オフセットも引き継がれる。
+// キーフレームから start までのプリロール部分は、編集リストによって再生時には非表示となる。
+// 入力の編集リストのうち、範囲内の空白区間や表示区間は出力の編集リストに引き継がれる。
+pub fn trim_mp4(
+    tracks: &[Track],
+    mp4_bytes: &[u8],
+    start: Duration,
+    end: Duration,
+) -> orfail::Result<Vec<u8>> {
+    (start < end).or_fail_with(|()| format!("Invalid trim range: {start:?}..{end:?}"))?;
+
+    let mut mux_tracks = Vec::new();
+    let mut source_samples = Vec::new();
+    for track in tracks {
+        let timescale = track.timescale;
+
+        // 範囲内の表示区間のメディア時刻を全て含むように、出力するサンプル群を決める
+        let edits = edits_in_range(track, start, end);
+        let media_ranges = edits.iter().filter_map(|e| match e {
+            MuxEdit::Empty(_) => None,
+            MuxEdit::Media(range) => Some(range),
+        });
+        let Some(start_ticks) = media_ranges.clone().map(|r| r.start).min() else {
+            // 範囲内が空白区間のみのトラックは出力しない
+            continue;
+        };
+        let end_ticks = media_ranges.map(|r| r.end).max().expect("unreachable");
+
+        // B フレームを含むトラックでは、範囲の判定には表示時刻（デコード時刻 + コンポジション時間オフセット）を使う
+        let composition_offsets = read_composition_offsets(
+            track.sample_table.stbl_box(),
+            track.sample_table.sample_count() as usize,
+        )
+        .or_fail()?;
+        let composition_time = |sample: &SampleAccessor<StblBox>| {
+            let offset = composition_offsets[sample.index().get() as usize - 1];
+            sample.timestamp() as i64 + offset as i64
+        };
+
+        let Some(mut first) = track.sample_table.get_sample_by_timestamp(start_ticks) else {
+            // 範囲内にサンプルが存在しないトラックは出力しない
+            continue;
+        };
+        loop {
+            first = first.sync_sample().or_fail_with(|()| {
+                format!(
+                    "No sync sample found before {start:?} in {:?} track",
+                    track.kind
+                )
+            })?;
+
+            // キーフレーム自体の表示時刻が start より後の場合には、一つ前のキーフレームから始める
+            let Some(prev_index) = NonZeroU32::new(first.index().get() - 1) else {
+                break;
+            };
+            if composition_time(&first) <= start_ticks as i64 {
+                break;
+            }
+            first = track.sample_table.get_sample(prev_index).or_fail()?;
+        }
+
+        // 表示時刻が end より前のサンプルは、デコード順では end 以降に現れることがあるので、
+        // 表示時刻が end より前の最後のサンプル（デコード順）までを出力対象とする
+        let max_composition_offset = composition_offsets
+            .iter()
+            .copied()
+            .max()
+            .unwrap_or(0)
+            .max(0);
+        let decode_end_ticks = end_ticks + max_composition_offset as u64;
+        let skip = first.index().get() as usize - 1;
+        let sample_count = track
+            .sample_table
+            .samples()
+            .skip(skip)
+            .take_while(|s| s.timestamp() < decode_end_ticks)
+            .enumerate()
+            .filter(|(_, s)| composition_time(s) < end_ticks as i64)
+            .last()
+            .map_or(1, |(i, _)| i + 1);
+
+        let mut mux_track = MuxTrack::new(track.kind, timescale);
+        let mut last_end_ticks = composition_time(&first);
+        for sample in track.sample_table.samples().skip(skip).take(sample_count) {
+            let sample_entry_index =
+                mux_track.add_sample_entry(sample.chunk().sample_entry().clone());
+            source_samples.push(SourceSample {
+                input_index: 0,
+                track_index: mux_tracks.len(),
+                sample_index: mux_track.samples.len(),
+                decode_time: Duration::from_secs(sample.timestamp()) / timescale.get(),
+                data_offset: sample.data_offset(),
+            });
+            mux_track.samples.push(MuxSample {
+                sample_entry_index,
+                duration: sample.duration(),
+                is_sync: sample.is_sync_sample(),
+                composition_offset: composition_offsets[sample.index().get() as usize - 1],
+                data_offset: 0, // copy_sample_data() の中で確定する
+                data_size: sample.data_size(),
+            });
+            last_end_ticks =
+                last_end_ticks.max(composition_time(&sample) + sample.duration() as i64);
+        }
+
+        // 出力ではキーフレームのデコード時刻が 0 となるので、表示区間もそれに合わせてずらす
+        // （先頭の空白区間はトラックの開始位置として扱う）
+        for edit in edits {
+            match edit {
+                MuxEdit::Empty(duration) if mux_track.edits.is_empty() => {
+                    mux_track.start_offset += duration;
+                }
+                MuxEdit::Empty(duration) => mux_track.push_edit(MuxEdit::Empty(duration)),
+                MuxEdit::Media(range) => {
+                    let presentation_start = range.start.saturating_sub(first.timestamp());
+                    let presentation_end = (range.end as i64)
+                        .min(last_end_ticks)
+                        .saturating_sub(first.timestamp() as i64)
+                        .max(presentation_start as i64)
+                        as u64;
+                    mux_track.push_edit(MuxEdit::Media(presentation_start..presentation_end));
+                }
+            }
+        }
+        mux_tracks.push(mux_track);
+    }
+    (!mux_tracks.is_empty())
+        .or_fail_with(|()| format!("No samples found in the range {start:?}..{end:?}"))?;
+
+    let mdat_payload = copy_sample_data(&mut mux_tracks, source_samples, &[mp4_bytes]).or_fail()?;
+    write_progressive_mp4(&mux_tracks, &mdat_payload).or_fail()
+}
+
+// 入力トラックの編集リストに従って、表示時刻の範囲 start..end に含まれる区間群を返す
+//
+// 表示区間（MuxEdit::Media）はトラックのタイムスケール単位のメディア時刻（表示時刻）の範囲となる。
+// 編集リストがない場合には、サンプル群の全体がファイル先頭から表示されるものとして扱う。
+fn edits_in_range(track: &Track, start: Duration, end: Duration) -> Vec<MuxEdit> {
+    let timescale = track.timescale;
+    let edits = if track.edits.is_empty() {
+        let media_duration = track
+            .sample_table
+            .samples()
+            .last()
+            .map_or(0, |s| s.timestamp() + s.duration() as u64);
+        vec![MuxEdit::Media(0..media_duration)]
+    } else {
+        track.edits.clone()
+    };
+
+    let mut result = Vec::new();
+    let mut position = Duration::ZERO;
+    for edit in edits {
+        if position >= end {
+            break;
+        }
+        let duration = match &edit {
+            MuxEdit::Empty(duration) => *duration,
+            MuxEdit::Media(range) => Duration::from_secs(range.end - range.start) / timescale.get(),
+        };
+        let edit_end = position + duration;
+        if start < edit_end {
+            let clipped_start = start.saturating_sub(position);
+            let clipped_end = end.min(edit_end) - position;
+            match edit {
+                MuxEdit::Empty(_) if clipped_start == clipped_end => {}
+                MuxEdit::Empty(_) => result.push(MuxEdit::Empty(clipped_end - clipped_start)),
+                MuxEdit::Media(range) => {
+                    let media_start = range.start + to_timescale(clipped_start, timescale);
+                    let media_end =
+                        (range.start + to_timescale(clipped_end, timescale)).min(range.end);
+                    if media_start < media_end {
+                        result.push(MuxEdit::Media(media_start..media_end));
+                    }
+                }
+            }
+        }
+        position = edit_end;
+    }
+    result
+}
+
+// moov ボックスが mdat ボックスよりも前に来るように MP4 を並べ替える（いわゆる faststart）
+//
+// 出力は ftyp / moov / 元のファイルのその他のボックス群（元の順番のまま）の順となり、
+// moov 内のチャンクオフセットは移動後の位置に書き換えられる。
+// ftyp ボックスを持たない QuickTime 形式 (MOV) のファイルの場合には、moov が先頭に置かれる。
+// 書き換え後のオフセットが 32 ビットに収まらないトラックの stco ボックスは co64 ボックスに変換される。
+//
+// [NOTE] moov 以外でファイル内の絶対位置を参照するボックス（meta 内の iloc など）には未対応
+pub fn faststart_mp4(mp4_bytes: &[u8]) -> orfail::Result<Vec<u8>> {
+    let (moov_box, moov_range) = Mp4::load_moov_box(mp4_bytes).or_fail()?;
+    let mut reader = mp4_bytes;
+    let first_box = IgnoredBox::decode(&mut reader).or_fail()?;
+    let ftyp_end = if first_box.box_type == FtypBox::TYPE {
+        mp4_bytes.len() - reader.len()
+    } else {
+        0
+    };
+
+    // moov 以外のボックス群は、ftyp と新しい moov の直後に元の順番のまま配置される
+    let before_moov = ftyp_end..moov_range.start;
+    let after_moov = moov_range.end..mp4_bytes.len();
+    let relocate = |offset: u64, new_moov_size: u64| -> orfail::Result<u64> {
+        let offset_usize = offset as usize;
+        if before_moov.contains(&offset_usize) {
+            Ok(offset + new_moov_size)
+        } else if after_moov.contains(&offset_usize) {
+            Ok(offset - moov_range.len() as u64 + new_moov_size)
+        } else {
+            Err(Failure::new(format!(
+                "Chunk offset {offset} does not point to media data"
+            )))
+        }
+    };
+
+    // オフセットの値によって moov のサイズ（stco か co64 か）が変わるので、サイズが変わらなくなるまで繰り返す
+    let mut new_moov_size = moov_range.len() as u64;
+    let new_moov_bytes = loop {
+        let new_moov_box =
+            relocate_chunk_offsets(&moov_box, |o| relocate(o, new_moov_size)).or_fail()?;
+        let mut bytes = Vec::new();
+        new_moov_box.encode(&mut bytes).or_fail()?;
+        if bytes.len() as u64 == new_moov_size {
+            break bytes;
+        }
+        new_moov_size = bytes.len() as u64;
+    };
+
+    let mut output = Vec::with_capacity(mp4_bytes.len() - moov_range.len() + new_moov_bytes.len());
+    output.extend_from_slice(&mp4_bytes[..ftyp_end]);
+    output.extend_from_slice(&new_moov_bytes);
+    output.extend_from_slice(&mp4_bytes[before_moov]);
+    output.extend_from_slice(&mp4_bytes[after_moov]);
+    Ok(output)
+}
+
+// moov 内の全てのチャンクオフセットを書き換える
+//
+// 書き換え後のオフセットが 32 ビットに収まらないトラックの stco ボックスは co64 ボックスに変換される
+fn relocate_chunk_offsets(
+    moov_box: &MoovBox,
+    relocate: impl Fn(u64) -> orfail::Result<u64>,
+) -> orfail::Result<MoovBox> {
+    let mut new_moov_box = moov_box.clone();
+    for trak_box in &mut new_moov_box.trak_boxes {
+        let stbl_box = &mut trak_box.mdia_box.minf_box.stbl_box;
+        let chunk_offsets = match &stbl_box.stco_or_co64_box {
+            Either::A(b) => b.chunk_offsets.iter().map(|&o| o as u64).collect(),
+            Either::B(b) => b.chunk_offsets.clone(),
+        };
+        let chunk_offsets = chunk_offsets
+            .into_iter()
+            .map(&relocate)
+            .collect::<orfail::Result<Vec<_>>>()
+            .or_fail()?;
+        stbl_box.stco_or_co64_box = make_stco_or_co64_box(chunk_offsets);
+    }
+    Ok(new_moov_box)
+}
+
+// 複数の MP4 を時間方向に連結して一つの MP4 を生成するための構造体
+//
+// 同じ種類（音声・映像）のトラックは一つにまとめられる。
+// 入力間でコーデックの種類・解像度・サンプリングレート・チャンネル数が異なる場合はエラーとなるが、
+// それ以外の設定（H.264 の SPS / PPS など）のみが異なる場合には、サンプルエントリーが追加される。
+// 各入力の編集リストは、出力の編集リストに引き継がれる。
+#[derive(Debug, Default)]
+pub struct Mp4Concatenator {
+    inputs: Vec<ConcatInput>,
+    output: Vec<u8>,
+}
+
+impl Mp4Concatenator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // 連結対象の MP4 を末尾に追加する
+    pub fn add_input(&mut self, mp4_bytes: Vec<u8>) -> orfail::Result<()> {
+        let mp4 = Mp4::load(&mp4_bytes).or_fail()?;
+        self.inputs.push(ConcatInput {
+            bytes: mp4_bytes,
+            tracks: mp4.tracks,
+        });
+        Ok(())
+    }
+
+    // 追加済みの MP4 を連結する
+    //
+    // 生成結果は take_output() で取得できる
+    pub fn finish(&mut self) -> orfail::Result<()> {
+        (!self.inputs.is_empty()).or_fail_with(|()| "No MP4 inputs".to_owned())?;
+        self.check_compatibility().or_fail()?;
+
+        let mut mux_tracks = Vec::<MuxTrack>::new();
+        let mut source_samples = Vec::new();
+
+        // 各入力の先頭に対応する、出力 MP4 内での時刻
+        let mut input_offset = Duration::ZERO;
+        for (input_index, input) in self.inputs.iter().enumerate() {
+            let mut input_end = input_offset;
+            for track in &input.tracks {
+                let track_index =
+                    if let Some(i) = mux_tracks.iter().position(|t| t.kind == track.kind) {
+                        i
+                    } else {
+                        // 途中の入力で初めて登場したトラックは、その入力の開始位置まで空白とする
+                        let mut mux_track = MuxTrack::new(track.kind, track.timescale);
+                        mux_track.start_offset = input_offset;
+                        mux_tracks.push(mux_track);
+                        mux_tracks.len() - 1
+                    };
+                let mux_track = &mut mux_tracks[track_index];
+
+                // 前の入力でこのトラックが他のトラックよりも短かった場合には、
+                // この入力の開始位置までの隙間を埋める
+                let track_end = mux_track.start_offset + mux_track.duration();
+                append_gap(mux_track, input_offset.saturating_sub(track_end));
+
+                // 入力と出力でタイムスケールが異なる場合には変換する
+                let input_timescale = track.timescale.get() as i128;
+                let output_timescale = mux_track.timescale.get() as i128;
+                let rescale =
+                    |ticks: i64| (ticks as i128 * output_timescale / input_timescale) as i64;
+
+                let composition_offsets = read_composition_offsets(
+                    track.sample_table.stbl_box(),
+                    track.sample_table.sample_count() as usize,
+                )
+                .or_fail()?;
+
+                // 出力のメディア時刻（トラックのタイムスケール単位）での、この入力のサンプル群の開始位置
+                let base_ticks = mux_track.media_duration();
+                let mut media_end_ticks = 0;
+                for (sample, &composition_offset) in
+                    track.sample_table.samples().zip(&composition_offsets)
+                {
+                    let sample_end_ticks = sample.timestamp() + sample.duration() as u64;
+                    let sample_entry_index =
+                        mux_track.add_sample_entry(sample.chunk().sample_entry().clone());
+                    source_samples.push(SourceSample {
+                        input_index,
+                        track_index,
+                        sample_index: mux_track.samples.len(),
+                        decode_time: input_offset
+                            + Duration::from_secs(sample.timestamp()) / track.timescale.get(),
+                        data_offset: sample.data_offset(),
+                    });
+                    let duration =
+                        rescale(sample_end_ticks as i64) - rescale(sample.timestamp() as i64);
+                    mux_track.samples.push(MuxSample {
+                        sample_entry_index,
+                        duration: duration as u32,
+                        is_sync: sample.is_sync_sample(),
+                        composition_offset: rescale(composition_offset as i64) as i32,
+                        data_offset: 0, // copy_sample_data() の中で確定する
+                        data_size: sample.data_size(),
+                    });
+                    media_end_ticks = sample_end_ticks;
+                }
+
+                // 入力の編集リストが示す表示区間を、出力のメディア時刻に変換して引き継ぐ
+                // （編集リストがない場合には、サンプル群の全体が表示される）
+                let edits = if track.edits.is_empty() {
+                    vec![MuxEdit::Media(0..media_end_ticks)]
+                } else {
+                    track.edits.clone()
+                };
+                for edit in edits {
+                    match edit {
+                        MuxEdit::Empty(duration) => append_gap(mux_track, duration),
+                        MuxEdit::Media(range) => {
+                            let to_output_ticks =
+                                |ticks: u64| base_ticks + rescale(ticks as i64) as u64;
+                            append_media_edit(
+                                mux_track,
+                                to_output_ticks(range.start)..to_output_ticks(range.end),
+                            );
+                        }
+                    }
+                }
+
+                input_end = input_end.max(mux_track.start_offset + mux_track.duration());
+            }
+            input_offset = input_end;
+        }
+
+        // 全体が表示されるだけの場合には、編集リストは不要
+        for mux_track in &mut mux_tracks {
+            if mux_track.edits == [MuxEdit::Media(0..mux_track.media_duration())] {
+                mux_track.edits.clear();
+            }
+        }
+
+        let inputs = self
+            .inputs
+            .iter()
+            .map(|i| i.bytes.as_slice())
+            .collect::<Vec<_>>();
+        let mdat_payload = copy_sample_data(&mut mux_tracks, source_samples, &inputs).or_fail()?;
+        self.output = write_progressive_mp4(&mux_tracks, &mdat_payload).or_fail()?;
+        self.inputs.clear();
+        Ok(())
+    }
+
+    // 同じトラックにまとめられない入力の組み合わせが含まれていないかを確認する
+    fn check_compatibility(&self) -> orfail::Result<()> {
+        // トラックの種類ごとの、最初に登場した入力の位置とコーデック設定
+        let mut first_keys = Vec::<(TrackKind, usize, String)>::new();
+        for (input_index, input) in self.inputs.iter().enumerate() {
+            for track in &input.tracks {
+                for entry in &track.sample_table.stbl_box().stsd_box.entries {
+                    // Mp4::load() の中でチェック済みなので、非対応のサンプルエントリーは存在しない
+                    let key = DecoderConfig::from_sample_entry(entry)
+                        .map(|c| compatibility_key(&c))
+                        .or_fail()?;
+                    let Some((_, first_index, first_key)) =
+                        first_keys.iter().find(|(kind, ..)| *kind == track.kind)
+                    else {
+                        first_keys.push((track.kind, input_index, key));
+                        continue;
+                    };
+                    (*first_key == key).or_fail_with(|()| {
+                        format!(
+                            "Incompatible {:?} tracks: {first_key} (input #{first_index}) and {key} (input #{input_index})",
+                            track.kind
+                        )
+                    })?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+}
+
+// 連結時に一致している必要がある、コーデック設定の項目を文字列にまとめる
+fn compatibility_key(config: &DecoderConfig) -> String {
+    match config {
+        DecoderConfig::Audio(c) => format!(
+            "{} {} Hz {} ch",
+            c.codec, c.sample_rate, c.number_of_channels
+        ),
+        DecoderConfig::Video(c) => {
+            // プロファイルやレベルは異なっていてもよいので、コーデックの種類のみを比較する
+            let codec = c.codec.split('.').next().unwrap_or_default();
+            format!("{codec} {}x{}", c.coded_width, c.coded_height)
+        }
+    }
+}
+
+// トラックの表示区間の末尾に空白を追加する
+//
+// 最後のサンプルまでが表示されている場合には、編集リストのエントリーを増やさないように、
+// そのサンプルの尺を伸ばして隙間を埋める
+fn append_gap(track: &mut MuxTrack, gap: Duration) {
+    if gap.is_zero() {
+        return;
+    }
+    if track.edits.is_empty() {
+        // トラックの先頭の空白
+        track.start_offset += gap;
+        return;
+    }
+
+    let media_duration = track.media_duration();
+    let gap_ticks = to_timescale(gap, track.timescale);
+    match (track.samples.last_mut(), track.edits.last_mut()) {
+        (Some(last), Some(MuxEdit::Media(range))) if range.end == media_duration => {
+            last.duration += gap_ticks as u32;
+            range.end += gap_ticks;
+        }
+        _ => track.push_edit(MuxEdit::Empty(gap)),
+    }
+}
+
+// トラックの表示区間の末尾に、メディア内の区間を追加する
+fn append_media_edit(track: &mut MuxTrack, mut range: Range<u64>) {
+    // 編集リストの尺はムービータイムスケール単位に丸められているので、
+    // そのずれ程度しか離れていない区間は連続しているものとして扱う
+    let tolerance = to_timescale(EDIT_ROUNDING_TOLERANCE, track.timescale);
+    if let Some(MuxEdit::Media(last)) = track.edits.last() {
+        if last.end.abs_diff(range.start) <= tolerance {
+            range.start = last.end;
+        }
+    }
+    track.push_edit(MuxEdit::Media(range));
+}
+
+#[derive(Debug)]
+struct ConcatInput {
+    bytes: Vec<u8>,
+    tracks: Vec<Track>,
+}
+
+// 入力 MP4 から出力先のトラックにコピーするサンプル
+#[derive(Debug)]
+struct SourceSample {
+    // 入力 MP4 の位置
+    input_index: usize,
+
+    // 出力先のトラックとサンプルの位置
+    track_index: usize,
+    sample_index: usize,
+
+    // トラック間でサンプルのデータを時刻順に並べるために使われるデコード時刻
+    decode_time: Duration,
+
+    // 入力 MP4 内でのデータ位置
+    data_offset: u64,
+}
+
+// サンプルのデータを、全トラックを通してデコード時刻順になるように並べた mdat のペイロードを生成する
+//
+// 出力先のサンプルのデータ位置もここで設定される
+fn copy_sample_data(
+    tracks: &mut [MuxTrack],
+    mut source_samples: Vec<SourceSample>,
+    inputs: &[&[u8]],
+) -> orfail::Result<Vec<u8>> {
+    source_samples.sort_by_key(|s| s.decode_time);
+
+    let mut payload = Vec::new();
+    for source in source_samples {
+        let sample = &mut tracks[source.track_index].samples[source.sample_index];
+        let start = source.data_offset as usize;
+        let end = start + sample.data_size as usize;
+        let data = inputs[source.input_index]
+            .get(start..end)
+            .or_fail_with(|()| "Sample data is out of range".to_owned())?;
+        sample.data_offset = payload.len() as u64;
+        payload.extend_from_slice(data);
+    }
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shiguredo_mp4::{boxes::MdatBox, BoxType};
+
+    use crate::{
+        mp4::VideoDecoderConfig,
+        muxer::{Mp4Muxer, VIDEO_TIMESCALE},
+    };
+
+    // 表示順が I0 B1 B2 P3 で、デコード順が I0 P3 B1 B2 となる GOP を gop_count 個含む MP4 を生成する
+    fn make_b_frame_mp4(gop_count: u64) -> Vec<u8> {
+        make_mp4("vp8", 320, gop_count)
+    }
+
+    fn make_mp4(codec: &str, coded_width: u16, gop_count: u64) -> Vec<u8> {
+        let mut muxer = Mp4Muxer::new(None);
+        muxer
+            .set_video_config(&VideoDecoderConfig {
+                codec: codec.to_owned(),
+                description: Vec::new(),
+                coded_width,
+                coded_height: 240,
+            })
+            .expect("set_video_config");
+        for gop in 0..gop_count {
+            let base = gop * 132;
+            for (pts, offset, is_key) in [
+                (0, 33, true),
+                (99, 99, false),
+                (33, 0, false),
+                (66, 0, false),
+            ] {
+                muxer
+                    .append_chunk(
+                        TrackKind::Video,
+                        Duration::from_millis(base + pts),
+                        Duration::from_millis(offset),
+                        Some(Duration::from_millis(33)),
+                        is_key,
+                        &[gop as u8; 10],
+                    )
+                    .expect("append_chunk");
+            }
+        }
+        muxer.finish().expect("finish");
+        muxer.take_output()
+    }
+
+    fn to_ticks(ms: i64) -> i64 {
+        ms * VIDEO_TIMESCALE.get() as i64 / 1000
+    }
+
+    #[test]
+    fn trim_keeps_composition_offsets() {
+        let input = make_b_frame_mp4(3);
+        let mp4 = Mp4::load(&input).expect("load input");
+        let output = trim_mp4(
+            &mp4.tracks,
+            &input,
+            Duration::from_millis(140),
+            Duration::from_millis(240),
+        )
+        .expect("trim_mp4");
+
+        let trimmed = Mp4::load(&output).expect("load output");
+        let sample_table = &trimmed.tracks[0].sample_table;
+        assert_eq!(sample_table.sample_count(), 4);
+        let offsets = read_composition_offsets(sample_table.stbl_box(), 4).expect("ctts");
+        assert_eq!(
+            offsets,
+            [0, to_ticks(66), to_ticks(-33), to_ticks(-33)].map(|t| t as i32)
+        );
+
+        // 二つ目の GOP の I フレーム（表示時刻 132 ms）から 140 ms までがプリロールとなる
+        let (moov_box, _) = Mp4::load_moov_box(&output).expect("moov");
+        let elst_box = moov_box.trak_boxes[0]
+            .edts_box
+            .as_ref()
+            .and_then(|b| b.elst_box.as_ref())
+            .expect("elst");
+        assert_eq!(elst_box.entries.len(), 1);
+        assert_eq!(elst_box.entries[0].media_time, to_ticks(8));
+        assert_eq!(elst_box.entries[0].edit_duration, 100);
+    }
+
+    #[test]
+    fn trim_keeps_reference_frames_displayed_after_end() {
+        let input = make_b_frame_mp4(3);
+        let mp4 = Mp4::load(&input).expect("load input");
+
+        // 二つ目の GOP の B フレーム（表示時刻 165, 198 ms）は、表示時刻が end より後の
+        // P フレーム（表示時刻 231 ms）を参照するので、その P フレームも出力に含まれる
+        let output = trim_mp4(
+            &mp4.tracks,
+            &input,
+            Duration::from_millis(100),
+            Duration::from_millis(200),
+        )
+        .expect("trim_mp4");
+        let trimmed = Mp4::load(&output).expect("load output");
+        assert_eq!(trimmed.tracks[0].sample_table.sample_count(), 8);
+    }
+
+    fn trim(input: &[u8], tracks: &[Track], start_ms: u64, end_ms: u64) -> Vec<u8> {
+        let start = Duration::from_millis(start_ms);
+        let end = Duration::from_millis(end_ms);
+        trim_mp4(tracks, input, start, end).expect("trim_mp4")
+    }
+
+    #[test]
+    fn trim_applies_input_edit_lists() {
+        let input = make_b_frame_mp4(3);
+        let tracks = Mp4::load(&input).expect("load input").tracks;
+        let trimmed = trim(&input, &tracks, 140, 240);
+
+        // 切り出し済みのファイルの表示時刻 20 ms は、元のファイルの 160 ms に対応する
+        let trimmed_tracks = Mp4::load(&trimmed).expect("load trimmed").tracks;
+        assert_eq!(
+            trim(&trimmed, &trimmed_tracks, 20, 60),
+            trim(&input, &tracks, 160, 200)
+        );
+    }
+
+    #[test]
+    fn trim_keeps_leading_empty_edits() {
+        let input = make_b_frame_mp4(3);
+        let tracks = Mp4::load(&input).expect("load input").tracks;
+        let mut delayed_tracks = tracks.clone();
+        let media_duration = delayed_tracks[0]
+            .sample_table
+            .samples()
+            .last()
+            .map_or(0, |s| s.timestamp() + s.duration() as u64);
+        delayed_tracks[0].edits = vec![
+            MuxEdit::Empty(Duration::from_millis(100)),
+            MuxEdit::Media(0..media_duration),
+        ];
+
+        // 空白区間の後ろだけを切り出す場合には、元のサンプル群の位置がその分だけずれる
+        assert_eq!(
+            trim(&input, &delayed_tracks, 140, 240),
+            trim(&input, &tracks, 40, 140)
+        );
+
+        // 空白区間の途中から切り出す場合には、残りの空白区間がトラックの開始位置となる
+        let output = trim(&input, &delayed_tracks, 50, 150);
+        let track = &Mp4::load(&output).expect("load output").tracks[0];
+        assert_eq!(track.start_offset(), Duration::from_millis(50));
+        assert_eq!(
+            track.edits,
+            [
+                MuxEdit::Empty(Duration::from_millis(50)),
+                MuxEdit::Media(0..to_ticks(50) as u64),
+            ]
+        );
+    }
+
+    // トップレベルのボックスの種別を順番に返す
+    fn top_level_box_types(mut bytes: &[u8]) -> Vec<BoxType> {
+        let mut box_types = Vec::new();
+        while !bytes.is_empty() {
+            box_types.push(IgnoredBox::decode(&mut bytes).expect("decode").box_type);
+        }
+        box_types
+    }
+
+    // 各トラックのサンプルのデータを順番に並べたもの
+    fn sample_data(mp4_bytes: &[u8]) -> Vec<Vec<&[u8]>> {
+        let mp4 = Mp4::load(mp4_bytes).expect("load");
+        mp4.tracks
+            .iter()
+            .map(|track| {
+                track
+                    .sample_table
+                    .samples()
+                    .map(|s| &mp4_bytes[s.data_offset() as usize..][..s.data_size() as usize])
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn faststart_moves_moov_before_mdat() {
+        let input = make_b_frame_mp4(3);
+        assert_eq!(
+            top_level_box_types(&input),
+            [FtypBox::TYPE, MdatBox::TYPE, MoovBox::TYPE]
+        );
+
+        let output = faststart_mp4(&input).expect("faststart_mp4");
+        assert_eq!(
+            top_level_box_types(&output),
+            [FtypBox::TYPE, MoovBox::TYPE, MdatBox::TYPE]
+        );
+        assert_eq!(sample_data(&output), sample_data(&input));
+
+        // 既に moov が先頭側にある場合には何も変わらない
+        assert_eq!(faststart_mp4(&output).expect("faststart_mp4"), output);
+    }
+
+    #[test]
+    fn faststart_accepts_files_without_ftyp() {
+        // ftyp ボックスを同じサイズの wide ボックスに置き換えた QuickTime 形式のファイル
+        let mut input = make_b_frame_mp4(3);
+        input[4..8].copy_from_slice(b"wide");
+
+        let output = faststart_mp4(&input).expect("faststart_mp4");
+        assert_eq!(
+            top_level_box_types(&output),
+            [MoovBox::TYPE, BoxType::Normal(*b"wide"), MdatBox::TYPE]
+        );
+        assert_eq!(sample_data(&output), sample_data(&input));
+    }
+
+    #[test]
+    fn faststart_promotes_large_chunk_offsets_to_co64() {
+        let input = make_b_frame_mp4(3);
+        let (moov_box, _) = Mp4::load_moov_box(&input).expect("moov");
+        let stco_or_co64 = |moov_box: &MoovBox| {
+            moov_box
+                .trak_boxes
+                .iter()
+                .map(|t| match &t.mdia_box.minf_box.stbl_box.stco_or_co64_box {
+                    Either::A(b) => Either::A(b.chunk_offsets.len()),
+                    Either::B(b) => Either::B(b.chunk_offsets.clone()),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let relocated = relocate_chunk_offsets(&moov_box, |o| Ok(o + 100)).expect("relocate");
+        assert!(matches!(stco_or_co64(&relocated)[..], [Either::A(_)]));
+
+        // 32 ビットに収まらなくなったトラックは co64 ボックスに変換される
+        let base = u32::MAX as u64;
+        let relocated = relocate_chunk_offsets(&moov_box, |o| Ok(o + base)).expect("relocate");
+        let [Either::B(chunk_offsets)] = &stco_or_co64(&relocated)[..] else {
+            panic!("co64 box expected");
+        };
+        let Either::A(original) = &moov_box.trak_boxes[0]
+            .mdia_box
+            .minf_box
+            .stbl_box
+            .stco_or_co64_box
+        else {
+            panic!("stco box expected");
+        };
+        let expected = original
+            .chunk_offsets
+            .iter()
+            .map(|&o| o as u64 + base)
+            .collect::<Vec<_>>();
+        assert_eq!(*chunk_offsets, expected);
+    }
+
+    fn concat(inputs: &[&[u8]]) -> orfail::Result<Vec<u8>> {
+        let mut concatenator = Mp4Concatenator::new();
+        for input in inputs {
+            concatenator.add_input(input.to_vec())?;
+        }
+        concatenator.finish()?;
+        Ok(concatenator.take_output())
+    }
+
+    #[test]
+    fn concat_keeps_composition_offsets() {
+        let input = make_b_frame_mp4(1);
+        let output = concat(&[&input, &input]).expect("concat");
+
+        let mp4 = Mp4::load(&output).expect("load output");
+        let sample_table = &mp4.tracks[0].sample_table;
+        assert_eq!(sample_table.sample_count(), 8);
+        let offsets = read_composition_offsets(sample_table.stbl_box(), 8).expect("ctts");
+        let gop = [0, to_ticks(66), to_ticks(-33), to_ticks(-33)].map(|t| t as i32);
+        assert_eq!(offsets, [gop, gop].concat());
+
+        // 入力全体がそのまま並ぶだけなので、編集リストは不要
+        assert!(mp4.tracks[0].edits.is_empty());
+    }
+
+    #[test]
+    fn concat_applies_input_edit_lists() {
+        let input = make_b_frame_mp4(3);
+        let mp4 = Mp4::load(&input).expect("load input");
+        let trimmed = trim_mp4(
+            &mp4.tracks,
+            &input,
+            Duration::from_millis(140),
+            Duration::from_millis(240),
+        )
+        .expect("trim_mp4");
+        let output = concat(&[&trimmed, &trimmed]).expect("concat");
+
+        // 各入力のプリロール部分は、出力でも表示されない
+        let mp4 = Mp4::load(&output).expect("load output");
+        let gop_ticks = to_ticks(132) as u64;
+        let pre_roll = to_ticks(8) as u64;
+        let presented = to_ticks(100) as u64;
+        assert_eq!(
+            mp4.tracks[0].edits,
+            [
+                MuxEdit::Media(pre_roll..pre_roll + presented),
+                MuxEdit::Media(gop_ticks + pre_roll..gop_ticks + pre_roll + presented),
+            ]
+        );
+    }
+
+    #[test]
+    fn concat_adds_sample_entries_for_different_codec_parameters() {
+        let first = make_mp4("vp09.00.10.08", 320, 1);
+        let second = make_mp4("vp09.00.20.08", 320, 1);
+        let output = concat(&[&first, &second]).expect("concat");
+
+        let mp4 = Mp4::load(&output).expect("load output");
+        let stsd_box = &mp4.tracks[0].sample_table.stbl_box().stsd_box;
+        assert_eq!(stsd_box.entries.len(), 2);
+    }
+
+    #[test]
+    fn concat_rejects_incompatible_inputs() {
+        let first = make_mp4("vp8", 320, 1);
+        for second in [make_mp4("vp8", 640, 1), make_mp4("vp09.00.10.08", 320, 1)] {
+            assert!(concat(&[&first, &second]).is_err());
+        }
+    }
+}
@@ -0,0 +1,438 @@
+use std::{cell::RefCell, collections::VecDeque, mem, time::Duration};
+
+use futures::channel::oneshot;
+use orfail::OrFail;
+use serde::Serialize;
+
+use crate::{
+    engine::Engine,
+    mp4::TrackKind,
+    player::{PlayOptions, PlayerId},
+    stats::{serialize_micros, PlayerStats},
+    wasm::{self, DecoderId},
+};
+
+// シミュレーターが使うプレイヤーの ID (一度に一つのプレイヤーしか扱わないので固定）
+const PLAYER_ID: PlayerId = 0;
+
+// TypeScript 側と同じデコーダーの ID
+const AUDIO_DECODER_ID: DecoderId = 0;
+const VIDEO_DECODER_ID: DecoderId = 1;
+
+// AudioWorklet が音声の再生位置を通知する間隔（audio_processor.js の 48 kHz の場合の値に合わせている）
+const AUDIO_POSITION_REPORT_INTERVAL: Duration = Duration::from_micros(85_333);
+
+thread_local! {
+    static HOST: RefCell<HostState> = RefCell::new(HostState::default());
+}
+
+#[derive(Debug, Clone)]
+pub struct SimulateOptions {
+    pub repeat: bool,
+
+    // 仮想時刻がこの値を超えたらシミュレーションを終了する（repeat=true の場合には指定が必須）
+    pub until: Option<Duration>,
+
+    // スリープからの復帰を指定時間だけ遅らせる（タイマーの精度が悪い環境やバックグラウンドタブの再現用）
+    pub timer_delay: Duration,
+
+    // 指定された場合には、音声データが出力されてからこの時間が経過した時点で再生されたものとして、
+    // AudioWorklet からの再生位置の通知を模擬する（デコーダーや出力バッファによる遅延の再現用）
+    pub audio_latency: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SimulationReport {
+    pub events: Vec<SimulationEvent>,
+    pub stats: PlayerStats,
+}
+
+// TypeScript 側（ブラウザ）に対して行われた呼び出しと、その時点の仮想時刻
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SimulationEvent {
+    #[serde(rename = "timeMicros", serialize_with = "serialize_micros")]
+    pub time: Duration,
+
+    #[serde(flatten)]
+    pub kind: SimulationEventKind,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum SimulationEventKind {
+    #[serde(rename_all = "camelCase")]
+    ConfigureAudioDecoder {
+        codec: String,
+        sample_rate: u32,
+        number_of_channels: u8,
+    },
+    #[serde(rename_all = "camelCase")]
+    ConfigureVideoDecoder {
+        codec: String,
+        coded_width: u16,
+        coded_height: u16,
+    },
+    #[serde(rename_all = "camelCase")]
+    Decode {
+        track_kind: TrackKind,
+        #[serde(rename = "timestampMicros", serialize_with = "serialize_micros")]
+        timestamp: Duration,
+        #[serde(rename = "durationMicros", serialize_with = "serialize_micros")]
+        duration: Duration,
+        is_key: bool,
+        data_size: u32,
+    },
+    #[serde(rename_all = "camelCase")]
+    OutputAudio {
+        #[serde(rename = "timestampMicros", serialize_with = "serialize_micros")]
+        timestamp: Duration,
+        number_of_samples: u32,
+    },
+    #[serde(rename_all = "camelCase")]
+    Cue {
+        track_id: u32,
+        #[serde(rename = "startMicros", serialize_with = "serialize_micros")]
+        start: Duration,
+        #[serde(rename = "endMicros", serialize_with = "serialize_micros")]
+        end: Duration,
+        text: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    ResetDecoder {
+        track_kind: TrackKind,
+    },
+    #[serde(rename_all = "camelCase")]
+    CloseDecoder {
+        track_kind: TrackKind,
+    },
+    #[serde(rename_all = "camelCase")]
+    TimeUpdate {
+        #[serde(rename = "positionMicros", serialize_with = "serialize_micros")]
+        position: Duration,
+    },
+    Eos,
+}
+
+// ブラウザを使わずに、仮想時刻の上で MP4 の再生をシミュレートする
+//
+// Engine / Player は Wasm 上で動作する場合と同じものが使われ、
+// TypeScript 側の処理（タイマーやデコーダー）だけが、以下の振る舞いをするものに置き換えられる:
+// - スリープは指定時間（+ timer_delay）が経過した時点で即座に復帰する
+// - デコーダーの生成は即座に完了し、デコード結果も即座に出力される
+// - PCM の音声データや字幕のキューは記録されるだけで、どこにも出力されない
+// - AudioWorklet からの音声の再生位置の通知は、audio_latency が指定された場合にのみ行われる
+//   (指定がない場合には、映像は仮想時刻のみに従って再生される）
+//
+// [NOTE] スレッドローカルな状態を使っているので、同じスレッド内で同時に複数のシミュレーションは実行できない
+pub fn simulate_playback(
+    mp4_bytes: Vec<u8>,
+    options: SimulateOptions,
+) -> orfail::Result<SimulationReport> {
+    (!options.repeat || options.until.is_some())
+        .or_fail_with(|()| "A time limit is required for repeated playback".to_owned())?;
+
+    HOST.with(|host| {
+        *host.borrow_mut() = HostState {
+            timer_delay: options.timer_delay,
+            audio_latency: options.audio_latency,
+            ..HostState::default()
+        }
+    });
+
+    // wasm.rs の各エクスポート関数と同様に、エンジンは生ポインタ経由で操作する
+    let engine = Box::into_raw(Box::new(Engine::new()));
+    let result = run(engine, mp4_bytes, &options);
+    let _ = unsafe { Box::from_raw(engine) };
+
+    let events = HOST.with(|host| mem::take(&mut host.borrow_mut().events));
+    Ok(SimulationReport {
+        events,
+        stats: result.or_fail()?,
+    })
+}
+
+fn run(
+    engine: *mut Engine,
+    mp4_bytes: Vec<u8>,
+    options: &SimulateOptions,
+) -> orfail::Result<PlayerStats> {
+    unsafe { &mut *engine }.load_mp4(mp4_bytes).or_fail()?;
+    unsafe { &mut *engine }.play(
+        PLAYER_ID,
+        PlayOptions {
+            repeat: options.repeat,
+        },
+    );
+
+    loop {
+        // デコード結果とデコーダーの生成完了の通知は、スリープからの復帰よりも先に処理する
+        let (outputs, created_decoders) = HOST.with(|host| {
+            let mut host = host.borrow_mut();
+            (
+                mem::take(&mut host.decoder_outputs),
+                mem::take(&mut host.created_decoders),
+            )
+        });
+        if !outputs.is_empty() || !created_decoders.is_empty() {
+            for (decoder, timestamp) in outputs {
+                if decoder == AUDIO_DECODER_ID {
+                    HOST.with(|host| host.borrow_mut().on_audio_output(timestamp));
+                }
+                wasm::notifyDecoderOutput(engine, PLAYER_ID, decoder, timestamp.as_micros() as f64);
+            }
+            for (result_tx, decoder) in created_decoders {
+                wasm::notifyDecoderId(engine, result_tx, decoder);
+            }
+            continue;
+        }
+
+        let Some(timer) = HOST.with(|host| host.borrow_mut().next_timer(options.until)) else {
+            // 終端に達したか、時間制限を超えた
+            break;
+        };
+        if let Some(timestamp) = HOST.with(|host| host.borrow_mut().audio_position()) {
+            wasm::notifyAudioPosition(engine, PLAYER_ID, timestamp.as_micros() as f64);
+        }
+        wasm::awake(engine, timer);
+    }
+
+    let stats = unsafe { &*engine }.stats(PLAYER_ID).or_fail()?;
+    unsafe { &mut *engine }.stop(PLAYER_ID);
+    Ok(stats)
+}
+
+#[derive(Debug, Default)]
+struct HostState {
+    now: Duration,
+    timer_delay: Duration,
+
+    // 復帰予定時刻が早い順に処理される（同じ時刻の場合は登録順）
+    timers: Vec<(Duration, *mut oneshot::Sender<()>)>,
+
+    created_decoders: Vec<(*mut oneshot::Sender<DecoderId>, DecoderId)>,
+    decoder_outputs: Vec<(DecoderId, Duration)>,
+    events: Vec<SimulationEvent>,
+
+    // 出力された音声データの再生開始時刻とタイムスタンプ（audio_latency が指定された場合のみ記録される）
+    audio_latency: Option<Duration>,
+    audio_outputs: VecDeque<(Duration, Duration)>,
+    last_audio_position_report: Option<Duration>,
+}
+
+impl HostState {
+    // 次に復帰するタイマーを取り出して、仮想時刻をその時刻まで進める
+    fn next_timer(&mut self, until: Option<Duration>) -> Option<*mut oneshot::Sender<()>> {
+        let (i, &(wake_at, _)) = self
+            .timers
+            .iter()
+            .enumerate()
+            .min_by_key(|(i, (wake_at, _))| (*wake_at, *i))?;
+        if until.is_some_and(|until| wake_at > until) {
+            return None;
+        }
+        self.now = self.now.max(wake_at);
+        Some(self.timers.remove(i).1)
+    }
+
+    fn on_audio_output(&mut self, timestamp: Duration) {
+        if let Some(latency) = self.audio_latency {
+            self.audio_outputs
+                .push_back((self.now + latency, timestamp));
+        }
+    }
+
+    // 通知間隔が経過していれば、現在再生中の音声データのタイムスタンプを返す
+    fn audio_position(&mut self) -> Option<Duration> {
+        if self
+            .last_audio_position_report
+            .is_some_and(|t| self.now < t + AUDIO_POSITION_REPORT_INTERVAL)
+        {
+            return None;
+        }
+        while self
+            .audio_outputs
+            .get(1)
+            .is_some_and(|&(play_at, _)| play_at <= self.now)
+        {
+            self.audio_outputs.pop_front();
+        }
+        let &(play_at, timestamp) = self.audio_outputs.front()?;
+        if play_at > self.now {
+            return None;
+        }
+        self.last_audio_position_report = Some(self.now);
+        Some(timestamp + (self.now - play_at))
+    }
+
+    fn push_event(&mut self, kind: SimulationEventKind) {
+        self.events.push(SimulationEvent {
+            time: self.now,
+            kind,
+        });
+    }
+}
+
+fn decoder_track_kind(decoder: DecoderId) -> TrackKind {
+    if decoder == AUDIO_DECODER_ID {
+        TrackKind::Audio
+    } else {
+        TrackKind::Video
+    }
+}
+
+// wasm.rs の extern "C" ブロック（TypeScript 側から提供される関数群）のネイティブ環境向けの代替実装
+//
+// 名前とシグネチャは extern "C" ブロックのものと一致させる必要がある
+#[expect(non_snake_case)]
+pub(crate) mod host {
+    use std::time::Duration;
+
+    use futures::channel::oneshot;
+
+    use super::{
+        decoder_track_kind, SimulationEventKind, AUDIO_DECODER_ID, HOST, VIDEO_DECODER_ID,
+    };
+    use crate::{
+        mp4::{AudioDecoderConfig, VideoDecoderConfig},
+        player::PlayerId,
+        subtitle::SubtitleCue,
+        wasm::{DecoderId, EncodedChunkMetadata, JsonVec},
+    };
+
+    pub(crate) unsafe fn consoleLog(messageWasmJson: JsonVec<String>) {
+        eprintln!("{}", messageWasmJson.into_value());
+    }
+
+    pub(crate) unsafe fn now() -> f64 {
+        HOST.with(|host| host.borrow().now.as_secs_f64() * 1000.0)
+    }
+
+    pub(crate) unsafe fn sleep(result_tx: *mut oneshot::Sender<()>, duration: u32) {
+        HOST.with(|host| {
+            let mut host = host.borrow_mut();
+            // ブラウザのタイマーと同様に、0 ミリ秒のスリープでも最低 1 ミリ秒は経過させる
+            // (そうしないと、ミリ秒未満の待ち時間が残っている場合に仮想時刻が進まなくなる）
+            let duration = Duration::from_millis(duration.max(1) as u64);
+            let wake_at = host.now + duration + host.timer_delay;
+            host.timers.push((wake_at, result_tx));
+        });
+    }
+
+    pub(crate) unsafe fn decode(
+        _player_id: PlayerId,
+        decoder: DecoderId,
+        metadata: *const EncodedChunkMetadata,
+        _data_ptr: *const u8,
+        data_len: u32,
+    ) {
+        let metadata = &*metadata;
+        let timestamp = Duration::from_micros(metadata.timestamp as u64);
+        HOST.with(|host| {
+            let mut host = host.borrow_mut();
+            host.push_event(SimulationEventKind::Decode {
+                track_kind: decoder_track_kind(decoder),
+                timestamp,
+                duration: Duration::from_micros(metadata.duration as u64),
+                is_key: metadata.is_key != 0,
+                data_size: data_len,
+            });
+            host.decoder_outputs.push((decoder, timestamp));
+        });
+    }
+
+    pub(crate) unsafe fn createVideoDecoder(
+        result_tx: *mut oneshot::Sender<DecoderId>,
+        _player_id: PlayerId,
+        config: JsonVec<VideoDecoderConfig>,
+    ) {
+        let config = config.into_value();
+        HOST.with(|host| {
+            let mut host = host.borrow_mut();
+            host.push_event(SimulationEventKind::ConfigureVideoDecoder {
+                codec: config.codec,
+                coded_width: config.coded_width,
+                coded_height: config.coded_height,
+            });
+            host.created_decoders.push((result_tx, VIDEO_DECODER_ID));
+        });
+    }
+
+    pub(crate) unsafe fn createAudioDecoder(
+        result_tx: *mut oneshot::Sender<DecoderId>,
+        _player_id: PlayerId,
+        config: JsonVec<AudioDecoderConfig>,
+    ) {
+        let config = config.into_value();
+        HOST.with(|host| {
+            let mut host = host.borrow_mut();
+            host.push_event(SimulationEventKind::ConfigureAudioDecoder {
+                codec: config.codec,
+                sample_rate: config.sample_rate,
+                number_of_channels: config.number_of_channels,
+            });
+            host.created_decoders.push((result_tx, AUDIO_DECODER_ID));
+        });
+    }
+
+    pub(crate) unsafe fn outputAudio(
+        _player_id: PlayerId,
+        timestamp_micros: f64,
+        _samples_ptr: *const f32,
+        samples_len: u32,
+    ) {
+        let timestamp = Duration::from_micros(timestamp_micros as u64);
+        HOST.with(|host| {
+            let mut host = host.borrow_mut();
+            host.push_event(SimulationEventKind::OutputAudio {
+                timestamp,
+                number_of_samples: samples_len,
+            });
+            host.on_audio_output(timestamp);
+        });
+    }
+
+    pub(crate) unsafe fn outputCue(_player_id: PlayerId, cue: JsonVec<SubtitleCue>) {
+        let cue = cue.into_value();
+        HOST.with(|host| {
+            host.borrow_mut().push_event(SimulationEventKind::Cue {
+                track_id: cue.track_id,
+                start: cue.start,
+                end: cue.end,
+                text: cue.text,
+            })
+        });
+    }
+
+    pub(crate) unsafe fn closeDecoder(_player_id: PlayerId, decoder: DecoderId) {
+        HOST.with(|host| {
+            host.borrow_mut()
+                .push_event(SimulationEventKind::CloseDecoder {
+                    track_kind: decoder_track_kind(decoder),
+                })
+        });
+    }
+
+    pub(crate) unsafe fn resetDecoder(_player_id: PlayerId, decoder: DecoderId) {
+        HOST.with(|host| {
+            host.borrow_mut()
+                .push_event(SimulationEventKind::ResetDecoder {
+                    track_kind: decoder_track_kind(decoder),
+                })
+        });
+    }
+
+    pub(crate) unsafe fn onEos(_player_id: PlayerId) {
+        HOST.with(|host| host.borrow_mut().push_event(SimulationEventKind::Eos));
+    }
+
+    pub(crate) unsafe fn onTimeUpdate(_player_id: PlayerId, position_micros: f64) {
+        HOST.with(|host| {
+            host.borrow_mut()
+                .push_event(SimulationEventKind::TimeUpdate {
+                    position: Duration::from_micros(position_micros as u64),
+                })
+        });
+    }
+}
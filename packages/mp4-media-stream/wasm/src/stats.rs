@@ -0,0 +1,411 @@
+use std::{collections::VecDeque, time::Duration};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{mp4::TrackKind, wasm::DecoderId};
+
+// 再生時刻からこれ以上遅れてデコーダーに渡されたサンプルを「遅延サンプル」として扱う
+const LATE_SAMPLE_THRESHOLD: Duration = Duration::from_millis(50);
+
+// デコード結果の通知待ちのサンプルを保持する上限
+// (TypeScript 側からの通知が来ない場合でもメモリ使用量が増え続けないようにするため）
+const MAX_PENDING_SAMPLES: usize = 256;
+
+// 再生中の統計情報
+//
+// 時間系の値は全てマイクロ秒単位で JSON にシリアライズされる
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerStats {
+    // 再生時刻から LATE_SAMPLE_THRESHOLD 以上遅れてデコーダーに渡されたサンプルの数
+    pub late_samples: u64,
+
+    #[serde(rename = "maxLatenessMicros", serialize_with = "serialize_micros")]
+    pub max_lateness: Duration,
+
+    // スリープから復帰した回数と、予定時刻からの超過時間
+    pub sleep_count: u64,
+
+    #[serde(
+        rename = "totalSleepOvershootMicros",
+        serialize_with = "serialize_micros"
+    )]
+    pub total_sleep_overshoot: Duration,
+
+    #[serde(
+        rename = "maxSleepOvershootMicros",
+        serialize_with = "serialize_micros"
+    )]
+    pub max_sleep_overshoot: Duration,
+
+    // 音声の再生位置と映像のスケジューリングに使う時計のズレ（再生開始直後に計測した音声の出力遅延は除く）
+    #[serde(rename = "audioClockDriftMicros", serialize_with = "serialize_micros")]
+    pub audio_clock_drift: Duration,
+
+    #[serde(
+        rename = "maxAudioClockDriftMicros",
+        serialize_with = "serialize_micros"
+    )]
+    pub max_audio_clock_drift: Duration,
+
+    // ズレが大きすぎたために、徐々に補正せずに一気に音声側の時計に合わせた回数
+    pub audio_clock_resyncs: u64,
+
+    pub tracks: Vec<TrackStats>,
+}
+
+impl PlayerStats {
+    pub fn new(track_kinds: impl Iterator<Item = TrackKind>) -> Self {
+        Self {
+            late_samples: 0,
+            max_lateness: Duration::ZERO,
+            sleep_count: 0,
+            total_sleep_overshoot: Duration::ZERO,
+            max_sleep_overshoot: Duration::ZERO,
+            audio_clock_drift: Duration::ZERO,
+            max_audio_clock_drift: Duration::ZERO,
+            audio_clock_resyncs: 0,
+            tracks: track_kinds.map(TrackStats::new).collect(),
+        }
+    }
+
+    pub fn on_sleep(&mut self, overshoot: Duration) {
+        self.sleep_count += 1;
+        self.total_sleep_overshoot += overshoot;
+        self.max_sleep_overshoot = self.max_sleep_overshoot.max(overshoot);
+    }
+
+    pub fn on_audio_clock_drift(&mut self, drift: Duration) {
+        self.audio_clock_drift = drift;
+        self.max_audio_clock_drift = self.max_audio_clock_drift.max(drift);
+    }
+
+    pub fn on_audio_clock_resync(&mut self) {
+        self.audio_clock_resyncs += 1;
+    }
+
+    pub fn on_catch_up(&mut self, track_index: usize, skipped_samples: u32) {
+        let track = &mut self.tracks[track_index];
+        track.catch_ups += 1;
+        track.skipped_samples += skipped_samples as u64;
+        track.pending_samples.clear();
+    }
+
+    pub fn on_decoder_configured(&mut self, track_index: usize, decoder: DecoderId) {
+        let track = &mut self.tracks[track_index];
+        track.decoder_configurations += 1;
+        track.decoder = Some(decoder);
+        track.pending_samples.clear();
+    }
+
+    // デコーダーにサンプルが渡された際に呼び出される
+    //
+    // timestamp は TypeScript 側に渡したタイムスタンプで、
+    // submitted_at と deadline は WasmApi::now() を基準とした時刻
+    pub fn on_sample_submitted(
+        &mut self,
+        track_index: usize,
+        data_size: usize,
+        lateness: Duration,
+        timestamp: Duration,
+        submitted_at: Duration,
+        deadline: Duration,
+    ) {
+        if lateness >= LATE_SAMPLE_THRESHOLD {
+            self.late_samples += 1;
+        }
+        self.max_lateness = self.max_lateness.max(lateness);
+
+        let track = &mut self.tracks[track_index];
+        track.samples += 1;
+        track.bytes += data_size as u64;
+        if track.pending_samples.len() == MAX_PENDING_SAMPLES {
+            track.pending_samples.pop_front();
+        }
+        track.pending_samples.push_back(PendingSample {
+            timestamp_micros: timestamp.as_micros() as u64,
+            submitted_at,
+            deadline,
+        });
+    }
+
+    // TypeScript 側のデコーダーがフレーム（あるいは音声データ）を出力した際に呼び出される
+    pub fn on_decoder_output(&mut self, decoder: DecoderId, timestamp_micros: u64, now: Duration) {
+        let Some(track) = self.tracks.iter_mut().find(|t| t.decoder == Some(decoder)) else {
+            return;
+        };
+        let Some(i) = track
+            .pending_samples
+            .iter()
+            .position(|s| s.timestamp_micros == timestamp_micros)
+        else {
+            // 上限を超えて破棄されたサンプル、あるいはデコーダー側でタイムスタンプが変わったケース
+            return;
+        };
+        let sample = track.pending_samples.remove(i).expect("unreachable");
+
+        let latency = now.saturating_sub(sample.submitted_at);
+        track.decoded_frames += 1;
+        track.total_decode_latency += latency;
+        track.max_decode_latency = track.max_decode_latency.max(latency);
+        if sample.deadline < now {
+            // 表示期限（サンプルの尺の終端）までに出力が間に合わなかった
+            track.dropped_frames += 1;
+        }
+    }
+}
+
+// トラック単位の統計情報
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrackStats {
+    pub kind: TrackKind,
+
+    // デコーダーが生成された回数（サンプルエントリーが変わるたびに再生成される）
+    pub decoder_configurations: u64,
+
+    // デコーダーに渡されたサンプル数とバイト数
+    pub samples: u64,
+    pub bytes: u64,
+
+    // 以降は TypeScript 側からのデコード結果の通知を元にした値
+    pub decoded_frames: u64,
+    pub dropped_frames: u64,
+
+    // 再生の遅れを取り戻すために同期サンプルまでスキップした回数と、読み飛ばしたサンプルの数
+    pub catch_ups: u64,
+    pub skipped_samples: u64,
+
+    #[serde(
+        rename = "totalDecodeLatencyMicros",
+        serialize_with = "serialize_micros"
+    )]
+    pub total_decode_latency: Duration,
+
+    #[serde(rename = "maxDecodeLatencyMicros", serialize_with = "serialize_micros")]
+    pub max_decode_latency: Duration,
+
+    #[serde(skip)]
+    decoder: Option<DecoderId>,
+
+    #[serde(skip)]
+    pending_samples: VecDeque<PendingSample>,
+}
+
+impl TrackStats {
+    fn new(kind: TrackKind) -> Self {
+        Self {
+            kind,
+            decoder_configurations: 0,
+            samples: 0,
+            bytes: 0,
+            decoded_frames: 0,
+            dropped_frames: 0,
+            catch_ups: 0,
+            skipped_samples: 0,
+            total_decode_latency: Duration::ZERO,
+            max_decode_latency: Duration::ZERO,
+            decoder: None,
+            pending_samples: VecDeque::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct PendingSample {
+    timestamp_micros: u64,
+    submitted_at: Duration,
+    deadline: Duration,
+}
+
+pub fn serialize_micros<S: Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(duration.as_micros() as u64)
+}
+
+pub fn deserialize_micros<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_micros)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AUDIO: usize = 0;
+    const VIDEO: usize = 1;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    fn new_stats() -> PlayerStats {
+        PlayerStats::new([TrackKind::Audio, TrackKind::Video].into_iter())
+    }
+
+    #[test]
+    fn late_samples_and_bytes_are_counted_per_track() {
+        let mut stats = new_stats();
+        stats.on_sample_submitted(AUDIO, 100, ms(0), ms(0), ms(0), ms(20));
+        stats.on_sample_submitted(VIDEO, 1000, ms(49), ms(0), ms(49), ms(82));
+        stats.on_sample_submitted(VIDEO, 500, LATE_SAMPLE_THRESHOLD, ms(33), ms(83), ms(66));
+        stats.on_sample_submitted(AUDIO, 200, ms(120), ms(20), ms(140), ms(160));
+
+        // 閾値ちょうどの遅れは遅延サンプルに含める
+        assert_eq!(stats.late_samples, 2);
+        assert_eq!(stats.max_lateness, ms(120));
+        assert_eq!(stats.tracks[AUDIO].samples, 2);
+        assert_eq!(stats.tracks[AUDIO].bytes, 300);
+        assert_eq!(stats.tracks[VIDEO].samples, 2);
+        assert_eq!(stats.tracks[VIDEO].bytes, 1500);
+    }
+
+    #[test]
+    fn sleep_overshoot_is_accumulated() {
+        let mut stats = new_stats();
+        stats.on_sleep(ms(3));
+        stats.on_sleep(ms(10));
+        stats.on_sleep(ms(0));
+        assert_eq!(stats.sleep_count, 3);
+        assert_eq!(stats.total_sleep_overshoot, ms(13));
+        assert_eq!(stats.max_sleep_overshoot, ms(10));
+    }
+
+    #[test]
+    fn audio_clock_drift_keeps_the_latest_and_max_values() {
+        let mut stats = new_stats();
+        stats.on_audio_clock_drift(ms(5));
+        stats.on_audio_clock_drift(ms(300));
+        stats.on_audio_clock_resync();
+        stats.on_audio_clock_drift(ms(1));
+        assert_eq!(stats.audio_clock_drift, ms(1));
+        assert_eq!(stats.max_audio_clock_drift, ms(300));
+        assert_eq!(stats.audio_clock_resyncs, 1);
+    }
+
+    #[test]
+    fn decode_latency_and_dropped_frames() {
+        let mut stats = new_stats();
+        stats.on_decoder_configured(VIDEO, 1);
+        stats.on_sample_submitted(VIDEO, 10, ms(0), ms(0), ms(0), ms(33));
+        stats.on_sample_submitted(VIDEO, 10, ms(0), ms(33), ms(33), ms(66));
+        stats.on_sample_submitted(VIDEO, 10, ms(0), ms(66), ms(66), ms(100));
+
+        // デコード結果は提出順に届くとは限らない
+        stats.on_decoder_output(1, 33_000, ms(40));
+        stats.on_decoder_output(1, 0, ms(30));
+        // 表示期限を過ぎてから出力されたフレームはドロップとして扱う
+        stats.on_decoder_output(1, 66_000, ms(101));
+
+        let video = &stats.tracks[VIDEO];
+        assert_eq!(video.decoded_frames, 3);
+        assert_eq!(video.dropped_frames, 1);
+        assert_eq!(video.total_decode_latency, ms(7 + 30 + 35));
+        assert_eq!(video.max_decode_latency, ms(35));
+        assert!(video.pending_samples.is_empty());
+    }
+
+    #[test]
+    fn unknown_decoder_outputs_are_ignored() {
+        let mut stats = new_stats();
+        stats.on_decoder_configured(AUDIO, 0);
+        stats.on_sample_submitted(AUDIO, 10, ms(0), ms(0), ms(0), ms(20));
+
+        // 別のデコーダー、あるいは提出していないタイムスタンプ
+        stats.on_decoder_output(1, 0, ms(1));
+        stats.on_decoder_output(0, 1, ms(1));
+        assert_eq!(stats.tracks[AUDIO].decoded_frames, 0);
+
+        // デコーダーが作り直された場合には、それ以前のサンプルの結果は待たない
+        stats.on_decoder_configured(AUDIO, 2);
+        stats.on_decoder_output(2, 0, ms(1));
+        assert_eq!(stats.tracks[AUDIO].decoded_frames, 0);
+        assert_eq!(stats.tracks[AUDIO].decoder_configurations, 2);
+    }
+
+    #[test]
+    fn catch_ups_discard_pending_samples() {
+        let mut stats = new_stats();
+        stats.on_decoder_configured(VIDEO, 1);
+        stats.on_sample_submitted(VIDEO, 10, ms(0), ms(0), ms(0), ms(33));
+        stats.on_catch_up(VIDEO, 12);
+        stats.on_catch_up(VIDEO, 3);
+        stats.on_decoder_output(1, 0, ms(10));
+
+        let video = &stats.tracks[VIDEO];
+        assert_eq!(video.catch_ups, 2);
+        assert_eq!(video.skipped_samples, 15);
+        assert_eq!(video.decoded_frames, 0);
+    }
+
+    #[test]
+    fn pending_samples_are_bounded() {
+        let mut stats = new_stats();
+        stats.on_decoder_configured(AUDIO, 0);
+        for i in 0..MAX_PENDING_SAMPLES as u64 + 1 {
+            stats.on_sample_submitted(AUDIO, 1, ms(0), ms(i), ms(i), ms(i + 1));
+        }
+        assert_eq!(
+            stats.tracks[AUDIO].pending_samples.len(),
+            MAX_PENDING_SAMPLES
+        );
+
+        // 最も古いサンプルが破棄されている
+        stats.on_decoder_output(0, 0, ms(1000));
+        stats.on_decoder_output(0, 1000, ms(1000));
+        assert_eq!(stats.tracks[AUDIO].decoded_frames, 1);
+    }
+
+    #[test]
+    fn stats_json_shape() {
+        let mut stats = new_stats();
+        stats.on_sleep(Duration::from_micros(1500));
+        stats.on_decoder_configured(VIDEO, 1);
+        stats.on_sample_submitted(VIDEO, 42, ms(60), ms(0), ms(60), ms(33));
+        stats.on_decoder_output(1, 0, ms(70));
+
+        let json = serde_json::to_value(&stats).expect("failed to serialize");
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "lateSamples": 1,
+                "maxLatenessMicros": 60_000,
+                "sleepCount": 1,
+                "totalSleepOvershootMicros": 1500,
+                "maxSleepOvershootMicros": 1500,
+                "audioClockDriftMicros": 0,
+                "maxAudioClockDriftMicros": 0,
+                "audioClockResyncs": 0,
+                "tracks": [
+                    {
+                        "kind": "audio",
+                        "decoderConfigurations": 0,
+                        "samples": 0,
+                        "bytes": 0,
+                        "decodedFrames": 0,
+                        "droppedFrames": 0,
+                        "catchUps": 0,
+                        "skippedSamples": 0,
+                        "totalDecodeLatencyMicros": 0,
+                        "maxDecodeLatencyMicros": 0,
+                    },
+                    {
+                        "kind": "video",
+                        "decoderConfigurations": 1,
+                        "samples": 1,
+                        "bytes": 42,
+                        "decodedFrames": 1,
+                        "droppedFrames": 1,
+                        "catchUps": 0,
+                        "skippedSamples": 0,
+                        "totalDecodeLatencyMicros": 10_000,
+                        "maxDecodeLatencyMicros": 10_000,
+                    },
+                ],
+            })
+        );
+    }
+}
@@ -0,0 +1,486 @@
+use std::time::Duration;
+
+use orfail::{Failure, OrFail};
+use serde::{Deserialize, Serialize};
+use shiguredo_mp4::{
+    aux::SampleTableAccessor,
+    boxes::{MdhdBox, MdiaBox, MinfBox, SampleEntry, StblBox},
+    BaseBox, BoxType,
+};
+
+use crate::{
+    mp4::{decode_box_payload, find_child_box_payload, read_u16, read_u32, split_box},
+    stats::{deserialize_micros, serialize_micros},
+};
+
+// タイムドテキスト（字幕）トラックのハンドラー種別
+//
+// text / sbtl は 3GPP および QuickTime の tx3g で、subt は ISO/IEC 14496-30 (wvtt / stpp) で使われる
+pub const SUBTITLE_HANDLER_TYPES: [[u8; 4]; 3] = [*b"text", *b"sbtl", *b"subt"];
+
+// 対応している字幕のサンプルエントリー
+const TX3G_BOX_TYPE: BoxType = BoxType::Normal(*b"tx3g");
+const WVTT_BOX_TYPE: BoxType = BoxType::Normal(*b"wvtt");
+const STPP_BOX_TYPE: BoxType = BoxType::Normal(*b"stpp");
+
+// WebVTT in MP4 (ISO/IEC 14496-30 7.4) のサンプル内のボックス
+const VTTC_BOX_TYPE: BoxType = BoxType::Normal(*b"vttc");
+const PAYL_BOX_TYPE: BoxType = BoxType::Normal(*b"payl");
+const IDEN_BOX_TYPE: BoxType = BoxType::Normal(*b"iden");
+const STTG_BOX_TYPE: BoxType = BoxType::Normal(*b"sttg");
+
+// TTML の ttp:frameRate が省略された場合のフレームレート
+const TTML_DEFAULT_FRAME_RATE: f64 = 30.0;
+
+// 字幕の一つの表示単位
+//
+// 時刻は（繰り返し再生時のオフセットを含まない）ファイル先頭からの位置で表される
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SubtitleCue {
+    pub track_id: u32,
+
+    #[serde(
+        rename = "startMicros",
+        serialize_with = "serialize_micros",
+        deserialize_with = "deserialize_micros"
+    )]
+    pub start: Duration,
+
+    #[serde(
+        rename = "endMicros",
+        serialize_with = "serialize_micros",
+        deserialize_with = "deserialize_micros"
+    )]
+    pub end: Duration,
+
+    // 表示するテキスト（改行は "\n" で表される）
+    pub text: String,
+
+    // WebVTT のキューの ID と設定（wvtt の場合のみ）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub settings: Option<String>,
+}
+
+// Mp4Info に含める字幕トラックの情報
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SubtitleTrackInfo {
+    pub track_id: u32,
+
+    // サンプルエントリーの種別 ("tx3g" / "wvtt" / "stpp")
+    pub codec: String,
+
+    // ISO 639-2/T の言語コード ("eng" や "jpn" など）
+    pub language: String,
+}
+
+// ロード時に全てのキューを取り出した字幕トラック
+//
+// 字幕はデータ量が小さいので、再生時にサンプルを読むのではなく、ロード時にまとめて解釈しておく
+#[derive(Debug, Clone)]
+pub struct SubtitleTrack {
+    pub info: SubtitleTrackInfo,
+    pub cues: Vec<SubtitleCue>,
+}
+
+impl SubtitleTrack {
+    // trak ボックスのペイロードから字幕トラックを読み込む
+    //
+    // 字幕トラックの minf ボックスは nmhd / sthd / gmhd などの shiguredo_mp4 が対応していない
+    // メディアヘッダーを持つので、TrakBox としてデコードせずに必要なボックスだけを個別に扱う
+    pub fn new(track_id: u32, trak_payload: &[u8], mp4_bytes: &[u8]) -> orfail::Result<Self> {
+        let mdia_payload = find_child_box_payload(trak_payload, MdiaBox::TYPE)
+            .or_fail_with(|()| "Missing 'mdia' box".to_owned())?;
+        let (timescale, language) = read_mdhd(mdia_payload)
+            .or_fail_with(|()| "Invalid 'mdhd' box in subtitle track".to_owned())?;
+        let stbl_payload = find_child_box_payload(mdia_payload, MinfBox::TYPE)
+            .and_then(|minf_payload| find_child_box_payload(minf_payload, StblBox::TYPE))
+            .or_fail_with(|()| "Missing 'stbl' box".to_owned())?;
+        let stbl_box: StblBox = decode_box_payload(StblBox::TYPE, stbl_payload).or_fail()?;
+
+        let codec = match stbl_box.stsd_box.entries.as_slice() {
+            [SampleEntry::Unknown(b)]
+                if [TX3G_BOX_TYPE, WVTT_BOX_TYPE, STPP_BOX_TYPE].contains(&b.box_type) =>
+            {
+                b.box_type
+            }
+            [entry] => {
+                return Err(Failure::new(format!(
+                    "Unsupported subtitle codec: {}",
+                    entry.box_type()
+                )));
+            }
+            _ => {
+                return Err(Failure::new(
+                    "Subtitle tracks with multiple sample entries are not supported",
+                ));
+            }
+        };
+
+        let sample_table = SampleTableAccessor::new(stbl_box).or_fail()?;
+        let mut cues = Vec::new();
+        for sample in sample_table.samples() {
+            let data = usize::try_from(sample.data_offset())
+                .ok()
+                .and_then(|offset| mp4_bytes.get(offset..)?.get(..sample.data_size() as usize))
+                .or_fail_with(|()| "Subtitle sample's data is out of range".to_owned())?;
+            let start = Duration::from_secs(sample.timestamp()) / timescale;
+            let end =
+                Duration::from_secs(sample.timestamp() + sample.duration() as u64) / timescale;
+            let sample_cues = match codec {
+                TX3G_BOX_TYPE => parse_tx3g_sample(data).map(|text| {
+                    text.map(|text| SubtitleCue::new(track_id, start, end, text))
+                        .into_iter()
+                        .collect()
+                }),
+                WVTT_BOX_TYPE => parse_wvtt_sample(data, track_id, start, end),
+                _ => parse_stpp_sample(data, track_id, start, end),
+            };
+            cues.extend(
+                sample_cues.or_fail_with(|()| {
+                    format!("Invalid {codec} sample: index={}", sample.index())
+                })?,
+            );
+        }
+        cues.sort_by_key(|cue| cue.start);
+
+        Ok(Self {
+            info: SubtitleTrackInfo {
+                track_id,
+                codec: codec.to_string(),
+                language,
+            },
+            cues,
+        })
+    }
+}
+
+impl SubtitleCue {
+    fn new(track_id: u32, start: Duration, end: Duration, text: String) -> Self {
+        Self {
+            track_id,
+            start,
+            end,
+            text,
+            id: None,
+            settings: None,
+        }
+    }
+}
+
+// 複数の字幕トラックのキューを、開始時刻順に並べた一つの列にまとめる
+pub fn merge_cues(tracks: &[SubtitleTrack]) -> Vec<SubtitleCue> {
+    let mut cues = tracks
+        .iter()
+        .flat_map(|t| t.cues.iter().cloned())
+        .collect::<Vec<_>>();
+    cues.sort_by_key(|cue| cue.start);
+    cues
+}
+
+// mdhd ボックスからタイムスケールと言語コードを読み込む
+fn read_mdhd(mdia_payload: &[u8]) -> Option<(u32, String)> {
+    let mdhd_payload = find_child_box_payload(mdia_payload, MdhdBox::TYPE)?;
+
+    // バージョン 1 では、作成・更新時刻と尺がそれぞれ 64 ビットになる
+    let (timescale_offset, language_offset) = if *mdhd_payload.first()? == 1 {
+        (20, 32)
+    } else {
+        (12, 20)
+    };
+    let timescale = read_u32(mdhd_payload, timescale_offset).filter(|&t| t > 0)?;
+
+    // 言語コードは 5 ビット毎に 0x60 を引いた値で 3 文字分が格納されている
+    let packed = read_u16(mdhd_payload, language_offset)?;
+    let language = [10, 5, 0]
+        .into_iter()
+        .map(|shift| char::from(((packed >> shift) & 0b1_1111) as u8 + 0x60))
+        .collect();
+    Some((timescale, language))
+}
+
+// 3GPP TS 26.245 5.17 Sample Format
+//
+// 空のテキストは字幕を表示しない区間を表すので None を返す
+fn parse_tx3g_sample(data: &[u8]) -> Option<Option<String>> {
+    let size = read_u16(data, 0)? as usize;
+    let text = data.get(2..2 + size)?;
+    if text.is_empty() {
+        return Some(None);
+    }
+
+    // テキストは UTF-8 だが、BOM が付いている場合には UTF-16 (ビッグエンディアン)
+    let text = if let Some(utf16) = text.strip_prefix(&[0xFE, 0xFF]) {
+        let units = utf16
+            .chunks_exact(2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .collect::<Vec<_>>();
+        String::from_utf16_lossy(&units)
+    } else {
+        String::from_utf8_lossy(text).into_owned()
+    };
+    Some(Some(text.replace("\r\n", "\n")))
+}
+
+// ISO/IEC 14496-30 7.4 Sample format
+//
+// 一つのサンプルには、同じ区間に表示される複数のキュー (vttc) が含まれることがある
+// (vtte は空区間、vtta はコメントなので無視する）
+fn parse_wvtt_sample(
+    mut data: &[u8],
+    track_id: u32,
+    start: Duration,
+    end: Duration,
+) -> Option<Vec<SubtitleCue>> {
+    let mut cues = Vec::new();
+    while !data.is_empty() {
+        let (header, payload, remaining) = split_box(data)?;
+        data = remaining;
+        if header.box_type != VTTC_BOX_TYPE {
+            continue;
+        }
+
+        let text = |box_type| {
+            find_child_box_payload(payload, box_type)
+                .map(|b| String::from_utf8_lossy(b).into_owned())
+        };
+        let mut cue = SubtitleCue::new(track_id, start, end, text(PAYL_BOX_TYPE)?);
+        cue.id = text(IDEN_BOX_TYPE);
+        cue.settings = text(STTG_BOX_TYPE);
+        cues.push(cue);
+    }
+    Some(cues)
+}
+
+// ISO/IEC 14496-30 6 TTML in ISOBMFF
+//
+// サンプルは一つの TTML 文書で、その中の <p> 要素をそれぞれ一つのキューとして扱う。
+// 文書内の時刻はトラックのメディア時間軸上の値として解釈され、サンプルの区間内に収まるように切り詰められる
+// (<p> に時刻の指定がない場合には、サンプルの区間がそのまま使われる）
+//
+// [NOTE] スタイルやレイアウト、<div> などの上位要素に指定された時刻、画像字幕には未対応
+fn parse_stpp_sample(
+    data: &[u8],
+    track_id: u32,
+    start: Duration,
+    end: Duration,
+) -> Option<Vec<SubtitleCue>> {
+    let document = std::str::from_utf8(data).ok()?;
+    let root = find_start_tag(document, "tt").map(|(tag, _)| tag);
+    let rate = |name| {
+        root.and_then(|tag| xml_attribute(tag, name))
+            .and_then(|v| v.trim().parse::<f64>().ok())
+            .filter(|&v| v > 0.0)
+    };
+    let frame_rate = rate("ttp:frameRate").unwrap_or(TTML_DEFAULT_FRAME_RATE);
+    let tick_rate = rate("ttp:tickRate").unwrap_or(1.0);
+    // 属性が存在しない場合には Some(None) を、値が不正な場合には None を返す
+    let parse_time = |tag, name| match xml_attribute(tag, name) {
+        Some(value) => parse_ttml_time(value, frame_rate, tick_rate).map(Some),
+        None => Some(None),
+    };
+
+    let mut cues = Vec::new();
+    let mut rest = document;
+    while let Some((tag, after_tag)) = find_start_tag(rest, "p") {
+        if tag.ends_with('/') {
+            // 空の要素
+            rest = after_tag;
+            continue;
+        }
+        let content_end = after_tag.find("</p>")?;
+        let content = &after_tag[..content_end];
+        rest = &after_tag[content_end..];
+
+        let begin = parse_time(tag, "begin")?;
+        let cue_end = parse_time(tag, "end")?;
+        let dur = parse_time(tag, "dur")?;
+        let cue_end = cue_end.or(dur.map(|dur| begin.unwrap_or(start) + dur));
+        let cue_start = begin.unwrap_or(start).max(start);
+        let cue_end = cue_end.unwrap_or(end).min(end);
+        let text = ttml_text(content);
+        if cue_start < cue_end && !text.is_empty() {
+            cues.push(SubtitleCue::new(track_id, cue_start, cue_end, text));
+        }
+    }
+    Some(cues)
+}
+
+// 指定された名前の要素の開始タグを探して、その属性部分とタグ以降の文字列を返す
+fn find_start_tag<'a>(document: &'a str, name: &str) -> Option<(&'a str, &'a str)> {
+    let mut rest = document;
+    loop {
+        let i = rest.find('<')?;
+        rest = &rest[i + 1..];
+
+        // 名前空間の接頭辞（"tt:p" など）は無視する
+        let tag_end = rest.find('>')?;
+        let tag = &rest[..tag_end];
+        let tag_name_end = tag
+            .find(|c: char| c.is_ascii_whitespace() || c == '/')
+            .unwrap_or(tag.len());
+        let tag_name = &tag[..tag_name_end];
+        let local_name = tag_name.rsplit(':').next().unwrap_or(tag_name);
+        if local_name == name {
+            return Some((&tag[tag_name_end..], &rest[tag_end + 1..]));
+        }
+    }
+}
+
+// 開始タグの属性部分から、指定された名前の属性の値を取り出す
+fn xml_attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = tag;
+    loop {
+        let i = rest.find(name)?;
+        let preceded_by_space = rest[..i]
+            .chars()
+            .next_back()
+            .is_none_or(|c| c.is_ascii_whitespace());
+        rest = &rest[i + name.len()..];
+        let Some(value) = rest.trim_start().strip_prefix('=') else {
+            continue;
+        };
+        if !preceded_by_space {
+            continue;
+        }
+        let value = value.trim_start();
+        let quote = value.chars().next().filter(|&c| c == '"' || c == '\'')?;
+        let value = &value[1..];
+        return value.find(quote).map(|end| &value[..end]);
+    }
+}
+
+// TTML 10.3.1 <timeExpression>
+//
+// clock-time ("00:00:01.5" / "00:00:01:12") と offset-time ("1.5s" / "1500ms" / "36f" / "10000t" など）に対応する
+fn parse_ttml_time(value: &str, frame_rate: f64, tick_rate: f64) -> Option<Duration> {
+    let value = value.trim();
+    let secs = if value.contains(':') {
+        let parts = value
+            .split(':')
+            .map(|v| v.parse::<f64>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        match parts.as_slice() {
+            [h, m, s] => h * 3600.0 + m * 60.0 + s,
+            [h, m, s, f] => h * 3600.0 + m * 60.0 + s + f / frame_rate,
+            _ => return None,
+        }
+    } else {
+        let metric_start = value.find(|c: char| c.is_ascii_alphabetic())?;
+        let count = value[..metric_start].parse::<f64>().ok()?;
+        match &value[metric_start..] {
+            "h" => count * 3600.0,
+            "m" => count * 60.0,
+            "s" => count,
+            "ms" => count / 1000.0,
+            "f" => count / frame_rate,
+            "t" => count / tick_rate,
+            _ => return None,
+        }
+    };
+    Duration::try_from_secs_f64(secs).ok()
+}
+
+// <p> 要素の内容から表示用のテキストを取り出す
+//
+// <br/> は改行に変換し、それ以外のタグ（<span> など）は取り除く。
+// XML の空白文字の並びは一つの空白にまとめる (xml:space="default" の扱い）
+fn ttml_text(content: &str) -> String {
+    let mut text = String::new();
+    let mut rest = content;
+    while let Some(c) = rest.chars().next() {
+        if c == '<' {
+            let Some(tag_end) = rest.find('>') else {
+                break;
+            };
+            let tag = rest[1..tag_end].trim_end_matches('/').trim();
+            let tag_name = tag.split_ascii_whitespace().next().unwrap_or_default();
+            if tag_name.rsplit(':').next() == Some("br") {
+                text.truncate(text.trim_end_matches(' ').len());
+                text.push('\n');
+            }
+            rest = &rest[tag_end + 1..];
+        } else if c == '&' {
+            let (decoded, len) = decode_xml_entity(rest);
+            text.push_str(&decoded);
+            rest = &rest[len..];
+        } else {
+            if c.is_ascii_whitespace() {
+                if !text.is_empty() && !text.ends_with([' ', '\n']) {
+                    text.push(' ');
+                }
+            } else {
+                text.push(c);
+            }
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    text.trim().to_owned()
+}
+
+// "&amp;" や "&#x3042;" などの文字参照を変換して、変換後の文字列と消費したバイト数を返す
+//
+// 解釈できない場合には "&" をそのまま返す
+fn decode_xml_entity(s: &str) -> (String, usize) {
+    let Some(end) = s.find(';').filter(|&i| i <= 10) else {
+        return ("&".to_owned(), 1);
+    };
+    let name = &s[1..end];
+    let decoded = match name {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        _ => name
+            .strip_prefix("#x")
+            .map(|hex| u32::from_str_radix(hex, 16))
+            .or_else(|| name.strip_prefix('#').map(|dec| dec.parse::<u32>()))
+            .and_then(|code| code.ok())
+            .and_then(char::from_u32),
+    };
+    match decoded {
+        Some(c) => (c.to_string(), end + 1),
+        None => ("&".to_owned(), 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 30 fps / 10000 ticks/s で解析した結果をミリ秒単位で返す
+    fn parse_ms(value: &str) -> Option<u128> {
+        parse_ttml_time(value, 30.0, 10_000.0).map(|d| d.as_millis())
+    }
+
+    #[test]
+    fn clock_time_expressions() {
+        assert_eq!(parse_ms("00:00:01.5"), Some(1500));
+        assert_eq!(parse_ms("01:02:03"), Some(3_723_000));
+        assert_eq!(parse_ms(" 00:00:02.000 "), Some(2000));
+
+        // 4 番目の要素はフレーム数
+        assert_eq!(parse_ms("00:00:01:15"), Some(1500));
+    }
+
+    #[test]
+    fn offset_time_expressions() {
+        assert_eq!(parse_ms("1h"), Some(3_600_000));
+        assert_eq!(parse_ms("0.5m"), Some(30_000));
+        assert_eq!(parse_ms("1.5s"), Some(1500));
+        assert_eq!(parse_ms("1500ms"), Some(1500));
+        assert_eq!(parse_ms("45f"), Some(1500));
+        assert_eq!(parse_ms("15000t"), Some(1500));
+    }
+
+    #[test]
+    fn invalid_time_expressions_are_rejected() {
+        for value in ["", "00:01", "00:00:xx", "1.5", "1.5x", "-1s"] {
+            assert!(parse_ms(value).is_none(), "{value:?}");
+        }
+    }
+}
@@ -0,0 +1,940 @@
+use std::{num::NonZeroU32, time::Duration};
+
+use orfail::OrFail;
+
+use crate::{
+    adts::{self, AdtsHeader},
+    annexb::{AnnexBConverter, H26xCodec},
+    es::opus_packet_samples,
+    mp4::{AudioDecoderConfig, Mp4, Track, TrackKind},
+    muxer::{MuxSample, MuxTrack},
+};
+
+const PACKET_SIZE: usize = 188;
+
+// Blu-ray などで使われる M2TS 形式では、各パケットの前に 4 バイトのタイムコードが付与されている
+const M2TS_PACKET_SIZE: usize = 192;
+const M2TS_HEADER_SIZE: usize = 4;
+
+const SYNC_BYTE: u8 = 0x47;
+
+// 形式の判定時に同期バイトを確認するパケット数
+const PROBE_PACKETS: usize = 4;
+
+const PID_PAT: u16 = 0x0000;
+
+const TABLE_ID_PAT: u8 = 0x00;
+const TABLE_ID_PMT: u8 = 0x02;
+
+// ISO/IEC 13818-1 Table 2-34 Stream type assignments
+const STREAM_TYPE_AAC_ADTS: u8 = 0x0F;
+const STREAM_TYPE_H264: u8 = 0x1B;
+const STREAM_TYPE_H265: u8 = 0x24;
+const STREAM_TYPE_PRIVATE_PES: u8 = 0x06;
+
+const DESCRIPTOR_TAG_REGISTRATION: u8 = 0x05;
+const DESCRIPTOR_TAG_EXTENSION: u8 = 0x7F;
+const EXTENSION_DESCRIPTOR_TAG_OPUS: u8 = 0x80;
+const OPUS_FORMAT_IDENTIFIER: &[u8] = b"Opus";
+
+// PTS / DTS / PCR (base) は 90 kHz の 33 ビット値
+const TIMESCALE: NonZeroU32 = NonZeroU32::MIN.saturating_add(90_000 - 1);
+const TIMESTAMP_PERIOD: i64 = 1 << 33;
+
+// Opus は常に 48 kHz でデコードされる
+const OPUS_SAMPLE_RATE: u32 = 48_000;
+
+// 最後のサンプルの尺が不明な場合に使うデフォルト値（90 kHz 単位、つまり 1/30 秒）
+const DEFAULT_LAST_SAMPLE_DURATION: u64 = 3000;
+
+// バイト列が MPEG-TS (または M2TS) ファイルかどうかを判定する
+pub fn is_ts(bytes: &[u8]) -> bool {
+    detect_packet_layout(bytes).is_some()
+}
+
+// パケットサイズと、パケット内での同期バイトの位置を返す
+fn detect_packet_layout(bytes: &[u8]) -> Option<(usize, usize)> {
+    [(PACKET_SIZE, 0), (M2TS_PACKET_SIZE, M2TS_HEADER_SIZE)]
+        .into_iter()
+        .find(|&(packet_size, offset)| {
+            let packets = (bytes.len() / packet_size).clamp(1, PROBE_PACKETS);
+            (0..packets).all(|i| bytes.get(i * packet_size + offset) == Some(&SYNC_BYTE))
+        })
+}
+
+// MPEG-TS ファイルを読み込んで、MP4 の場合と同じ形式のトラック群に変換する
+//
+// MP4 や WebM とは異なり、各サンプルのデータは TS パケットに分割されて格納されている上に、
+// 映像は Annex-B 形式（スタートコード区切り）、音声は ADTS ヘッダーなどが付与された形式となっているため、
+// デコーダーにそのまま渡せる形式に変換したサンプルデータを格納したバイト列もあわせて返す。
+// (返り値のトラックのサンプルのデータ位置は、このバイト列の先頭からのオフセットとなる）
+//
+// 対応しているのは、最初のプログラムに含まれる H.264 / H.265 / AAC (ADTS) / Opus のストリームのみで、
+// それ以外のストリームは無視される。
+// 33 ビットの PTS / DTS / PCR の周回（約 26.5 時間毎）は、直前に出現した値を基準にして補正される。
+//
+// 最も早く始まるトラックの先頭の PTS が 0 となり、他のトラックとの開始時刻の差は、
+// そのトラックの先頭の空白（MuxTrack::start_offset）として扱われる。
+// (映像の場合には、デコードできない最初のキーフレームより前のサンプルを取り除いた後の先頭が基準となる）
+pub fn load_ts(bytes: &[u8]) -> orfail::Result<(Mp4, Vec<u8>)> {
+    let (packet_size, offset) =
+        detect_packet_layout(bytes).or_fail_with(|()| "No TS sync byte found".to_owned())?;
+
+    let mut demuxer = TsDemuxer::default();
+    for packet in bytes[offset..].chunks(packet_size) {
+        let Some(packet) = packet.get(..PACKET_SIZE) else {
+            // 末尾の欠けたパケットは無視する
+            break;
+        };
+        demuxer.handle_packet(packet).or_fail()?;
+    }
+    demuxer.finish().or_fail()
+}
+
+#[derive(Debug, Default)]
+struct TsDemuxer {
+    pmt_pid: Option<u16>,
+    pcr_pid: Option<u16>,
+    sections: Vec<(u16, Vec<u8>)>,
+    streams: Vec<TsStream>,
+    clock: TimestampUnwrapper,
+
+    // 変換後のサンプルデータ
+    media: Vec<u8>,
+}
+
+impl TsDemuxer {
+    // ISO/IEC 13818-1 2.4.3.2 Transport stream packet layer
+    fn handle_packet(&mut self, packet: &[u8]) -> orfail::Result<()> {
+        (packet[0] == SYNC_BYTE).or_fail_with(|()| "Lost TS sync byte".to_owned())?;
+
+        let transport_error = packet[1] & 0x80 != 0;
+        let payload_unit_start = packet[1] & 0x40 != 0;
+        let pid = (u16::from(packet[1] & 0x1F) << 8) | u16::from(packet[2]);
+        let adaptation_field_control = (packet[3] >> 4) & 0b11;
+        if transport_error {
+            return Ok(());
+        }
+
+        let mut payload_start = 4;
+        if adaptation_field_control & 0b10 != 0 {
+            let length = packet[4] as usize;
+            let adaptation_field = packet.get(5..5 + length).unwrap_or_default();
+            if Some(pid) == self.pcr_pid {
+                self.handle_adaptation_field(adaptation_field);
+            }
+            payload_start = 5 + length;
+        }
+        if adaptation_field_control & 0b01 == 0 {
+            return Ok(());
+        }
+        let payload = packet.get(payload_start..).unwrap_or_default();
+
+        if pid == PID_PAT || Some(pid) == self.pmt_pid {
+            self.handle_psi(pid, payload_unit_start, payload)
+                .or_fail()?;
+        } else if let Some(i) = self.streams.iter().position(|s| s.pid == pid) {
+            if payload_unit_start {
+                self.flush_pes(i).or_fail()?;
+                self.streams[i].pes = Some(Vec::new());
+            }
+            if let Some(pes) = &mut self.streams[i].pes {
+                pes.extend_from_slice(payload);
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_adaptation_field(&mut self, field: &[u8]) {
+        // PCR は再生には使わないが、PES の時刻が長時間出現しない場合にも周回を検出できるように参照する
+        let pcr_flag = field.first().is_some_and(|flags| flags & 0x10 != 0);
+        if let (true, Some(pcr)) = (pcr_flag, field.get(1..7)) {
+            let base = (u64::from(pcr[0]) << 25)
+                | (u64::from(pcr[1]) << 17)
+                | (u64::from(pcr[2]) << 9)
+                | (u64::from(pcr[3]) << 1)
+                | (u64::from(pcr[4]) >> 7);
+            self.clock.unwrap(base);
+        }
+    }
+
+    // PAT / PMT のセクションを（複数パケットにまたがる場合には結合した上で）処理する
+    fn handle_psi(
+        &mut self,
+        pid: u16,
+        payload_unit_start: bool,
+        payload: &[u8],
+    ) -> orfail::Result<()> {
+        let i = match self.sections.iter().position(|s| s.0 == pid) {
+            Some(i) => i,
+            None => {
+                self.sections.push((pid, Vec::new()));
+                self.sections.len() - 1
+            }
+        };
+        let buf = &mut self.sections[i].1;
+        if payload_unit_start {
+            let pointer_field = *payload.first().or_fail()? as usize;
+            buf.clear();
+            buf.extend_from_slice(payload.get(1 + pointer_field..).unwrap_or_default());
+        } else if !buf.is_empty() {
+            buf.extend_from_slice(payload);
+        }
+
+        if buf.len() < 3 {
+            return Ok(());
+        }
+        let section_length = ((buf[1] as usize & 0x0F) << 8) | buf[2] as usize;
+        if buf.len() < 3 + section_length {
+            return Ok(());
+        }
+        let section = std::mem::take(buf);
+        let section = &section[..3 + section_length];
+        match section[0] {
+            TABLE_ID_PAT => self.handle_pat(section),
+            TABLE_ID_PMT => self.handle_pmt(section),
+            _ => Ok(()),
+        }
+    }
+
+    // ISO/IEC 13818-1 2.4.4.3 Program association Table
+    fn handle_pat(&mut self, section: &[u8]) -> orfail::Result<()> {
+        if self.pmt_pid.is_some() {
+            return Ok(());
+        }
+        // 先頭 8 バイトのヘッダーと末尾 4 バイトの CRC の間に、4 バイト単位でプログラムが並んでいる
+        let programs = section.get(8..section.len().saturating_sub(4)).or_fail()?;
+        for program in programs.chunks_exact(4) {
+            let program_number = u16::from_be_bytes([program[0], program[1]]);
+            if program_number != 0 {
+                // ネットワーク情報テーブル（番号 0）以外で最初のプログラムのみを扱う
+                self.pmt_pid = Some((u16::from(program[2] & 0x1F) << 8) | u16::from(program[3]));
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // ISO/IEC 13818-1 2.4.4.8 Program Map Table
+    fn handle_pmt(&mut self, section: &[u8]) -> orfail::Result<()> {
+        if self.pcr_pid.is_some() {
+            // 同じ内容の PMT が繰り返し送られてくるので、最初のもののみを扱う
+            return Ok(());
+        }
+        (section.len() >= 16).or_fail_with(|()| "Too short PMT section".to_owned())?;
+        self.pcr_pid = Some((u16::from(section[8] & 0x1F) << 8) | u16::from(section[9]));
+        let program_info_length = ((section[10] as usize & 0x0F) << 8) | section[11] as usize;
+
+        let end = section.len() - 4;
+        let mut position = 12 + program_info_length;
+        while position + 5 <= end {
+            let stream_type = section[position];
+            let pid =
+                (u16::from(section[position + 1] & 0x1F) << 8) | u16::from(section[position + 2]);
+            let es_info_length =
+                ((section[position + 3] as usize & 0x0F) << 8) | section[position + 4] as usize;
+            let descriptors = section
+                .get(position + 5..position + 5 + es_info_length)
+                .or_fail_with(|()| "Invalid PMT ES info length".to_owned())?;
+            position += 5 + es_info_length;
+
+            let codec = match stream_type {
+                STREAM_TYPE_H264 => TsCodec::Video(AnnexBConverter::new(H26xCodec::H264)),
+                STREAM_TYPE_H265 => TsCodec::Video(AnnexBConverter::new(H26xCodec::H265)),
+                STREAM_TYPE_AAC_ADTS => TsCodec::Aac(Vec::new()),
+                STREAM_TYPE_PRIVATE_PES => match opus_channel_count(descriptors) {
+                    Some(channels) => TsCodec::Opus(channels),
+                    None => continue,
+                },
+                _ => continue,
+            };
+            let kind = codec.kind();
+            if self.streams.iter().any(|s| s.codec.kind() == kind) {
+                // 同じ種類のトラックは一つしか扱えないので、二つ目以降は無視する
+                continue;
+            }
+            self.streams.push(TsStream {
+                pid,
+                codec,
+                pes: None,
+                pending_audio: Vec::new(),
+                pending_audio_time: None,
+                audio_clock: None,
+                samples: Vec::new(),
+            });
+        }
+        Ok(())
+    }
+
+    fn flush_pes(&mut self, i: usize) -> orfail::Result<()> {
+        let Some(pes) = self.streams[i].pes.take() else {
+            return Ok(());
+        };
+        let Some(pes) = Pes::parse(&pes) else {
+            // 壊れた PES パケットは無視する
+            return Ok(());
+        };
+        let pts = pes.pts.map(|t| self.clock.unwrap(t));
+        let dts = pes.dts.map(|t| self.clock.unwrap(t)).or(pts);
+        self.streams[i]
+            .handle_pes(pts, dts, pes.payload, &mut self.media)
+            .or_fail()
+    }
+
+    fn finish(mut self) -> orfail::Result<(Mp4, Vec<u8>)> {
+        for i in 0..self.streams.len() {
+            self.flush_pes(i).or_fail()?;
+        }
+        (!self.streams.is_empty())
+            .or_fail_with(|()| "No supported audio or video stream found in TS".to_owned())?;
+
+        let mut mux_tracks = Vec::new();
+        for stream in &self.streams {
+            if stream.samples.is_empty() {
+                continue;
+            }
+            let (mux_track, start) = stream.to_mux_track().or_fail()?;
+            mux_tracks.push((u32::from(stream.pid), mux_track, start));
+        }
+
+        // トラック間の開始時刻の差は、後から始まるトラックの先頭の空白として保持する
+        let first = mux_tracks.iter().map(|(_, _, start)| *start).min();
+        let mut tracks = Vec::new();
+        for (pid, mut mux_track, start) in mux_tracks {
+            let offset = (start - first.unwrap_or(start)) as u64;
+            mux_track.start_offset =
+                Duration::from_micros(offset * 1_000_000 / u64::from(TIMESCALE.get()));
+            // トラック ID には PID を使う
+            tracks.push(Track::from_mux_track(&mux_track, pid, self.media.len()).or_fail()?);
+        }
+        let mp4 = Mp4::from_tracks(tracks).or_fail()?;
+        Ok((mp4, self.media))
+    }
+}
+
+#[derive(Debug)]
+enum TsCodec {
+    Video(AnnexBConverter),
+
+    // ADTS ヘッダーから取得した設定（途中で変わった場合には、出現順に複数保持する）
+    Aac(Vec<AudioDecoderConfig>),
+
+    // チャンネル数
+    Opus(u8),
+}
+
+impl TsCodec {
+    fn kind(&self) -> TrackKind {
+        match self {
+            Self::Video(_) => TrackKind::Video,
+            Self::Aac(_) | Self::Opus(_) => TrackKind::Audio,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TsStream {
+    pid: u16,
+    codec: TsCodec,
+
+    // 組み立て中の PES パケット
+    pes: Option<Vec<u8>>,
+
+    // PES パケットをまたいでいる音声フレームの断片と、その PES の時刻
+    pending_audio: Vec<u8>,
+    pending_audio_time: Option<i64>,
+
+    // 音声フレームの時刻を、フレーム毎のサンプル数の累積から求めるための時計
+    audio_clock: Option<AudioClock>,
+
+    samples: Vec<TsSample>,
+}
+
+impl TsStream {
+    fn handle_pes(
+        &mut self,
+        pts: Option<i64>,
+        dts: Option<i64>,
+        payload: &[u8],
+        media: &mut Vec<u8>,
+    ) -> orfail::Result<()> {
+        match &mut self.codec {
+            TsCodec::Video(converter) => {
+                let data_offset = media.len();
+                let is_key = converter.convert_access_unit(payload, media);
+                let data_size = media.len() - data_offset;
+                match (dts, self.samples.last_mut()) {
+                    (Some(time), _) => self.samples.push(TsSample {
+                        time,
+                        pts: pts.unwrap_or(time),
+                        duration: None,
+                        config_index: 0,
+                        is_key,
+                        data_offset,
+                        data_size,
+                    }),
+                    // 時刻を持たない PES は、直前のアクセスユニットの続きとして扱う
+                    (None, Some(last)) => {
+                        last.data_size += data_size;
+                        last.is_key |= is_key;
+                    }
+                    (None, None) => media.truncate(data_offset),
+                }
+            }
+            TsCodec::Aac(_) | TsCodec::Opus(_) => {
+                // PES の時刻は、その先頭から始まるフレームの時刻となる
+                // (前の PES から続いているフレームがある場合には、直前のフレームの終端から求める）
+                if self.pending_audio.is_empty() {
+                    self.pending_audio_time = pts;
+                }
+                self.pending_audio.extend_from_slice(payload);
+                self.split_audio_frames(media).or_fail()?;
+            }
+        }
+        Ok(())
+    }
+
+    // 結合済みの音声データから完全なフレームを取り出してサンプルにする
+    fn split_audio_frames(&mut self, media: &mut Vec<u8>) -> orfail::Result<()> {
+        let mut position = 0;
+        loop {
+            let data = &self.pending_audio[position..];
+            let (frame, payload, frame_samples, sample_rate, config_index) = match &mut self.codec {
+                TsCodec::Aac(configs) => {
+                    if data.len() >= 2 && !AdtsHeader::is_sync_word(data) {
+                        // 同期が外れている場合には、次の同期ワードまで読み飛ばす
+                        position += 1;
+                        continue;
+                    }
+                    let header = match AdtsHeader::parse(data) {
+                        Ok(Some(header)) => header,
+                        Ok(None) => break,
+                        Err(_) => {
+                            // 壊れたヘッダー（あるいは偶然同期ワードと一致したデータ）は読み飛ばす
+                            position += 1;
+                            continue;
+                        }
+                    };
+                    if data.len() < header.frame_length {
+                        break;
+                    }
+
+                    // サンプリングレートやチャンネル数が途中で変わった場合には、別のサンプルエントリーとする
+                    let config = header.to_decoder_config();
+                    if configs.last() != Some(&config) {
+                        configs.push(config);
+                    }
+                    (
+                        header.frame_length,
+                        header.header_length..header.frame_length,
+                        u64::from(adts::SAMPLES_PER_FRAME),
+                        header.sample_rate,
+                        configs.len() - 1,
+                    )
+                }
+                TsCodec::Opus(_) => {
+                    let Some((frame, payload)) = parse_opus_access_unit(data).or_fail()? else {
+                        break;
+                    };
+                    let samples = opus_packet_samples(&data[payload.clone()]);
+                    (frame, payload, samples, OPUS_SAMPLE_RATE, 0)
+                }
+                TsCodec::Video(_) => unreachable!(),
+            };
+
+            let time = AudioClock::next_frame_time(
+                &mut self.audio_clock,
+                self.pending_audio_time.take(),
+                frame_samples,
+                sample_rate,
+            );
+            let data_offset = media.len();
+            media.extend_from_slice(&data[payload]);
+            self.samples.push(TsSample {
+                time,
+                pts: time,
+                duration: Some(frame_samples * u64::from(TIMESCALE.get()) / u64::from(sample_rate)),
+                config_index,
+                is_key: true,
+                data_offset,
+                data_size: media.len() - data_offset,
+            });
+            position += frame;
+        }
+        self.pending_audio.drain(..position);
+        Ok(())
+    }
+
+    // トラックと、その先頭の表示時刻 (90 kHz 単位の PTS) を返す
+    fn to_mux_track(&self) -> orfail::Result<(MuxTrack, i64)> {
+        let (kind, sample_entries) = match &self.codec {
+            TsCodec::Video(converter) => (
+                TrackKind::Video,
+                vec![converter
+                    .decoder_config()
+                    .or_fail()?
+                    .to_sample_entry()
+                    .or_fail()?],
+            ),
+            TsCodec::Aac(configs) => {
+                (!configs.is_empty()).or_fail_with(|()| "No ADTS frame found".to_owned())?;
+                (
+                    TrackKind::Audio,
+                    configs
+                        .iter()
+                        .map(|c| c.to_sample_entry().or_fail())
+                        .collect::<orfail::Result<Vec<_>>>()?,
+                )
+            }
+            TsCodec::Opus(channels) => (
+                TrackKind::Audio,
+                vec![AudioDecoderConfig {
+                    codec: "opus".to_owned(),
+                    sample_rate: OPUS_SAMPLE_RATE,
+                    number_of_channels: *channels,
+                    description: Vec::new(),
+                }
+                .to_sample_entry()
+                .or_fail()?],
+            ),
+        };
+
+        // 途中から録画されたストリームなどでは、最初のキーフレームより前の映像はデコードできないので取り除く
+        let first_key = self
+            .samples
+            .iter()
+            .position(|s| s.is_key)
+            .or_fail_with(|()| {
+                format!("No key frame found in {kind:?} stream (PID {})", self.pid)
+            })?;
+        let samples = &self.samples[first_key..];
+
+        let mut mux_track = MuxTrack::new(kind, TIMESCALE);
+        let sample_entry_indices = sample_entries
+            .into_iter()
+            .map(|entry| mux_track.add_sample_entry(entry))
+            .collect::<Vec<_>>();
+        let mut last_duration = DEFAULT_LAST_SAMPLE_DURATION;
+        for (i, sample) in samples.iter().enumerate() {
+            // 尺は次のサンプルとの時刻の差分から求める
+            // (時刻が巻き戻っている場合や最後のサンプルでは、音声ならフレームの尺、映像なら直前の尺を使う）
+            let duration = samples
+                .get(i + 1)
+                .and_then(|next| u64::try_from(next.time - sample.time).ok())
+                .filter(|&d| d > 0)
+                .or(sample.duration)
+                .unwrap_or(last_duration);
+            last_duration = duration;
+            mux_track.samples.push(MuxSample {
+                sample_entry_index: sample_entry_indices[sample.config_index],
+                duration: u32::try_from(duration)
+                    .or_fail_with(|_| format!("Too long sample duration: {duration}"))?,
+                is_sync: sample.is_key,
+                composition_offset: 0,
+                data_offset: sample.data_offset as u64,
+                data_size: sample.data_size as u32,
+            });
+        }
+        Ok((mux_track, samples[0].pts))
+    }
+}
+
+// 音声フレームのサンプル数の累積から時刻を求めるための時計
+#[derive(Debug, Clone, Copy)]
+struct AudioClock {
+    // 基準となる時刻 (90 kHz 単位）
+    base: i64,
+    sample_rate: u32,
+
+    // 基準時刻からのサンプル数
+    samples: u64,
+}
+
+impl AudioClock {
+    fn time(&self) -> i64 {
+        self.base + (self.samples * u64::from(TIMESCALE.get()) / u64::from(self.sample_rate)) as i64
+    }
+
+    // 次の音声フレームの時刻を求めて、時計をそのフレームの終端まで進める
+    //
+    // フレームの尺を 90 kHz 単位に丸めた値を積み上げると誤差が蓄積するので、
+    // 時刻はサンプル数の累積から求める。
+    // PES の PTS が時計から一フレーム分以上ずれている場合（ストリームの途中での欠落や時刻のリセットなど）には、
+    // その PTS を基準にして時計を合わせ直す。
+    fn next_frame_time(
+        clock: &mut Option<Self>,
+        pts: Option<i64>,
+        frame_samples: u64,
+        sample_rate: u32,
+    ) -> i64 {
+        let expected = clock.as_ref().map(|c| c.time());
+        let frame_duration =
+            (frame_samples * u64::from(TIMESCALE.get()) / u64::from(sample_rate)).max(1) as i64;
+        let time = match (pts, expected) {
+            (Some(pts), Some(expected)) if (pts - expected).abs() < frame_duration => expected,
+            (Some(pts), _) => pts,
+            (None, Some(expected)) => expected,
+            (None, None) => 0,
+        };
+
+        let current = match clock.take() {
+            Some(c) if c.time() == time && c.sample_rate == sample_rate => c,
+            _ => Self {
+                base: time,
+                sample_rate,
+                samples: 0,
+            },
+        };
+        *clock = Some(Self {
+            samples: current.samples + frame_samples,
+            ..current
+        });
+        time
+    }
+}
+
+#[derive(Debug)]
+struct TsSample {
+    // 90 kHz 単位の DTS
+    time: i64,
+
+    // 90 kHz 単位の PTS（音声の場合には DTS と同じ）
+    pts: i64,
+
+    // 音声の場合にはフレームの内容から尺が決まる
+    duration: Option<u64>,
+
+    // TsCodec::Aac の設定のインデックス（それ以外のコーデックでは常に 0）
+    config_index: usize,
+
+    is_key: bool,
+
+    // 変換後のバイト列内でのデータ位置
+    data_offset: usize,
+    data_size: usize,
+}
+
+// ISO/IEC 13818-1 2.4.3.6 PES packet
+#[derive(Debug)]
+struct Pes<'a> {
+    pts: Option<u64>,
+    dts: Option<u64>,
+    payload: &'a [u8],
+}
+
+impl<'a> Pes<'a> {
+    fn parse(bytes: &'a [u8]) -> Option<Self> {
+        if !bytes.starts_with(&[0, 0, 1]) || bytes.len() < 9 {
+            return None;
+        }
+        let packet_length = u16::from_be_bytes([bytes[4], bytes[5]]) as usize;
+        let pts_dts_flags = bytes[7] >> 6;
+        let header_data_length = bytes[8] as usize;
+        let header = bytes.get(9..9 + header_data_length)?;
+
+        let pts = (pts_dts_flags & 0b10 != 0)
+            .then(|| header.get(0..5).map(parse_timestamp))
+            .flatten();
+        let dts = (pts_dts_flags == 0b11)
+            .then(|| header.get(5..10).map(parse_timestamp))
+            .flatten();
+
+        // PES_packet_length が 0 の場合（映像では一般的）には、次の PES の開始までが全てペイロードとなる
+        let end = if packet_length == 0 {
+            bytes.len()
+        } else {
+            (6 + packet_length).min(bytes.len())
+        };
+        let payload = bytes.get(9 + header_data_length..end)?;
+        Some(Self { pts, dts, payload })
+    }
+}
+
+fn parse_timestamp(b: &[u8]) -> u64 {
+    (u64::from(b[0] >> 1 & 0b111) << 30)
+        | (u64::from(b[1]) << 22)
+        | (u64::from(b[2] >> 1) << 15)
+        | (u64::from(b[3]) << 7)
+        | u64::from(b[4] >> 1)
+}
+
+// 33 ビットのタイムスタンプの周回を補正して、単調な時刻に変換する
+//
+// PTS / DTS / PCR の全てで同じ基準を共有し、直前の値に最も近くなるように周回数を決める
+#[derive(Debug, Default)]
+struct TimestampUnwrapper {
+    last: Option<i64>,
+}
+
+impl TimestampUnwrapper {
+    fn unwrap(&mut self, raw: u64) -> i64 {
+        let raw = raw as i64 & (TIMESTAMP_PERIOD - 1);
+        let unwrapped = match self.last {
+            None => raw,
+            Some(last) => {
+                let base = last - last.rem_euclid(TIMESTAMP_PERIOD) + raw;
+                [base - TIMESTAMP_PERIOD, base, base + TIMESTAMP_PERIOD]
+                    .into_iter()
+                    .min_by_key(|t| (t - last).abs())
+                    .expect("infallible")
+            }
+        };
+        self.last = Some(unwrapped);
+        unwrapped
+    }
+}
+
+// PMT の記述子から Opus のチャンネル数を取得する（Opus のストリームではない場合には None を返す）
+//
+// https://opus-codec.org/docs/ETSI_TS_opus-v0.1.3-draft.pdf
+fn opus_channel_count(mut descriptors: &[u8]) -> Option<u8> {
+    let mut is_opus = false;
+    let mut channels = 2;
+    while let [tag, length, rest @ ..] = descriptors {
+        let (value, rest) = rest.split_at_checked(*length as usize)?;
+        match (*tag, value) {
+            (DESCRIPTOR_TAG_REGISTRATION, identifier) => {
+                is_opus |= identifier.starts_with(OPUS_FORMAT_IDENTIFIER);
+            }
+            (
+                DESCRIPTOR_TAG_EXTENSION,
+                [EXTENSION_DESCRIPTOR_TAG_OPUS, channel_config_code, ..],
+            ) => {
+                // 0x00 はデュアルモノ、0x01..=0x08 はチャンネル数そのもの、
+                // それ以外は拡張されたマッピングだが、ここではステレオとして扱う
+                channels = match *channel_config_code {
+                    0 => 2,
+                    n @ 1..=8 => n,
+                    _ => 2,
+                };
+            }
+            _ => {}
+        }
+        descriptors = rest;
+    }
+    is_opus.then_some(channels)
+}
+
+// opus_control_header 付きの Opus アクセスユニットを読み込み、
+// 全体のバイト数と Opus パケット部分の範囲を返す（アクセスユニット全体がない場合には None を返す）
+fn parse_opus_access_unit(data: &[u8]) -> orfail::Result<Option<(usize, std::ops::Range<usize>)>> {
+    let [b0, b1, ..] = data else {
+        return Ok(None);
+    };
+    (*b0 == 0x7F && b1 & 0xE0 == 0xE0)
+        .or_fail_with(|()| "Invalid Opus control header prefix".to_owned())?;
+    let start_trim_flag = b1 & 0x10 != 0;
+    let end_trim_flag = b1 & 0x08 != 0;
+    let control_extension_flag = b1 & 0x04 != 0;
+
+    let mut position = 2;
+    let mut au_size = 0;
+    loop {
+        let Some(&b) = data.get(position) else {
+            return Ok(None);
+        };
+        position += 1;
+        au_size += b as usize;
+        if b != 0xFF {
+            break;
+        }
+    }
+    if start_trim_flag {
+        position += 2;
+    }
+    if end_trim_flag {
+        position += 2;
+    }
+    if control_extension_flag {
+        let Some(&length) = data.get(position) else {
+            return Ok(None);
+        };
+        position += 1 + length as usize;
+    }
+
+    let end = position + au_size;
+    if data.len() < end {
+        return Ok(None);
+    }
+    Ok(Some((end, position..end)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn adts_frame(frequency_index: u8, channels: u8, payload_size: usize) -> Vec<u8> {
+        let length = 7 + payload_size;
+        let mut frame = vec![
+            0xFF,
+            0xF1,
+            (1 << 6) | (frequency_index << 2) | (channels >> 2),
+            ((channels & 0b11) << 6) | (length >> 11) as u8,
+            (length >> 3) as u8,
+            ((length & 0b111) << 5) as u8 | 0x1F,
+            0xFC,
+        ];
+        frame.resize(length, 0x21);
+        frame
+    }
+
+    fn aac_stream() -> TsStream {
+        TsStream {
+            pid: 0x101,
+            codec: TsCodec::Aac(Vec::new()),
+            pes: None,
+            pending_audio: Vec::new(),
+            pending_audio_time: None,
+            audio_clock: None,
+            samples: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn aac_frame_times_do_not_drift() {
+        // 44.1 kHz の AAC の 1 フレームは 90 kHz 単位で 2089.79... となる
+        let mut stream = aac_stream();
+        let mut media = Vec::new();
+        let frames = (0..1000).map(|_| adts_frame(4, 2, 10)).collect::<Vec<_>>();
+        for (i, pes) in frames.chunks(10).enumerate() {
+            // 先頭以外の PES の PTS には、丸め誤差程度のズレを含める
+            let pts = (i as i64 * 10 * 1024 * 90_000).div_euclid(44_100) + 1000 + (i as i64 % 3);
+            stream
+                .handle_pes(Some(pts), None, &pes.concat(), &mut media)
+                .expect("failed to handle PES");
+        }
+        let (track, start) = stream.to_mux_track().expect("failed to build track");
+        assert_eq!(start, 1000);
+        assert_eq!(track.samples.len(), 1000);
+        assert_eq!(track.samples[0].duration, 2089);
+        assert_eq!(track.samples[1].duration, 2090);
+
+        // 最後のサンプルを除く尺の合計は、サンプル数から求めた時刻と一致する
+        let total = track.samples[..999]
+            .iter()
+            .map(|s| u64::from(s.duration))
+            .sum::<u64>();
+        assert_eq!(total, 999 * 1024 * 90_000 / 44_100);
+    }
+
+    #[test]
+    fn aac_pts_discontinuities_resync_the_clock() {
+        let mut stream = aac_stream();
+        let mut media = Vec::new();
+        let frame = adts_frame(3, 2, 10); // 48 kHz (1 フレームは 1920)
+                                          // 2 つ目の PES の PTS は 1 フレーム未満のズレなので無視され、3 つ目以降は時計が合わせ直される
+        for pts in [0, 4_500, 10_000, 100_000] {
+            stream
+                .handle_pes(Some(pts), None, &[&frame[..], &frame].concat(), &mut media)
+                .expect("failed to handle PES");
+        }
+        let times = stream.samples.iter().map(|s| s.time).collect::<Vec<_>>();
+        assert_eq!(
+            times,
+            [0, 1920, 3840, 5760, 10_000, 11_920, 100_000, 101_920]
+        );
+
+        // 時刻が飛んだ箇所では、直前のサンプルの尺が空白を埋める
+        let (track, _) = stream.to_mux_track().expect("failed to build track");
+        let durations = track.samples.iter().map(|s| s.duration).collect::<Vec<_>>();
+        assert_eq!(
+            durations,
+            [1920, 1920, 1920, 4240, 1920, 88_080, 1920, 1920]
+        );
+    }
+
+    #[test]
+    fn adts_config_changes_add_sample_entries() {
+        let mut stream = aac_stream();
+        let mut media = Vec::new();
+
+        // サンプリング周波数インデックスが不正なヘッダーは、次の同期ワードまで読み飛ばされる
+        let broken = [0xFF, 0xF1, (1 << 6) | (15 << 2), 0x80, 0x02, 0x00, 0xFC];
+        let payload = [
+            adts_frame(3, 2, 10),
+            broken.to_vec(),
+            adts_frame(3, 2, 10),
+            adts_frame(4, 1, 10),
+            adts_frame(3, 2, 10),
+        ]
+        .concat();
+        stream
+            .handle_pes(Some(0), None, &payload, &mut media)
+            .expect("failed to handle PES");
+
+        let (track, _) = stream.to_mux_track().expect("failed to build track");
+        assert_eq!(track.sample_entries.len(), 2);
+        let indices = track
+            .samples
+            .iter()
+            .map(|s| s.sample_entry_index)
+            .collect::<Vec<_>>();
+        assert_eq!(indices, [0, 0, 1, 0]);
+        assert_eq!(media.len(), 4 * 10);
+
+        // サンプリングレートが変わっても時刻は連続する
+        let times = stream.samples.iter().map(|s| s.time).collect::<Vec<_>>();
+        assert_eq!(times, [0, 1920, 3840, 3840 + 2089]);
+    }
+
+    #[test]
+    fn timestamps_are_unwrapped_across_the_33_bit_boundary() {
+        let period = TIMESTAMP_PERIOD;
+        let mut unwrapper = TimestampUnwrapper::default();
+        assert_eq!(unwrapper.unwrap(period as u64 - 100), period - 100);
+        assert_eq!(unwrapper.unwrap(period as u64 - 10), period - 10);
+        assert_eq!(unwrapper.unwrap(20), period + 20);
+        assert_eq!(unwrapper.unwrap(3000), period + 3000);
+
+        // 33 ビットを超える上位ビットは無視される
+        let mut unwrapper = TimestampUnwrapper::default();
+        assert_eq!(unwrapper.unwrap(period as u64 + 1), 1);
+        assert_eq!(unwrapper.unwrap(2), 2);
+    }
+
+    #[test]
+    fn small_backward_steps_are_not_treated_as_wrap_around() {
+        // B フレームの PTS のように、直前の値より少し戻るのは周回ではない
+        let mut unwrapper = TimestampUnwrapper::default();
+        assert_eq!(unwrapper.unwrap(9000), 9000);
+        assert_eq!(unwrapper.unwrap(3000), 3000);
+        assert_eq!(unwrapper.unwrap(12000), 12000);
+
+        // 先頭付近から周回前の値に戻った場合には、負の時刻になる
+        let mut unwrapper = TimestampUnwrapper::default();
+        assert_eq!(unwrapper.unwrap(10), 10);
+        assert_eq!(unwrapper.unwrap(TIMESTAMP_PERIOD as u64 - 10), -10);
+        assert_eq!(unwrapper.unwrap(5), 5);
+    }
+
+    #[test]
+    fn opus_access_unit_payload_is_located_after_the_control_header() {
+        let parse = |data: &[u8]| parse_opus_access_unit(data).expect("parse_opus_access_unit");
+
+        assert_eq!(parse(&[0x7F, 0xE0, 3, 1, 2, 3]), Some((6, 3..6)));
+
+        // 後続のアクセスユニットがあっても、最初の一つ分だけが返される
+        assert_eq!(parse(&[0x7F, 0xE0, 1, 1, 0x7F, 0xE0]), Some((4, 3..4)));
+
+        // 255 以上の au_size は 0xFF の並びと、その後の残りの値で表される
+        let mut data = vec![0x7F, 0xE0, 0xFF, 0x01];
+        data.resize(4 + 256, 0);
+        assert_eq!(parse(&data), Some((260, 4..260)));
+
+        // start_trim (0x10) / end_trim (0x08) / control_extension (0x04) のフィールドは読み飛ばされる
+        assert_eq!(parse(&[0x7F, 0xF0, 2, 0, 0, 1, 2]), Some((7, 5..7)));
+        assert_eq!(parse(&[0x7F, 0xF8, 1, 0, 0, 0, 0, 1]), Some((8, 7..8)));
+        assert_eq!(parse(&[0x7F, 0xE4, 1, 2, 0, 0, 1]), Some((7, 6..7)));
+    }
+
+    #[test]
+    fn incomplete_opus_access_units_wait_for_more_data() {
+        for data in [&[][..], &[0x7F], &[0x7F, 0xE0, 0xFF], &[0x7F, 0xE0, 3, 1]] {
+            assert_eq!(parse_opus_access_unit(data).ok(), Some(None), "{data:02x?}");
+        }
+
+        // opus_control_header のプレフィックスが不正な場合はエラーになる
+        assert!(parse_opus_access_unit(&[0x00, 0xE0, 0]).is_err());
+        assert!(parse_opus_access_unit(&[0x7F, 0x00, 0]).is_err());
+    }
+}
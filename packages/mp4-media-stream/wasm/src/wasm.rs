@@ -6,20 +6,39 @@ use serde::{Deserialize, Serialize};
 use shiguredo_mp4::{aux::SampleAccessor, boxes::StblBox};
 
 use crate::{
+    cmaf::{CmafSegmenter, CmafTrackInfo},
     engine::Engine,
-    mp4::{AudioDecoderConfig, Mp4Info, VideoDecoderConfig},
-    player::{PlayOptions, PlayerId},
+    inspect::{self, Mp4Report},
+    manifest::{HlsPlaylists, ManifestOptions},
+    mp4::{AudioDecoderConfig, Mp4Info, TrackKind, VideoDecoderConfig},
+    muxer::Mp4Muxer,
+    player::{self, PlayOptions, PlayerId},
+    recover,
+    remux::{self, Mp4Concatenator},
+    stats::PlayerStats,
+    subtitle::SubtitleCue,
 };
 
 pub type DecoderId = u32;
 
-#[derive(Debug, Serialize)]
-#[serde(rename_all = "camelCase")]
+// サンプル毎に TypeScript 側に渡すメタデータ
+//
+// デコードは頻繁に呼び出されるので、JSON を経由せずに固定レイアウトのまま Wasm のメモリ上で参照してもらう
+// (TypeScript 側ではリトルエンディアンの DataView として読み込まれる）
+//
+// | offset | size | field                                  |
+// |--------|------|----------------------------------------|
+// | 0      | 8    | timestamp (f64, micros)                |
+// | 8      | 8    | duration (f64, micros)                 |
+// | 16     | 4    | is_key (u32, 1 = key, 0 = delta)       |
+// | 20     | 4    | (padding)                              |
+#[derive(Debug)]
+#[repr(C)]
 pub struct EncodedChunkMetadata {
-    #[serde(rename = "type")]
-    pub ty: &'static str, // key | delta
-    pub timestamp: u64, // micros
-    pub duration: u32,  // micros
+    pub timestamp: f64,
+    pub duration: f64,
+    pub is_key: u32,
+    _padding: u32,
 }
 
 #[derive(Debug)]
@@ -69,32 +88,60 @@ impl WasmApi {
         sample_data: &[u8],
     ) {
         let metadata = EncodedChunkMetadata {
-            ty: if sample.is_sync_sample() {
-                "key"
-            } else {
-                "delta"
-            },
             timestamp: (Duration::from_secs(sample.timestamp()) / timescale.get()
                 + timestamp_offset)
-                .as_micros() as u64,
+                .as_micros() as f64,
             duration: (Duration::from_secs(sample.duration() as u64) / timescale.get()).as_micros()
-                as u32,
+                as f64,
+            is_key: sample.is_sync_sample() as u32,
+            _padding: 0,
         };
+
+        // metadata は呼び出し中のみ有効なので、TypeScript 側では同期的に読み取る必要がある
         unsafe {
             decode(
                 player_id,
                 decoder,
-                JsonVec::new(metadata),
+                &metadata,
                 sample_data.as_ptr(),
                 sample_data.len() as u32,
             );
         }
     }
 
+    // デコーダーを経由せずに、変換済みの音声データ（インターリーブされた f32 のサンプル列）を TypeScript 側の音声出力に渡す
+    //
+    // samples は呼び出し中のみ有効なので、TypeScript 側ではコピーを作成する必要がある
+    pub fn output_audio(player_id: PlayerId, timestamp: Duration, samples: &[f32]) {
+        unsafe {
+            outputAudio(
+                player_id,
+                timestamp.as_micros() as f64,
+                samples.as_ptr(),
+                samples.len() as u32,
+            );
+        }
+    }
+
+    // 開始時刻に達した字幕のキューを TypeScript 側に渡す
+    pub fn output_cue(player_id: PlayerId, cue: &SubtitleCue) {
+        unsafe { outputCue(player_id, JsonVec::new(cue.clone())) }
+    }
+
     pub fn close_decoder(player_id: PlayerId, decoder: DecoderId) {
         unsafe { closeDecoder(player_id, decoder) }
     }
 
+    // デコード待ちのサンプルを破棄する（デコーダーの設定はそのまま引き継がれる）
+    pub fn reset_decoder(player_id: PlayerId, decoder: DecoderId) {
+        unsafe { resetDecoder(player_id, decoder) }
+    }
+
+    // 現在の再生位置（ファイル先頭からの経過時間）を TypeScript 側に伝える
+    pub fn notify_time_update(player_id: PlayerId, position: Duration) {
+        unsafe { onTimeUpdate(player_id, position.as_micros() as f64) }
+    }
+
     // MP4 の終端に達したことを TypeScript 側に伝える
     // (repeat=true の場合にはこれが呼ばれることはない）
     pub fn notify_eos(player_id: PlayerId) {
@@ -102,6 +149,7 @@ impl WasmApi {
     }
 }
 
+#[cfg(target_arch = "wasm32")]
 extern "C" {
     #[expect(improper_ctypes)]
     pub fn consoleLog(messageWasmJson: JsonVec<String>);
@@ -111,11 +159,10 @@ extern "C" {
     #[expect(improper_ctypes)]
     pub fn sleep(result_tx: *mut oneshot::Sender<()>, duration: u32);
 
-    #[expect(improper_ctypes)]
     pub fn decode(
         player_id: PlayerId,
         decoder: DecoderId,
-        metadata: JsonVec<EncodedChunkMetadata>,
+        metadata: *const EncodedChunkMetadata,
         data_ptr: *const u8,
         data_len: u32,
     );
@@ -134,11 +181,32 @@ extern "C" {
         config: JsonVec<AudioDecoderConfig>,
     );
 
+    pub fn outputAudio(
+        player_id: PlayerId,
+        timestamp_micros: f64,
+        samples_ptr: *const f32,
+        samples_len: u32,
+    );
+
+    #[expect(improper_ctypes)]
+    pub fn outputCue(player_id: PlayerId, cue: JsonVec<SubtitleCue>);
+
     pub fn closeDecoder(player_id: PlayerId, decoder: DecoderId);
 
+    pub fn resetDecoder(player_id: PlayerId, decoder: DecoderId);
+
     pub fn onEos(player_id: PlayerId);
+
+    pub fn onTimeUpdate(player_id: PlayerId, position_micros: f64);
 }
 
+// Wasm 以外の環境（ネイティブ向けの再生シミュレーター）では、TypeScript 側の代わりに simulator モジュールの実装が使われる
+#[cfg(not(target_arch = "wasm32"))]
+use crate::simulator::host::{
+    closeDecoder, consoleLog, createAudioDecoder, createVideoDecoder, decode, now, onEos,
+    onTimeUpdate, outputAudio, outputCue, resetDecoder, sleep,
+};
+
 #[no_mangle]
 #[expect(clippy::not_unsafe_ptr_arg_deref)]
 pub fn awake(engine: *mut Engine, result_tx: *mut oneshot::Sender<()>) {
@@ -186,6 +254,34 @@ pub fn loadMp4(engine: *mut Engine, mp4_bytes: *mut Vec<u8>) -> JsonVec<orfail::
     JsonVec::new(result)
 }
 
+// ロード済みの MP4 の [start_micros, end_micros) の範囲を切り出す
+//
+// 成功した場合には takeOutput() で結果の MP4 を取得できる
+#[no_mangle]
+#[expect(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+pub fn trimMp4(
+    engine: *mut Engine,
+    start_micros: f64,
+    end_micros: f64,
+) -> JsonVec<orfail::Result<()>> {
+    let engine = unsafe { &mut *engine };
+    let result = engine
+        .trim(
+            Duration::from_micros(start_micros.max(0.0) as u64),
+            Duration::from_micros(end_micros.max(0.0) as u64),
+        )
+        .or_fail();
+    JsonVec::new(result)
+}
+
+// (返り値のメモリ領域を解放するのは呼び出し側の責務）
+#[no_mangle]
+#[expect(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+pub fn takeOutput(engine: *mut Engine) -> *mut Vec<u8> {
+    let engine = unsafe { &mut *engine };
+    Box::into_raw(Box::new(engine.take_output()))
+}
+
 #[no_mangle]
 #[expect(clippy::not_unsafe_ptr_arg_deref)]
 pub fn play(engine: *mut Engine, player_id: PlayerId, options: JsonVec<PlayOptions>) {
@@ -201,6 +297,311 @@ pub fn stop(engine: *mut Engine, player_id: PlayerId) {
     engine.stop(player_id)
 }
 
+#[no_mangle]
+#[expect(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+pub fn getStats(engine: *mut Engine, player_id: PlayerId) -> JsonVec<Option<PlayerStats>> {
+    let engine = unsafe { &mut *engine };
+    JsonVec::new(engine.stats(player_id))
+}
+
+// TypeScript 側のデコーダーが出力を生成したことを通知する（統計情報の収集用）
+#[no_mangle]
+#[expect(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+pub fn notifyDecoderOutput(
+    engine: *mut Engine,
+    player_id: PlayerId,
+    decoder_id: DecoderId,
+    timestamp_micros: f64,
+) {
+    let engine = unsafe { &mut *engine };
+    engine.notify_decoder_output(player_id, decoder_id, timestamp_micros as u64);
+}
+
+// AudioWorklet が現在再生している音声データのタイムスタンプを通知する
+// (映像の再生タイミングを音声の時計に追従させるために使われる）
+#[no_mangle]
+#[expect(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+pub fn notifyAudioPosition(engine: *mut Engine, player_id: PlayerId, timestamp_micros: f64) {
+    let engine = unsafe { &mut *engine };
+    engine.notify_audio_position(
+        player_id,
+        Duration::from_micros(timestamp_micros.max(0.0) as u64),
+    );
+}
+
+// 指定のプレイヤーの現在の再生位置をマイクロ秒単位で返す
+// (存在しないプレイヤーが指定された場合には -1 が返される）
+#[no_mangle]
+#[expect(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+pub fn currentTime(engine: *mut Engine, player_id: PlayerId) -> f64 {
+    let engine = unsafe { &mut *engine };
+    engine
+        .current_time(player_id)
+        .map(|t| t.as_micros() as f64)
+        .unwrap_or(-1.0)
+}
+
+// デコーダーに渡されたタイムスタンプ（マイクロ秒単位）を、繰り返し再生の周回毎のオフセットを除いた
+// ファイル先頭からの位置（マイクロ秒単位）に変換する
+#[no_mangle]
+#[expect(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+pub fn mediaTime(engine: *mut Engine, timestamp_micros: f64) -> f64 {
+    let engine = unsafe { &mut *engine };
+    let timestamp = Duration::from_micros(timestamp_micros.max(0.0) as u64);
+    player::media_time(timestamp, engine.file_duration()).as_micros() as f64
+}
+
+// fragment_duration_micros に正の値が指定された場合には Fragmented MP4 を生成する
+#[no_mangle]
+#[expect(non_snake_case)]
+pub fn newMp4Muxer(fragment_duration_micros: f64) -> *mut Mp4Muxer {
+    let fragment_duration = (fragment_duration_micros >= 1.0)
+        .then(|| Duration::from_micros(fragment_duration_micros as u64));
+    Box::into_raw(Box::new(Mp4Muxer::new(fragment_duration)))
+}
+
+#[no_mangle]
+#[expect(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+pub fn freeMp4Muxer(muxer: *mut Mp4Muxer) {
+    let _ = unsafe { Box::from_raw(muxer) };
+}
+
+#[no_mangle]
+#[expect(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+pub fn mp4MuxerSetVideoConfig(
+    muxer: *mut Mp4Muxer,
+    config: JsonVec<VideoDecoderConfig>,
+) -> JsonVec<orfail::Result<()>> {
+    let muxer = unsafe { &mut *muxer };
+    let config = unsafe { config.into_value() };
+    JsonVec::new(muxer.set_video_config(&config).or_fail())
+}
+
+#[no_mangle]
+#[expect(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+pub fn mp4MuxerSetAudioConfig(
+    muxer: *mut Mp4Muxer,
+    config: JsonVec<AudioDecoderConfig>,
+) -> JsonVec<orfail::Result<()>> {
+    let muxer = unsafe { &mut *muxer };
+    let config = unsafe { config.into_value() };
+    JsonVec::new(muxer.set_audio_config(&config).or_fail())
+}
+
+// エンコード済みのチャンクを追加する
+// (duration_micros が負の場合には尺が不明であることを意味する）
+//
+// composition_offset_micros はチャンクの表示時刻からデコード時刻を引いた値（B フレームを含まない場合は 0）
+#[no_mangle]
+#[expect(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+pub fn mp4MuxerAppendChunk(
+    muxer: *mut Mp4Muxer,
+    is_video: u32,
+    timestamp_micros: f64,
+    composition_offset_micros: f64,
+    duration_micros: f64,
+    is_key: u32,
+    data: *mut Vec<u8>,
+) -> JsonVec<orfail::Result<()>> {
+    let muxer = unsafe { &mut *muxer };
+    let data = unsafe { Box::from_raw(data) };
+    let kind = if is_video != 0 {
+        TrackKind::Video
+    } else {
+        TrackKind::Audio
+    };
+    let result = (composition_offset_micros >= 0.0)
+        .or_fail_with(|()| format!("Negative composition offset: {composition_offset_micros} us"))
+        .and_then(|()| {
+            muxer
+                .append_chunk(
+                    kind,
+                    Duration::from_micros(timestamp_micros.max(0.0) as u64),
+                    Duration::from_micros(composition_offset_micros as u64),
+                    (duration_micros >= 0.0).then(|| Duration::from_micros(duration_micros as u64)),
+                    is_key != 0,
+                    &data,
+                )
+                .or_fail()
+        });
+    JsonVec::new(result)
+}
+
+#[no_mangle]
+#[expect(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+pub fn mp4MuxerFinish(muxer: *mut Mp4Muxer) -> JsonVec<orfail::Result<()>> {
+    let muxer = unsafe { &mut *muxer };
+    JsonVec::new(muxer.finish().or_fail())
+}
+
+// これまでに生成された MP4 のバイト列を取り出す
+// (返り値のメモリ領域を解放するのは呼び出し側の責務）
+#[no_mangle]
+#[expect(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+pub fn mp4MuxerTakeOutput(muxer: *mut Mp4Muxer) -> *mut Vec<u8> {
+    let muxer = unsafe { &mut *muxer };
+    Box::into_raw(Box::new(muxer.take_output()))
+}
+
+// MP4 のボックス構造やサンプルテーブルの要約、仕様違反の一覧を返す
+#[no_mangle]
+#[expect(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+pub fn inspectMp4(mp4_bytes: *mut Vec<u8>) -> JsonVec<Mp4Report> {
+    let mp4_bytes = *unsafe { Box::from_raw(mp4_bytes) };
+    JsonVec::new(inspect::inspect_mp4(&mp4_bytes))
+}
+
+// moov ボックスが mdat ボックスよりも前に来るように MP4 を並べ替える
+//
+// 成功した場合には mp4_bytes の中身が変換後の MP4 で置き換えられる
+// (失敗した場合には中身は変更されない。いずれの場合も mp4_bytes を解放するのは呼び出し側の責務）
+#[no_mangle]
+#[expect(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+pub fn faststartMp4(mp4_bytes: *mut Vec<u8>) -> JsonVec<orfail::Result<()>> {
+    let mp4_bytes = unsafe { &mut *mp4_bytes };
+    let result = remux::faststart_mp4(mp4_bytes)
+        .map(|output| *mp4_bytes = output)
+        .or_fail();
+    JsonVec::new(result)
+}
+
+// moov ボックスが書き込まれずに途中で終わっている MP4 を、同じ設定で録画された参照用 MP4 を使って復旧する
+//
+// 成功した場合には mp4_bytes の中身が復旧後の MP4 で置き換えられる
+// (mp4_bytes の扱いは faststartMp4() と同様。reference_bytes はこの関数内で解放される）
+#[no_mangle]
+#[expect(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+pub fn recoverMp4(
+    mp4_bytes: *mut Vec<u8>,
+    reference_bytes: *mut Vec<u8>,
+) -> JsonVec<orfail::Result<()>> {
+    let mp4_bytes = unsafe { &mut *mp4_bytes };
+    let reference_bytes = *unsafe { Box::from_raw(reference_bytes) };
+    let result = recover::recover_mp4(mp4_bytes, &reference_bytes)
+        .map(|output| *mp4_bytes = output)
+        .or_fail();
+    JsonVec::new(result)
+}
+
+#[no_mangle]
+#[expect(non_snake_case)]
+pub fn newMp4Concatenator() -> *mut Mp4Concatenator {
+    Box::into_raw(Box::new(Mp4Concatenator::new()))
+}
+
+#[no_mangle]
+#[expect(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+pub fn freeMp4Concatenator(concatenator: *mut Mp4Concatenator) {
+    let _ = unsafe { Box::from_raw(concatenator) };
+}
+
+#[no_mangle]
+#[expect(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+pub fn mp4ConcatenatorAddInput(
+    concatenator: *mut Mp4Concatenator,
+    mp4_bytes: *mut Vec<u8>,
+) -> JsonVec<orfail::Result<()>> {
+    let concatenator = unsafe { &mut *concatenator };
+    let mp4_bytes = *unsafe { Box::from_raw(mp4_bytes) };
+    JsonVec::new(concatenator.add_input(mp4_bytes).or_fail())
+}
+
+#[no_mangle]
+#[expect(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+pub fn mp4ConcatenatorFinish(concatenator: *mut Mp4Concatenator) -> JsonVec<orfail::Result<()>> {
+    let concatenator = unsafe { &mut *concatenator };
+    JsonVec::new(concatenator.finish().or_fail())
+}
+
+// (返り値のメモリ領域を解放するのは呼び出し側の責務）
+#[no_mangle]
+#[expect(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+pub fn mp4ConcatenatorTakeOutput(concatenator: *mut Mp4Concatenator) -> *mut Vec<u8> {
+    let concatenator = unsafe { &mut *concatenator };
+    Box::into_raw(Box::new(concatenator.take_output()))
+}
+
+#[no_mangle]
+#[expect(non_snake_case)]
+pub fn newCmafSegmenter() -> *mut CmafSegmenter {
+    Box::into_raw(Box::new(CmafSegmenter::new()))
+}
+
+#[no_mangle]
+#[expect(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+pub fn freeCmafSegmenter(segmenter: *mut CmafSegmenter) {
+    let _ = unsafe { Box::from_raw(segmenter) };
+}
+
+#[no_mangle]
+#[expect(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+pub fn cmafSegmenterLoad(
+    segmenter: *mut CmafSegmenter,
+    mp4_bytes: *mut Vec<u8>,
+    segment_duration_micros: f64,
+) -> JsonVec<orfail::Result<Vec<CmafTrackInfo>>> {
+    let segmenter = unsafe { &mut *segmenter };
+    let mp4_bytes = *unsafe { Box::from_raw(mp4_bytes) };
+    let segment_duration = Duration::from_micros(segment_duration_micros.max(0.0) as u64);
+    JsonVec::new(segmenter.load(mp4_bytes, segment_duration).or_fail())
+}
+
+// 成功した場合には cmafSegmenterTakeOutput() で初期化セグメントを取得できる
+#[no_mangle]
+#[expect(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+pub fn cmafSegmenterInitSegment(
+    segmenter: *mut CmafSegmenter,
+    track_index: u32,
+) -> JsonVec<orfail::Result<()>> {
+    let segmenter = unsafe { &mut *segmenter };
+    JsonVec::new(segmenter.init_segment(track_index as usize).or_fail())
+}
+
+// 成功した場合には cmafSegmenterTakeOutput() でメディアセグメントを取得できる
+#[no_mangle]
+#[expect(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+pub fn cmafSegmenterMediaSegment(
+    segmenter: *mut CmafSegmenter,
+    track_index: u32,
+    segment_index: u32,
+) -> JsonVec<orfail::Result<()>> {
+    let segmenter = unsafe { &mut *segmenter };
+    let result = segmenter
+        .media_segment(track_index as usize, segment_index as usize)
+        .or_fail();
+    JsonVec::new(result)
+}
+
+#[no_mangle]
+#[expect(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+pub fn cmafSegmenterHlsPlaylists(
+    segmenter: *mut CmafSegmenter,
+    options: JsonVec<ManifestOptions>,
+) -> JsonVec<orfail::Result<HlsPlaylists>> {
+    let segmenter = unsafe { &mut *segmenter };
+    let options = unsafe { options.into_value() };
+    JsonVec::new(segmenter.hls_playlists(&options).or_fail())
+}
+
+#[no_mangle]
+#[expect(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+pub fn cmafSegmenterDashMpd(
+    segmenter: *mut CmafSegmenter,
+    options: JsonVec<ManifestOptions>,
+) -> JsonVec<orfail::Result<String>> {
+    let segmenter = unsafe { &mut *segmenter };
+    let options = unsafe { options.into_value() };
+    JsonVec::new(segmenter.dash_mpd(&options).or_fail())
+}
+
+// (返り値のメモリ領域を解放するのは呼び出し側の責務）
+#[no_mangle]
+#[expect(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+pub fn cmafSegmenterTakeOutput(segmenter: *mut CmafSegmenter) -> *mut Vec<u8> {
+    let segmenter = unsafe { &mut *segmenter };
+    Box::into_raw(Box::new(segmenter.take_output()))
+}
+
 #[no_mangle]
 #[expect(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
 pub fn vecOffset(v: *mut Vec<u8>) -> *mut u8 {
@@ -243,7 +644,7 @@ impl<T: Serialize> JsonVec<T> {
 
 impl<T: for<'de> Deserialize<'de>> JsonVec<T> {
     #[track_caller]
-    unsafe fn into_value(self) -> T {
+    pub(crate) unsafe fn into_value(self) -> T {
         let bytes = Box::from_raw(self.bytes);
         let value: T = serde_json::from_slice(&bytes).expect("Invalid JSON");
         value
@@ -0,0 +1,1148 @@
+use std::{collections::HashSet, num::NonZeroU32, ops::Range, time::Duration};
+
+use orfail::{Failure, OrFail};
+
+use crate::{
+    es::{is_av1_key_frame, is_vp8_key_frame, is_vp9_key_frame},
+    mp4::{AudioDecoderConfig, Mp4, Track, TrackKind, VideoDecoderConfig},
+    muxer::{MuxSample, MuxTrack},
+};
+
+// EBML / Matroska の要素 ID
+// https://www.matroska.org/technical/elements.html
+const ID_EBML: u32 = 0x1A45DFA3;
+const ID_DOC_TYPE: u32 = 0x4282;
+const ID_SEGMENT: u32 = 0x18538067;
+const ID_SEEK_HEAD: u32 = 0x114D9B74;
+const ID_INFO: u32 = 0x1549A966;
+const ID_TIMESTAMP_SCALE: u32 = 0x2AD7B1;
+const ID_TRACKS: u32 = 0x1654AE6B;
+const ID_TRACK_ENTRY: u32 = 0xAE;
+const ID_TRACK_NUMBER: u32 = 0xD7;
+const ID_TRACK_TYPE: u32 = 0x83;
+const ID_CODEC_ID: u32 = 0x86;
+const ID_CODEC_PRIVATE: u32 = 0x63A2;
+const ID_DEFAULT_DURATION: u32 = 0x23E383;
+const ID_CONTENT_ENCODINGS: u32 = 0x6D80;
+const ID_VIDEO: u32 = 0xE0;
+const ID_PIXEL_WIDTH: u32 = 0xB0;
+const ID_PIXEL_HEIGHT: u32 = 0xBA;
+const ID_AUDIO: u32 = 0xE1;
+const ID_SAMPLING_FREQUENCY: u32 = 0xB5;
+const ID_CHANNELS: u32 = 0x9F;
+const ID_CLUSTER: u32 = 0x1F43B675;
+const ID_CLUSTER_TIMESTAMP: u32 = 0xE7;
+const ID_SIMPLE_BLOCK: u32 = 0xA3;
+const ID_BLOCK_GROUP: u32 = 0xA0;
+const ID_BLOCK: u32 = 0xA1;
+const ID_BLOCK_DURATION: u32 = 0x9B;
+const ID_REFERENCE_BLOCK: u32 = 0xFB;
+const ID_CUES: u32 = 0x1C53BB6B;
+const ID_CUE_POINT: u32 = 0xBB;
+const ID_CUE_TIME: u32 = 0xB3;
+const ID_CUE_TRACK_POSITIONS: u32 = 0xB7;
+const ID_CUE_TRACK: u32 = 0xF7;
+const ID_CHAPTERS: u32 = 0x1043A770;
+const ID_TAGS: u32 = 0x1254C367;
+const ID_ATTACHMENTS: u32 = 0x1941A469;
+
+const TRACK_TYPE_VIDEO: u64 = 1;
+const TRACK_TYPE_AUDIO: u64 = 2;
+
+// Info 要素に TimestampScale がない場合のデフォルト値（ナノ秒単位、つまりタイムスタンプはミリ秒単位となる）
+const DEFAULT_TIMESTAMP_SCALE: u64 = 1_000_000;
+
+// 変換後のトラックのタイムスケール（サンプルの時刻と尺はマイクロ秒単位で扱う）
+const TIMESCALE: NonZeroU32 = NonZeroU32::MIN.saturating_add(1_000_000 - 1);
+
+// 最後のサンプルの尺が不明な場合に使うデフォルト値（マイクロ秒単位）
+const DEFAULT_LAST_SAMPLE_DURATION: u64 = 20_000;
+
+// WebM の EBML ヘッダーの先頭バイト列
+const EBML_MAGIC: [u8; 4] = ID_EBML.to_be_bytes();
+
+// バイト列が WebM (Matroska) ファイルかどうかを判定する
+pub fn is_webm(bytes: &[u8]) -> bool {
+    bytes.starts_with(&EBML_MAGIC)
+}
+
+// WebM (Matroska) ファイルを読み込んで、MP4 の場合と同じ形式のトラック群に変換する
+//
+// 変換後の各サンプルのデータ位置は、WebM ファイルの先頭からのオフセットとなるので、
+// 再生時には MP4 の場合と同様に元のバイト列をそのまま参照することができる。
+//
+// 同期サンプルは SimpleBlock のキーフレームフラグ（BlockGroup の場合は ReferenceBlock の有無）で判定し、
+// Cues 要素に記載された時刻のサンプルは、ビットストリームからキーフレームであることを確認できた場合にのみ同期サンプルとして扱う。
+// 録画の中断などで末尾が欠けているファイルや、サイズが不明な要素（MediaRecorder の出力など）にも対応している。
+//
+// 最も早く始まるトラックの最初のサンプルの時刻が 0 となり、
+// 他のトラックとの開始時刻の差は、そのトラックの先頭の空白（MuxTrack::start_offset）として扱われる。
+pub fn load_webm(bytes: &[u8]) -> orfail::Result<Mp4> {
+    let mut reader = EbmlReader::new(bytes, 0..bytes.len());
+    let header = reader
+        .next_element()
+        .or_fail()?
+        .filter(|e| e.id == ID_EBML)
+        .or_fail_with(|()| "No EBML header found".to_owned())?;
+    let mut doc_type = "matroska".to_owned();
+    let mut header_reader = reader.children(&header);
+    while let Some(element) = header_reader.next_element().or_fail()? {
+        if element.id == ID_DOC_TYPE {
+            doc_type = read_string(bytes, &element);
+        }
+    }
+    matches!(doc_type.as_str(), "webm" | "matroska")
+        .or_fail_with(|()| format!("Unsupported EBML document type: {doc_type}"))?;
+
+    let segment = loop {
+        let element = reader
+            .next_element()
+            .or_fail()?
+            .or_fail_with(|()| "No Segment element found".to_owned())?;
+        if element.id == ID_SEGMENT {
+            break element;
+        }
+    };
+
+    let mut demuxer = WebmDemuxer::new(bytes);
+    demuxer.read_segment(&segment).or_fail()?;
+    let tracks = demuxer.finish().or_fail()?;
+    Mp4::from_tracks(tracks).or_fail()
+}
+
+#[derive(Debug)]
+struct WebmDemuxer<'a> {
+    bytes: &'a [u8],
+    timestamp_scale: u64,
+    tracks: Vec<WebmTrack>,
+
+    // Cues 要素に記載されていた (トラック番号, 時刻) の組（時刻はマイクロ秒単位）
+    cue_points: Vec<(u64, i64)>,
+}
+
+impl<'a> WebmDemuxer<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self {
+            bytes,
+            timestamp_scale: DEFAULT_TIMESTAMP_SCALE,
+            tracks: Vec::new(),
+            cue_points: Vec::new(),
+        }
+    }
+
+    fn read_segment(&mut self, segment: &Element) -> orfail::Result<()> {
+        let mut reader = EbmlReader::new(self.bytes, segment.data.clone());
+        while let Some(element) = reader.next_element().or_fail()? {
+            match element.id {
+                ID_INFO => self.read_info(&element).or_fail()?,
+                ID_TRACKS => self.read_tracks(&element).or_fail()?,
+                ID_CLUSTER => {
+                    let end = self.read_cluster(&element).or_fail()?;
+                    // サイズが不明なクラスターの場合には、実際に読み込んだ位置から再開する
+                    reader.position = end;
+                }
+                ID_CUES => self.read_cues(&element).or_fail()?,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn read_info(&mut self, info: &Element) -> orfail::Result<()> {
+        let mut reader = EbmlReader::new(self.bytes, info.data.clone());
+        while let Some(element) = reader.next_element().or_fail()? {
+            if element.id == ID_TIMESTAMP_SCALE {
+                self.timestamp_scale = read_uint(self.bytes, &element).or_fail()?;
+                (self.timestamp_scale > 0)
+                    .or_fail_with(|()| "Invalid TimestampScale: 0".to_owned())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_tracks(&mut self, tracks: &Element) -> orfail::Result<()> {
+        let mut reader = EbmlReader::new(self.bytes, tracks.data.clone());
+        while let Some(element) = reader.next_element().or_fail()? {
+            if element.id != ID_TRACK_ENTRY {
+                continue;
+            }
+            if let Some(track) = self.read_track_entry(&element).or_fail()? {
+                self.tracks.push(track);
+            }
+        }
+        Ok(())
+    }
+
+    // 音声・映像以外のトラックの場合には None が返される
+    fn read_track_entry(&self, entry: &Element) -> orfail::Result<Option<WebmTrack>> {
+        let bytes = self.bytes;
+        let mut number = None;
+        let mut track_type = 0;
+        let mut codec_id = String::new();
+        let mut codec_private = Vec::new();
+        let mut default_duration = None;
+        let mut width = 0;
+        let mut height = 0;
+        let mut sample_rate = 8000.0;
+        let mut channels = 1;
+
+        let mut reader = EbmlReader::new(bytes, entry.data.clone());
+        while let Some(element) = reader.next_element().or_fail()? {
+            match element.id {
+                ID_TRACK_NUMBER => number = Some(read_uint(bytes, &element).or_fail()?),
+                ID_TRACK_TYPE => track_type = read_uint(bytes, &element).or_fail()?,
+                ID_CODEC_ID => codec_id = read_string(bytes, &element),
+                ID_CODEC_PRIVATE => codec_private = bytes[element.data.clone()].to_vec(),
+                ID_DEFAULT_DURATION => {
+                    // ナノ秒単位
+                    default_duration = Some(read_uint(bytes, &element).or_fail()? / 1000);
+                }
+                ID_CONTENT_ENCODINGS => {
+                    return Err(Failure::new(
+                        "Unsupported: compressed or encrypted WebM track",
+                    ));
+                }
+                ID_VIDEO => {
+                    let mut video_reader = EbmlReader::new(bytes, element.data.clone());
+                    while let Some(element) = video_reader.next_element().or_fail()? {
+                        match element.id {
+                            ID_PIXEL_WIDTH => width = read_uint(bytes, &element).or_fail()?,
+                            ID_PIXEL_HEIGHT => height = read_uint(bytes, &element).or_fail()?,
+                            _ => {}
+                        }
+                    }
+                }
+                ID_AUDIO => {
+                    let mut audio_reader = EbmlReader::new(bytes, element.data.clone());
+                    while let Some(element) = audio_reader.next_element().or_fail()? {
+                        match element.id {
+                            ID_SAMPLING_FREQUENCY => {
+                                sample_rate = read_float(bytes, &element).or_fail()?
+                            }
+                            ID_CHANNELS => channels = read_uint(bytes, &element).or_fail()?,
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        let number = number.or_fail_with(|()| "Missing TrackNumber".to_owned())?;
+
+        let kind = match track_type {
+            TRACK_TYPE_VIDEO => TrackKind::Video,
+            TRACK_TYPE_AUDIO => TrackKind::Audio,
+            _ => return Ok(None),
+        };
+        let codec = match kind {
+            TrackKind::Video => WebmCodec::Video(VideoDecoderConfig {
+                codec: video_codec_string(
+                    &codec_id,
+                    &codec_private,
+                    (width, height),
+                    default_duration,
+                )
+                .or_fail()?,
+                description: codec_private,
+                coded_width: u16::try_from(width)
+                    .or_fail_with(|_| format!("Too large video width: {width}"))?,
+                coded_height: u16::try_from(height)
+                    .or_fail_with(|_| format!("Too large video height: {height}"))?,
+            }),
+            TrackKind::Audio => WebmCodec::Audio(AudioDecoderConfig {
+                codec: audio_codec_string(&codec_id, &codec_private).or_fail()?,
+                sample_rate: u32::try_from(sample_rate as u64)
+                    .or_fail_with(|_| format!("Unsupported audio sample rate: {sample_rate}"))?,
+                number_of_channels: u8::try_from(channels)
+                    .or_fail_with(|_| format!("Too many audio channels: {channels}"))?,
+                description: codec_private,
+            }),
+        };
+
+        Ok(Some(WebmTrack {
+            number,
+            kind,
+            codec,
+            default_duration,
+            blocks: Vec::new(),
+        }))
+    }
+
+    // クラスター内のブロック群を読み込んで、クラスターの終端位置を返す
+    fn read_cluster(&mut self, cluster: &Element) -> orfail::Result<usize> {
+        let bytes = self.bytes;
+        let mut cluster_timestamp = 0;
+        let mut reader = EbmlReader::new(bytes, cluster.data.clone());
+        loop {
+            let start = reader.position;
+            let Some(element) = reader.next_element().or_fail()? else {
+                break;
+            };
+            if cluster.unknown_size && is_top_level_id(element.id) {
+                // サイズが不明なクラスターは、次のトップレベル要素が現れた位置で終わる
+                return Ok(start);
+            }
+            match element.id {
+                ID_CLUSTER_TIMESTAMP => {
+                    cluster_timestamp = read_uint(bytes, &element).or_fail()?;
+                }
+                ID_SIMPLE_BLOCK => {
+                    self.read_block(&element, cluster_timestamp, None, None)
+                        .or_fail()?;
+                }
+                ID_BLOCK_GROUP => {
+                    let mut block = None;
+                    let mut duration = None;
+                    let mut has_reference = false;
+                    let mut group_reader = EbmlReader::new(bytes, element.data.clone());
+                    while let Some(element) = group_reader.next_element().or_fail()? {
+                        match element.id {
+                            ID_BLOCK => block = Some(element),
+                            ID_BLOCK_DURATION => {
+                                duration = Some(read_uint(bytes, &element).or_fail()?);
+                            }
+                            ID_REFERENCE_BLOCK => has_reference = true,
+                            _ => {}
+                        }
+                    }
+                    if let Some(block) = block {
+                        self.read_block(&block, cluster_timestamp, Some(!has_reference), duration)
+                            .or_fail()?;
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(reader.position)
+    }
+
+    // SimpleBlock あるいは Block 要素を読み込む
+    //
+    // Block 要素の場合には is_key と duration に BlockGroup から得られた値が指定される
+    fn read_block(
+        &mut self,
+        block: &Element,
+        cluster_timestamp: u64,
+        is_key: Option<bool>,
+        duration: Option<u64>,
+    ) -> orfail::Result<()> {
+        if block.truncated {
+            // 途中で切れているブロックは無視する
+            return Ok(());
+        }
+        let data = &self.bytes[block.data.clone()];
+        let (track_number, n) = read_vint(data)
+            .or_fail()?
+            .or_fail_with(|()| "Too short block".to_owned())?;
+        let header = data
+            .get(n..n + 3)
+            .or_fail_with(|()| "Too short block".to_owned())?;
+        let relative_timestamp = i16::from_be_bytes([header[0], header[1]]);
+        let flags = header[2];
+        let is_key = is_key.unwrap_or(flags & 0x80 != 0);
+
+        let timestamp_scale = self.timestamp_scale;
+        let to_micros = |t: i128| (t * timestamp_scale as i128 / 1000) as i64;
+        let Some(track) = self.tracks.iter_mut().find(|t| t.number == track_number) else {
+            return Ok(());
+        };
+
+        let frames_start = block.data.start + n + 3;
+        let frames = split_laced_frames(self.bytes, frames_start..block.data.end, flags)
+            .or_fail_with(|()| "Invalid block lacing".to_owned())?;
+        track.blocks.push(WebmBlock {
+            timestamp: to_micros(cluster_timestamp as i128 + relative_timestamp as i128),
+            duration: duration.map(|d| to_micros(d as i128) as u64),
+            is_key,
+            frames,
+        });
+        Ok(())
+    }
+
+    fn read_cues(&mut self, cues: &Element) -> orfail::Result<()> {
+        let bytes = self.bytes;
+        let mut reader = EbmlReader::new(bytes, cues.data.clone());
+        while let Some(cue_point) = reader.next_element().or_fail()? {
+            if cue_point.id != ID_CUE_POINT {
+                continue;
+            }
+            let mut time = None;
+            let mut track_numbers = Vec::new();
+            let mut point_reader = EbmlReader::new(bytes, cue_point.data.clone());
+            while let Some(element) = point_reader.next_element().or_fail()? {
+                match element.id {
+                    ID_CUE_TIME => time = Some(read_uint(bytes, &element).or_fail()?),
+                    ID_CUE_TRACK_POSITIONS => {
+                        let mut positions_reader = EbmlReader::new(bytes, element.data.clone());
+                        while let Some(element) = positions_reader.next_element().or_fail()? {
+                            if element.id == ID_CUE_TRACK {
+                                track_numbers.push(read_uint(bytes, &element).or_fail()?);
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            if let Some(time) = time {
+                let time = (time as i128 * self.timestamp_scale as i128 / 1000) as i64;
+                self.cue_points
+                    .extend(track_numbers.into_iter().map(|n| (n, time)));
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> orfail::Result<Vec<Track>> {
+        // トラック間の開始時刻の差は、後から始まるトラックの先頭の空白として保持する
+        let start_timestamp = |track: &WebmTrack| track.blocks.iter().map(|b| b.timestamp).min();
+        let first_timestamp = self.tracks.iter().filter_map(start_timestamp).min();
+
+        let mut tracks = Vec::new();
+        for track in &self.tracks {
+            let (Some(track_start), Some(first)) = (start_timestamp(track), first_timestamp) else {
+                continue;
+            };
+            let cue_times = self
+                .cue_points
+                .iter()
+                .filter(|(n, _)| *n == track.number)
+                .map(|(_, t)| *t)
+                .collect::<HashSet<_>>();
+            let mut mux_track = track.to_mux_track(self.bytes, &cue_times).or_fail()?;
+            mux_track.start_offset = Duration::from_micros((track_start - first) as u64);
+            let track_id = u32::try_from(track.number)
+                .or_fail_with(|_| format!("Too large track number: {}", track.number))?;
+            tracks.push(Track::from_mux_track(&mux_track, track_id, self.bytes.len()).or_fail()?);
+        }
+        Ok(tracks)
+    }
+}
+
+#[derive(Debug)]
+enum WebmCodec {
+    Audio(AudioDecoderConfig),
+    Video(VideoDecoderConfig),
+}
+
+#[derive(Debug)]
+struct WebmTrack {
+    number: u64,
+    kind: TrackKind,
+    codec: WebmCodec,
+
+    // マイクロ秒単位
+    default_duration: Option<u64>,
+
+    blocks: Vec<WebmBlock>,
+}
+
+impl WebmTrack {
+    fn to_mux_track(&self, bytes: &[u8], cue_times: &HashSet<i64>) -> orfail::Result<MuxTrack> {
+        let sample_entry = match &self.codec {
+            WebmCodec::Audio(config) => config.to_sample_entry().or_fail()?,
+            WebmCodec::Video(config) => {
+                let mut config = config.clone();
+                if config.codec.starts_with("vp09.") {
+                    // プロファイルとビット深度は、CodecPrivate での指定よりも最初のキーフレームの値を優先する
+                    // (レベルはビットストリームには含まれないので、read_track_entry() で決定した値のまま）
+                    if let Some((profile, bit_depth)) =
+                        self.blocks.iter().filter(|b| b.is_key).find_map(|b| {
+                            parse_vp9_profile_and_bit_depth(&bytes[b.frames[0].clone()])
+                        })
+                    {
+                        let level = config.codec.split('.').nth(2).unwrap_or("10");
+                        config.codec = format!("vp09.{profile:02}.{level}.{bit_depth:02}");
+                    }
+                    config.description.clear();
+                }
+                config.to_sample_entry().or_fail()?
+            }
+        };
+
+        // レーシングで一つのブロックに複数のフレームが含まれている場合には、次のブロックまでの間に均等に配置する
+        // (次のブロックがない場合には、ブロックの尺か、直前のブロックのフレームの尺を使う）
+        let mut frames = Vec::new();
+        let mut frame_duration = 0;
+        for (i, block) in self.blocks.iter().enumerate() {
+            let frame_count = block.frames.len() as u64;
+            frame_duration = if let Some(d) = self.default_duration {
+                d
+            } else if let Some(next) = self.blocks.get(i + 1) {
+                (next.timestamp - block.timestamp).max(0) as u64 / frame_count
+            } else if let Some(d) = block.duration {
+                d / frame_count
+            } else {
+                frame_duration
+            };
+            for (j, range) in block.frames.iter().enumerate() {
+                frames.push((
+                    block.timestamp + (frame_duration * j as u64) as i64,
+                    range.clone(),
+                    block.is_key && j == 0,
+                ));
+            }
+        }
+
+        // WebM のタイムスタンプは表示時刻なので、B フレームによる並べ替えがある場合にも
+        // デコード順で単調増加となるように、ソートした時刻をデコード順に割り当てる
+        let mut decode_times = frames.iter().map(|f| f.0).collect::<Vec<_>>();
+        decode_times.sort_unstable();
+        let last_duration = if frame_duration > 0 {
+            frame_duration
+        } else {
+            DEFAULT_LAST_SAMPLE_DURATION
+        };
+
+        let mut mux_track = MuxTrack::new(self.kind, TIMESCALE);
+        let sample_entry_index = mux_track.add_sample_entry(sample_entry);
+        for (i, (timestamp, range, is_key)) in frames.into_iter().enumerate() {
+            let duration = decode_times
+                .get(i + 1)
+                .map(|next| (next - decode_times[i]) as u64)
+                .unwrap_or(last_duration);
+            mux_track.samples.push(MuxSample {
+                sample_entry_index,
+                duration: u32::try_from(duration)
+                    .or_fail_with(|_| format!("Too long sample duration: {duration} us"))?,
+                // 音声は全てのフレームを単独でデコードできる
+                is_sync: self.kind == TrackKind::Audio
+                    || is_key
+                    || (cue_times.contains(&timestamp)
+                        && self.is_key_frame_data(&bytes[range.clone()])),
+                composition_offset: 0,
+                data_offset: range.start as u64,
+                data_size: range.len() as u32,
+            });
+        }
+        Ok(mux_track)
+    }
+}
+
+impl WebmTrack {
+    // フレームのビットストリームから、キーフレームであることを確認できるかどうかを判定する
+    //
+    // Cues 要素に記載された時刻のフレームは、キーフレームフラグが立っていなくても
+    // キーフレームであることが多いが、誤っている可能性もあるので、ここで確認できた場合にのみ同期サンプルとする。
+    // H.264 / H.265 などの確認手段がないコーデックでは常に false となる。
+    fn is_key_frame_data(&self, data: &[u8]) -> bool {
+        let WebmCodec::Video(config) = &self.codec else {
+            return false;
+        };
+        if config.codec == "vp8" {
+            is_vp8_key_frame(data)
+        } else if config.codec.starts_with("vp09.") {
+            is_vp9_key_frame(data)
+        } else if config.codec.starts_with("av01.") {
+            is_av1_key_frame(data)
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Debug)]
+struct WebmBlock {
+    // マイクロ秒単位の表示時刻
+    timestamp: i64,
+    duration: Option<u64>,
+    is_key: bool,
+
+    // ファイル内での各フレームのデータ位置
+    frames: Vec<Range<usize>>,
+}
+
+#[derive(Debug, Clone)]
+struct Element {
+    id: u32,
+
+    // ファイル内でのデータ部分の位置
+    data: Range<usize>,
+
+    // サイズが不明な要素の場合には、data の終端は親要素の終端となる
+    unknown_size: bool,
+
+    // ファイルの末尾が欠けていて、データ部分が途中で切れている
+    truncated: bool,
+}
+
+// bytes の指定範囲内の要素群を先頭から順に読み込むためのリーダー
+#[derive(Debug)]
+struct EbmlReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    end: usize,
+}
+
+impl<'a> EbmlReader<'a> {
+    fn new(bytes: &'a [u8], range: Range<usize>) -> Self {
+        Self {
+            bytes,
+            position: range.start,
+            end: range.end,
+        }
+    }
+
+    fn children(&self, element: &Element) -> Self {
+        Self::new(self.bytes, element.data.clone())
+    }
+
+    // 次の要素を読み込む
+    //
+    // 範囲の終端に達した場合や、末尾が欠けていて要素のヘッダーが読み込めない場合には None が返される。
+    // データ部分が範囲の外まで続いている場合には、範囲の終端までがデータとして扱われる。
+    fn next_element(&mut self) -> orfail::Result<Option<Element>> {
+        let data = &self.bytes[self.position..self.end];
+        let Some((id, id_len)) = read_element_id(data).or_fail()? else {
+            return Ok(None);
+        };
+        let Some((size, size_len)) = read_vint(&data[id_len..]).or_fail()? else {
+            return Ok(None);
+        };
+
+        // 全ビットが 1 の場合はサイズ不明を意味する
+        let unknown_size = size == (1 << (7 * size_len)) - 1;
+        let data_start = self.position + id_len + size_len;
+        let declared_end = data_start.saturating_add(size as usize);
+        let data_end = if unknown_size {
+            self.end
+        } else {
+            declared_end.min(self.end)
+        };
+        self.position = data_end;
+        Ok(Some(Element {
+            id,
+            data: data_start..data_end,
+            unknown_size,
+            truncated: !unknown_size && declared_end > self.end,
+        }))
+    }
+}
+
+// サイズが不明な要素の終端を判定するために使う、Segment 直下の要素の ID かどうか
+fn is_top_level_id(id: u32) -> bool {
+    matches!(
+        id,
+        ID_EBML
+            | ID_SEGMENT
+            | ID_SEEK_HEAD
+            | ID_INFO
+            | ID_TRACKS
+            | ID_CLUSTER
+            | ID_CUES
+            | ID_CHAPTERS
+            | ID_TAGS
+            | ID_ATTACHMENTS
+    )
+}
+
+// 要素 ID を読み込む（ID は長さを示すマーカービットを含めた値として扱う）
+fn read_element_id(data: &[u8]) -> orfail::Result<Option<(u32, usize)>> {
+    let Some(&first) = data.first() else {
+        return Ok(None);
+    };
+    let len = first.leading_zeros() as usize + 1;
+    (len <= 4).or_fail_with(|()| format!("Invalid EBML element ID: {first:#04x}"))?;
+    let Some(bytes) = data.get(..len) else {
+        return Ok(None);
+    };
+    Ok(Some((
+        bytes.iter().fold(0, |acc, &b| (acc << 8) | b as u32),
+        len,
+    )))
+}
+
+// 可変長整数を読み込んで、その値（マーカービットを除いたもの）とバイト数を返す
+fn read_vint(data: &[u8]) -> orfail::Result<Option<(u64, usize)>> {
+    let Some(&first) = data.first() else {
+        return Ok(None);
+    };
+    let len = first.leading_zeros() as usize + 1;
+    (len <= 8).or_fail_with(|()| "Invalid EBML variable size integer".to_owned())?;
+    let Some(bytes) = data.get(..len) else {
+        return Ok(None);
+    };
+    let value = bytes[1..]
+        .iter()
+        .fold((first as u64) & (0xFF >> len), |acc, &b| {
+            (acc << 8) | b as u64
+        });
+    Ok(Some((value, len)))
+}
+
+fn read_uint(bytes: &[u8], element: &Element) -> orfail::Result<u64> {
+    let data = &bytes[element.data.clone()];
+    (data.len() <= 8).or_fail_with(|()| {
+        format!(
+            "Too large unsigned integer element: id={:#x}, size={}",
+            element.id,
+            data.len()
+        )
+    })?;
+    Ok(data.iter().fold(0, |acc, &b| (acc << 8) | b as u64))
+}
+
+fn read_float(bytes: &[u8], element: &Element) -> orfail::Result<f64> {
+    let data = &bytes[element.data.clone()];
+    match data.len() {
+        0 => Ok(0.0),
+        4 => Ok(f32::from_be_bytes(data.try_into().expect("unreachable")) as f64),
+        8 => Ok(f64::from_be_bytes(data.try_into().expect("unreachable"))),
+        n => Err(Failure::new(format!("Invalid float element size: {n}"))),
+    }
+}
+
+fn read_string(bytes: &[u8], element: &Element) -> String {
+    let data = &bytes[element.data.clone()];
+    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).into_owned()
+}
+
+// ブロックのレーシング（複数フレームの格納）を解いて、各フレームのデータ位置を返す
+//
+// レーシングの情報が不正な場合には None が返される
+fn split_laced_frames(bytes: &[u8], range: Range<usize>, flags: u8) -> Option<Vec<Range<usize>>> {
+    let lacing = (flags >> 1) & 0b11;
+    if lacing == 0 {
+        return Some(vec![range]);
+    }
+
+    let data = &bytes[range.clone()];
+    let frame_count = *data.first()? as usize + 1;
+    let mut position = 1;
+    let mut sizes = Vec::with_capacity(frame_count);
+    match lacing {
+        // Xiph レーシング
+        0b01 => {
+            for _ in 0..frame_count - 1 {
+                let mut size = 0;
+                loop {
+                    let b = *data.get(position)?;
+                    position += 1;
+                    size += b as usize;
+                    if b != 0xFF {
+                        break;
+                    }
+                }
+                sizes.push(size);
+            }
+        }
+        // EBML レーシング（二番目以降のサイズは、前のフレームとの差分が符号付きで格納されている）
+        0b11 => {
+            let (first_size, n) = read_vint(&data[position..]).ok()??;
+            position += n;
+            sizes.push(first_size as usize);
+            for _ in 1..frame_count - 1 {
+                let (raw, n) = read_vint(&data[position..]).ok()??;
+                position += n;
+                let bias = (1i64 << (7 * n - 1)) - 1;
+                let size = *sizes.last()? as i64 + (raw as i64 - bias);
+                sizes.push(usize::try_from(size).ok()?);
+            }
+        }
+        // 固定サイズレーシング
+        _ => {
+            let size = (data.len() - position) / frame_count;
+            sizes.resize(frame_count - 1, size);
+        }
+    }
+
+    let total = sizes.iter().sum::<usize>();
+    let last_size = (data.len() - position).checked_sub(total)?;
+    sizes.push(last_size);
+
+    let mut offset = range.start + position;
+    let mut frames = Vec::with_capacity(frame_count);
+    for size in sizes {
+        frames.push(offset..offset + size);
+        offset += size;
+    }
+    Some(frames)
+}
+
+// VP9 の場合には、CodecPrivate にレベルが指定されていなければ、解像度とフレームレート（不明な場合は 30 fps）から推定する
+fn video_codec_string(
+    codec_id: &str,
+    codec_private: &[u8],
+    (width, height): (u64, u64),
+    default_duration: Option<u64>,
+) -> orfail::Result<String> {
+    match codec_id {
+        "V_VP8" => Ok("vp8".to_owned()),
+        "V_VP9" => {
+            // CodecPrivate にはプロファイルなどが (ID, 長さ, 値) の形式で格納されていることがある
+            // https://www.webmproject.org/docs/container/#vp9-codec-feature-metadata-codecprivate
+            let mut profile = 0;
+            let mut level = None;
+            let mut bit_depth = 8;
+            let mut features = codec_private;
+            while let [id, len, rest @ ..] = features {
+                let Some((value, rest)) = rest.split_at_checked(*len as usize) else {
+                    break;
+                };
+                match (id, value) {
+                    (1, [v]) => profile = *v,
+                    (2, [v]) => level = Some(*v),
+                    (3, [v]) => bit_depth = *v,
+                    _ => {}
+                }
+                features = rest;
+            }
+            let level = level.unwrap_or_else(|| {
+                let frame_rate = default_duration
+                    .filter(|&d| d > 0)
+                    .map_or(DEFAULT_VP9_FRAME_RATE, |d| 1_000_000.0 / d as f64);
+                estimate_vp9_level(width, height, frame_rate)
+            });
+            Ok(format!("vp09.{profile:02}.{level:02}.{bit_depth:02}"))
+        }
+        "V_AV1" => {
+            // CodecPrivate には av1C ボックスのペイロードが格納されている
+            // https://github.com/ietf-wg-cellar/matroska-specification/blob/master/codec/av1.md
+            let [_, b1, b2, ..] = codec_private else {
+                return Err(Failure::new("Missing CodecPrivate for AV1"));
+            };
+            let profile = b1 >> 5;
+            let level = b1 & 0b1_1111;
+            let tier = if b2 >> 7 == 0 { 'M' } else { 'H' };
+            let bit_depth = match ((b2 >> 6) & 1, (b2 >> 5) & 1) {
+                (1, 1) => 12,
+                (1, 0) => 10,
+                _ => 8,
+            };
+            Ok(format!("av01.{profile}.{level:02}{tier}.{bit_depth:02}"))
+        }
+        // H.264 / H.265 の場合には、CodecPrivate に avcC / hvcC ボックスのペイロードが格納されている
+        // (コーデック文字列はサンプルエントリーの生成後に改めて作り直されるので、ここでは接頭辞のみでいい）
+        "V_MPEG4/ISO/AVC" => Ok("avc1.".to_owned()),
+        "V_MPEGH/ISO/HEVC" => Ok("hev1.".to_owned()),
+        _ => Err(Failure::new(format!("Unsupported video codec: {codec_id}"))),
+    }
+}
+
+fn audio_codec_string(codec_id: &str, codec_private: &[u8]) -> orfail::Result<String> {
+    match codec_id {
+        "A_OPUS" => Ok("opus".to_owned()),
+        "A_AAC" => {
+            // CodecPrivate には AudioSpecificConfig が格納されている
+            let first = codec_private
+                .first()
+                .or_fail_with(|()| "Missing CodecPrivate for AAC".to_owned())?;
+            Ok(format!("mp4a.40.{}", first >> 3))
+        }
+        _ => Err(Failure::new(format!("Unsupported audio codec: {codec_id}"))),
+    }
+}
+
+// VP9 のレベル毎の最大輝度ピクチャサイズと最大輝度サンプルレート（昇順）
+// https://www.webmproject.org/vp9/levels/
+const VP9_LEVELS: &[(u8, u64, u64)] = &[
+    (10, 36_864, 829_440),
+    (11, 73_728, 2_764_800),
+    (20, 122_880, 4_608_000),
+    (21, 245_760, 9_216_000),
+    (30, 552_960, 20_736_000),
+    (31, 983_040, 36_864_000),
+    (40, 2_228_224, 83_558_400),
+    (41, 2_228_224, 160_432_128),
+    (50, 8_912_896, 311_951_360),
+    (51, 8_912_896, 588_251_136),
+    (52, 8_912_896, 1_176_502_272),
+    (60, 35_651_584, 1_176_502_272),
+    (61, 35_651_584, 2_353_004_544),
+    (62, 35_651_584, 4_706_009_088),
+];
+
+// レベルの推定時に、フレームレートが不明な場合に仮定する値
+pub(crate) const DEFAULT_VP9_FRAME_RATE: f64 = 30.0;
+
+// 解像度とフレームレートを満たす最小の VP9 のレベルを返す
+//
+// VP9 のビットストリームにはレベルが含まれないので、コンテナ側で指定されていない場合にはこれで推定する
+pub(crate) fn estimate_vp9_level(width: u64, height: u64, frame_rate: f64) -> u8 {
+    let picture_size = width * height;
+    let sample_rate = (picture_size as f64 * frame_rate).ceil() as u64;
+    VP9_LEVELS
+        .iter()
+        .find(|&&(_, max_size, max_rate)| picture_size <= max_size && sample_rate <= max_rate)
+        .map_or(62, |&(level, ..)| level)
+}
+
+// VP9 のキーフレームのヘッダーからプロファイルとビット深度を取得する
+// (VP9 Bitstream Specification 6.2 Uncompressed header syntax)
+pub(crate) fn parse_vp9_profile_and_bit_depth(frame: &[u8]) -> Option<(u8, u8)> {
+    let mut bits = frame
+        .iter()
+        .flat_map(|b| (0..8).rev().map(move |i| (b >> i) & 1));
+    let mut read = |n: usize| -> Option<u32> {
+        (0..n).try_fold(0, |acc, _| Some((acc << 1) | bits.next()? as u32))
+    };
+
+    let frame_marker = read(2)?;
+    let profile_low_bit = read(1)?;
+    let profile_high_bit = read(1)?;
+    let profile = ((profile_high_bit << 1) | profile_low_bit) as u8;
+    if frame_marker != 2 {
+        return None;
+    }
+    if profile == 3 {
+        read(1)?; // reserved_zero
+    }
+    let show_existing_frame = read(1)?;
+    let frame_type = read(1)?;
+    if show_existing_frame == 1 || frame_type != 0 {
+        return None;
+    }
+    read(2)?; // show_frame, error_resilient_mode
+    let frame_sync_code = read(24)?;
+    if frame_sync_code != 0x498342 {
+        return None;
+    }
+
+    let bit_depth = if profile >= 2 {
+        if read(1)? == 1 {
+            12
+        } else {
+            10
+        }
+    } else {
+        8
+    };
+    Some((profile, bit_depth))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // サイズを常に 8 バイトの可変長整数で表す EBML 要素を生成する
+    fn element(id: u32, data: &[u8]) -> Vec<u8> {
+        let id_bytes = id.to_be_bytes();
+        let id_start = id_bytes.iter().position(|&b| b != 0).unwrap_or(3);
+        let mut bytes = id_bytes[id_start..].to_vec();
+        bytes.push(0x01);
+        bytes.extend_from_slice(&(data.len() as u64).to_be_bytes()[1..]);
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    fn uint_element(id: u32, value: u64) -> Vec<u8> {
+        element(id, &value.to_be_bytes())
+    }
+
+    fn simple_block(track_number: u8, timestamp: i16, is_key: bool, frame: &[u8]) -> Vec<u8> {
+        let mut data = vec![0x80 | track_number];
+        data.extend_from_slice(&timestamp.to_be_bytes());
+        data.push(if is_key { 0x80 } else { 0x00 });
+        data.extend_from_slice(frame);
+        element(ID_SIMPLE_BLOCK, &data)
+    }
+
+    fn cue_point(time: u64, track_number: u64) -> Vec<u8> {
+        let positions = uint_element(ID_CUE_TRACK, track_number);
+        [
+            uint_element(ID_CUE_TIME, time),
+            element(ID_CUE_TRACK_POSITIONS, &positions),
+        ]
+        .concat()
+    }
+
+    // 映像 (VP8, トラック番号 1) が音声 (Opus, トラック番号 2) よりも 100 ms 遅れて始まる WebM を生成する
+    //
+    // VP8 のフレームは先頭バイトの最下位ビットが 0 ならキーフレームとなる
+    fn make_webm() -> Vec<u8> {
+        let video = [
+            uint_element(ID_TRACK_NUMBER, 1),
+            uint_element(ID_TRACK_TYPE, TRACK_TYPE_VIDEO),
+            element(ID_CODEC_ID, b"V_VP8"),
+            element(
+                ID_VIDEO,
+                &[
+                    uint_element(ID_PIXEL_WIDTH, 320),
+                    uint_element(ID_PIXEL_HEIGHT, 240),
+                ]
+                .concat(),
+            ),
+        ]
+        .concat();
+        let audio = [
+            uint_element(ID_TRACK_NUMBER, 2),
+            uint_element(ID_TRACK_TYPE, TRACK_TYPE_AUDIO),
+            element(ID_CODEC_ID, b"A_OPUS"),
+            element(
+                ID_AUDIO,
+                &[
+                    element(ID_SAMPLING_FREQUENCY, &48000.0f64.to_be_bytes()),
+                    uint_element(ID_CHANNELS, 2),
+                ]
+                .concat(),
+            ),
+        ]
+        .concat();
+        let tracks = [
+            element(ID_TRACK_ENTRY, &video),
+            element(ID_TRACK_ENTRY, &audio),
+        ]
+        .concat();
+
+        let cluster = [
+            uint_element(ID_CLUSTER_TIMESTAMP, 0),
+            simple_block(2, 0, true, &[0xFC; 4]),
+            simple_block(2, 20, true, &[0xFC; 4]),
+            simple_block(2, 40, true, &[0xFC; 4]),
+            // キーフレームフラグ付き
+            simple_block(1, 100, true, &[0x00; 4]),
+            simple_block(1, 133, false, &[0x01; 4]),
+            // Cues に記載されていて、ビットストリーム上もキーフレーム
+            simple_block(1, 166, false, &[0x00; 4]),
+            // Cues に記載されているが、ビットストリーム上はキーフレームではない
+            simple_block(1, 200, false, &[0x01; 4]),
+            // Cues に記載されていない
+            simple_block(1, 233, false, &[0x00; 4]),
+        ]
+        .concat();
+        let cues = [
+            element(ID_CUE_POINT, &cue_point(166, 1)),
+            element(ID_CUE_POINT, &cue_point(200, 1)),
+        ]
+        .concat();
+
+        let segment = [
+            element(
+                ID_INFO,
+                &uint_element(ID_TIMESTAMP_SCALE, DEFAULT_TIMESTAMP_SCALE),
+            ),
+            element(ID_TRACKS, &tracks),
+            element(ID_CLUSTER, &cluster),
+            element(ID_CUES, &cues),
+        ]
+        .concat();
+        [
+            element(ID_EBML, &element(ID_DOC_TYPE, b"webm")),
+            element(ID_SEGMENT, &segment),
+        ]
+        .concat()
+    }
+
+    fn find_track(mp4: &Mp4, kind: TrackKind) -> &Track {
+        mp4.tracks
+            .iter()
+            .find(|t| t.kind == kind)
+            .expect("track not found")
+    }
+
+    #[test]
+    fn track_start_offsets_are_kept() {
+        let mp4 = load_webm(&make_webm()).expect("failed to load WebM");
+        assert_eq!(
+            find_track(&mp4, TrackKind::Audio).start_offset(),
+            Duration::ZERO
+        );
+        assert_eq!(
+            find_track(&mp4, TrackKind::Video).start_offset(),
+            Duration::from_millis(100)
+        );
+    }
+
+    #[test]
+    fn cues_only_confirm_key_frames() {
+        let mp4 = load_webm(&make_webm()).expect("failed to load WebM");
+        let video = find_track(&mp4, TrackKind::Video);
+        let sync = video
+            .sample_table
+            .samples()
+            .map(|s| s.is_sync_sample())
+            .collect::<Vec<_>>();
+        assert_eq!(sync, [true, false, true, false, false]);
+    }
+
+    #[test]
+    fn read_vint_table() {
+        let cases = [
+            (vec![], None),
+            (vec![0x81], Some((1, 1))),
+            (vec![0xFF], Some((0x7F, 1))),
+            (vec![0x40, 0x02], Some((2, 2))),
+            (vec![0x40], None),
+            (vec![0x1A, 0x45, 0xDF, 0xA3], Some((0x0A45_DFA3, 4))),
+            (vec![0x01, 0, 0, 0, 0, 0, 0x01, 0x05], Some((0x0105, 8))),
+            (
+                vec![0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF],
+                Some(((1 << 56) - 1, 8)),
+            ),
+        ];
+        for (data, expected) in cases {
+            assert_eq!(read_vint(&data).ok(), Some(expected), "{data:02x?}");
+        }
+
+        // 9 バイト以上の長さはエラー
+        assert!(read_vint(&[0x00, 0x01]).is_err());
+    }
+
+    #[test]
+    fn split_laced_frames_table() {
+        // (フラグ, ブロックのフレーム部分, 期待するフレーム毎のサイズ)
+        let cases = [
+            // レーシングなし
+            (0x80, vec![0; 5], Some(vec![5])),
+            // Xiph レーシング（255 以上のサイズは 0xFF の並びで表される）
+            (
+                0b010,
+                [&[2, 0xFF, 0x01, 3][..], &[0; 256 + 3 + 2]].concat(),
+                Some(vec![256, 3, 2]),
+            ),
+            // Xiph レーシングで、サイズの合計がデータの長さを超えている
+            (0b010, vec![1, 10, 0, 0], None),
+            // 固定サイズレーシング
+            (0b100, [&[2][..], &[0; 9]].concat(), Some(vec![3, 3, 3])),
+            // EBML レーシング（二番目のサイズは 3 + 1 = 4）
+            (
+                0b110,
+                [&[2, 0x83, 0xC0][..], &[0; 3 + 4 + 2]].concat(),
+                Some(vec![3, 4, 2]),
+            ),
+            // EBML レーシングで、差分が負になる（二番目のサイズは 3 - 2 = 1）
+            (
+                0b110,
+                [&[2, 0x83, 0xBD][..], &[0; 3 + 1 + 5]].concat(),
+                Some(vec![3, 1, 5]),
+            ),
+            // フレーム数がない
+            (0b110, vec![], None),
+        ];
+        for (flags, frames, expected) in cases {
+            // ブロックがファイルの途中にある場合を模擬するために、先頭にダミーのバイト列を置く
+            let prefix = [0xAA; 7];
+            let bytes = [&prefix[..], &frames].concat();
+            let actual = split_laced_frames(&bytes, prefix.len()..bytes.len(), flags);
+            let sizes = actual
+                .as_ref()
+                .map(|ranges| ranges.iter().map(|r| r.len()).collect::<Vec<_>>());
+            assert_eq!(sizes, expected, "flags={flags:#b}");
+            if let Some(ranges) = actual {
+                assert_eq!(ranges.last().map(|r| r.end), Some(bytes.len()));
+                assert!(ranges.windows(2).all(|w| w[0].end == w[1].start));
+            }
+        }
+    }
+
+    #[test]
+    fn vp9_level_is_estimated_from_resolution_and_frame_rate() {
+        let cases = [
+            (256, 144, 20.0, 10),
+            (256, 144, 30.0, 11),
+            (320, 240, 30.0, 20),
+            (1280, 720, 30.0, 31),
+            (1920, 1080, 30.0, 40),
+            (1920, 1080, 60.0, 41),
+            (3840, 2160, 30.0, 50),
+            (16384, 16384, 120.0, 62),
+        ];
+        for (width, height, frame_rate, expected) in cases {
+            assert_eq!(
+                estimate_vp9_level(width, height, frame_rate),
+                expected,
+                "{width}x{height}@{frame_rate}"
+            );
+        }
+    }
+}